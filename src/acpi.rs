@@ -0,0 +1,143 @@
+use crate::memory;
+use crate::sync::IrqMutex;
+use core::convert::TryInto;
+use core::slice;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the BIOS keeps the EBDA's segment here, the RSDP may be in the EBDA's first KiB
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LENGTH: u64 = 1024;
+// otherwise it is somewhere in the BIOS area, on a 16 byte boundary
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+const RSDP_ALIGNMENT: u64 = 16;
+
+// RSDP fields: ACPI 1.0 has the RSDT's address, 2.0 extends it with the XSDT's, each part with its own checksum
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT_ADDRESS: usize = 16;
+const RSDP_XSDT_ADDRESS: usize = 24;
+
+// every table starts with a header: its signature, its length, and some IDs to be checksummed along
+const HEADER_LENGTH: usize = 36;
+const HEADER_LENGTH_OFFSET: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    // the firmware has no ACPI, or it was not found
+    NoRsdp,
+    // a table's bytes don't add up to 0
+    BadChecksum([u8; 4]),
+    NoTable([u8; 4])
+}
+
+#[derive(Clone, Copy)]
+struct RootTable {
+    address: PhysAddr,
+    // the XSDT lists its tables with 8 byte addresses, the RSDT with 4 byte ones
+    entry_size: usize
+}
+
+static ROOT: IrqMutex<Option<RootTable>> = IrqMutex::new(None);
+
+/**
+ * Finds the RSDP and checks the root table it points to, so the other tables can be looked up.
+ * Needs memory::init() first.
+ */
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    // some firmware fills in a revision 2 RSDP without an XSDT
+    let root = if rsdp[RSDP_REVISION] >= 2 && read_u64(rsdp, RSDP_XSDT_ADDRESS) != 0 {
+        RootTable {
+            address: PhysAddr::new(read_u64(rsdp, RSDP_XSDT_ADDRESS)),
+            entry_size: 8
+        }
+    } else {
+        RootTable {
+            address: PhysAddr::new(u64::from(read_u32(rsdp, RSDP_RSDT_ADDRESS))),
+            entry_size: 4
+        }
+    };
+    table_at(root.address)?;
+    *ROOT.lock() = Some(root);
+    Ok(())
+}
+
+/**
+ * Returns the table with the given signature, e.g. `b"HPET"` or `b"APIC"`, header included.
+ */
+pub fn find_table(signature: &[u8; 4]) -> Result<&'static [u8], AcpiError> {
+    let root = (*ROOT.lock()).ok_or(AcpiError::NoRsdp)?;
+    let entries = &table_at(root.address)?[HEADER_LENGTH..];
+    for entry in entries.chunks_exact(root.entry_size) {
+        let address = if root.entry_size == 8 { read_u64(entry, 0) } else { u64::from(read_u32(entry, 0)) };
+        let address = PhysAddr::new(address);
+        if read_physical(address, 4) == signature {
+            return table_at(address);
+        }
+    }
+    Err(AcpiError::NoTable(*signature))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/**
+ * Returns the table at the given address, after checking its checksum.
+ */
+fn table_at(address: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header = read_physical(address, HEADER_LENGTH);
+    let length = read_u32(header, HEADER_LENGTH_OFFSET) as usize;
+    let table = read_physical(address, length.max(HEADER_LENGTH));
+    if !checksum_ok(table) {
+        let mut signature = [0; 4];
+        signature.copy_from_slice(&header[..4]);
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok(table)
+}
+
+fn find_rsdp() -> Option<&'static [u8]> {
+    let segment = read_physical(PhysAddr::new(EBDA_SEGMENT_POINTER), 2);
+    let ebda = u64::from(u16::from_le_bytes([segment[0], segment[1]])) << 4;
+    let areas = [(ebda, ebda + EBDA_SEARCH_LENGTH), (BIOS_AREA_START, BIOS_AREA_END)];
+    for &(start, end) in areas.iter() {
+        // a missing EBDA leaves its segment at 0
+        if start == 0 {
+            continue;
+        }
+        let mut address = start;
+        while address + RSDP_V1_LENGTH as u64 <= end {
+            let rsdp = read_physical(PhysAddr::new(address), RSDP_V1_LENGTH);
+            if &rsdp[..8] == RSDP_SIGNATURE && checksum_ok(rsdp) {
+                let length = if rsdp[RSDP_REVISION] >= 2 { RSDP_V2_LENGTH } else { RSDP_V1_LENGTH };
+                let rsdp = read_physical(PhysAddr::new(address), length);
+                if checksum_ok(rsdp) {
+                    return Some(rsdp);
+                }
+            }
+            address += RSDP_ALIGNMENT;
+        }
+    }
+    None
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/**
+ * Returns the given length of physical memory, through the bootloader's mapping.
+ * The firmware's tables are never freed or moved, so they can be kept around.
+ */
+fn read_physical(address: PhysAddr, length: usize) -> &'static [u8] {
+    let virtual_address = memory::phys_to_virt(address);
+    unsafe { slice::from_raw_parts(virtual_address.as_ptr(), length) }
+}
@@ -0,0 +1,295 @@
+use crate::cow;
+use crate::demand;
+use crate::frame_allocator::{self, GlobalFrameAllocator};
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, PagingError};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    UnusedPhysFrame};
+use x86_64::structures::paging::page_table::PageTableEntry;
+
+const ENTRIES: usize = 512;
+
+/**
+ * A set of page tables of its own, e.g. for a process, with the kernel's mappings shared in.
+ *
+ * The level 4 entries the kernel's table uses are copied in, so the kernel's tables below them are shared,
+ * and changes to them through the paging module show up everywhere. The other level 4 entries are the space's own.
+ * A level 4 entry the kernel starts using later only shows up after share_kernel().
+ */
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // the level 4 entries shared with the kernel, one bit each
+    kernel_entries: [u64; ENTRIES / 64]
+}
+
+impl AddressSpace {
+    /**
+     * Makes a space with nothing but the kernel's mappings. Needs paging::init().
+     */
+    pub fn new() -> Result<AddressSpace, PagingError> {
+        let kernel = paging::kernel_level_4_frame().ok_or(PagingError::NotInitialized)?;
+        let level_4_frame = frame_allocator::allocate_frame().ok_or(PagingError::FrameAllocationFailed)?;
+        memory::frames_taken(Subsystem::PageTables, 1);
+        table(level_4_frame).zero();
+        let mut space = AddressSpace {
+            level_4_frame,
+            kernel_entries: [0; ENTRIES / 64]
+        };
+        space.share_kernel_from(kernel);
+        Ok(space)
+    }
+
+    /**
+     * Copies in the level 4 entries the kernel started using since new().
+     */
+    pub fn share_kernel(&mut self) -> Result<(), PagingError> {
+        let kernel = paging::kernel_level_4_frame().ok_or(PagingError::NotInitialized)?;
+        self.share_kernel_from(kernel);
+        Ok(())
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /**
+     * Tells whether the CPU is using the space's tables.
+     */
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /**
+     * Loads the space's tables into CR3, which flushes the TLB of everything but global pages.
+     *
+     * # Safety
+     *
+     * The space must outlive its use, so something else has to be switched to before it is dropped.
+     */
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /**
+     * Maps a page of the space to a frame. The page must be outside the level 4 entries shared with the kernel.
+     * Only flushes the TLB while the space is active.
+     *
+     * # Safety
+     *
+     * The frame must not be in use elsewhere, e.g. one from frame_allocator::allocate_frame(), or one the page is
+     * meant to share, like a copy-on-write one.
+     */
+    pub unsafe fn map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), PagingError> {
+        self.check_own(page)?;
+        let active = self.is_active();
+        let flush = self.mapper().map_to(page, UnusedPhysFrame::new(frame), flags, &mut GlobalFrameAllocator)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        open_to_user(self.level_4_frame, page, flags);
+        Ok(())
+    }
+
+    /**
+     * Reserves pages of the space without frames, each getting a zeroed one with the flags the first time it is
+     * touched, see the demand module. The pages must be outside the level 4 entries shared with the kernel.
+     */
+    pub fn reserve(&mut self, start: Page, pages: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+        self.check_own(start)?;
+        self.check_own(start + pages.saturating_sub(1))?;
+        demand::reserve(Some(self.level_4_frame), start, pages, flags)
+    }
+
+    /**
+     * Removes a page's mapping from the space. Returns the frame it was mapped to, which is not freed.
+     */
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, PagingError> {
+        self.check_own(page)?;
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(frame)
+    }
+
+    pub fn translate_addr(&mut self, address: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(address)
+    }
+
+    /**
+     * Returns the flags a page is mapped with in the space, None if it is not present or in a huge page.
+     */
+    pub fn page_flags(&self, page: Page) -> Option<PageTableFlags> {
+        let mut frame = self.level_4_frame;
+        for &index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+            let entry = &table(frame)[index];
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            frame = PhysFrame::containing_address(entry.addr());
+        }
+        Some(table(frame)[page.p1_index()].flags()).filter(|flags| flags.contains(PageTableFlags::PRESENT))
+    }
+
+    /**
+     * Makes a copy of the space sharing its frames, for fork(). Writable pages become copy-on-write in both,
+     * read-only ones are just shared, see the cow module. Only 4 KiB pages can be shared.
+     */
+    pub fn fork(&mut self) -> Result<AddressSpace, PagingError> {
+        let child = AddressSpace::new()?;
+        let active = self.is_active();
+        let level_4 = table(self.level_4_frame);
+        let child_level_4 = table(child.level_4_frame);
+        for index in 0..ENTRIES {
+            if self.is_kernel_entry(index) || level_4[index].is_unused() {
+                continue;
+            }
+            if child.is_kernel_entry(index) {
+                return Err(PagingError::KernelPage);
+            }
+            let copy = fork_table(&mut level_4[index], 3)?;
+            child_level_4[index].set_frame(copy, level_4[index].flags());
+        }
+        // the space's writable pages are read-only now
+        if active {
+            tlb::flush_all();
+        }
+        demand::copy_reservations(self.level_4_frame, child.level_4_frame);
+        Ok(child)
+    }
+
+    fn share_kernel_from(&mut self, kernel: PhysFrame) {
+        let kernel_table = table(kernel);
+        let own_table = table(self.level_4_frame);
+        for index in 0..ENTRIES {
+            if !kernel_table[index].is_unused() && own_table[index].is_unused() {
+                own_table[index] = kernel_table[index].clone();
+                self.kernel_entries[index / 64] |= 1 << (index % 64);
+            }
+        }
+    }
+
+    fn is_kernel_entry(&self, index: usize) -> bool {
+        self.kernel_entries[index / 64] & 1 << (index % 64) != 0
+    }
+
+    fn check_own(&self, page: Page) -> Result<(), PagingError> {
+        if self.is_kernel_entry(usize::from(u16::from(page.p4_index()))) {
+            return Err(PagingError::KernelPage);
+        }
+        Ok(())
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = memory::phys_to_virt(PhysAddr::new(0));
+        // the table is only reached through &mut self
+        unsafe { OffsetPageTable::new(table(self.level_4_frame), physical_memory_offset) }
+    }
+}
+
+/**
+ * Frees the space's own page tables, and the SHARED frames it had the last mapping of, but not the other frames
+ * mapped in them. Switches to the kernel's tables first if the space is active.
+ */
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            if let Some(kernel) = paging::kernel_level_4_frame() {
+                unsafe { Cr3::write(kernel, Cr3Flags::empty()) };
+            }
+        }
+        let level_4 = table(self.level_4_frame);
+        for index in 0..ENTRIES {
+            if !self.is_kernel_entry(index) && !level_4[index].is_unused() {
+                free_table(&level_4[index], 3);
+            }
+        }
+        demand::forget(self.level_4_frame);
+        if frame_allocator::free_frame(self.level_4_frame).is_ok() {
+            memory::frames_freed(Subsystem::PageTables, 1);
+        }
+    }
+}
+
+/**
+ * Lets ring 3 through the tables above a user page. The x86_64 crate makes new tables kernel only,
+ * and the CPU checks every level.
+ */
+pub(crate) fn open_to_user(level_4_frame: PhysFrame, page: Page, flags: PageTableFlags) {
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return;
+    }
+    let mut frame = level_4_frame;
+    for &index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+        let entry = &mut table(frame)[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+}
+
+/**
+ * Frees the table an entry points to, and the tables below it, down to level 1. Huge pages have no table.
+ */
+fn free_table(entry: &PageTableEntry, level: u8) {
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    for lower in table(frame).iter().filter(|lower| !lower.is_unused()) {
+        if level > 1 {
+            free_table(lower, level - 1);
+        } else if lower.flags().contains(cow::SHARED) {
+            let shared = PhysFrame::containing_address(lower.addr());
+            if cow::release(shared) && frame_allocator::free_frame(shared).is_ok() {
+                memory::frames_freed(Subsystem::UserPages, 1);
+            }
+        }
+    }
+    if frame_allocator::free_frame(frame).is_ok() {
+        memory::frames_freed(Subsystem::PageTables, 1);
+    }
+}
+
+/**
+ * Copies the table an entry points to, and the tables below it, sharing the frames mapped at level 1.
+ * Returns the copy's frame.
+ */
+fn fork_table(entry: &mut PageTableEntry, level: u8) -> Result<PhysFrame, PagingError> {
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(PagingError::HugePage);
+    }
+    let copy = frame_allocator::allocate_frame().ok_or(PagingError::FrameAllocationFailed)?;
+    memory::frames_taken(Subsystem::PageTables, 1);
+    table(copy).zero();
+    let original = table(PhysFrame::containing_address(entry.addr()));
+    for (lower, lower_copy) in original.iter_mut().zip(table(copy).iter_mut()) {
+        if lower.is_unused() {
+            continue;
+        }
+        if level > 1 {
+            let flags = lower.flags();
+            lower_copy.set_frame(fork_table(lower, level - 1)?, flags);
+            continue;
+        }
+        let mut flags = lower.flags() | cow::SHARED;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | cow::COPY_ON_WRITE;
+        }
+        lower.set_flags(flags);
+        lower_copy.set_addr(lower.addr(), flags);
+        cow::share(PhysFrame::containing_address(lower.addr()));
+    }
+    Ok(copy)
+}
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
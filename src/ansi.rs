@@ -0,0 +1,168 @@
+const ESCAPE: u8 = 0x1B;
+const MAX_PARAMS: usize = 8;
+
+/**
+ * A decoded piece of output: either a byte to put on the screen, or a control sequence for the writer to carry out.
+ * Parameters are kept as they were sent, so a missing or zero parameter is left to the writer to default.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(u8),
+    SelectGraphicRendition(Params),
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    CursorPosition { row: usize, column: usize },
+    EraseInDisplay(usize),
+    EraseInLine(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [usize; MAX_PARAMS],
+    len: usize
+}
+
+impl Params {
+    const fn new() -> Params {
+        Params {
+            values: [0; MAX_PARAMS],
+            len: 0
+        }
+    }
+
+    /**
+     * Returns the parameter at the given position, or 0 if it was omitted.
+     */
+    pub fn get(&self, index: usize) -> usize {
+        if index < self.len {
+            self.values[index]
+        } else {
+            0
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &usize> {
+        self.values[..self.len].iter()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    ControlSequence
+}
+
+/**
+ * A byte-at-a-time parser for the subset of ANSI/VT100 escape sequences the console understands:
+ * ESC [ <params> m (colors), ESC [ <n> A/B/C/D (cursor movement), ESC [ <row> ; <col> H/f (cursor position),
+ * ESC [ <n> J (erase display) and ESC [ <n> K (erase line).
+ * Sequences the console does not know are swallowed silently instead of being printed as garbage.
+ */
+pub struct Parser {
+    state: State,
+    params: Params
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: Params::new()
+        }
+    }
+
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => {
+                if byte == ESCAPE {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Action::Print(byte))
+                }
+            }
+            State::Escape => {
+                if byte == b'[' {
+                    self.params = Params::new();
+                    self.state = State::ControlSequence;
+                } else {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::ControlSequence => self.advance_control_sequence(byte)
+        }
+    }
+
+    fn advance_control_sequence(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.params.len == 0 {
+                    self.params.len = 1;
+                }
+                let index = self.params.len - 1;
+                let digit = usize::from(byte - b'0');
+                self.params.values[index] = self.params.values[index].saturating_mul(10).saturating_add(digit);
+                None
+            }
+            b';' => {
+                if self.params.len == 0 {
+                    self.params.len = 1;
+                }
+                if self.params.len < MAX_PARAMS {
+                    self.params.len += 1;
+                }
+                None
+            }
+            // final byte, the sequence is complete
+            0x40..=0x7E => {
+                self.state = State::Ground;
+                self.dispatch(byte)
+            }
+            // intermediate and private marker bytes are accepted but ignored
+            0x20..=0x3F => None,
+            // anything else is not part of a valid sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn dispatch(&self, final_byte: u8) -> Option<Action> {
+        let params = self.params;
+        let count = params.get(0).max(1);
+
+        match final_byte {
+            b'm' => Some(Action::SelectGraphicRendition(params)),
+            b'A' => Some(Action::CursorUp(count)),
+            b'B' => Some(Action::CursorDown(count)),
+            b'C' => Some(Action::CursorForward(count)),
+            b'D' => Some(Action::CursorBack(count)),
+            b'H' | b'f' => Some(Action::CursorPosition {
+                row: params.get(0).max(1),
+                column: params.get(1).max(1)
+            }),
+            b'J' => Some(Action::EraseInDisplay(params.get(0))),
+            b'K' => Some(Action::EraseInLine(params.get(0))),
+            _ => None
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
@@ -0,0 +1,281 @@
+use crate::paging::{self, PagingError};
+use crate::time;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+
+// CPUID leaf 1 EDX tells whether there is a local APIC, ECX whether it can be an x2APIC
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_APIC: u32 = 1 << 9;
+const CPUID_X2APIC: u32 = 1 << 21;
+// and EBX's top byte the initial APIC ID
+const CPUID_INITIAL_ID_SHIFT: u32 = 24;
+// the MSR holding the registers' physical address and the enable bits
+const APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const REGISTERS_SIZE: u64 = 0x1000;
+// in x2APIC mode the registers are MSRs from here on, one for each 16 bytes of the MMIO layout
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// registers, at byte offsets from the base address
+const ID: usize = 0x020;
+const EOI: usize = 0x0B0;
+const SPURIOUS_VECTOR: usize = 0x0F0;
+// the interrupt command register, a single 64 bit MSR in x2APIC mode
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+// spurious vector register: software enable
+const APIC_ENABLE: u32 = 1 << 8;
+// LVT entries
+const MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+// ICR: delivery modes, the xAPIC's busy bit, level and the destination shorthand
+const DELIVERY_FIXED: u32 = 0b000 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+// the xAPIC takes the destination APIC ID in the top byte of the upper half, the x2APIC all of the upper half
+const XAPIC_DESTINATION_SHIFT: u32 = 24;
+// the timer counts down at the bus clock divided by 16
+const DIVIDE_BY_16: u32 = 0x3;
+
+/**
+ * The interrupt the local APIC raises when it has nothing to deliver after all. It must not be acknowledged.
+ */
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xFF;
+
+// the registers' virtual address, 0 without a local APIC, and the timer's rate in counts a second
+static BASE: AtomicU64 = AtomicU64::new(0);
+// the registers are MSRs instead, BASE is still set
+static X2APIC: AtomicBool = AtomicBool::new(false);
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    // the CPU has no local APIC
+    NotPresent,
+    NotAvailable,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for ApicError {
+    fn from(error: PagingError) -> ApicError {
+        ApicError::Paging(error)
+    }
+}
+
+/**
+ * How the timer counts: once down to 0, or over and over.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic
+}
+
+/**
+ * Which CPUs an inter-processor interrupt goes to.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    // the CPU with the given local APIC ID
+    Apic(u32),
+    // every CPU but the sending one
+    AllExcludingSelf
+}
+
+/**
+ * Enables this CPU's local APIC, as an x2APIC if it can be one, and measures its timer's rate against the PIT,
+ * with the timer stopped. Interrupts from the PICs still come through, on the APIC's LINT0 as the firmware set it up.
+ * Must run with interrupts disabled.
+ */
+pub fn init() -> Result<(), ApicError> {
+    let features = unsafe { __cpuid(CPUID_FEATURES) };
+    if features.edx & CPUID_APIC == 0 {
+        return Err(ApicError::NotPresent);
+    }
+    let x2apic = features.ecx & CPUID_X2APIC != 0;
+    let mut base_msr = Msr::new(APIC_BASE_MSR);
+    let apic_base = unsafe { base_msr.read() };
+    // the x2APIC mode can only be entered from the enabled xAPIC mode
+    unsafe { base_msr.write(apic_base | APIC_BASE_ENABLE) };
+    if x2apic {
+        unsafe { base_msr.write(apic_base | APIC_BASE_ENABLE | APIC_BASE_X2APIC) };
+    }
+    X2APIC.store(x2apic, Ordering::Relaxed);
+    let base = paging::map_mmio(PhysAddr::new(apic_base & APIC_BASE_ADDRESS_MASK), REGISTERS_SIZE)?.as_u64();
+    BASE.store(base, Ordering::Relaxed);
+
+    write(base, SPURIOUS_VECTOR, APIC_ENABLE | u32::from(SPURIOUS_INTERRUPT_VECTOR));
+
+    // the timer counts down from the top while the PIT times a known interval
+    write(base, TIMER_DIVIDE, DIVIDE_BY_16);
+    write(base, LVT_TIMER, MASKED);
+    write(base, TIMER_INITIAL_COUNT, u32::max_value());
+    time::count_down(time::CALIBRATION_COUNT);
+    let elapsed = u32::max_value() - read(base, TIMER_CURRENT_COUNT);
+    write(base, TIMER_INITIAL_COUNT, 0);
+    TIMER_FREQUENCY.store(
+        u64::from(elapsed) * u64::from(time::PIT_FREQUENCY) / u64::from(time::CALIBRATION_COUNT),
+        Ordering::Relaxed
+    );
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/**
+ * Tells whether the registers are reached through MSRs, which also lifts the 255 CPU limit of APIC IDs.
+ */
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+/**
+ * Returns this CPU's local APIC ID, which interrupts are sent to.
+ */
+pub fn id() -> u32 {
+    match BASE.load(Ordering::Relaxed) {
+        0 => 0,
+        // the xAPIC keeps its 8 bit ID in the top byte
+        base if is_x2apic() => read(base, ID),
+        base => read(base, ID) >> 24
+    }
+}
+
+/**
+ * Returns the 8 bit APIC ID the CPU started with, from CPUID, which is there even before init().
+ */
+pub fn initial_id() -> u32 {
+    let features = unsafe { __cpuid(CPUID_FEATURES) };
+    features.ebx >> CPUID_INITIAL_ID_SHIFT
+}
+
+/**
+ * Returns the timer's rate in counts a second, 0 without a local APIC.
+ */
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/**
+ * Starts the timer raising the given vector after about the given number of nanoseconds, once or periodically.
+ * Returns the interval it really got.
+ */
+pub fn start_timer(vector: u8, interval_ns: u64, mode: TimerMode) -> Result<u64, ApicError> {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return Err(ApicError::NotAvailable);
+    }
+    let frequency = timer_frequency();
+    if frequency == 0 {
+        return Err(ApicError::NotAvailable);
+    }
+    let count = (u128::from(interval_ns) * u128::from(frequency) / 1_000_000_000)
+        .max(1)
+        .min(u128::from(u32::max_value())) as u32;
+    let periodic = match mode {
+        TimerMode::OneShot => 0,
+        TimerMode::Periodic => TIMER_PERIODIC
+    };
+    write(base, LVT_TIMER, periodic | u32::from(vector));
+    // writing the count starts the timer
+    write(base, TIMER_INITIAL_COUNT, count);
+    Ok(u64::from(count) * 1_000_000_000 / frequency)
+}
+
+pub fn stop_timer() {
+    let base = BASE.load(Ordering::Relaxed);
+    if base != 0 {
+        write(base, LVT_TIMER, MASKED);
+        write(base, TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+/**
+ * Acknowledges the interrupt being handled, for those delivered by the local APIC itself.
+ */
+pub fn eoi() {
+    let base = BASE.load(Ordering::Relaxed);
+    if base != 0 {
+        write(base, EOI, 0);
+    }
+}
+
+/**
+ * Raises the given vector on other CPUs, e.g. interrupts::RESCHEDULE_VECTOR.
+ */
+pub fn send_ipi(destination: IpiDestination, vector: u8) -> Result<(), ApicError> {
+    send_command(destination, DELIVERY_FIXED | u32::from(vector))
+}
+
+/**
+ * Sends an INIT IPI, which resets the destination CPUs to wait for a startup IPI.
+ */
+pub fn send_init(destination: IpiDestination) -> Result<(), ApicError> {
+    send_command(destination, DELIVERY_INIT | LEVEL_ASSERT)
+}
+
+/**
+ * Sends a startup IPI, which starts the destination CPUs in real mode at the given 4 KiB page, e.g. 0x08 for 0x8000.
+ * The CPUs must have had an INIT IPI first, the specification sends it twice.
+ */
+pub fn send_startup(destination: IpiDestination, page: u8) -> Result<(), ApicError> {
+    send_command(destination, DELIVERY_STARTUP | LEVEL_ASSERT | u32::from(page))
+}
+
+/**
+ * Writes the interrupt command register and waits until the xAPIC has taken the IPI.
+ */
+fn send_command(destination: IpiDestination, command: u32) -> Result<(), ApicError> {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return Err(ApicError::NotAvailable);
+    }
+    let (command, apic_id) = match destination {
+        IpiDestination::Apic(apic_id) => (command, apic_id),
+        IpiDestination::AllExcludingSelf => (command | ALL_EXCLUDING_SELF, 0)
+    };
+    if is_x2apic() {
+        // a single write sends it, there is nothing to wait for
+        unsafe { x2apic_msr(ICR_LOW).write(u64::from(apic_id) << 32 | u64::from(command)) };
+        return Ok(());
+    }
+    // writing the lower half sends it, so the destination goes in first
+    write(base, ICR_HIGH, apic_id << XAPIC_DESTINATION_SHIFT);
+    write(base, ICR_LOW, command);
+    while read(base, ICR_LOW) & DELIVERY_PENDING != 0 {}
+    Ok(())
+}
+
+fn read(base: u64, register: usize) -> u32 {
+    if is_x2apic() {
+        return unsafe { x2apic_msr(register).read() } as u32;
+    }
+    unsafe { ptr::read_volatile((base as usize + register) as *const u32) }
+}
+
+fn write(base: u64, register: usize, value: u32) {
+    if is_x2apic() {
+        unsafe { x2apic_msr(register).write(u64::from(value)) };
+        return;
+    }
+    unsafe { ptr::write_volatile((base as usize + register) as *mut u32, value) }
+}
+
+fn x2apic_msr(register: usize) -> Msr {
+    Msr::new(X2APIC_MSR_BASE + (register >> 4) as u32)
+}
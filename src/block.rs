@@ -0,0 +1,175 @@
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // the blocks go past the end of the device
+    OutOfRange,
+    // the buffer is not a whole number of blocks
+    BadLength,
+    // the device can't be written
+    ReadOnly,
+    // the device reported an error, or did not answer
+    Io,
+    // there is a device by that name already
+    AlreadyRegistered,
+    EmptyName
+}
+
+/**
+ * A disk, or anything else read and written in blocks of a fixed size, by block number. Implementations lock what
+ * they share themselves, a file system may be reading more files from it at once.
+ */
+pub trait BlockDevice: Send + Sync {
+    /**
+     * The size of a block in bytes, a power of two, 512 for most disks.
+     */
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /**
+     * Reads blocks from the given one on, as many as fit in the buffer, which has to be a whole number of them.
+     */
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /**
+     * Writes the buffer's blocks from the given one on.
+     */
+    fn write_blocks(&self, _block: u64, _buffer: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+}
+
+lazy_static! {
+    /** The block devices file systems can be mounted from, by name, which their drivers give them. */
+    static ref DEVICES: IrqMutex<BTreeMap<String, Arc<dyn BlockDevice>>> = IrqMutex::new(BTreeMap::new());
+}
+
+/**
+ * Makes a block device known under the given name, e.g. for fat::mount().
+ */
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    if name.is_empty() {
+        return Err(BlockError::EmptyName);
+    }
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(BlockError::AlreadyRegistered);
+    }
+    devices.insert(String::from(name), device);
+    Ok(())
+}
+
+/**
+ * Returns the block device registered under the name.
+ */
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/**
+ * Lists the names of the registered block devices, in order.
+ */
+pub fn devices() -> Vec<String> {
+    DEVICES.lock().keys().cloned().collect()
+}
+
+/**
+ * Reads from any byte offset of a device, reading the blocks the range is in, for structures that are not
+ * aligned to blocks, e.g. a file system's. The whole blocks in the range are read right into the buffer.
+ */
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let end = offset.checked_add(buffer.len() as u64).ok_or(BlockError::OutOfRange)?;
+    if end > device.block_count() * block_size as u64 {
+        return Err(BlockError::OutOfRange);
+    }
+    let mut bounce = Vec::new();
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let block = position / block_size as u64;
+        let start = (position % block_size as u64) as usize;
+        let whole = (buffer.len() - done) / block_size * block_size;
+        if start == 0 && whole > 0 {
+            device.read_blocks(block, &mut buffer[done..done + whole])?;
+            done += whole;
+            continue;
+        }
+        bounce.resize(block_size, 0);
+        device.read_blocks(block, &mut bounce)?;
+        let chunk = (block_size - start).min(buffer.len() - done);
+        buffer[done..done + chunk].copy_from_slice(&bounce[start..start + chunk]);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/**
+ * A block device in memory, e.g. for a disk image shipped in the initrd, read with vfs::read_all().
+ */
+pub struct RamDisk {
+    block_size: usize,
+    bytes: IrqMutex<Vec<u8>>
+}
+
+impl RamDisk {
+    /**
+     * Makes a disk of the given bytes, padded with zeros to a whole block. The block size has to be a power of two.
+     */
+    pub fn new(mut bytes: Vec<u8>, block_size: usize) -> RamDisk {
+        assert!(block_size.is_power_of_two(), "the block size of a RAM disk has to be a power of two");
+        let length = (bytes.len() + block_size - 1) / block_size * block_size;
+        bytes.resize(length, 0);
+        RamDisk {
+            block_size,
+            bytes: IrqMutex::new(bytes)
+        }
+    }
+
+    /**
+     * Makes a disk of zeros, of the given number of blocks.
+     */
+    pub fn empty(block_count: usize, block_size: usize) -> RamDisk {
+        RamDisk::new(vec![0; block_count * block_size], block_size)
+    }
+
+    fn range(&self, block: u64, length: usize, size: usize) -> Result<(usize, usize), BlockError> {
+        if length % self.block_size != 0 {
+            return Err(BlockError::BadLength);
+        }
+        let start = (block as usize).checked_mul(self.block_size).ok_or(BlockError::OutOfRange)?;
+        let end = start.checked_add(length).filter(|&end| end <= size).ok_or(BlockError::OutOfRange)?;
+        Ok((start, end))
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.bytes.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let bytes = self.bytes.lock();
+        let (start, end) = self.range(block, buffer.len(), bytes.len())?;
+        buffer.copy_from_slice(&bytes[start..end]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let mut bytes = self.bytes.lock();
+        let (start, end) = self.range(block, buffer.len(), bytes.len())?;
+        bytes[start..end].copy_from_slice(buffer);
+        Ok(())
+    }
+}
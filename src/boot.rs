@@ -0,0 +1,149 @@
+use crate::{debug_println, print, print_colored, println};
+use crate::sync::IrqMutex;
+use crate::time;
+use crate::vga_buffer::Colors;
+use core::convert::Infallible;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// stages recorded for timings(), later ones are still run but not recorded
+const MAX_STAGES: usize = 32;
+
+/**
+ * How an init stage went: when it started and how long it took, in TSC cycles.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageTiming {
+    pub name: &'static str,
+    // cycles from the banner to the stage's start
+    pub start: u64,
+    pub cycles: u64,
+    pub ok: bool
+}
+
+/**
+ * The init stages run so far, printed as a table with the time each took.
+ */
+#[derive(Clone, Copy)]
+pub struct Timings {
+    stages: [Option<StageTiming>; MAX_STAGES],
+    // cycles from the banner to when the timings were taken
+    total: u64
+}
+
+impl Timings {
+    pub fn iter(&self) -> impl Iterator<Item = &StageTiming> {
+        self.stages.iter().flatten()
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for stage in self.iter() {
+            writeln!(
+                f,
+                "{:<8} {:>4} at {} took {}",
+                stage.name,
+                if stage.ok { "ok" } else { "FAIL" },
+                Cycles(stage.start),
+                Cycles(stage.cycles)
+            )?;
+        }
+        write!(f, "boot took {}", Cycles(self.total))
+    }
+}
+
+/**
+ * Shows cycles in microseconds once the TSC is calibrated, as they are before.
+ */
+struct Cycles(u64);
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match time::tsc_frequency() {
+            0 => write!(f, "{} cycles", self.0),
+            frequency => write!(f, "{} us", u128::from(self.0) * 1_000_000 / u128::from(frequency))
+        }
+    }
+}
+
+// the TSC when the banner was printed, boot timings count from there
+static BOOT_START: AtomicU64 = AtomicU64::new(0);
+static STAGES: IrqMutex<[Option<StageTiming>; MAX_STAGES]> = IrqMutex::new([None; MAX_STAGES]);
+
+/**
+ * Prints the kernel name, version and build profile, the first thing on the screen.
+ */
+pub fn banner() {
+    BOOT_START.store(time::read_tsc(), Ordering::Relaxed);
+    let profile = if cfg!(debug_assertions) { "debug" } else { "release" };
+    print_colored!(Colors::White, Colors::Blue, " {} {} ", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    println!(" {} build, x86_64\n", profile);
+    debug_println!("{} {} {} build", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), profile);
+}
+
+/**
+ * Runs an init stage that cannot fail, see try_stage().
+ */
+pub fn stage<F: FnOnce()>(name: &'static str, init: F) {
+    let _ = try_stage(name, || {
+        init();
+        Ok::<(), Infallible>(())
+    });
+}
+
+/**
+ * Runs an init stage and reports how it went: the stage's name is printed before it starts,
+ * so a stage that hangs or crashes can be told from the screen, then it is marked OK or FAIL,
+ * along with the CPU cycles it took. Everything is echoed to the debug console too,
+ * and the timing is kept for timings().
+ */
+pub fn try_stage<E: fmt::Debug, F: FnOnce() -> Result<(), E>>(name: &'static str, init: F) -> Result<(), E> {
+    print!("[    ] {}", name);
+    debug_println!("boot: {}", name);
+    let start = time::read_tsc();
+    let result = init();
+    let cycles = time::read_tsc().wrapping_sub(start);
+
+    print!("\r[");
+    match result {
+        Ok(()) => {
+            print_colored!(Colors::LightGreen, Colors::Black, " OK ");
+            println!("] {} ({} cycles)", name, cycles);
+            debug_println!("boot: {} OK ({} cycles)", name, cycles);
+        }
+        Err(ref error) => {
+            print_colored!(Colors::LightRed, Colors::Black, "FAIL");
+            println!("] {}: {:?}", name, error);
+            debug_println!("boot: {} FAIL: {:?}", name, error);
+        }
+    }
+    record(StageTiming {
+        name,
+        start: start.wrapping_sub(BOOT_START.load(Ordering::Relaxed)),
+        cycles,
+        ok: result.is_ok()
+    });
+    result
+}
+
+/**
+ * Returns the timings of the init stages run so far.
+ */
+pub fn timings() -> Timings {
+    Timings {
+        stages: *STAGES.lock(),
+        total: time::read_tsc().wrapping_sub(BOOT_START.load(Ordering::Relaxed))
+    }
+}
+
+fn record(timing: StageTiming) {
+    let mut stages = STAGES.lock();
+    if let Some(slot) = stages.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(timing);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::frame_allocator;
+use crate::memory::{self, Subsystem};
+use crate::sync::IrqMutex;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::PhysFrame;
+
+const FRAME_SIZE: u64 = 4096;
+/**
+ * The biggest blocks are 2^MAX_ORDER frames, 4 MiB.
+ */
+pub const MAX_ORDER: u8 = 10;
+const ORDERS: usize = MAX_ORDER as usize + 1;
+// ends a free list, frame 0 is never handed out
+const END: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuddyError {
+    // more than 2^MAX_ORDER frames
+    InvalidOrder(u8),
+    NoMemory,
+    // the block does not start at a multiple of its size
+    Misaligned(PhysAddr)
+}
+
+/**
+ * A free list for each order, of the physical addresses of the free blocks of 2^order frames.
+ * Each block holds the address of the next one in its first 8 bytes, written through the physical memory mapping.
+ *
+ * Blocks of MAX_ORDER come from the frame allocator, and stay taken there while they are split up here.
+ * Once a freed block merges with its buddies back to a whole one, it goes back.
+ */
+struct FreeLists {
+    heads: [u64; ORDERS],
+    lengths: [usize; ORDERS]
+}
+
+static FREE_LISTS: IrqMutex<FreeLists> = IrqMutex::new(FreeLists {
+    heads: [END; ORDERS],
+    lengths: [0; ORDERS]
+});
+
+impl FreeLists {
+    fn push(&mut self, order: usize, block: u64) {
+        unsafe { *next(block) = self.heads[order] };
+        self.heads[order] = block;
+        self.lengths[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<u64> {
+        let block = self.heads[order];
+        if block == END {
+            return None;
+        }
+        self.heads[order] = unsafe { *next(block) };
+        self.lengths[order] -= 1;
+        Some(block)
+    }
+
+    /**
+     * Takes the block out of the list if it is in it, i.e. if it is free.
+     * The lists are searched from the start, they are short as long as the blocks merge.
+     */
+    fn remove(&mut self, order: usize, block: u64) -> bool {
+        let mut link: *mut u64 = &mut self.heads[order];
+        unsafe {
+            while *link != END {
+                if *link == block {
+                    *link = *next(block);
+                    self.lengths[order] -= 1;
+                    return true;
+                }
+                link = next(*link);
+            }
+        }
+        false
+    }
+}
+
+/**
+ * Takes 2^order physically contiguous frames, starting at a multiple of their size, e.g. for DMA buffers.
+ * Their contents are whatever was left in them.
+ */
+pub fn allocate(order: u8) -> Result<PhysFrame, BuddyError> {
+    if order > MAX_ORDER {
+        return Err(BuddyError::InvalidOrder(order));
+    }
+    let mut lists = FREE_LISTS.lock();
+    let order = usize::from(order);
+    let found = (order..ORDERS).find_map(|bigger| lists.pop(bigger).map(|block| (bigger, block)));
+    let (mut bigger, block) = match found {
+        Some(found) => found,
+        None => {
+            let whole = 1 << MAX_ORDER;
+            match frame_allocator::allocate_contiguous(whole, whole) {
+                Some(frame) => {
+                    memory::frames_taken(Subsystem::Buddy, whole);
+                    (ORDERS - 1, frame.start_address().as_u64())
+                }
+                // the memory is too fragmented for a whole block, but maybe not for this one
+                None => {
+                    let frames = 1 << order;
+                    let frame = frame_allocator::allocate_contiguous(frames, frames).ok_or(BuddyError::NoMemory)?;
+                    memory::frames_taken(Subsystem::Buddy, frames);
+                    return Ok(frame);
+                }
+            }
+        }
+    };
+
+    // the upper halves of the splits stay free
+    while bigger > order {
+        bigger -= 1;
+        lists.push(bigger, block + (FRAME_SIZE << bigger));
+    }
+    Ok(PhysFrame::containing_address(PhysAddr::new(block)))
+}
+
+/**
+ * Gives back a block allocate() handed out, merging it with its free buddies.
+ *
+ * # Safety
+ *
+ * The block must have come from allocate() with the same order, and not be used anymore.
+ */
+pub unsafe fn free(frame: PhysFrame, order: u8) -> Result<(), BuddyError> {
+    if order > MAX_ORDER {
+        return Err(BuddyError::InvalidOrder(order));
+    }
+    let address = frame.start_address();
+    let mut order = usize::from(order);
+    if address.as_u64() & ((FRAME_SIZE << order) - 1) != 0 {
+        return Err(BuddyError::Misaligned(address));
+    }
+
+    let mut lists = FREE_LISTS.lock();
+    let mut block = address.as_u64();
+    while order < ORDERS - 1 && lists.remove(order, block ^ (FRAME_SIZE << order)) {
+        block &= !(FRAME_SIZE << order);
+        order += 1;
+    }
+    if order == ORDERS - 1 {
+        let whole = PhysFrame::containing_address(PhysAddr::new(block));
+        // it is taken there, it came from allocate_contiguous()
+        if frame_allocator::free_contiguous(whole, 1 << MAX_ORDER).is_ok() {
+            memory::frames_freed(Subsystem::Buddy, 1 << MAX_ORDER);
+        }
+    } else {
+        lists.push(order, block);
+    }
+    Ok(())
+}
+
+/**
+ * Returns the smallest order whose blocks hold the given number of bytes, None if it's more than 2^MAX_ORDER frames.
+ */
+pub fn order_for(bytes: u64) -> Option<u8> {
+    let frames = ((bytes + FRAME_SIZE - 1) / FRAME_SIZE).max(1);
+    let order = 64 - (frames - 1).leading_zeros() as u8;
+    if order <= MAX_ORDER { Some(order) } else { None }
+}
+
+/**
+ * Returns how many free blocks there are of each order, split off bigger ones.
+ */
+pub fn free_blocks() -> [usize; ORDERS] {
+    FREE_LISTS.lock().lengths
+}
+
+unsafe fn next(block: u64) -> *mut u64 {
+    memory::phys_to_virt(PhysAddr::new(block)).as_mut_ptr()
+}
@@ -0,0 +1,90 @@
+use crate::keyboard;
+use crate::print;
+use crate::vga_buffer::ScreenChar;
+use pc_keyboard::DecodedKey;
+
+const BACKSPACE: char = '\x08';
+
+/**
+ * A surface the console draws character cells on: the VGA text buffer, later a framebuffer or a serial terminal.
+ * The text window keeps the text and decides what goes where, a backend only has to show the cells it is given.
+ * Drawing may be buffered, nothing has to be visible before flush() is called.
+ */
+pub trait ConsoleBackend: Send {
+    /**
+     * Returns the number of columns and rows the backend shows.
+     */
+    fn dimensions(&self) -> (usize, usize);
+
+    fn put_char(&mut self, row: usize, column: usize, character: ScreenChar);
+
+    /**
+     * Returns the cell last drawn at the given position, flushed or not, e.g. for vga_buffer::dump_screen().
+     */
+    fn get_char(&self, row: usize, column: usize) -> ScreenChar;
+
+    /**
+     * Shifts the contents up by the given number of lines, filling the rows coming in at the bottom with `blank`.
+     */
+    fn scroll(&mut self, lines: usize, blank: ScreenChar);
+
+    fn clear(&mut self, blank: ScreenChar);
+
+    /**
+     * Moves the cursor to the given cell. Backends without a cursor ignore it.
+     */
+    fn set_cursor(&mut self, _row: usize, _column: usize) {}
+
+    fn set_cursor_visible(&mut self, _visible: bool) {}
+
+    /**
+     * Makes everything drawn so far visible.
+     */
+    fn flush(&mut self) {}
+}
+
+/**
+ * Reads a line typed on the keyboard into `buffer`, echoing it on the screen, and returns it without the newline.
+ * Backspace erases the last character, Enter ends the line. Characters that don't fit in the buffer are ignored.
+ * The CPU is halted while waiting for keys, interrupts must be enabled, or this never returns.
+ */
+pub fn read_line(buffer: &mut [u8]) -> &str {
+    let mut len = 0;
+    loop {
+        let key = match keyboard::read_key() {
+            Some(key) => key,
+            None => {
+                // a key pressed right before the hlt is only noticed at the next interrupt, a timer tick at the latest
+                x86_64::instructions::hlt();
+                continue;
+            }
+        };
+
+        match key {
+            DecodedKey::Unicode('\n') => {
+                print!("\n");
+                break;
+            }
+            DecodedKey::Unicode(BACKSPACE) => {
+                if len > 0 {
+                    // back to the start of the last character
+                    len -= 1;
+                    while len > 0 && buffer[len] & 0xC0 == 0x80 {
+                        len -= 1;
+                    }
+                    print!("{}", BACKSPACE);
+                }
+            }
+            DecodedKey::Unicode(character) if !character.is_control() => {
+                if len + character.len_utf8() <= buffer.len() {
+                    character.encode_utf8(&mut buffer[len..]);
+                    len += character.len_utf8();
+                    print!("{}", character);
+                }
+            }
+            _ => {}
+        }
+    }
+    // only whole characters were ever put in
+    unsafe { core::str::from_utf8_unchecked(&buffer[..len]) }
+}
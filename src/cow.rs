@@ -0,0 +1,125 @@
+use crate::frame_allocator;
+use crate::memory::{self, Subsystem};
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use core::ptr;
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::page_table::PageTableEntry;
+
+/**
+ * Marks a read-only page whose frame is copied on the first write to it, see handle_fault().
+ * One of the bits the CPU leaves to the kernel.
+ */
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/**
+ * Marks a page whose frame may be mapped more than once, and is freed with its last mapping, see release().
+ */
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+const FRAME_SIZE: usize = 4096;
+
+lazy_static! {
+    /** How many mappings there are of the frames mapped more than once, by physical address. A SHARED frame that is not in it has one. */
+    static ref REFERENCES: IrqMutex<BTreeMap<u64, usize>> = IrqMutex::new(BTreeMap::new());
+}
+
+/**
+ * Counts one more mapping of a frame, which all need to be marked SHARED.
+ */
+pub fn share(frame: PhysFrame) {
+    *REFERENCES.lock().entry(frame.start_address().as_u64()).or_insert(1) += 1;
+}
+
+/**
+ * Returns how many mappings there are of a SHARED frame.
+ */
+pub fn references(frame: PhysFrame) -> usize {
+    REFERENCES.lock().get(&frame.start_address().as_u64()).copied().unwrap_or(1)
+}
+
+/**
+ * Counts one mapping of a SHARED frame less. Returns whether it was the last one, so the frame is free to go.
+ */
+pub fn release(frame: PhysFrame) -> bool {
+    let mut references = REFERENCES.lock();
+    let address = frame.start_address().as_u64();
+    match references.get_mut(&address) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                references.remove(&address);
+            }
+            false
+        }
+        None => true
+    }
+}
+
+/**
+ * Resolves a write to a copy-on-write page of the active page tables: the last mapping of a frame just gets it
+ * writable, the others get a copy of their own. Returns whether the fault was one, it is handled if so.
+ */
+pub fn handle_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if !error_code.contains(write) {
+        return false;
+    }
+    let entry = match level_1_entry(address) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false
+    };
+    let frame = PhysFrame::containing_address(entry.addr());
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if release(frame) {
+        entry.set_flags(flags - SHARED);
+    } else {
+        let copy = match frame_allocator::allocate_frame() {
+            Some(copy) => {
+                memory::frames_taken(Subsystem::UserPages, 1);
+                copy
+            }
+            // the fault is reported as it is, there is nothing to write to
+            None => {
+                share(frame);
+                return false;
+            }
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                memory::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                FRAME_SIZE
+            );
+        }
+        entry.set_frame(copy, flags - SHARED);
+    }
+    tlb::flush(address);
+    true
+}
+
+/**
+ * Returns the level 1 entry of a 4 KiB page in the active page tables, None if it has none or is in a huge page.
+ */
+fn level_1_entry(address: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let (level_4_frame, _) = Cr3::read();
+    let mut frame = level_4_frame;
+    let indices = [address.p4_index(), address.p3_index(), address.p2_index()];
+    for &index in indices.iter() {
+        let entry = &table(frame)[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    let entry = &mut table(frame)[address.p1_index()];
+    if entry.is_unused() { None } else { Some(entry) }
+}
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
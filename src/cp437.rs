@@ -0,0 +1,67 @@
+// Translation of Unicode characters to Code Page 437, the character set built into the VGA text mode font.
+
+// the glyphs of the bytes 0x80..=0xFF, in order
+const UPPER_HALF: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}'
+];
+
+// the glyphs the font has for the control bytes 0x01..=0x1F, index 0 is unused
+const CONTROL_GLYPHS: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼'
+];
+
+const HOUSE: u8 = 0x7F;
+
+/**
+ * Returns the Code Page 437 byte showing the given character, or None if the font has nothing resembling it.
+ * Printable ASCII maps to itself. Characters missing from the font but having a close look-alike
+ * (Greek letters with identical Latin-1 forms, typographic quotes and dashes) are transliterated to it.
+ */
+pub fn encode(character: char) -> Option<u8> {
+    if (' '..='~').contains(&character) {
+        return Some(character as u8);
+    }
+
+    if let Some(index) = UPPER_HALF.iter().position(|&glyph| glyph == character) {
+        return Some(0x80 + index as u8);
+    }
+
+    if let Some(index) = CONTROL_GLYPHS.iter().skip(1).position(|&glyph| glyph == character) {
+        return Some(1 + index as u8);
+    }
+
+    match character {
+        '⌂' => Some(HOUSE),
+        'β' => Some(0xE1),
+        'μ' => Some(0xE6),
+        // ohm sign
+        '\u{2126}' => Some(0xEA),
+        '∑' => Some(0xE4),
+        '∈' => Some(0xEE),
+        '‘' | '’' | '‚' | '′' => Some(b'\''),
+        '“' | '”' | '„' | '″' => Some(b'"'),
+        '‐' | '‑' | '‒' | '–' | '—' | '−' => Some(b'-'),
+        _ => None
+    }
+}
+
+/**
+ * Returns the character the font shows for the given Code Page 437 byte. Byte 0 shows nothing, it is a space.
+ */
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0 => ' ',
+        0x01..=0x1F => CONTROL_GLYPHS[usize::from(byte)],
+        HOUSE => '⌂',
+        0x80..=0xFF => UPPER_HALF[usize::from(byte - 0x80)],
+        _ => byte as char
+    }
+}
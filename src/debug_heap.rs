@@ -0,0 +1,204 @@
+use crate::heap::KernelAllocator;
+use crate::sync::IrqMutex;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
+
+// written over blocks as they are handed out and as they are freed, so stale reads stand out
+const ALLOCATED_POISON: u8 = 0xCD;
+const FREED_POISON: u8 = 0xDD;
+// every block starts with a header of this size, the caller's part after it
+const HEADER_SIZE: usize = 16;
+const ALLOCATED: u64 = 0xA110_CA7E_DB10_C000;
+const FREED: u64 = 0xF4EE_DB10_C000_0000;
+// the allocations tracked at once, later ones are only counted
+const MAX_TRACKED: usize = 1024;
+// the return addresses kept of each allocation, from the frame pointers
+const SITES: usize = 4;
+// how far above the stack pointer a frame pointer may be to be followed
+const MAX_FRAME_DISTANCE: usize = 1024 * 1024;
+
+/**
+ * The allocator the heap uses with the debug_heap feature, around the KernelAllocator. It poisons blocks when they
+ * are handed out and freed, panics on double and invalid frees, and keeps track of the outstanding allocations
+ * with the return addresses of the calls that made them, see write_outstanding().
+ * The return addresses come from the frame pointers, so the kernel needs building with -C force-frame-pointers=yes
+ * for them, without it they are mostly 0.
+ */
+pub struct DebugAllocator {
+    inner: KernelAllocator
+}
+
+impl DebugAllocator {
+    pub const fn new(inner: KernelAllocator) -> DebugAllocator {
+        DebugAllocator { inner }
+    }
+}
+
+/**
+ * An outstanding allocation.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    // return addresses, innermost first, 0 where there are no more
+    pub sites: [usize; SITES]
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}: {} bytes from", self.address, self.size)?;
+        for &site in self.sites.iter().take_while(|&&site| site != 0) {
+            write!(f, " {:#x}", site)?;
+        }
+        Ok(())
+    }
+}
+
+struct Tracker {
+    allocations: [Option<Allocation>; MAX_TRACKED],
+    outstanding: usize,
+    untracked: usize
+}
+
+static TRACKER: IrqMutex<Tracker> = IrqMutex::new(Tracker {
+    allocations: [None; MAX_TRACKED],
+    outstanding: 0,
+    untracked: 0
+});
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (inner_layout, offset) = match inner_layout(layout) {
+            Some(inner) => inner,
+            None => return ptr::null_mut()
+        };
+        let block = self.inner.alloc(inner_layout);
+        if block.is_null() {
+            return block;
+        }
+        let address = block.add(offset);
+        header(address).write([ALLOCATED, layout.size() as u64]);
+        ptr::write_bytes(address, ALLOCATED_POISON, layout.size());
+
+        let sites = call_sites();
+        let mut tracker = TRACKER.lock();
+        tracker.outstanding += 1;
+        match tracker.allocations.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(Allocation {
+                    address: address as usize,
+                    size: layout.size(),
+                    sites
+                })
+            }
+            None => tracker.untracked += 1
+        }
+        address
+    }
+
+    unsafe fn dealloc(&self, address: *mut u8, layout: Layout) {
+        let (inner_layout, offset) = match inner_layout(layout) {
+            Some(inner) => inner,
+            None => panic!("invalid free of {:p}, with an impossible {:?}", address, layout)
+        };
+        let [magic, size] = header(address).read();
+        match magic {
+            ALLOCATED if size == layout.size() as u64 => {}
+            FREED => panic!("double free of {:p}, {} bytes", address, layout.size()),
+            _ => panic!("invalid free of {:p}, {:?}, the block's header is {:#x} for {} bytes", address, layout, magic, size)
+        }
+
+        let mut tracker = TRACKER.lock();
+        tracker.outstanding -= 1;
+        let entry = tracker.allocations.iter_mut().find(|entry| entry.map_or(false, |entry| entry.address == address as usize));
+        match entry {
+            Some(entry) => *entry = None,
+            None => tracker.untracked -= 1
+        }
+        drop(tracker);
+
+        header(address).write([FREED, layout.size() as u64]);
+        ptr::write_bytes(address, FREED_POISON, layout.size());
+        self.inner.dealloc(address.sub(offset), inner_layout);
+    }
+}
+
+/**
+ * Returns how many allocations are outstanding, tracked or not.
+ */
+pub fn outstanding() -> usize {
+    TRACKER.lock().outstanding
+}
+
+/**
+ * Writes the tracked outstanding allocations a line each, e.g. to look for leaks after some work is done.
+ * The writer must not allocate, the tracker is locked meanwhile.
+ */
+pub fn write_outstanding(out: &mut dyn fmt::Write) -> fmt::Result {
+    let tracker = TRACKER.lock();
+    for allocation in tracker.allocations.iter().flatten() {
+        writeln!(out, "{}", allocation)?;
+    }
+    writeln!(out, "{} outstanding, {} of them not tracked", tracker.outstanding, tracker.untracked)
+}
+
+/**
+ * Returns the layout of the block for an allocation, with room for the header before it,
+ * and where the caller's part starts in it.
+ */
+fn inner_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(HEADER_SIZE);
+    let offset = align;
+    let inner = Layout::from_size_align(layout.size().checked_add(offset)?, align).ok()?;
+    Some((inner, offset))
+}
+
+fn header(address: *mut u8) -> *mut [u64; 2] {
+    unsafe { address.sub(HEADER_SIZE) as *mut [u64; 2] }
+}
+
+// returns RBP without a frame of its own, so the caller's frame pointer
+global_asm!("
+.att_syntax prefix
+.global debug_heap_frame_pointer
+debug_heap_frame_pointer:
+    movq %rbp, %rax
+    ret
+");
+
+extern "C" {
+    fn debug_heap_frame_pointer() -> usize;
+}
+
+/**
+ * Follows the frame pointers up from the allocator for the return addresses of its callers,
+ * as long as they stay on the stack above this frame.
+ */
+#[inline(never)]
+fn call_sites() -> [usize; SITES] {
+    let mut sites = [0; SITES];
+    let stack_pointer = &sites as *const _ as usize;
+    let mut frame = unsafe { debug_heap_frame_pointer() };
+    // the first return address is into the allocator, which is no news
+    let mut skip = 1;
+    let mut index = 0;
+    while index < SITES {
+        if frame < stack_pointer || frame - stack_pointer > MAX_FRAME_DISTANCE || frame % 8 != 0 {
+            break;
+        }
+        let [next, return_address] = unsafe { (frame as *const [usize; 2]).read() };
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            sites[index] = return_address;
+            index += 1;
+        }
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    sites
+}
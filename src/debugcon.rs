@@ -0,0 +1,50 @@
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+// QEMU (-debugcon) and Bochs (port_e9_hack) print every byte written to this port
+const DEBUGCON_PORT: u16 = 0xE9;
+
+/**
+ * Output to the emulator's debug console, through port 0xE9.
+ * It needs no setup and takes no lock, so it works from the very first instruction of the kernel and while panicking.
+ * Without an emulator listening, the bytes go nowhere.
+ */
+pub struct DebugCon;
+
+impl DebugCon {
+    /**
+     * Tells whether an emulator is listening: reading the port gives back its number then.
+     */
+    pub fn is_present() -> bool {
+        let mut port: Port<u8> = Port::new(DEBUGCON_PORT);
+        unsafe { port.read() == DEBUGCON_PORT as u8 }
+    }
+}
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let mut port: Port<u8> = Port::new(DEBUGCON_PORT);
+        for byte in text.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! debug_print {
+    ($($arg:tt)*) => ($crate::debugcon::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! debug_println {
+    () => ($crate::debug_print!("\n"));
+    ($($arg:tt)*) => ($crate::debug_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    DebugCon.write_fmt(args).unwrap();
+}
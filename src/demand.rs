@@ -0,0 +1,177 @@
+use crate::address_space;
+use crate::cow;
+use crate::frame_allocator::{self, GlobalFrameAllocator};
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, PagingError};
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
+use core::ptr;
+use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, UnusedPhysFrame};
+
+const FRAME_SIZE: usize = 4096;
+
+/**
+ * A range of pages reserved without frames, mapped with the flags on first access.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    // the level 4 table of the address space it is in, None for the kernel's, which every space shares
+    level_4: Option<PhysFrame>,
+    start: u64,
+    end: u64,
+    flags: PageTableFlags
+}
+
+lazy_static! {
+    /** The reserved regions of every address space. */
+    static ref REGIONS: IrqMutex<Vec<Region>> = IrqMutex::new(Vec::new());
+}
+
+// the frame read faults in writable regions get, copy-on-write, None if there was no frame for it
+static ZERO_FRAME: Once<Option<PhysFrame>> = Once::new();
+
+/**
+ * Reserves pages of the kernel's address space, each getting a zeroed frame the first time it is touched.
+ * The frames are never freed.
+ */
+pub fn reserve_kernel(start: Page, pages: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+    reserve(None, start, pages, flags)
+}
+
+/**
+ * Reserves pages of the address space with the given level 4 table, see AddressSpace::reserve().
+ */
+pub(crate) fn reserve(level_4: Option<PhysFrame>, start: Page, pages: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+    let region = Region {
+        level_4,
+        start: start.start_address().as_u64(),
+        end: (start + pages).start_address().as_u64(),
+        flags
+    };
+    let mut regions = REGIONS.lock();
+    let overlaps = regions.iter().any(|other| {
+        (other.level_4 == level_4 || other.level_4.is_none() || level_4.is_none())
+            && other.start < region.end
+            && region.start < other.end
+    });
+    if overlaps {
+        return Err(PagingError::PageAlreadyMapped);
+    }
+    regions.push(region);
+    Ok(())
+}
+
+/**
+ * Gives a forked address space the reservations of the one it came from.
+ */
+pub(crate) fn copy_reservations(from: PhysFrame, to: PhysFrame) {
+    let mut regions = REGIONS.lock();
+    let copies: Vec<Region> = regions
+        .iter()
+        .filter(|region| region.level_4 == Some(from))
+        .map(|region| Region { level_4: Some(to), ..*region })
+        .collect();
+    regions.extend(copies);
+}
+
+/**
+ * Returns the flags a reserved page of the address space with the given level 4 table gets, None if it is not reserved.
+ */
+pub(crate) fn reserved_flags(level_4: PhysFrame, page: Page) -> Option<PageTableFlags> {
+    let address = page.start_address().as_u64();
+    REGIONS.lock().iter().find(|region| {
+        region.level_4 == Some(level_4) && (region.start..region.end).contains(&address)
+    }).map(|region| region.flags)
+}
+
+/**
+ * Drops the reservations of an address space going away.
+ */
+pub(crate) fn forget(level_4: PhysFrame) {
+    REGIONS.lock().retain(|region| region.level_4 != Some(level_4));
+}
+
+/**
+ * Maps the page of a reserved region a fault on a missing page is in. A read in a writable region of an address space
+ * gets the shared zero frame copy-on-write, anything else a zeroed frame of its own.
+ * Returns whether the fault was in a region, it is handled if so.
+ */
+pub fn handle_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let (level_4, _) = Cr3::read();
+    let region = REGIONS.lock().iter().copied().find(|region| {
+        (region.level_4.is_none() || region.level_4 == Some(level_4))
+            && (region.start..region.end).contains(&address.as_u64())
+    });
+    let region = match region {
+        Some(region) => region,
+        None => return false
+    };
+    let page = Page::containing_address(address);
+
+    if region.level_4.is_none() {
+        return match zeroed_frame(Subsystem::Vmm) {
+            // the frame is fresh from the allocator
+            Some(frame) => unsafe { paging::map_to(page, frame, region.flags).is_ok() },
+            None => false
+        };
+    }
+
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let zero_frame = *ZERO_FRAME.call_once(|| {
+        let frame = zeroed_frame(Subsystem::UserPages)?;
+        // the count keeps the frame from going away with the last of its mappings
+        cow::share(frame);
+        Some(frame)
+    });
+    // the mappings own their frames, so the address space frees them, see cow::SHARED
+    let (frame, flags) = match zero_frame {
+        Some(zero_frame) if !write => {
+            cow::share(zero_frame);
+            let flags = if region.flags.contains(PageTableFlags::WRITABLE) {
+                (region.flags - PageTableFlags::WRITABLE) | cow::COPY_ON_WRITE
+            } else {
+                region.flags
+            };
+            (zero_frame, flags | cow::SHARED)
+        }
+        _ => match zeroed_frame(Subsystem::UserPages) {
+            Some(frame) => (frame, region.flags | cow::SHARED),
+            None => return false
+        }
+    };
+
+    let physical_memory_offset = memory::phys_to_virt(PhysAddr::new(0));
+    let mut mapper = unsafe { OffsetPageTable::new(table(level_4), physical_memory_offset) };
+    match unsafe { mapper.map_to(page, UnusedPhysFrame::new(frame), flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            address_space::open_to_user(level_4, page, flags);
+            true
+        }
+        Err(_) => {
+            if cow::release(frame) && frame_allocator::free_frame(frame).is_ok() {
+                memory::frames_freed(Subsystem::UserPages, 1);
+            }
+            false
+        }
+    }
+}
+
+fn zeroed_frame(subsystem: Subsystem) -> Option<PhysFrame> {
+    let frame = frame_allocator::allocate_frame()?;
+    memory::frames_taken(subsystem, 1);
+    unsafe { ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, FRAME_SIZE) };
+    Some(frame)
+}
+
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
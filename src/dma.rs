@@ -0,0 +1,145 @@
+use crate::frame_allocator;
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, CacheMode, PagingError};
+use core::arch::x86_64::_mm_clflush;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::PhysFrame;
+
+const FRAME_SIZE: u64 = 4096;
+const CACHE_LINE_SIZE: u64 = 64;
+// buffers are aligned to their size up to this, so one of up to 64 KiB never crosses an ISA DMA page boundary
+const MAX_ALIGN: u64 = 64 * 1024;
+
+/**
+ * Which physical memory a device can reach.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    // the 8237 DMA controller's 24 bit addresses, below 16 MiB
+    Isa,
+    // 32 bit bus masters, below 4 GiB
+    Dma32,
+    Any
+}
+
+impl Zone {
+    fn limit(self) -> PhysAddr {
+        match self {
+            Zone::Isa => PhysAddr::new(16 * 1024 * 1024),
+            Zone::Dma32 => PhysAddr::new(4 * 1024 * 1024 * 1024),
+            Zone::Any => PhysAddr::new(0x000F_FFFF_FFFF_F000)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    // there is no free run of frames big enough in the zone
+    NoMemory,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for DmaError {
+    fn from(error: PagingError) -> DmaError {
+        DmaError::Paging(error)
+    }
+}
+
+/**
+ * A physically contiguous buffer for a device to read or write, freed when dropped.
+ * The kernel reaches it through the physical memory mapping, cached as allocate() was told.
+ */
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    frames: u64,
+    cache_mode: CacheMode
+}
+
+impl DmaBuffer {
+    /**
+     * Returns the address to hand the device.
+     */
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.virt.as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&mut self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    /**
+     * Changes how the kernel's accesses to the buffer are cached, writing its cached lines back first.
+     */
+    pub fn set_cache_mode(&mut self, mode: CacheMode) -> Result<(), DmaError> {
+        flush_cache(self.virt, self.frames * FRAME_SIZE);
+        paging::set_cache_mode(self.phys, self.frames * FRAME_SIZE, mode)?;
+        self.cache_mode = mode;
+        Ok(())
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // the frames go back as ordinary memory
+        if self.cache_mode != CacheMode::WriteBack {
+            let _ = paging::set_cache_mode(self.phys, self.frames * FRAME_SIZE, CacheMode::WriteBack);
+        }
+        let start = PhysFrame::containing_address(self.phys);
+        if frame_allocator::free_contiguous(start, self.frames).is_ok() {
+            memory::frames_freed(Subsystem::Dma, self.frames);
+        }
+    }
+}
+
+/**
+ * Takes a zeroed, physically contiguous buffer of at least size bytes in the zone, aligned to its size rounded up
+ * to a power of two, up to 64 KiB. Needs frame_allocator::init() and paging::init().
+ */
+pub fn allocate(size: u64, zone: Zone, cache_mode: CacheMode) -> Result<DmaBuffer, DmaError> {
+    let frames = ((size + FRAME_SIZE - 1) / FRAME_SIZE).max(1);
+    let align = (frames * FRAME_SIZE).next_power_of_two().min(MAX_ALIGN) / FRAME_SIZE;
+    let start = frame_allocator::allocate_contiguous_below(frames, align, zone.limit()).ok_or(DmaError::NoMemory)?;
+    memory::frames_taken(Subsystem::Dma, frames);
+    let mut buffer = DmaBuffer {
+        virt: memory::phys_to_virt(start.start_address()),
+        phys: start.start_address(),
+        size,
+        frames,
+        cache_mode: CacheMode::WriteBack
+    };
+    unsafe { buffer.as_mut_ptr::<u8>().write_bytes(0, (frames * FRAME_SIZE) as usize) };
+    if cache_mode != CacheMode::WriteBack {
+        buffer.set_cache_mode(cache_mode)?;
+    }
+    Ok(buffer)
+}
+
+/**
+ * Writes the cache lines of a range back to memory and drops them.
+ */
+fn flush_cache(start: VirtAddr, size: u64) {
+    let mut line = start.as_u64() & !(CACHE_LINE_SIZE - 1);
+    while line < start.as_u64() + size {
+        unsafe { _mm_clflush(line as *const u8) };
+        line += CACHE_LINE_SIZE;
+    }
+}
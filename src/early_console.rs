@@ -0,0 +1,84 @@
+use crate::cp437;
+use crate::debugcon::DebugCon;
+use crate::vga_buffer::{ColorCode, Colors, ScreenChar};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const COLUMNS: usize = 80;
+const ROWS: usize = 25;
+// starts where the global writer does, below the status bar
+const FIRST_CELL: usize = crate::status_bar::ROWS * COLUMNS;
+const EARLY_COLOR: ColorCode = ColorCode::new(Colors::LightGray, Colors::Black);
+const UNPRINTABLE: u8 = 0xFE;
+
+// the cell the next character goes to, counted from the top left corner
+static POSITION: AtomicUsize = AtomicUsize::new(FIRST_CELL);
+
+/**
+ * A console for the very first instructions of the kernel, before anything is set up.
+ * It needs no lazy_static and takes no lock, only an atomic cursor, so it can be used from anywhere at any time.
+ * It writes straight into the 80x25 VGA text buffer, wrapping around to the top instead of scrolling,
+ * and echoes everything to the debug console. Once the global writer is in use, its output paints over this one.
+ */
+pub struct EarlyConsole;
+
+impl EarlyConsole {
+    fn put(&mut self, glyph: u8) {
+        let cell = next_cell(|cell| cell + 1) % (COLUMNS * ROWS);
+        let character = ScreenChar {
+            ascii_char: glyph,
+            color_code: EARLY_COLOR
+        };
+        unsafe { core::ptr::write_volatile((0xB8000 as *mut ScreenChar).add(cell), character) };
+    }
+
+    fn newline(&mut self) {
+        next_cell(|cell| (cell / COLUMNS + 1) * COLUMNS);
+    }
+}
+
+/**
+ * Moves the cursor with the given step, returning the cell it was on. The cursor wraps around to the first cell.
+ */
+fn next_cell<F: Fn(usize) -> usize>(step: F) -> usize {
+    let mut current = POSITION.load(Ordering::Relaxed);
+    loop {
+        let mut next = step(current);
+        if next >= COLUMNS * ROWS {
+            next = FIRST_CELL;
+        }
+        match POSITION.compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return current,
+            Err(actual) => current = actual
+        }
+    }
+}
+
+impl fmt::Write for EarlyConsole {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let _ = DebugCon.write_str(text);
+        for character in text.chars() {
+            match character {
+                '\n' => self.newline(),
+                character => self.put(cp437::encode(character).unwrap_or(UNPRINTABLE))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ($crate::early_console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = EarlyConsole.write_fmt(args);
+}
@@ -0,0 +1,218 @@
+use crate::address_space::AddressSpace;
+use crate::cow;
+use crate::frame_allocator;
+use crate::memory::{self, Subsystem};
+use crate::paging::PagingError;
+use crate::user_access::USER_END;
+use core::convert::TryInto;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+
+// the file header: the identification bytes, then the fields at these offsets
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_OFFSET: usize = 4;
+const DATA_OFFSET: usize = 5;
+const VERSION_OFFSET: usize = 6;
+const TYPE_OFFSET: usize = 16;
+const MACHINE_OFFSET: usize = 18;
+const ENTRY_OFFSET: usize = 24;
+const PROGRAM_HEADERS_OFFSET: usize = 32;
+const PROGRAM_HEADER_SIZE_OFFSET: usize = 54;
+const PROGRAM_HEADER_COUNT_OFFSET: usize = 56;
+const HEADER_SIZE: usize = 64;
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const CURRENT_VERSION: u8 = 1;
+// only static executables, no shared objects, which would need relocating
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+// a program header's fields
+const SEGMENT_TYPE_OFFSET: usize = 0;
+const SEGMENT_FLAGS_OFFSET: usize = 4;
+const SEGMENT_OFFSET_OFFSET: usize = 8;
+const SEGMENT_ADDRESS_OFFSET: usize = 16;
+const SEGMENT_FILE_SIZE_OFFSET: usize = 32;
+const SEGMENT_MEMORY_SIZE_OFFSET: usize = 40;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_EXECUTABLE: u32 = 1 << 0;
+const SEGMENT_WRITABLE: u32 = 1 << 1;
+const PAGE_SIZE: u64 = 4096;
+// the initial stack, at the top of the lower half, its pages get frames as it grows into them
+const USER_STACK_TOP: u64 = 0x_7FFF_FFFF_F000;
+const USER_STACK_PAGES: u64 = 16; // 64 KiB
+// what the System V ABI has _start find on the stack: argc, the argv and envp terminators and an AT_NULL auxv entry,
+// rounded up to keep the stack 16 byte aligned, all zero
+const INITIAL_STACK_SIZE: u64 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // shorter than its headers say
+    Truncated,
+    NotElf,
+    // not a 64 bit little endian x86_64 file of the current version
+    Unsupported,
+    // a shared object or anything else that is not a static executable
+    NotExecutable,
+    // a segment's addresses are outside the user half, or overflow
+    BadSegment,
+    // no free frames for the segments
+    NoMemory,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> ElfError {
+        ElfError::Paging(error)
+    }
+}
+
+/**
+ * Where a loaded executable starts: its entry point and the stack pointer to start it with.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    // the end of the mapped segments, where a heap could start
+    pub end: VirtAddr
+}
+
+/**
+ * Loads a static ELF64 executable into an address space, which is not switched to: maps its PT_LOAD segments with the
+ * permissions they ask for, copies in their contents, zeroes the rest of them, the BSS, and reserves a stack.
+ * The segments' frames belong to the space, which frees them when it is dropped.
+ * Segments sharing a page share its frame, mapped with the permissions of the first of them.
+ */
+pub fn load(space: &mut AddressSpace, file: &[u8]) -> Result<Image, ElfError> {
+    let entry = check_header(file)?;
+    let headers = u64_at(file, PROGRAM_HEADERS_OFFSET)? as usize;
+    let header_size = usize::from(u16_at(file, PROGRAM_HEADER_SIZE_OFFSET)?);
+    let count = usize::from(u16_at(file, PROGRAM_HEADER_COUNT_OFFSET)?);
+    if header_size < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+
+    let mut end = 0;
+    for index in 0..count {
+        let header = headers.checked_add(index * header_size).ok_or(ElfError::Truncated)?;
+        let header = file.get(header..).ok_or(ElfError::Truncated)?;
+        if u32_at(header, SEGMENT_TYPE_OFFSET)? != SEGMENT_LOAD {
+            continue;
+        }
+        let segment_end = load_segment(space, file, header)?;
+        end = end.max(segment_end);
+    }
+
+    let stack_top = Page::containing_address(VirtAddr::new(USER_STACK_TOP));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    space.reserve(stack_top - USER_STACK_PAGES, USER_STACK_PAGES, flags)?;
+    Ok(Image {
+        entry: VirtAddr::new(entry),
+        stack_pointer: VirtAddr::new(USER_STACK_TOP - INITIAL_STACK_SIZE),
+        end: VirtAddr::new(end)
+    })
+}
+
+/**
+ * Checks the file header, returns the entry point.
+ */
+fn check_header(file: &[u8]) -> Result<u64, ElfError> {
+    if file.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if file[..MAGIC.len()] != MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if file[CLASS_OFFSET] != CLASS_64 || file[DATA_OFFSET] != LITTLE_ENDIAN || file[VERSION_OFFSET] != CURRENT_VERSION
+        || u16_at(file, MACHINE_OFFSET)? != MACHINE_X86_64 {
+        return Err(ElfError::Unsupported);
+    }
+    if u16_at(file, TYPE_OFFSET)? != TYPE_EXECUTABLE {
+        return Err(ElfError::NotExecutable);
+    }
+    let entry = u64_at(file, ENTRY_OFFSET)?;
+    if entry >= USER_END {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(entry)
+}
+
+/**
+ * Maps a PT_LOAD segment, given its program header, returns where it ends.
+ */
+fn load_segment(space: &mut AddressSpace, file: &[u8], header: &[u8]) -> Result<u64, ElfError> {
+    let segment_flags = u32_at(header, SEGMENT_FLAGS_OFFSET)?;
+    let offset = u64_at(header, SEGMENT_OFFSET_OFFSET)? as usize;
+    let address = u64_at(header, SEGMENT_ADDRESS_OFFSET)?;
+    let file_size = u64_at(header, SEGMENT_FILE_SIZE_OFFSET)? as usize;
+    let memory_size = u64_at(header, SEGMENT_MEMORY_SIZE_OFFSET)?;
+    let end = address.checked_add(memory_size).filter(|&end| end <= USER_END).ok_or(ElfError::BadSegment)?;
+    if file_size as u64 > memory_size {
+        return Err(ElfError::BadSegment);
+    }
+    let data = file.get(offset..offset.checked_add(file_size).ok_or(ElfError::Truncated)?).ok_or(ElfError::Truncated)?;
+    if memory_size == 0 {
+        return Ok(end);
+    }
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | cow::SHARED;
+    if segment_flags & SEGMENT_WRITABLE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment_flags & SEGMENT_EXECUTABLE == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let first = Page::containing_address(VirtAddr::new(address));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        let frame = match space.translate_addr(page.start_address()) {
+            Some(frame) => PhysFrame::containing_address(frame),
+            None => new_frame(space, page, flags)?
+        };
+        // the part of the data that goes in the page, the rest of the page stays zero
+        let page_start = page.start_address().as_u64();
+        let from = page_start.max(address);
+        let to = (page_start + PAGE_SIZE).min(address + file_size as u64);
+        if from < to {
+            let source = &data[(from - address) as usize..(to - address) as usize];
+            let destination = memory::phys_to_virt(frame.start_address()) + (from - page_start);
+            unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), destination.as_mut_ptr(), source.len()) };
+        }
+    }
+    Ok(end)
+}
+
+/**
+ * Maps a page of the space to a zeroed frame of its own.
+ */
+fn new_frame(space: &mut AddressSpace, page: Page, flags: PageTableFlags) -> Result<PhysFrame, ElfError> {
+    let frame = frame_allocator::allocate_frame().ok_or(ElfError::NoMemory)?;
+    memory::frames_taken(Subsystem::UserPages, 1);
+    let contents = memory::phys_to_virt(frame.start_address());
+    unsafe { core::ptr::write_bytes(contents.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    // the frame is fresh from the allocator
+    if let Err(error) = unsafe { space.map_to(page, frame, flags) } {
+        if frame_allocator::free_frame(frame).is_ok() {
+            memory::frames_freed(Subsystem::UserPages, 1);
+        }
+        return Err(error.into());
+    }
+    Ok(frame)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().map_err(|_| ElfError::Truncated)?))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().map_err(|_| ElfError::Truncated)?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = bytes.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| ElfError::Truncated)?))
+}
@@ -0,0 +1,584 @@
+use crate::block::{self, BlockDevice, BlockError};
+use crate::cp437;
+use crate::sync::IrqMutex;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::char;
+use core::mem;
+
+const BOOT_SECTOR_SIZE: usize = 512;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+// a directory has at most 65536 entries, a longer chain is a corrupt one
+const MAX_DIRECTORY_SIZE: u64 = 65536 * DIRECTORY_ENTRY_SIZE as u64;
+// the cluster counts FAT12 and FAT16 stay below, the count alone tells which FAT a volume has
+const FAT12_CLUSTERS: u64 = 4085;
+const FAT16_CLUSTERS: u64 = 65525;
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+// the clusters are numbered from 2, 0 in a directory entry means none, or the root directory for ".."
+const FIRST_CLUSTER: u32 = 2;
+const ROOT_INODE: u64 = 1;
+// the first byte of a directory entry: none after it, or a deleted one
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xE5;
+// a short name starting with 0xE5 has 0x05 instead, 0xE5 marks deleted entries
+const ESCAPED_E5: u8 = 0x05;
+const ATTRIBUTES: usize = 11;
+const ATTRIBUTE_VOLUME_LABEL: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+// read-only, hidden, system and volume label all set mark a part of a long name
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ATTRIBUTE_LONG_NAME_MASK: u8 = 0x3F;
+// from Windows NT: the name, or the extension, of a short name is shown in lowercase
+const CASE_FLAGS: usize = 12;
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+// the first byte of a long name's entry: its part's number, and whether it is the last part, which comes first
+const LONG_NAME_ORDINAL: u8 = 0x1F;
+const LAST_LONG_NAME_PART: u8 = 0x40;
+const LONG_NAME_CHECKSUM: usize = 13;
+// where the UCS-2 characters of a part are in its entry, 13 of them
+const LONG_NAME_CHARACTERS: [(usize, usize); 3] = [(1, 11), (14, 26), (28, 32)];
+const LONG_NAME_PART_LENGTH: usize = 13;
+const LONG_NAME_END: u16 = 0x0000;
+const LONG_NAME_PADDING: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    // the boot sector has no signature, or no BIOS parameter block that makes sense
+    NotFat,
+    // no block device is registered by the name
+    NoSuchDevice,
+    Block(BlockError),
+    Vfs(VfsError)
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> FatError {
+        FatError::Block(error)
+    }
+}
+
+impl From<VfsError> for FatError {
+    fn from(error: VfsError) -> FatError {
+        FatError::Vfs(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+/**
+ * A FAT12, FAT16 or FAT32 file system on a block device, read-only: what would change it fails with ReadOnly.
+ * Names are matched without regard to ASCII case, by their long name or their 8.3 one.
+ */
+pub struct FatFs {
+    root: Arc<FatInode>
+}
+
+impl FatFs {
+    /**
+     * Reads the BIOS parameter block from the device's boot sector, the volume's first, there is no partition table.
+     */
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, FatError> {
+        let volume = Arc::new(Volume::new(device)?);
+        Ok(FatFs {
+            root: Arc::new(FatInode::new(volume, ROOT_INODE, FileType::Directory, 0, 0))
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.root.volume.fat_type
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.fat_type() {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32"
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/**
+ * Where a volume's parts are, from its BIOS parameter block, in bytes from the start of the device.
+ */
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    // the first FAT, the others are copies of it
+    fat_start: u64,
+    // FAT12 and FAT16 have the root directory between the FATs and the clusters, FAT32 in a chain of clusters
+    root_start: u64,
+    root_size: u64,
+    root_cluster: u32,
+    data_start: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    // the block of the FAT read last, a chain's entries are mostly in the same one
+    fat_block: IrqMutex<Option<(u64, Vec<u8>)>>
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Volume, FatError> {
+        let mut boot = [0; BOOT_SECTOR_SIZE];
+        block::read_bytes(&*device, 0, &mut boot)?;
+        if boot[510..512] != BOOT_SIGNATURE {
+            return Err(FatError::NotFat);
+        }
+        let bytes_per_sector = u64::from(u16_at(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(u16_at(&boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(u16_at(&boot, 17));
+        // the 16 bit fields are 0 where the 32 bit ones are needed
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u64::from(u32_at(&boot, 32)),
+            sectors => u64::from(sectors)
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u64::from(u32_at(&boot, 36)),
+            sectors => u64::from(sectors)
+        };
+        let valid = bytes_per_sector >= 512 && bytes_per_sector <= 4096 && bytes_per_sector.is_power_of_two()
+            && sectors_per_cluster.is_power_of_two() && reserved_sectors > 0 && fat_count > 0 && fat_sectors > 0;
+        if !valid {
+            return Err(FatError::NotFat);
+        }
+        let root_size = root_entries * DIRECTORY_ENTRY_SIZE as u64;
+        let root_sectors = (root_size + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        if data_sector >= total_sectors {
+            return Err(FatError::NotFat);
+        }
+        let cluster_count = (total_sectors - data_sector) / sectors_per_cluster;
+        let fat_type = if cluster_count < FAT12_CLUSTERS {
+            FatType::Fat12
+        } else if cluster_count < FAT16_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        // only FAT32 has its root directory in clusters
+        if cluster_count == 0 || cluster_count > MAX_CLUSTERS || (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err(FatError::NotFat);
+        }
+        Ok(Volume {
+            device,
+            fat_type,
+            fat_start: reserved_sectors * bytes_per_sector,
+            root_start: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_size,
+            root_cluster: if fat_type == FatType::Fat32 { u32_at(&boot, 44) } else { 0 },
+            data_start: data_sector * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            fat_block: IrqMutex::new(None)
+        })
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.cluster_size
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), VfsError> {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.cluster_count {
+            return Err(VfsError::Io);
+        }
+        Ok(())
+    }
+
+    /**
+     * Follows a chain of clusters in the FAT from its first cluster, 0 for an empty one.
+     * Fails with Io for a chain that leaves the volume, goes through a free or bad cluster, or loops.
+     */
+    fn chain(&self, first: u32) -> Result<Vec<u32>, VfsError> {
+        let mut clusters = Vec::new();
+        if first == 0 {
+            return Ok(clusters);
+        }
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            self.check_cluster(current)?;
+            // longer than there are clusters, it goes around
+            if clusters.len() >= self.cluster_count as usize {
+                return Err(VfsError::Io);
+            }
+            clusters.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(clusters)
+    }
+
+    /**
+     * Returns the cluster after the given one in its chain, from the FAT, or None if the chain ends there.
+     */
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, VfsError> {
+        let (offset, width, end) = match self.fat_type {
+            // two entries in three bytes
+            FatType::Fat12 => (u64::from(cluster) * 3 / 2, 2, 0xFF8),
+            FatType::Fat16 => (u64::from(cluster) * 2, 2, 0xFFF8),
+            FatType::Fat32 => (u64::from(cluster) * 4, 4, 0x0FFF_FFF8)
+        };
+        let mut bytes = [0; 4];
+        for (index, byte) in bytes[..width].iter_mut().enumerate() {
+            *byte = self.fat_byte(self.fat_start + offset + index as u64)?;
+        }
+        let entry = u32::from_le_bytes(bytes);
+        let next = match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => entry >> 4,
+            FatType::Fat12 => entry & 0xFFF,
+            FatType::Fat16 => entry,
+            // the top 4 bits are reserved
+            FatType::Fat32 => entry & 0x0FFF_FFFF
+        };
+        if next >= end {
+            return Ok(None);
+        }
+        Ok(Some(next))
+    }
+
+    fn fat_byte(&self, position: u64) -> Result<u8, VfsError> {
+        let block_size = self.device.block_size() as u64;
+        let block = position / block_size;
+        let index = (position % block_size) as usize;
+        if let Some((cached, bytes)) = &*self.fat_block.lock() {
+            if *cached == block {
+                return Ok(bytes[index]);
+            }
+        }
+        // the device is read without the cache locked, it may block
+        let mut bytes = vec![0; block_size as usize];
+        self.device.read_blocks(block, &mut bytes).map_err(io_error)?;
+        let byte = bytes[index];
+        *self.fat_block.lock() = Some((block, bytes));
+        Ok(byte)
+    }
+
+    /**
+     * Reads the entries of the directory starting at the given cluster, 0 for the root directory,
+     * without the deleted ones, the volume label, "." and "..".
+     */
+    fn entries(&self, first: u32) -> Result<Vec<Entry>, VfsError> {
+        let regions = match (first, self.fat_type) {
+            (0, FatType::Fat12) | (0, FatType::Fat16) => vec![(self.root_start, self.root_size)],
+            _ => {
+                let first = if first == 0 { self.root_cluster } else { first };
+                let clusters = self.chain(first)?;
+                if clusters.len() as u64 * self.cluster_size > MAX_DIRECTORY_SIZE {
+                    return Err(VfsError::Io);
+                }
+                clusters.iter().map(|&cluster| (self.cluster_offset(cluster), self.cluster_size)).collect()
+            }
+        };
+        let mut entries = Vec::new();
+        // a long name's parts may be in the cluster before its short entry's
+        let mut long_name = LongName::default();
+        for (start, size) in regions {
+            let mut bytes = vec![0; size as usize];
+            block::read_bytes(&*self.device, start, &mut bytes).map_err(io_error)?;
+            for (index, raw) in bytes.chunks_exact(DIRECTORY_ENTRY_SIZE).enumerate() {
+                match raw[0] {
+                    END_OF_DIRECTORY => return Ok(entries),
+                    DELETED => {
+                        long_name.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = raw[ATTRIBUTES];
+                if attributes & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+                    long_name.add(raw);
+                    continue;
+                }
+                if attributes & ATTRIBUTE_VOLUME_LABEL != 0 {
+                    long_name.clear();
+                    continue;
+                }
+                let short_name = short_name(raw);
+                let name = long_name.take(checksum(&raw[..11])).unwrap_or_else(|| short_name.clone());
+                if short_name == "." || short_name == ".." {
+                    continue;
+                }
+                entries.push(Entry {
+                    name,
+                    short_name,
+                    directory: attributes & ATTRIBUTE_DIRECTORY != 0,
+                    first_cluster: u32::from(u16_at(raw, 20)) << 16 | u32::from(u16_at(raw, 26)),
+                    size: u32_at(raw, 28),
+                    position: start + (index * DIRECTORY_ENTRY_SIZE) as u64
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/**
+ * A directory entry, parsed.
+ */
+struct Entry {
+    // the long name if there is one, the 8.3 one otherwise
+    name: String,
+    short_name: String,
+    directory: bool,
+    first_cluster: u32,
+    size: u32,
+    // where the entry is on the device, its inode's number
+    position: u64
+}
+
+/**
+ * The parts of a long name read so far, which are in the entries before its short entry, the last part first.
+ */
+#[derive(Default)]
+struct LongName {
+    characters: Vec<u16>,
+    // of the short name the parts belong to, a short entry with another one is not theirs
+    checksum: u8,
+    // the part expected next, counting down to 1, 0 once all are read
+    next: u8
+}
+
+impl LongName {
+    fn add(&mut self, raw: &[u8]) {
+        let ordinal = raw[0] & LONG_NAME_ORDINAL;
+        if raw[0] & LAST_LONG_NAME_PART != 0 {
+            self.characters = vec![LONG_NAME_PADDING; usize::from(ordinal) * LONG_NAME_PART_LENGTH];
+            self.checksum = raw[LONG_NAME_CHECKSUM];
+        } else if ordinal != self.next || raw[LONG_NAME_CHECKSUM] != self.checksum {
+            self.clear();
+            return;
+        }
+        if ordinal == 0 {
+            self.clear();
+            return;
+        }
+        let start = usize::from(ordinal - 1) * LONG_NAME_PART_LENGTH;
+        let characters = LONG_NAME_CHARACTERS.iter()
+            .flat_map(|&(start, end)| raw[start..end].chunks_exact(2))
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+        for (slot, character) in self.characters[start..start + LONG_NAME_PART_LENGTH].iter_mut().zip(characters) {
+            *slot = character;
+        }
+        self.next = ordinal - 1;
+    }
+
+    /**
+     * Returns the long name if all its parts were read and it belongs to the short name with the given checksum,
+     * and starts over for the next one.
+     */
+    fn take(&mut self, checksum: u8) -> Option<String> {
+        let complete = !self.characters.is_empty() && self.next == 0 && self.checksum == checksum;
+        let characters = mem::take(&mut self.characters);
+        self.clear();
+        if !complete {
+            return None;
+        }
+        let length = characters.iter()
+            .position(|&character| character == LONG_NAME_END || character == LONG_NAME_PADDING)
+            .unwrap_or(characters.len());
+        let name = char::decode_utf16(characters[..length].iter().cloned())
+            .map(|character| character.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some(name)
+    }
+
+    fn clear(&mut self) {
+        self.characters.clear();
+        self.next = 0;
+    }
+}
+
+/**
+ * Returns the 8.3 name of a short entry, with a dot between the name and the extension if there is one.
+ * The bytes are taken as Code Page 437, what most volumes are written in.
+ */
+fn short_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    let base = trim_spaces(&raw[..8]);
+    for (index, &byte) in base.iter().enumerate() {
+        let byte = if index == 0 && byte == ESCAPED_E5 { DELETED } else { byte };
+        name.push(short_name_character(byte, raw[CASE_FLAGS] & LOWERCASE_BASE != 0));
+    }
+    let extension = trim_spaces(&raw[8..11]);
+    if !extension.is_empty() {
+        name.push('.');
+        for &byte in extension {
+            name.push(short_name_character(byte, raw[CASE_FLAGS] & LOWERCASE_EXTENSION != 0));
+        }
+    }
+    name
+}
+
+fn short_name_character(byte: u8, lowercase: bool) -> char {
+    if lowercase {
+        cp437::decode(byte.to_ascii_lowercase())
+    } else {
+        cp437::decode(byte)
+    }
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let length = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+    &bytes[..length]
+}
+
+/**
+ * The checksum of an 8.3 name, as stored in the name's padded 11 bytes, that its long name's parts carry.
+ */
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    inode: u64,
+    file_type: FileType,
+    // 0 for an empty file, or the root directory
+    first_cluster: u32,
+    size: u64,
+    // read at the first read, or lookup, the volume does not change under a read-only mount
+    clusters: IrqMutex<Option<Arc<Vec<u32>>>>,
+    entries: IrqMutex<Option<Arc<Vec<Entry>>>>
+}
+
+impl FatInode {
+    fn new(volume: Arc<Volume>, inode: u64, file_type: FileType, first_cluster: u32, size: u64) -> FatInode {
+        FatInode {
+            volume,
+            inode,
+            file_type,
+            first_cluster,
+            size,
+            clusters: IrqMutex::new(None),
+            entries: IrqMutex::new(None)
+        }
+    }
+
+    fn clusters(&self) -> Result<Arc<Vec<u32>>, VfsError> {
+        if let Some(clusters) = &*self.clusters.lock() {
+            return Ok(clusters.clone());
+        }
+        let clusters = Arc::new(self.volume.chain(self.first_cluster)?);
+        *self.clusters.lock() = Some(clusters.clone());
+        Ok(clusters)
+    }
+
+    fn entries(&self) -> Result<Arc<Vec<Entry>>, VfsError> {
+        if self.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        if let Some(entries) = &*self.entries.lock() {
+            return Ok(entries.clone());
+        }
+        let entries = Arc::new(self.volume.entries(self.first_cluster)?);
+        *self.entries.lock() = Some(entries.clone());
+        Ok(entries)
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: self.file_type,
+            size: self.size,
+            inode: self.inode
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if self.file_type == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((self.size - offset) as usize);
+        let clusters = self.clusters()?;
+        let cluster_size = self.volume.cluster_size;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            // a chain shorter than the size is a corrupt one
+            let cluster = *clusters.get((position / cluster_size) as usize).ok_or(VfsError::Io)?;
+            let start = position % cluster_size;
+            let chunk = ((cluster_size - start) as usize).min(count - done);
+            let from = self.volume.cluster_offset(cluster) + start;
+            block::read_bytes(&*self.volume.device, from, &mut buffer[done..done + chunk]).map_err(io_error)?;
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let entries = self.entries()?;
+        let entry = entries.iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name) || entry.short_name.eq_ignore_ascii_case(name))
+            .ok_or(VfsError::NotFound)?;
+        let (file_type, size) = if entry.directory {
+            (FileType::Directory, 0)
+        } else {
+            (FileType::Regular, u64::from(entry.size))
+        };
+        Ok(Arc::new(FatInode::new(self.volume.clone(), entry.position, file_type, entry.first_cluster, size)))
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        Ok(self.entries()?.get(index).map(|entry| DirEntry {
+            name: entry.name.clone(),
+            file_type: if entry.directory { FileType::Directory } else { FileType::Regular },
+            inode: entry.position
+        }))
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn io_error(_: BlockError) -> VfsError {
+    VfsError::Io
+}
+
+/**
+ * Mounts the FAT file system of a registered block device at an absolute path, read-only.
+ */
+pub fn mount(path: &str, device: &str) -> Result<(), FatError> {
+    let device = block::find(device).ok_or(FatError::NoSuchDevice)?;
+    vfs::mount(path, Arc::new(FatFs::new(device)?))?;
+    Ok(())
+}
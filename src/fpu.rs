@@ -0,0 +1,160 @@
+use crate::sync::IrqMutex;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _fxrstor64, _fxsave64, _xrstor64, _xsave64, _xsetbv};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// CPUID leaf 1 EDX tells whether there are fxsave/fxrstor and SSE, ECX whether there are xsave and AVX
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_FXSR: u32 = 1 << 24;
+const CPUID_SSE: u32 = 1 << 25;
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+// CPUID leaf 0xD subleaf 0: EAX has the state components XCR0 may enable, EBX the xsave area size for the enabled ones
+const CPUID_XSAVE_STATE: u32 = 0xD;
+// state components, bits of XCR0 and of the masks xsave and xrstor take
+const XCR0: u32 = 0;
+const STATE_X87: u64 = 1 << 0;
+const STATE_SSE: u64 = 1 << 1;
+const STATE_AVX: u64 = 1 << 2;
+// fxsave writes 512 bytes to a 16 byte aligned area, xsave adds a 64 byte header and the AVX registers' upper halves
+// to a 64 byte aligned one, 832 bytes for the components enabled here
+const FXSAVE_AREA_SIZE: usize = 512;
+const STATE_AREA_SIZE: usize = 1024;
+// the header's first field tells xrstor which components the area holds
+const XSTATE_BV_OFFSET: usize = 512;
+// the control words in the fxsave area, and their values after a reset: every exception masked, round to nearest
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+// the components saved with xsave, 0 when fxsave is used instead, and how much of the area is used
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpuError {
+    // the CPU has no SSE or no fxsave, which every x86_64 CPU should have
+    NotSupported
+}
+
+/**
+ * The x87 FPU, MMX, SSE and AVX registers of a task, saved when it is switched out and restored when it is switched back in.
+ * The kernel itself is built without SSE, see x86_64.json, so interrupt handlers leave these registers alone
+ * and only a context switch has to save them.
+ */
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct FpuState([u8; STATE_AREA_SIZE]);
+
+// the state right after init(), new tasks start with a copy of it
+static INITIAL_STATE: IrqMutex<FpuState> = IrqMutex::new(FpuState([0; STATE_AREA_SIZE]));
+
+impl FpuState {
+    /**
+     * Returns the state a new task starts with: no exceptions unmasked, an empty x87 stack and zeroed registers.
+     */
+    pub fn new() -> FpuState {
+        INITIAL_STATE.lock().clone()
+    }
+
+    /**
+     * Saves the calling CPU's registers here, in the context switch before switching the task out.
+     */
+    pub fn save(&mut self) {
+        match XSAVE_MASK.load(Ordering::Relaxed) {
+            0 => unsafe { _fxsave64(self.0.as_mut_ptr()) },
+            mask => unsafe { _xsave64(self.0.as_mut_ptr(), mask) }
+        }
+    }
+
+    /**
+     * Loads the registers saved here into the calling CPU, when the task is switched back in.
+     */
+    pub fn restore(&self) {
+        match XSAVE_MASK.load(Ordering::Relaxed) {
+            0 => unsafe { _fxrstor64(self.0.as_ptr()) },
+            mask => unsafe { _xrstor64(self.0.as_ptr(), mask) }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> FpuState {
+        FpuState::new()
+    }
+}
+
+/**
+ * Enables the FPU and SSE: the x87 instructions run instead of faulting, its errors are reported as exceptions,
+ * and the OS saves the SSE state, so the SSE instructions don't fault either.
+ * Where the CPU has xsave, AVX is enabled too, and the state is saved with xsave instead of fxsave.
+ */
+pub fn init() -> Result<(), FpuError> {
+    let features = unsafe { __cpuid(CPUID_FEATURES) };
+    if features.edx & (CPUID_FXSR | CPUID_SSE) != CPUID_FXSR | CPUID_SSE {
+        return Err(FpuError::NotSupported);
+    }
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+    if features.ecx & CPUID_XSAVE != 0 {
+        enable_xsave(features.ecx & CPUID_AVX != 0);
+    }
+
+    // whatever the firmware left in the registers, new tasks get the default control words
+    let mut initial = INITIAL_STATE.lock();
+    initial.save();
+    initial.0[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+    initial.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+    if XSAVE_MASK.load(Ordering::Relaxed) != 0 {
+        // xsave leaves out components still in their initial state, the control words would not be restored then
+        let mut xstate_bv = [0; 8];
+        xstate_bv.copy_from_slice(&initial.0[XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8]);
+        let xstate_bv = u64::from_le_bytes(xstate_bv) | STATE_X87 | STATE_SSE;
+        initial.0[XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8].copy_from_slice(&xstate_bv.to_le_bytes());
+    }
+    Ok(())
+}
+
+/**
+ * Tells whether AVX instructions can be used, that is whether their registers are saved with the task.
+ */
+pub fn avx_enabled() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) & STATE_AVX != 0
+}
+
+/**
+ * Returns how many bytes of an FpuState the CPU uses.
+ */
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/**
+ * Enables xsave and the x87, SSE and, if asked, AVX state components, unless they need more room than an FpuState has.
+ */
+fn enable_xsave(avx: bool) {
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE)) };
+    let supported = u64::from(unsafe { __cpuid_count(CPUID_XSAVE_STATE, 0) }.eax);
+    let mut mask = STATE_X87 | STATE_SSE;
+    if avx && supported & STATE_AVX != 0 {
+        mask |= STATE_AVX;
+    }
+    unsafe { _xsetbv(XCR0, mask) };
+    // the size depends on the components just enabled
+    let size = unsafe { __cpuid_count(CPUID_XSAVE_STATE, 0) }.ebx as usize;
+    if size > STATE_AREA_SIZE {
+        unsafe {
+            _xsetbv(XCR0, STATE_X87 | STATE_SSE);
+            Cr4::update(|flags| flags.remove(Cr4Flags::OSXSAVE));
+        }
+        return;
+    }
+    XSAVE_MASK.store(mask, Ordering::Relaxed);
+    STATE_SIZE.store(size, Ordering::Relaxed);
+}
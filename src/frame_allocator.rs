@@ -0,0 +1,271 @@
+use crate::memory::{self, Subsystem};
+use crate::sync::IrqMutex;
+use bootloader::bootinfo::MemoryRegionType;
+use core::slice;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB, UnusedPhysFrame};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    // the memory map has no usable region, or none big enough for the bitmap
+    NoMemory,
+    NotInitialized,
+    // the frame is not one the allocator hands out, or it is already free
+    NotAllocated(PhysAddr)
+}
+
+/**
+ * One bit for each frame up to the highest usable one, set for the frames that are taken.
+ * The bitmap itself sits in the first usable region big enough for it, reached through the physical memory mapping.
+ */
+struct Bitmap {
+    words: &'static mut [u64],
+    free_frames: u64,
+    used_frames: u64,
+    // where the last search stopped, the frames below it are likely taken
+    next_word: usize
+}
+
+impl Bitmap {
+    fn is_used(&self, frame: u64) -> bool {
+        self.words[(frame / BITS_PER_WORD) as usize] & 1 << (frame % BITS_PER_WORD) != 0
+    }
+
+    fn set_used(&mut self, frame: u64, used: bool) {
+        let word = &mut self.words[(frame / BITS_PER_WORD) as usize];
+        if used {
+            *word |= 1 << (frame % BITS_PER_WORD);
+        } else {
+            *word &= !(1 << (frame % BITS_PER_WORD));
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u64> {
+        let words = self.words.len();
+        for offset in 0..words {
+            let index = (self.next_word + offset) % words;
+            let word = self.words[index];
+            if word != u64::max_value() {
+                let frame = index as u64 * BITS_PER_WORD + u64::from((!word).trailing_zeros());
+                self.set_used(frame, true);
+                self.next_word = index;
+                self.free_frames -= 1;
+                self.used_frames += 1;
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /**
+     * Takes the first run of free frames starting at a multiple of align, a power of two, and ending below limit.
+     */
+    fn allocate_run(&mut self, frames: u64, align: u64, limit: u64) -> Option<u64> {
+        let end = (self.words.len() as u64 * BITS_PER_WORD).min(limit);
+        let mut start = 0;
+        'search: while start + frames <= end {
+            for frame in start..start + frames {
+                if self.is_used(frame) {
+                    // no run starting before the taken frame fits
+                    start = (frame + align) & !(align - 1);
+                    continue 'search;
+                }
+            }
+            for frame in start..start + frames {
+                self.set_used(frame, true);
+            }
+            self.free_frames -= frames;
+            self.used_frames += frames;
+            return Some(start);
+        }
+        None
+    }
+}
+
+static BITMAP: IrqMutex<Option<Bitmap>> = IrqMutex::new(None);
+
+/**
+ * Builds the bitmap from the memory map: only the usable regions are free, so the kernel, its stack, the page tables,
+ * the boot info and frame 0 never are. Needs memory::init() first.
+ */
+pub fn init() -> Result<(), FrameError> {
+    let usable = || memory::memory_map().iter().filter(|region| region.region_type == MemoryRegionType::Usable);
+    let frames = usable().map(|region| region.range.end_addr() / FRAME_SIZE).max().ok_or(FrameError::NoMemory)?;
+    let words = ((frames + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+    let bitmap_frames = (words as u64 * 8 + FRAME_SIZE - 1) / FRAME_SIZE;
+    let bitmap_region = usable()
+        .find(|region| region.range.end_addr() - region.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+        .ok_or(FrameError::NoMemory)?;
+    let bitmap_start = bitmap_region.range.start_addr();
+    let address = memory::phys_to_virt(PhysAddr::new(bitmap_start)).as_u64();
+    let mut bitmap = Bitmap {
+        words: unsafe { slice::from_raw_parts_mut(address as *mut u64, words) },
+        free_frames: 0,
+        used_frames: 0,
+        next_word: 0
+    };
+
+    // everything is taken but the usable frames
+    for word in bitmap.words.iter_mut() {
+        *word = u64::max_value();
+    }
+    for region in usable() {
+        for frame in region.range.start_addr() / FRAME_SIZE..region.range.end_addr() / FRAME_SIZE {
+            bitmap.set_used(frame, false);
+            bitmap.free_frames += 1;
+        }
+    }
+    for frame in bitmap_start / FRAME_SIZE..bitmap_start / FRAME_SIZE + bitmap_frames {
+        bitmap.set_used(frame, true);
+        bitmap.free_frames -= 1;
+        bitmap.used_frames += 1;
+    }
+    // what the bootloader took is memory in use, it may be given back, e.g. its page tables by paging::merge()
+    let frames = words as u64 * BITS_PER_WORD;
+    for region in memory::memory_map().iter().filter(|region| taken_at_boot(region.region_type)) {
+        let end = (region.range.end_addr() / FRAME_SIZE).min(frames);
+        bitmap.used_frames += end.saturating_sub(region.range.start_addr() / FRAME_SIZE);
+    }
+    // the bootloader calls it usable, but address 0 looks like a null pointer
+    if !bitmap.is_used(0) {
+        bitmap.set_used(0, true);
+        bitmap.free_frames -= 1;
+        bitmap.used_frames += 1;
+    }
+    *BITMAP.lock() = Some(bitmap);
+    Ok(())
+}
+
+/**
+ * Tells whether a region of the memory map is memory the kernel or the bootloader took, rather than none or the
+ * firmware's.
+ */
+fn taken_at_boot(region_type: MemoryRegionType) -> bool {
+    matches!(
+        region_type,
+        MemoryRegionType::InUse
+            | MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::FrameZero
+            | MemoryRegionType::Package
+    )
+}
+
+/**
+ * Takes a free 4 KiB frame, its contents are whatever was left in it.
+ */
+pub fn allocate_frame() -> Option<PhysFrame> {
+    let frame = BITMAP.lock().as_mut()?.allocate()?;
+    Some(PhysFrame::containing_address(PhysAddr::new(frame * FRAME_SIZE)))
+}
+
+/**
+ * Gives back a frame allocate_frame() handed out. Nothing may be mapped to it anymore.
+ */
+pub fn free_frame(frame: PhysFrame) -> Result<(), FrameError> {
+    let mut bitmap = BITMAP.lock();
+    let bitmap = bitmap.as_mut().ok_or(FrameError::NotInitialized)?;
+    let address = frame.start_address();
+    let index = address.as_u64() / FRAME_SIZE;
+    if index >= bitmap.words.len() as u64 * BITS_PER_WORD || !bitmap.is_used(index) {
+        return Err(FrameError::NotAllocated(address));
+    }
+    bitmap.set_used(index, false);
+    bitmap.free_frames += 1;
+    bitmap.used_frames -= 1;
+    Ok(())
+}
+
+/**
+ * Takes a run of physically contiguous frames, starting at a multiple of align frames, a power of two.
+ * Slow, it searches the whole bitmap, see the buddy module for frequent contiguous allocations.
+ */
+pub fn allocate_contiguous(frames: u64, align: u64) -> Option<PhysFrame> {
+    let frame = BITMAP.lock().as_mut()?.allocate_run(frames, align, u64::max_value())?;
+    Some(PhysFrame::containing_address(PhysAddr::new(frame * FRAME_SIZE)))
+}
+
+/**
+ * Takes a run of physically contiguous frames like allocate_contiguous(), all of it below the given address,
+ * e.g. for a device that can only address the low 4 GiB.
+ */
+pub fn allocate_contiguous_below(frames: u64, align: u64, limit: PhysAddr) -> Option<PhysFrame> {
+    let frame = BITMAP.lock().as_mut()?.allocate_run(frames, align, limit.as_u64() / FRAME_SIZE)?;
+    Some(PhysFrame::containing_address(PhysAddr::new(frame * FRAME_SIZE)))
+}
+
+/**
+ * Gives back a run of frames allocate_contiguous() or allocate_frame() handed out, all or none of them.
+ */
+pub fn free_contiguous(start: PhysFrame, frames: u64) -> Result<(), FrameError> {
+    let mut bitmap = BITMAP.lock();
+    let bitmap = bitmap.as_mut().ok_or(FrameError::NotInitialized)?;
+    let first = start.start_address().as_u64() / FRAME_SIZE;
+    for frame in first..first + frames {
+        if frame >= bitmap.words.len() as u64 * BITS_PER_WORD || !bitmap.is_used(frame) {
+            return Err(FrameError::NotAllocated(PhysAddr::new(frame * FRAME_SIZE)));
+        }
+    }
+    for frame in first..first + frames {
+        bitmap.set_used(frame, false);
+    }
+    bitmap.free_frames += frames;
+    bitmap.used_frames -= frames;
+    Ok(())
+}
+
+/**
+ * Takes a free 2 MiB frame, for paging::map_huge().
+ */
+pub fn allocate_huge_frame() -> Option<PhysFrame<Size2MiB>> {
+    let frames = Size2MiB::SIZE / FRAME_SIZE;
+    let frame = allocate_contiguous(frames, frames)?;
+    Some(PhysFrame::containing_address(frame.start_address()))
+}
+
+pub fn free_huge_frame(frame: PhysFrame<Size2MiB>) -> Result<(), FrameError> {
+    free_contiguous(PhysFrame::containing_address(frame.start_address()), Size2MiB::SIZE / FRAME_SIZE)
+}
+
+/**
+ * Returns how many frames are free to allocate.
+ */
+pub fn free_frames() -> u64 {
+    BITMAP.lock().as_ref().map_or(0, |bitmap| bitmap.free_frames)
+}
+
+/**
+ * Returns how many frames of memory are taken, by allocate_frame(), the bitmap, or the kernel and the bootloader at
+ * boot.
+ */
+pub fn used_frames() -> u64 {
+    BITMAP.lock().as_ref().map_or(0, |bitmap| bitmap.used_frames)
+}
+
+/**
+ * The frame allocator for the x86_64 crate's page table mappers, handing out the same frames as allocate_frame().
+ */
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        // nothing else has the frame, the bitmap marks it taken
+        let frame = allocate_frame()?;
+        memory::frames_taken(Subsystem::PageTables, 1);
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        if free_frame(frame.frame()).is_ok() {
+            memory::frames_freed(Subsystem::PageTables, 1);
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use crate::serial::{SerialError, SerialPort, COM2, DEFAULT_BAUD_RATE};
+use crate::sync::IrqMutex;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+// the longest packet taken or sent, told to gdb in the reply to qSupported
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 16;
+const INT3: u8 = 0xCC;
+// every stop is reported to gdb as a SIGTRAP
+const STOP_REPLY: &str = "S05";
+
+// gdb's amd64 register numbers: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15, rip, eflags, cs, ss, ds, es, fs, gs
+const RSP: usize = 7;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+const CS: usize = 18;
+const SS: usize = 19;
+const REGISTER_COUNT: usize = 24;
+
+/**
+ * The debugger's end of COM2 and the breakpoints it has set, as (address, the byte the int3 replaced).
+ */
+static GDB: IrqMutex<GdbStub> = IrqMutex::new(GdbStub {
+    port: SerialPort::new(COM2),
+    breakpoints: [None; MAX_BREAKPOINTS]
+});
+
+// set once COM2 is up, until then the exception handlers don't stop for gdb
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+struct GdbStub {
+    port: SerialPort,
+    breakpoints: [Option<(u64, u8)>; MAX_BREAKPOINTS]
+}
+
+/**
+ * What to do once gdb lets the kernel run again.
+ */
+enum Resume {
+    Continue,
+    Step
+}
+
+/**
+ * Sets up COM2 for gdb's remote serial protocol and stops right away, waiting for gdb to attach, e.g. with
+ * `target remote /dev/pts/N` for QEMU's `-serial pty` or `-serial tcp::1234,server`. Booting goes on once gdb continues.
+ * Only the interrupt stack frame is known to the stub: rip, rsp, eflags, cs and ss can be inspected, the rest are unavailable.
+ * Memory is read and written as is, touching an unmapped address takes the kernel down.
+ */
+pub fn init() -> Result<(), SerialError> {
+    GDB.lock().port.init(DEFAULT_BAUD_RATE)?;
+    ATTACHED.store(true, Ordering::Relaxed);
+    x86_64::instructions::interrupts::int3();
+    Ok(())
+}
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+/**
+ * Stops for gdb at a breakpoint exception.
+ * rip is moved back onto the int3 if gdb put it there, gdb expects to be told the address of the breakpoint.
+ */
+pub(crate) fn breakpoint(stack_frame: &mut InterruptStackFrame) {
+    let mut gdb = GDB.lock();
+    let address = stack_frame.instruction_pointer.as_u64() - 1;
+    if gdb.breakpoints.iter().flatten().any(|&(breakpoint, _)| breakpoint == address) {
+        unsafe { stack_frame.as_mut().instruction_pointer = VirtAddr::new(address) };
+    }
+    gdb.stop(stack_frame);
+}
+
+/**
+ * Stops for gdb at a debug exception, after a single step.
+ */
+pub(crate) fn debug(stack_frame: &mut InterruptStackFrame) {
+    GDB.lock().stop(stack_frame);
+}
+
+impl GdbStub {
+    /**
+     * Tells gdb the kernel stopped and serves its requests until it lets the kernel run again.
+     */
+    fn stop(&mut self, stack_frame: &mut InterruptStackFrame) {
+        self.send_packet(STOP_REPLY.as_bytes());
+        let mut packet = [0; PACKET_SIZE];
+        loop {
+            let len = self.receive_packet(&mut packet);
+            let mut reply = Reply::new();
+            match self.handle(&packet[..len], stack_frame, &mut reply) {
+                Some(resume) => {
+                    let mut flags = RFlags::from_bits_truncate(stack_frame.cpu_flags);
+                    flags.set(RFlags::TRAP_FLAG, match resume {
+                        Resume::Continue => false,
+                        Resume::Step => true
+                    });
+                    unsafe { stack_frame.as_mut().cpu_flags = flags.bits() };
+                    return;
+                }
+                None => self.send_packet(reply.as_bytes())
+            }
+        }
+    }
+
+    /**
+     * Answers a request in `reply`, or returns how to resume if it lets the kernel run.
+     * Unsupported requests get an empty reply, as the protocol asks.
+     */
+    fn handle(&mut self, packet: &[u8], stack_frame: &mut InterruptStackFrame, reply: &mut Reply) -> Option<Resume> {
+        let (&command, arguments) = packet.split_first()?;
+        match command {
+            b'?' => reply.push_str(STOP_REPLY),
+            b'g' => {
+                for register in 0..REGISTER_COUNT {
+                    write_register(reply, stack_frame, register);
+                }
+            }
+            b'p' => match parse_hex(arguments) {
+                Some(register) if (register as usize) < REGISTER_COUNT => write_register(reply, stack_frame, register as usize),
+                _ => reply.push_str("E01")
+            },
+            b'P' => {
+                let result = split(arguments, b'=').and_then(|(register, value)| {
+                    set_register(stack_frame, parse_hex(register)? as usize, parse_little_endian(value)?)
+                });
+                reply.push_str(if result.is_some() { "OK" } else { "E01" });
+            }
+            b'm' => match split(arguments, b',').and_then(|(address, len)| Some((parse_hex(address)?, parse_hex(len)?))) {
+                Some((address, len)) => {
+                    // two hex digits per byte
+                    let len = (len as usize).min(PACKET_SIZE / 2);
+                    for offset in 0..len {
+                        let byte = unsafe { core::ptr::read_volatile((address + offset as u64) as *const u8) };
+                        let _ = write!(reply, "{:02x}", byte);
+                    }
+                }
+                None => reply.push_str("E01")
+            },
+            b'M' => {
+                let result = split(arguments, b':').and_then(|(range, data)| {
+                    let (address, len) = split(range, b',')?;
+                    write_memory(parse_hex(address)?, parse_hex(len)? as usize, data)
+                });
+                reply.push_str(if result.is_some() { "OK" } else { "E01" });
+            }
+            b'Z' | b'z' => {
+                // only software breakpoints, the empty reply to the rest makes gdb write the int3 itself
+                if let Some(address) = parse_breakpoint(arguments) {
+                    let result = if command == b'Z' { self.insert(address) } else { self.remove(address) };
+                    reply.push_str(if result.is_some() { "OK" } else { "E01" });
+                }
+            }
+            b'c' | b's' => {
+                if !arguments.is_empty() {
+                    let address = VirtAddr::try_new(parse_hex(arguments)?).ok()?;
+                    unsafe { stack_frame.as_mut().instruction_pointer = address };
+                }
+                return Some(if command == b'c' { Resume::Continue } else { Resume::Step });
+            }
+            b'D' | b'k' => {
+                // let the kernel go on as if gdb was never there
+                for slot in 0..MAX_BREAKPOINTS {
+                    if let Some((address, _)) = self.breakpoints[slot] {
+                        self.remove(address);
+                    }
+                }
+                if command == b'D' {
+                    self.send_packet(b"OK");
+                }
+                return Some(Resume::Continue);
+            }
+            b'q' => {
+                if arguments.starts_with(b"Supported") {
+                    let _ = write!(reply, "PacketSize={:x}", PACKET_SIZE);
+                } else if arguments.starts_with(b"Attached") {
+                    reply.push_str("1");
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn insert(&mut self, address: u64) -> Option<()> {
+        if self.breakpoints.iter().flatten().any(|&(breakpoint, _)| breakpoint == address) {
+            return Some(());
+        }
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.is_none())?;
+        let original = unsafe { core::ptr::read_volatile(address as *const u8) };
+        write_memory_bytes(address, &[INT3]);
+        *slot = Some((address, original));
+        Some(())
+    }
+
+    fn remove(&mut self, address: u64) -> Option<()> {
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.map_or(false, |(breakpoint, _)| breakpoint == address))?;
+        if let Some((address, original)) = slot.take() {
+            write_memory_bytes(address, &[original]);
+        }
+        Some(())
+    }
+
+    /**
+     * Waits for a packet, `$data#checksum`, acknowledging it if it arrived intact and asking for it again otherwise.
+     * Returns the length of the data, longer packets are cut off.
+     */
+    fn receive_packet(&mut self, packet: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            while self.receive_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.receive_byte();
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    packet[len] = byte;
+                    len += 1;
+                }
+            }
+
+            let expected = [self.receive_byte(), self.receive_byte()];
+            if parse_hex(&expected) == Some(u64::from(checksum)) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /**
+     * Sends a packet until gdb acknowledges it.
+     */
+    fn send_packet(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send(b'$');
+            for &byte in data {
+                self.port.send(byte);
+            }
+            let _ = write!(self.port, "#{:02x}", checksum);
+            if self.receive_byte() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn receive_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.try_receive() {
+                return byte;
+            }
+        }
+    }
+}
+
+/**
+ * A reply on its way to gdb. What doesn't fit is dropped.
+ */
+struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply {
+            data: [0; PACKET_SIZE],
+            len: 0
+        }
+    }
+
+    fn push_str(&mut self, text: &str) {
+        let _ = self.write_str(text);
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let len = text.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&text.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/**
+ * Returns the value of the register if the stub knows it, and its size in bytes.
+ */
+fn register(stack_frame: &InterruptStackFrame, register: usize) -> (Option<u64>, usize) {
+    match register {
+        RSP => (Some(stack_frame.stack_pointer.as_u64()), 8),
+        RIP => (Some(stack_frame.instruction_pointer.as_u64()), 8),
+        EFLAGS => (Some(stack_frame.cpu_flags), 4),
+        CS => (Some(stack_frame.code_segment), 4),
+        SS => (Some(stack_frame.stack_segment), 4),
+        register if register < RIP => (None, 8),
+        _ => (None, 4)
+    }
+}
+
+/**
+ * Writes the register in target byte order, or as "xx"s if it is unavailable.
+ */
+fn write_register(reply: &mut Reply, stack_frame: &InterruptStackFrame, number: usize) {
+    let (value, size) = register(stack_frame, number);
+    for byte in 0..size {
+        let _ = match value {
+            Some(value) => write!(reply, "{:02x}", (value >> (byte * 8)) as u8),
+            None => reply.write_str("xx")
+        };
+    }
+}
+
+/**
+ * Only rip and eflags can be changed, the others are either unknown to the stub or not safe to change.
+ */
+fn set_register(stack_frame: &mut InterruptStackFrame, register: usize, value: u64) -> Option<()> {
+    match register {
+        RIP => unsafe { stack_frame.as_mut().instruction_pointer = VirtAddr::try_new(value).ok()? },
+        EFLAGS => unsafe { stack_frame.as_mut().cpu_flags = value },
+        _ => return None
+    }
+    Some(())
+}
+
+fn write_memory(address: u64, len: usize, data: &[u8]) -> Option<()> {
+    if data.len() != len * 2 {
+        return None;
+    }
+    for (offset, digits) in data.chunks(2).enumerate() {
+        write_memory_bytes(address + offset as u64, &[parse_hex(digits)? as u8]);
+    }
+    Some(())
+}
+
+/**
+ * Writes memory even if it is mapped read-only, like the kernel's code.
+ */
+fn write_memory_bytes(address: u64, bytes: &[u8]) {
+    unsafe {
+        let write_protect = Cr0::read().contains(Cr0Flags::WRITE_PROTECT);
+        Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT));
+        for (offset, &byte) in bytes.iter().enumerate() {
+            core::ptr::write_volatile((address + offset as u64) as *mut u8, byte);
+        }
+        Cr0::update(|flags| flags.set(Cr0Flags::WRITE_PROTECT, write_protect));
+    }
+}
+
+/**
+ * Parses `type,address,kind` of a Z or z packet, only software breakpoints (type 0) are taken.
+ */
+fn parse_breakpoint(arguments: &[u8]) -> Option<u64> {
+    let (kind, rest) = split(arguments, b',')?;
+    if kind != b"0" {
+        return None;
+    }
+    let (address, _) = split(rest, b',')?;
+    parse_hex(address)
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..position], &bytes[position + 1..]))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| Some(value << 4 | u64::from(hex_digit(digit)?)))
+}
+
+/**
+ * Parses a register value the way gdb sends it, in target byte order.
+ */
+fn parse_little_endian(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || digits.len() % 2 != 0 {
+        return None;
+    }
+    let mut value = 0;
+    for (byte, pair) in digits.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (byte * 8);
+    }
+    Some(value)
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None
+    }
+}
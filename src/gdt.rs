@@ -1,47 +1,236 @@
-use lazy_static::lazy_static;
-use x86_64::VirtAddr;
-use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use crate::apic;
+use crate::stack::{self, Stack, StackError};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::{PrivilegeLevel, VirtAddr};
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, DescriptorFlags, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 
+// each of these handlers gets a known good stack of its own, as they may come in while the kernel stack is bad,
+// or in the middle of one another
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-const STACK_SIZE: usize = 4096; // 4 KiB
-
-lazy_static! {
-    static ref TSS : TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK : [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe {&STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+pub const NMI_IST_INDEX: u16 = 1;
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+const IST_STACKS: usize = 4;
+const STACK_PAGES: u64 = 1; // 4 KiB
+// the stack the CPU switches to when an interrupt or a system call comes from ring 3 (RSP0)
+const PRIVILEGE_STACK_INDEX: usize = 0;
+const PRIVILEGE_STACK_PAGES: u64 = 4; // 16 KiB
+// the entries' indices, kernel data right after kernel code, and user data before user code, the order syscall and sysret expect
+const KERNEL_CODE_INDEX: u16 = 1;
+const USER_DATA_INDEX: u16 = 3;
+const USER_CODE_INDEX: u16 = 4;
+
+/**
+ * The CPUs with a GDT and TSS of their own, numbered in the order they run init(), whatever their APIC IDs.
+ */
+pub const MAX_CPUS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdtError {
+    // MAX_CPUS CPUs have a number already, the calling one with this APIC ID can't get one
+    TooManyCpus(u32),
+    // init() did not run on the CPU yet
+    NotLoaded,
+    Stack(StackError)
 }
 
-lazy_static! {
-    static ref GDT : (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
-    };
+impl From<StackError> for GdtError {
+    fn from(error: StackError) -> GdtError {
+        GdtError::Stack(error)
+    }
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+/**
+ * A CPU's stacks, each above a guard page, so a handler overflowing one faults instead of running into the next.
+ * Only the CPU itself touches them, the kernel just hands their tops to the TSS.
+ */
+struct CpuStacks {
+    interrupt: [Stack; IST_STACKS],
+    privilege: Stack
+}
+
+impl CpuStacks {
+    fn allocate() -> Result<CpuStacks, StackError> {
+        let interrupt = [
+            stack::allocate(STACK_PAGES)?,
+            stack::allocate(STACK_PAGES)?,
+            stack::allocate(STACK_PAGES)?,
+            stack::allocate(STACK_PAGES)?
+        ];
+        let privilege = stack::allocate(PRIVILEGE_STACK_PAGES)?;
+        Ok(CpuStacks {interrupt, privilege})
+    }
+}
+
+/**
+ * A CPU's TSS. The scheduler points its RSP0 at the running thread's stack at every switch, see set_kernel_stack(),
+ * while the CPU reads it through the GDT's descriptor, so it can't be behind a shared reference only.
+ */
+struct CpuTss(UnsafeCell<TaskStateSegment>);
+
+// only the CPU the TSS belongs to writes it
+unsafe impl Sync for CpuTss {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TSS: Once<CpuTss> = Once::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+// the initial APIC ID of each numbered CPU, and how many numbers were handed out
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+static CPUS: AtomicUsize = AtomicUsize::new(0);
+
+// built the first time each CPU runs init()
+static TSS: [Once<CpuTss>; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] = [NO_GDT; MAX_CPUS];
+
+/**
+ * Loads the calling CPU's GDT and TSS, building them and the stacks the TSS points at the first time.
+ * Each CPU runs it while starting up, after paging::init().
+ */
+pub fn init() -> Result<(), GdtError> {
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
+    let cpu = cpu_index()?;
+    let tss = match TSS[cpu].r#try() {
+        Some(tss) => tss,
+        None => {
+            let stacks = CpuStacks::allocate()?;
+            TSS[cpu].call_once(|| CpuTss(UnsafeCell::new(new_tss(&stacks))))
+        }
+    };
+    let (gdt, selectors) = GDT[cpu].call_once(|| new_gdt(unsafe { &*tss.0.get() }));
     // We can use the selectors to reload the cs segment register and load our TSS:
     // unsafe because it might be possible to break memory safety by loading invalid selectors.
-    GDT.0.load();
+    gdt.load();
+    unsafe {
+        set_cs(selectors.code_selector);
+        // the bootloader's selectors point into its own GDT, iretq would fault on a stale SS
+        load_ss(selectors.data_selector);
+        load_ds(selectors.data_selector);
+        load_es(selectors.data_selector);
+        load_tss(selectors.tss_selector);
+    }
+    Ok(())
+}
+
+/**
+ * The selector the kernel's code runs with.
+ */
+pub fn kernel_code_selector() -> SegmentSelector {
+    SegmentSelector::new(KERNEL_CODE_INDEX, PrivilegeLevel::Ring0)
+}
+
+/**
+ * The selectors to iretq into ring 3 with, CS and SS respectively. Both request privilege level 3.
+ * Every CPU's GDT has the same layout, so they are the same everywhere.
+ */
+pub fn user_code_selector() -> SegmentSelector {
+    with_ring_3(SegmentSelector::new(USER_CODE_INDEX, PrivilegeLevel::Ring0))
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    with_ring_3(SegmentSelector::new(USER_DATA_INDEX, PrivilegeLevel::Ring0))
+}
+
+/**
+ * Returns the top of the stack the calling CPU switches to when ring 3 code is interrupted.
+ */
+pub fn kernel_stack_top() -> Result<VirtAddr, GdtError> {
+    let tss = TSS[cpu_index()?].r#try().ok_or(GdtError::NotLoaded)?;
+    let privilege_stack_table = unsafe { (*tss.0.get()).privilege_stack_table };
+    Ok(privilege_stack_table[PRIVILEGE_STACK_INDEX])
+}
+
+/**
+ * Points the calling CPU's RSP0 at the given stack top, the stack of the thread switched in, see scheduler.rs.
+ * Interrupts must be disabled, the CPU must not take one from ring 3 halfway through.
+ */
+pub fn set_kernel_stack(top: VirtAddr) -> Result<(), GdtError> {
+    let tss = TSS[cpu_index()?].r#try().ok_or(GdtError::NotLoaded)?;
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        // the TSS is packed, its table can only be copied out and back in
+        let mut privilege_stack_table = (*tss.0.get()).privilege_stack_table;
+        privilege_stack_table[PRIVILEGE_STACK_INDEX] = top;
+        (*tss.0.get()).privilege_stack_table = privilege_stack_table;
     }
+    Ok(())
+}
+
+fn new_tss(stacks: &CpuStacks) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    // the TSS is packed, its table can only be copied out and back in
+    let mut interrupt_stack_table = tss.interrupt_stack_table;
+    for (entry, stack) in interrupt_stack_table.iter_mut().zip(stacks.interrupt.iter()) {
+        // the stacks grow down, the TSS points at their end
+        *entry = stack.top();
+    }
+    tss.interrupt_stack_table = interrupt_stack_table;
+    tss.privilege_stack_table[PRIVILEGE_STACK_INDEX] = stacks.privilege.top();
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(kernel_data_segment());
+    // add_entry() gives ring 0 selectors, the user ones must request ring 3
+    let user_data_selector = with_ring_3(gdt.add_entry(Descriptor::user_data_segment()));
+    let user_code_selector = with_ring_3(gdt.add_entry(Descriptor::user_code_segment()));
+    debug_assert_eq!(
+        (code_selector.index(), user_data_selector.index(), user_code_selector.index()),
+        (KERNEL_CODE_INDEX, USER_DATA_INDEX, USER_CODE_INDEX)
+    );
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors {code_selector, data_selector, tss_selector})
+}
+
+/**
+ * Returns the calling CPU's slot in the tables. It is found by the initial APIC ID, which the CPU reports even before
+ * its APIC is enabled, but the IDs can be sparse and large, so the first time a CPU asks it is given the next free slot:
+ * the boot CPU 0, the others in the order they start up.
+ */
+pub(crate) fn cpu_index() -> Result<usize, GdtError> {
+    let apic_id = apic::initial_id();
+    let cpus = CPUS.load(Ordering::Acquire);
+    // a slot taken by another CPU that has not stored its ID yet can't be the calling CPU's
+    if let Some(cpu) = APIC_IDS[..cpus].iter().position(|id| id.load(Ordering::Relaxed) == apic_id) {
+        return Ok(cpu);
+    }
+    let mut cpu = cpus;
+    loop {
+        if cpu >= MAX_CPUS {
+            return Err(GdtError::TooManyCpus(apic_id));
+        }
+        match CPUS.compare_exchange(cpu, cpu + 1, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(taken) => cpu = taken
+        }
+    }
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+    Ok(cpu)
+}
+
+/**
+ * The x86_64 crate has no ring 0 data segment, it is the user one without the ring 3 privilege level.
+ */
+fn kernel_data_segment() -> Descriptor {
+    let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+    Descriptor::UserSegment(flags.bits())
+}
+
+fn with_ring_3(selector: SegmentSelector) -> SegmentSelector {
+    SegmentSelector::new(selector.index(), PrivilegeLevel::Ring3)
 }
 
 struct Selectors {
     code_selector : SegmentSelector,
+    data_selector : SegmentSelector,
     tss_selector : SegmentSelector
-}
\ No newline at end of file
+}
@@ -0,0 +1,224 @@
+#[cfg(feature = "debug_heap")]
+use crate::debug_heap::DebugAllocator;
+use crate::frame_allocator;
+use crate::kaslr::{self, Region};
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, PagingError};
+use crate::sync::IrqMutex;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+// far from the kernel and the physical memory mapping, moved up by the KASLR slide
+pub const HEAP_START: u64 = 0x_4444_4444_0000;
+pub const HEAP_SIZE: u64 = 1024 * 1024; // 1 MiB
+const PAGE_SIZE: u64 = 4096;
+
+// every block is a multiple of this, so whatever is left of a free region can hold a Node
+const BLOCK_SIZE: usize = mem::size_of::<Node>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    // there are no free frames left for the heap
+    NoMemory,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for HeapError {
+    fn from(error: PagingError) -> HeapError {
+        HeapError::Paging(error)
+    }
+}
+
+/**
+ * A free region, written at its own start.
+ */
+#[repr(C, align(16))]
+struct Node {
+    size: usize,
+    next: *mut Node
+}
+
+/**
+ * The free regions in address order, so a freed block merges with the free ones right before and after it.
+ */
+struct Heap {
+    head: *mut Node,
+    size: usize,
+    used: usize
+}
+
+// the regions are only reached through HEAP's lock
+unsafe impl Send for Heap {}
+
+static HEAP: IrqMutex<Heap> = IrqMutex::new(Heap {
+    head: ptr::null_mut(),
+    size: 0,
+    used: 0
+});
+
+/**
+ * The allocator behind Box, Vec and the rest of the alloc crate, handing out blocks of the kernel heap.
+ * Allocations fail until init().
+ */
+pub struct KernelAllocator;
+
+#[cfg(not(feature = "debug_heap"))]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+// poisons, checks and tracks the allocations around the heap, see debug_heap.rs
+#[cfg(feature = "debug_heap")]
+#[global_allocator]
+static ALLOCATOR: DebugAllocator = DebugAllocator::new(KernelAllocator);
+
+/**
+ * How much of the heap is taken, see stats().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize
+}
+
+/**
+ * Maps fresh frames for the heap and hands it to the allocator. Needs frame_allocator::init() and paging::init().
+ */
+pub fn init() -> Result<(), HeapError> {
+    let heap_start = start();
+    let start = Page::containing_address(VirtAddr::new(heap_start));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in Page::range(start, start + HEAP_SIZE / PAGE_SIZE) {
+        let frame = frame_allocator::allocate_frame().ok_or(HeapError::NoMemory)?;
+        memory::frames_taken(Subsystem::Heap, 1);
+        // the frame is fresh from the allocator
+        unsafe { paging::map_to(page, frame, flags)? };
+    }
+
+    let node = heap_start as *mut Node;
+    unsafe {
+        node.write(Node {
+            size: HEAP_SIZE as usize,
+            next: ptr::null_mut()
+        });
+    }
+    let mut heap = HEAP.lock();
+    heap.head = node;
+    heap.size = HEAP_SIZE as usize;
+    Ok(())
+}
+
+/**
+ * Returns where the heap starts, after the KASLR slide.
+ */
+pub fn start() -> u64 {
+    HEAP_START + kaslr::slide(Region::Heap)
+}
+
+pub fn stats() -> HeapStats {
+    let heap = HEAP.lock();
+    HeapStats {
+        size: heap.size,
+        used: heap.used,
+        free: heap.size - heap.used
+    }
+}
+
+impl Heap {
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut previous: *mut *mut Node = &mut self.head;
+        while !(*previous).is_null() {
+            let region = *previous;
+            let region_start = region as usize;
+            let region_end = region_start + (*region).size;
+            let start = align_up(region_start, align);
+            let end = match start.checked_add(size) {
+                Some(end) if end <= region_end => end,
+                _ => {
+                    previous = &mut (*region).next;
+                    continue;
+                }
+            };
+
+            // what is left before and after the block stays free, in address order
+            let mut next = (*region).next;
+            if end < region_end {
+                let after = end as *mut Node;
+                after.write(Node {
+                    size: region_end - end,
+                    next
+                });
+                next = after;
+            }
+            if start > region_start {
+                (*region).size = start - region_start;
+                (*region).next = next;
+                next = region;
+            }
+            *previous = next;
+            self.used += size;
+            return start as *mut u8;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn free(&mut self, block: *mut u8, size: usize) {
+        let start = block as usize;
+        let mut before: *mut Node = ptr::null_mut();
+        let mut after = self.head;
+        while !after.is_null() && (after as usize) < start {
+            before = after;
+            after = (*after).next;
+        }
+
+        let node = block as *mut Node;
+        node.write(Node { size, next: after });
+        if !after.is_null() && start + size == after as usize {
+            (*node).size += (*after).size;
+            (*node).next = (*after).next;
+        }
+        if before.is_null() {
+            self.head = node;
+        } else if before as usize + (*before).size == start {
+            (*before).size += (*node).size;
+            (*before).next = (*node).next;
+        } else {
+            (*before).next = node;
+        }
+        self.used -= size;
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        HEAP.lock().allocate(size, align)
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        HEAP.lock().free(block, size);
+    }
+}
+
+/**
+ * Returns the size and alignment of the block for an allocation, both multiples of BLOCK_SIZE,
+ * so the free regions always start and end at multiples of it too.
+ */
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(BLOCK_SIZE), BLOCK_SIZE);
+    let align = layout.align().max(mem::align_of::<Node>());
+    (size, align)
+}
+
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("the kernel heap is out of memory allocating {:?}, see heap::stats()", layout);
+}
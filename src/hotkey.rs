@@ -0,0 +1,228 @@
+use crate::info;
+use crate::ipc;
+use crate::keyboard::Modifiers;
+use crate::power;
+use crate::process;
+use crate::sched;
+use crate::sync::IrqMutex;
+use crate::vga_buffer::WRITER;
+use crate::vt;
+use pc_keyboard::KeyCode;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+// how many key combinations and SysRq keys can be bound at the same time
+const MAX_HOTKEYS: usize = 16;
+const MAX_SYSRQ_KEYS: usize = 16;
+
+/**
+ * Called in kernel context, with no keyboard lock held, when its key combination is pressed.
+ */
+pub type Handler = fn();
+
+// a SysRq key, what it does for the help and its handler
+type SysRqKey = (KeyCode, &'static str, Handler);
+
+/**
+ * A key pressed with exactly the given modifiers held down, e.g. `KeyCombination::new(KeyCode::Delete).with_ctrl().with_alt()`.
+ * Left and right modifiers are not told apart.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCombination {
+    pub key: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool
+}
+
+impl KeyCombination {
+    pub const fn new(key: KeyCode) -> KeyCombination {
+        KeyCombination {
+            key,
+            ctrl: false,
+            alt: false,
+            shift: false
+        }
+    }
+
+    pub const fn with_ctrl(self) -> KeyCombination {
+        KeyCombination { ctrl: true, ..self }
+    }
+
+    pub const fn with_alt(self) -> KeyCombination {
+        KeyCombination { alt: true, ..self }
+    }
+
+    pub const fn with_shift(self) -> KeyCombination {
+        KeyCombination { shift: true, ..self }
+    }
+
+    fn matches(&self, key: KeyCode, modifiers: &Modifiers) -> bool {
+        self.key == key && self.ctrl == modifiers.ctrl && self.alt == modifiers.alt && self.shift == modifiers.shift
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyError {
+    TooManyHotkeys,
+    AlreadyBound,
+    NotBound
+}
+
+/**
+ * Key combinations handled by the kernel itself instead of being passed on as input.
+ * By default Alt+F1..Alt+F4 switch terminals, Shift+PageUp/PageDown scroll back and Ctrl+Alt+Delete reboots.
+ */
+static HOTKEYS: IrqMutex<[Option<(KeyCombination, Handler)>; MAX_HOTKEYS]> = IrqMutex::new([
+    Some((KeyCombination::new(KeyCode::F1).with_alt(), switch_to_vt1)),
+    Some((KeyCombination::new(KeyCode::F2).with_alt(), switch_to_vt2)),
+    Some((KeyCombination::new(KeyCode::F3).with_alt(), switch_to_vt3)),
+    Some((KeyCombination::new(KeyCode::F4).with_alt(), switch_to_vt4)),
+    Some((KeyCombination::new(KeyCode::PageUp).with_shift(), scrollback_up)),
+    Some((KeyCombination::new(KeyCode::PageDown).with_shift(), scrollback_down)),
+    Some((KeyCombination::new(KeyCode::Delete).with_ctrl().with_alt(), reboot)),
+    None, None, None, None, None, None, None, None, None
+]);
+
+/**
+ * Debug keys, pressed while SysRq (Alt+PrintScreen) is held, as (key, what it does, handler).
+ * They work whatever the terminals are doing, as long as the keyboard is decoded.
+ */
+static SYSRQ_KEYS: IrqMutex<[Option<SysRqKey>; MAX_SYSRQ_KEYS]> = IrqMutex::new([
+    Some((KeyCode::B, "reboot", reboot)),
+    Some((KeyCode::H, "list the SysRq keys", sysrq_help)),
+    Some((KeyCode::P, "dump the control registers", dump_registers)),
+    Some((KeyCode::T, "dump the task list", dump_tasks)),
+    None, None, None, None, None, None, None, None, None, None, None, None
+]);
+
+/**
+ * Binds a key combination to a handler. A combination can only be bound once.
+ */
+pub fn register(combination: KeyCombination, handler: Handler) -> Result<(), HotkeyError> {
+    let mut hotkeys = HOTKEYS.lock();
+    if hotkeys.iter().flatten().any(|&(bound, _)| bound == combination) {
+        return Err(HotkeyError::AlreadyBound);
+    }
+    let slot = hotkeys.iter_mut().find(|slot| slot.is_none()).ok_or(HotkeyError::TooManyHotkeys)?;
+    *slot = Some((combination, handler));
+    Ok(())
+}
+
+pub fn unregister(combination: KeyCombination) -> Result<(), HotkeyError> {
+    let mut hotkeys = HOTKEYS.lock();
+    let slot = hotkeys.iter_mut()
+        .find(|slot| slot.map_or(false, |(bound, _)| bound == combination))
+        .ok_or(HotkeyError::NotBound)?;
+    *slot = None;
+    Ok(())
+}
+
+/**
+ * Binds a SysRq key to a handler, replacing the one it had, e.g. for the scheduler to provide the task list.
+ */
+pub fn register_sysrq(key: KeyCode, description: &'static str, handler: Handler) -> Result<(), HotkeyError> {
+    let mut keys = SYSRQ_KEYS.lock();
+    let slot = match keys.iter().position(|slot| slot.map_or(false, |(bound, _, _)| bound == key)) {
+        Some(index) => &mut keys[index],
+        None => keys.iter_mut().find(|slot| slot.is_none()).ok_or(HotkeyError::TooManyHotkeys)?
+    };
+    *slot = Some((key, description, handler));
+    Ok(())
+}
+
+/**
+ * Returns the handler bound to the key pressed with the given modifiers, if any.
+ * With SysRq held, only the SysRq keys count.
+ */
+pub(crate) fn find(key: KeyCode, modifiers: &Modifiers, sysrq: bool) -> Option<Handler> {
+    if sysrq {
+        SYSRQ_KEYS.lock().iter().flatten()
+            .find(|&&(bound, _, _)| bound == key)
+            .map(|&(_, _, handler)| handler)
+    } else {
+        HOTKEYS.lock().iter().flatten()
+            .find(|(combination, _)| combination.matches(key, modifiers))
+            .map(|&(_, handler)| handler)
+    }
+}
+
+fn switch_to_vt1() {
+    vt::switch_to(0);
+}
+
+fn switch_to_vt2() {
+    vt::switch_to(1);
+}
+
+fn switch_to_vt3() {
+    vt::switch_to(2);
+}
+
+fn switch_to_vt4() {
+    vt::switch_to(3);
+}
+
+fn scrollback_up() {
+    WRITER.lock().page_up();
+}
+
+fn scrollback_down() {
+    WRITER.lock().page_down();
+}
+
+fn reboot() {
+    info!("rebooting");
+    power::reboot();
+}
+
+fn sysrq_help() {
+    // copied, so the log sinks run without the lock held
+    let keys = *SYSRQ_KEYS.lock();
+    for &(key, description, _) in keys.iter().flatten() {
+        info!("sysrq {:?}: {}", key, description);
+    }
+}
+
+fn dump_registers() {
+    let (page_table, _) = Cr3::read();
+    info!(
+        "cr0 {:#x} cr2 {:#x} cr3 {:#x} cr4 {:#x} rflags {:#x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        page_table.start_address().as_u64(),
+        Cr4::read_raw(),
+        x86_64::registers::rflags::read_raw()
+    );
+}
+
+fn dump_tasks() {
+    for process in process::processes() {
+        info!("process {} {}: {} threads, exit status {:?}", process.pid, process.name, process.threads, process.exit_status);
+    }
+    let stats = sched::stats();
+    info!(
+        "scheduler: {} switches, {} running, {} ready {:?}, {} blocked, {} idle ticks",
+        stats.switches,
+        stats.running,
+        stats.ready,
+        stats.queued,
+        stats.blocked,
+        stats.idle_ticks
+    );
+    for thread in stats.threads {
+        info!(
+            "thread {}: {:?} on {:?}, {:?}, {} ticks, {} us, {} switches, {} preempted",
+            thread.id,
+            thread.state,
+            thread.cpu,
+            thread.priority,
+            thread.ticks,
+            thread.run_time_ns / 1000,
+            thread.switches,
+            thread.preemptions
+        );
+    }
+    for port in ipc::ports() {
+        info!("port {} {:?}: owner {:?}, {} queued", port.id, port.name, port.owner, port.queued);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::acpi::{self, AcpiError};
+use crate::paging::{self, PagingError};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+const SIGNATURE: &[u8; 4] = b"HPET";
+// the table's base address is a generic address structure, whose address is 4 bytes in
+const BASE_ADDRESS_OFFSET: usize = 44;
+const TABLE_LENGTH: usize = 56;
+
+// registers, at byte offsets from the base address, in a 1 KiB block
+const REGISTERS_SIZE: u64 = 0x400;
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+const TIMER_0_CONFIGURATION: usize = 0x100;
+const TIMER_0_COMPARATOR: usize = 0x108;
+
+// capabilities: the upper half is the counter's period in femtoseconds
+const PERIOD_SHIFT: u32 = 32;
+const LEGACY_ROUTE_CAPABLE: u64 = 1 << 15;
+// configuration: the legacy route sends timer 0 to IRQ0 instead of the PIT, and timer 1 to IRQ8 instead of the RTC
+const ENABLE: u64 = 1 << 0;
+const LEGACY_ROUTE: u64 = 1 << 1;
+// timer configuration
+const INTERRUPT_ENABLE: u64 = 1 << 2;
+const PERIODIC: u64 = 1 << 3;
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+// the next comparator write sets the period instead of the next expiry
+const VALUE_SET: u64 = 1 << 6;
+
+// the specification allows counter periods up to 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+// the registers' virtual address and the counter's period, 0 without an HPET
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    // the table is too short to have the base address
+    InvalidTable,
+    // the counter's period is 0 or longer than allowed, the registers are probably not there
+    InvalidPeriod(u64),
+    // timer 0 can't interrupt periodically through IRQ0
+    NoLegacyPeriodicTimer,
+    NotAvailable,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for HpetError {
+    fn from(error: PagingError) -> HpetError {
+        HpetError::Paging(error)
+    }
+}
+
+impl From<AcpiError> for HpetError {
+    fn from(error: AcpiError) -> HpetError {
+        HpetError::Acpi(error)
+    }
+}
+
+/**
+ * Finds the HPET through ACPI and starts its main counter from 0, with its timers off.
+ * Needs acpi::init() first.
+ */
+pub fn init() -> Result<(), HpetError> {
+    let table = acpi::find_table(SIGNATURE)?;
+    if table.len() < TABLE_LENGTH {
+        return Err(HpetError::InvalidTable);
+    }
+    let address = PhysAddr::new(acpi::read_u64(table, BASE_ADDRESS_OFFSET));
+    let base = paging::map_mmio(address, REGISTERS_SIZE)?.as_u64();
+
+    let period = read(base, CAPABILITIES) >> PERIOD_SHIFT;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return Err(HpetError::InvalidPeriod(period));
+    }
+    // the counter can only be written while it is stopped
+    write(base, CONFIGURATION, read(base, CONFIGURATION) & !(ENABLE | LEGACY_ROUTE));
+    write(base, MAIN_COUNTER, 0);
+    write(base, CONFIGURATION, read(base, CONFIGURATION) | ENABLE);
+
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    BASE.store(base, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/**
+ * Returns the main counter, which counts up once a period since init().
+ */
+pub fn counter() -> u64 {
+    match BASE.load(Ordering::Relaxed) {
+        0 => 0,
+        base => read(base, MAIN_COUNTER)
+    }
+}
+
+/**
+ * Returns the main counter's period in femtoseconds, 0 without an HPET.
+ */
+pub fn period_fs() -> u64 {
+    PERIOD_FS.load(Ordering::Relaxed)
+}
+
+/**
+ * Returns the nanoseconds since init(), as precise as the counter's period.
+ */
+pub fn nanos() -> u64 {
+    (u128::from(counter()) * u128::from(period_fs()) / u128::from(FEMTOS_PER_NANO)) as u64
+}
+
+/**
+ * Makes timer 0 interrupt periodically through IRQ0, in place of the PIT, about every given number of nanoseconds.
+ * Returns the period it really got. The legacy route this needs takes IRQ8 from the RTC as well.
+ */
+pub fn start_periodic(period_ns: u64) -> Result<u64, HpetError> {
+    let base = BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return Err(HpetError::NotAvailable);
+    }
+    let timer = read(base, TIMER_0_CONFIGURATION);
+    if read(base, CAPABILITIES) & LEGACY_ROUTE_CAPABLE == 0 || timer & PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NoLegacyPeriodicTimer);
+    }
+    let period = period_fs();
+    let ticks = (period_ns * FEMTOS_PER_NANO / period).max(1);
+
+    // the counter is stopped so the first expiry can't be missed while it is set up
+    let configuration = read(base, CONFIGURATION);
+    write(base, CONFIGURATION, configuration & !ENABLE);
+    write(base, TIMER_0_CONFIGURATION, timer | INTERRUPT_ENABLE | PERIODIC | VALUE_SET);
+    write(base, TIMER_0_COMPARATOR, read(base, MAIN_COUNTER) + ticks);
+    write(base, TIMER_0_COMPARATOR, ticks);
+    write(base, CONFIGURATION, configuration | ENABLE | LEGACY_ROUTE);
+    Ok(ticks * period / FEMTOS_PER_NANO)
+}
+
+fn read(base: u64, register: usize) -> u64 {
+    unsafe { ptr::read_volatile((base as usize + register) as *const u64) }
+}
+
+fn write(base: u64, register: usize, value: u64) {
+    unsafe { ptr::write_volatile((base as usize + register) as *mut u64, value) }
+}
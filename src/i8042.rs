@@ -0,0 +1,255 @@
+use crate::time;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+// the keyboard's scancodes and the answers to commands come in here, data for the devices goes out here
+pub(crate) const DATA_PORT: u16 = 0x60;
+// reading gives the status, writing sends a command to the controller itself
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+// status bits
+const OUTPUT_BUFFER_FULL: u8 = 0x01;
+const INPUT_BUFFER_FULL: u8 = 0x02;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xA7;
+const ENABLE_SECOND_PORT: u8 = 0xA8;
+const TEST_SECOND_PORT: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_FIRST_PORT: u8 = 0xAB;
+const DISABLE_FIRST_PORT: u8 = 0xAD;
+const ENABLE_FIRST_PORT: u8 = 0xAE;
+// the next byte written to the data port goes to the second port's device
+const WRITE_SECOND_PORT: u8 = 0xD4;
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// configuration byte bits
+const FIRST_PORT_INTERRUPT: u8 = 0x01;
+const SECOND_PORT_INTERRUPT: u8 = 0x02;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 0x20;
+// the controller translates the keyboard's set 2 scancodes to set 1
+const TRANSLATION: u8 = 0x40;
+
+// keyboard commands
+const SET_SCANCODE_SET: u8 = 0xF0;
+const SCANCODE_SET_2: u8 = 0x02;
+const ACK: u8 = 0xFA;
+
+// how long to wait for the controller or a device, a mouse can take half a second for its self test
+const TIMEOUT_US: u64 = 500_000;
+const POLL_INTERVAL_US: u64 = 10;
+
+// whether the controller has a port for a mouse
+static DUAL_CHANNEL: AtomicBool = AtomicBool::new(false);
+// whether scancodes arrive translated to set 1, they come in set 2 otherwise
+static TRANSLATED: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I8042Error {
+    // the controller or the keyboard didn't answer in time
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    // the keyboard refused to switch to scancode set 2
+    ScancodeSetRejected(u8),
+    // the keyboard asked for a byte again, it did not take it
+    Resend
+}
+
+/**
+ * The scancode set the keyboard's bytes are in, as decoded by keyboard::read_key().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2
+}
+
+/**
+ * Brings up the PS/2 controller: runs its self test, finds out whether it has a second port,
+ * tests the keyboard port and enables it with its interrupt.
+ * Scancodes are translated to set 1 where the controller can do it, otherwise the keyboard is switched to set 2.
+ * Must run with interrupts disabled, the answers are polled for.
+ */
+pub fn init() -> Result<(), I8042Error> {
+    write_command(DISABLE_FIRST_PORT)?;
+    write_command(DISABLE_SECOND_PORT)?;
+    flush();
+
+    let mut config = read_config()?;
+    config &= !(FIRST_PORT_INTERRUPT | SECOND_PORT_INTERRUPT);
+    write_config(config)?;
+
+    write_command(SELF_TEST)?;
+    match read_data()? {
+        SELF_TEST_PASSED => {}
+        answer => return Err(I8042Error::SelfTestFailed(answer))
+    }
+    // the self test may have reset the controller
+    write_config(config)?;
+
+    // the second port's clock only comes on if there is one
+    let mut dual_channel = false;
+    if config & SECOND_PORT_CLOCK_DISABLED != 0 {
+        write_command(ENABLE_SECOND_PORT)?;
+        dual_channel = read_config()? & SECOND_PORT_CLOCK_DISABLED == 0;
+        write_command(DISABLE_SECOND_PORT)?;
+    }
+    if dual_channel {
+        write_command(TEST_SECOND_PORT)?;
+        dual_channel = read_data()? == PORT_TEST_PASSED;
+    }
+    DUAL_CHANNEL.store(dual_channel, Ordering::Relaxed);
+
+    write_command(TEST_FIRST_PORT)?;
+    match read_data()? {
+        PORT_TEST_PASSED => {}
+        answer => return Err(I8042Error::PortTestFailed(answer))
+    }
+
+    write_command(ENABLE_FIRST_PORT)?;
+    write_config(config | TRANSLATION)?;
+    let translated = read_config()? & TRANSLATION != 0;
+    if !translated {
+        // the keyboard may have been left in another set by the firmware
+        send_to_first_port(SET_SCANCODE_SET)?;
+        expect_ack()?;
+        send_to_first_port(SCANCODE_SET_2)?;
+        expect_ack()?;
+    }
+    TRANSLATED.store(translated, Ordering::Relaxed);
+
+    // the interrupt comes on last, the answers above were polled
+    write_config(read_config()? | FIRST_PORT_INTERRUPT)?;
+    flush();
+    Ok(())
+}
+
+/**
+ * Returns the scancode set the keyboard's bytes arrive in.
+ */
+pub fn scancode_set() -> ScancodeSet {
+    if TRANSLATED.load(Ordering::Relaxed) {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    }
+}
+
+/**
+ * Tells whether the controller has a second port, usually for a mouse.
+ */
+pub fn has_second_port() -> bool {
+    DUAL_CHANNEL.load(Ordering::Relaxed)
+}
+
+/**
+ * Enables the second port, with its interrupt still off, so its device can be set up by polling.
+ */
+pub fn enable_second_port() -> Result<(), I8042Error> {
+    write_command(ENABLE_SECOND_PORT)
+}
+
+/**
+ * Lets the second port's device raise IRQ12.
+ */
+pub fn enable_second_port_interrupt() -> Result<(), I8042Error> {
+    write_config(read_config()? | SECOND_PORT_INTERRUPT)
+}
+
+pub fn disable_second_port() -> Result<(), I8042Error> {
+    write_config(read_config()? & !SECOND_PORT_INTERRUPT)?;
+    write_command(DISABLE_SECOND_PORT)
+}
+
+/**
+ * Sends a byte to the device on the first port, the keyboard.
+ */
+pub fn send_to_first_port(byte: u8) -> Result<(), I8042Error> {
+    write_data(byte)
+}
+
+/**
+ * Sends a byte to the device on the second port, the mouse.
+ */
+pub fn send_to_second_port(byte: u8) -> Result<(), I8042Error> {
+    write_command(WRITE_SECOND_PORT)?;
+    write_data(byte)
+}
+
+/**
+ * Waits for the next byte from either device. Only meant for setting them up before their interrupts are on,
+ * the interrupt handlers take the bytes otherwise.
+ */
+pub fn read_response() -> Result<u8, I8042Error> {
+    read_data()
+}
+
+fn expect_ack() -> Result<(), I8042Error> {
+    match read_data()? {
+        ACK => Ok(()),
+        answer => Err(I8042Error::ScancodeSetRejected(answer))
+    }
+}
+
+fn read_config() -> Result<u8, I8042Error> {
+    write_command(READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), I8042Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/**
+ * Throws away whatever is waiting in the output buffer.
+ */
+fn flush() {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+        if status() & OUTPUT_BUFFER_FULL == 0 {
+            return;
+        }
+        unsafe { data.read() };
+    }
+}
+
+fn write_command(command: u8) -> Result<(), I8042Error> {
+    wait_for(|status| status & INPUT_BUFFER_FULL == 0)?;
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), I8042Error> {
+    wait_for(|status| status & INPUT_BUFFER_FULL == 0)?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, I8042Error> {
+    wait_for(|status| status & OUTPUT_BUFFER_FULL != 0)?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+fn wait_for<F: Fn(u8) -> bool>(ready: F) -> Result<(), I8042Error> {
+    for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+        if ready(status()) {
+            return Ok(());
+        }
+        time::busy_wait_us(POLL_INTERVAL_US);
+    }
+    Err(I8042Error::Timeout)
+}
+
+fn status() -> u8 {
+    let mut port: Port<u8> = Port::new(STATUS_PORT);
+    unsafe { port.read() }
+}
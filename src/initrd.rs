@@ -0,0 +1,186 @@
+use crate::vfs::{self, OpenOptions, VfsError};
+#[cfg(feature = "initrd")]
+use crate::info;
+use crate::{debug, warn};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+// headers and file contents are in blocks of this size, the contents padded with zeros to a whole block
+const BLOCK_SIZE: usize = 512;
+const NAME: (usize, usize) = (0, 100);
+const SIZE: (usize, usize) = (124, 136);
+const CHECKSUM: (usize, usize) = (148, 156);
+const TYPE_FLAG: usize = 156;
+// "ustar\0" from POSIX, "ustar " from GNU tar, either is followed by the same fields
+const MAGIC: (usize, usize) = (257, 262);
+const PREFIX: (usize, usize) = (345, 500);
+const REGULAR: u8 = b'0';
+// a regular file to tar programs older than ustar
+const OLD_REGULAR: u8 = 0;
+const DIRECTORY: u8 = b'5';
+
+/**
+ * The initial ramdisk, linked into the kernel with the initrd feature: the bootloader can't load modules,
+ * so the archive is the file initrd.tar in the crate's root at build time. The one in the repository is empty.
+ */
+#[cfg(feature = "initrd")]
+static ARCHIVE: &[u8] = include_bytes!("../initrd.tar");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    // the archive ends in the middle of a header or a file
+    Truncated,
+    // a header is not a ustar one, or a number in it is not octal
+    BadHeader,
+    BadChecksum
+}
+
+/**
+ * Unpacks the initial ramdisk into the file system mounted at /. Needs the ramfs.
+ */
+#[cfg(feature = "initrd")]
+pub fn init() -> Result<(), InitrdError> {
+    let files = unpack(ARCHIVE)?;
+    info!("initrd: {} files, {} bytes", files, ARCHIVE.len());
+    Ok(())
+}
+
+/**
+ * Makes the directories and regular files of a ustar archive under /, the missing directories on their paths too,
+ * and returns how many files it made. A file that is there already is overwritten.
+ * Links, devices and the headers of tar's extensions, such as long names, are skipped.
+ * An entry that can't be made is skipped with a warning, a malformed archive stops it.
+ */
+pub fn unpack(archive: &[u8]) -> Result<usize, InitrdError> {
+    let mut files = 0;
+    let mut offset = 0;
+    // two zero blocks end it, but an archive cut short after the last file is taken as whole
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        check(header)?;
+        let size = octal(field(header, SIZE))? as usize;
+        let start = offset + BLOCK_SIZE;
+        let end = start.checked_add(size).filter(|&end| end <= archive.len()).ok_or(InitrdError::Truncated)?;
+        let path = path(header)?;
+        match header[TYPE_FLAG] {
+            REGULAR | OLD_REGULAR => match make_file(&path, &archive[start..end]) {
+                Ok(()) => files += 1,
+                Err(error) => warn!("initrd: {}: {:?}", path, error)
+            },
+            DIRECTORY => {
+                if let Err(error) = make_directories(&path, true) {
+                    warn!("initrd: {}: {:?}", path, error);
+                }
+            }
+            flag => debug!("initrd: {}: skipped, type {:?}", path, flag as char)
+        }
+        offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+    Ok(files)
+}
+
+/**
+ * Checks a header's magic and checksum: the sum of its bytes, with the checksum's own field taken as spaces.
+ */
+fn check(header: &[u8]) -> Result<(), InitrdError> {
+    if &header[MAGIC.0..MAGIC.1] != b"ustar" {
+        return Err(InitrdError::BadHeader);
+    }
+    let expected = octal(field(header, CHECKSUM))?;
+    let sum = header.iter().enumerate().map(|(index, &byte)| {
+        if index >= CHECKSUM.0 && index < CHECKSUM.1 {
+            u64::from(b' ')
+        } else {
+            u64::from(byte)
+        }
+    }).sum::<u64>();
+    if sum != expected {
+        return Err(InitrdError::BadChecksum);
+    }
+    Ok(())
+}
+
+/**
+ * Returns a field of a header, up to its terminating NUL if it is shorter than the field.
+ */
+fn field(header: &[u8], (start, end): (usize, usize)) -> &[u8] {
+    let field = &header[start..end];
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..length]
+}
+
+/**
+ * Parses a number of a header, in octal digits between spaces.
+ */
+fn octal(field: &[u8]) -> Result<u64, InitrdError> {
+    let digits = str::from_utf8(field).map_err(|_| InitrdError::BadHeader)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| InitrdError::BadHeader)
+}
+
+/**
+ * Returns the absolute path of an entry, from its prefix and name, without the "." names tar puts in front.
+ * A path going up with ".." is refused, the archive's files stay under /.
+ */
+fn path(header: &[u8]) -> Result<String, InitrdError> {
+    let prefix = str::from_utf8(field(header, PREFIX)).map_err(|_| InitrdError::BadHeader)?;
+    let name = str::from_utf8(field(header, NAME)).map_err(|_| InitrdError::BadHeader)?;
+    let names = prefix.split('/').chain(name.split('/')).filter(|&name| !name.is_empty() && name != ".");
+    let mut path = String::new();
+    for name in names {
+        if name == ".." {
+            return Err(InitrdError::BadHeader);
+        }
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+/**
+ * Makes the directories on an absolute path that are not there yet, but for the last name unless last is set.
+ */
+fn make_directories(path: &str, last: bool) -> Result<(), VfsError> {
+    let mut names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    if !last {
+        names.pop();
+    }
+    let mut directory = String::new();
+    for name in names {
+        directory.push('/');
+        directory.push_str(name);
+        match vfs::create_dir(&directory) {
+            Ok(()) | Err(VfsError::AlreadyExists) => {}
+            Err(error) => return Err(error)
+        }
+    }
+    Ok(())
+}
+
+fn make_file(path: &str, contents: &[u8]) -> Result<(), VfsError> {
+    make_directories(path, false)?;
+    let options = OpenOptions {
+        write: true,
+        create: true,
+        truncate: true,
+        ..OpenOptions::default()
+    };
+    let file = vfs::open(path, options)?;
+    let mut written = 0;
+    while written < contents.len() {
+        match vfs::write(&*file, &contents[written..])? {
+            0 => return Err(VfsError::NoSpace),
+            count => written += count
+        }
+    }
+    Ok(())
+}
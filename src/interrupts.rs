@@ -1,16 +1,118 @@
-use crate::println;
-use crate::print;
+use crate::apic;
+use crate::cow;
+use crate::debugcon::DebugCon;
+use crate::demand;
+use crate::info;
 use crate::gdt;
+use crate::gdbstub;
+use crate::i8042;
+use crate::ioapic;
+use crate::keyboard;
+use crate::mouse;
+use crate::panic_screen;
+use crate::percpu::InterruptGs;
+use crate::rtc;
+use crate::scheduler;
+use crate::serial;
+use crate::signal;
+use crate::stack;
+use crate::status_bar;
+use crate::sync::IrqMutex;
+use crate::syscall::{self, Registers};
+use crate::syscall_abi::{self, SIGBUS, SIGFPE, SIGILL, SIGSEGV};
+use crate::time;
+use crate::user_access;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{Keyboard, ScancodeSet1, layouts};
 use pic8259_simple::ChainedPics;
-use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{HandlerFunc, HandlerFuncWithErrCode, InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{PageFaultErrorCode, PageFaultHandlerFunc};
 
 const PIC_1_OFFSET: u8 = 32;
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+// the data ports of the PICs, writing them sets which lines are masked
+const PIC_1_DATA_PORT: u16 = 0x21;
+const PIC_2_DATA_PORT: u16 = 0xA1;
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xA0;
+// makes the next command port read return the in-service register, the interrupts being handled
+const READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+// each PIC's lowest priority line, where it reports an interrupt that went away before the CPU took it
+const SPURIOUS_LINE: u8 = 7;
+const PIC_1_SPURIOUS_IRQ: u8 = 7;
+const PIC_2_SPURIOUS_IRQ: u8 = 15;
+// the PICs mask every line when they are bypassed
+const ALL_LINES: u8 = 0xFF;
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const COM1_IRQ: u8 = 4;
+pub const RTC_IRQ: u8 = 8;
+pub const MOUSE_IRQ: u8 = 12;
+// above the PICs' vectors, below the local APIC's spurious one
+pub const APIC_TIMER_VECTOR: u8 = 0xF0;
+// sent by other CPUs through apic::send_ipi(): to make this one look for other work, and to flush its TLB after they changed the page tables
+pub const RESCHEDULE_VECTOR: u8 = 0xF1;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF2;
+/**
+ * The first of the vectors handed out to drivers at runtime, e.g. for MSI, see allocate_vector().
+ * Every vector from here on is given out, but the local APIC's own ones.
+ */
+pub const DYNAMIC_VECTOR_BASE: u8 = PIC_2_OFFSET + 8;
+const DYNAMIC_VECTORS: usize = 256 - DYNAMIC_VECTOR_BASE as usize;
+// the stubs come in groups of 16, one for each upper nibble of the vector
+const STUB_GROUP_SIZE: usize = 16;
+const STUB_GROUPS: usize = DYNAMIC_VECTORS / STUB_GROUP_SIZE;
+// the CPU's exceptions
+const EXCEPTION_VECTORS: u8 = 32;
+// the exceptions with a handler, counted like the interrupts
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const DEBUG_VECTOR: u8 = 1;
+const NMI_VECTOR: u8 = 2;
+const BREAKPOINT_VECTOR: u8 = 3;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const STACK_SEGMENT_FAULT_VECTOR: u8 = 12;
+const GENERAL_PROTECTION_FAULT_VECTOR: u8 = 13;
+const PAGE_FAULT_VECTOR: u8 = 14;
+const X87_FLOATING_POINT_VECTOR: u8 = 16;
+const MACHINE_CHECK_VECTOR: u8 = 18;
+const SIMD_FLOATING_POINT_VECTOR: u8 = 19;
+const VECTORS: usize = 256;
+// the system control port's upper bits tell what raised an NMI on the chipset: a memory parity or PCI system error, or an I/O channel check
+const SYSTEM_CONTROL_PORT: u16 = 0x61;
+const NMI_PARITY_ERROR: u8 = 1 << 7;
+const NMI_CHANNEL_CHECK: u8 = 1 << 6;
+
+/**
+ * Called in interrupt context when its vector is raised, before the local APIC is acknowledged.
+ */
+pub type Handler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorError {
+    NoFreeVector,
+    NotAllocated(u8)
+}
 
-static PICS: Mutex<ChainedPics> = Mutex::new(
+// how many times each vector was raised, see stats()
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; VECTORS] = [NO_INTERRUPTS; VECTORS];
+// spurious interrupts seen on IRQ7 and IRQ15
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+// the IRQ lines let through, the firmware leaves the timer's and the keyboard's unmasked on the PICs
+static ENABLED_IRQS: AtomicU16 = AtomicU16::new(1 << TIMER_IRQ | 1 << KEYBOARD_IRQ);
+// the IRQs come through the I/O APIC and are acknowledged to the local APIC, the PICs are masked
+static IO_APIC_ROUTING: AtomicBool = AtomicBool::new(false);
+
+// the owners and handlers of the vectors handed out by allocate_vector(), from DYNAMIC_VECTOR_BASE on
+static DYNAMIC_HANDLERS: IrqMutex<[Option<(&'static str, Handler)>; DYNAMIC_VECTORS]> = IrqMutex::new([None; DYNAMIC_VECTORS]);
+
+static PICS: IrqMutex<ChainedPics> = IrqMutex::new(
     // wrong offsets leads to Undefined Behavior
     unsafe{ ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }
 );
@@ -20,7 +122,21 @@ static PICS: Mutex<ChainedPics> = Mutex::new(
 enum InterruptIndex {
     // Intel 8253 timer uses line 0 of the primary PIC, but we remapped it, so it arrives to the CPU as interrupt 0 + 32 = 32
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard = PIC_1_OFFSET + KEYBOARD_IRQ,
+    // the first serial port uses line 4
+    Com1 = PIC_1_OFFSET + COM1_IRQ,
+    // the real-time clock uses line 0 of the secondary PIC
+    Rtc = PIC_1_OFFSET + RTC_IRQ,
+    // nothing uses these lines, but the PICs raise them for spurious interrupts
+    Pic1Spurious = PIC_1_OFFSET + PIC_1_SPURIOUS_IRQ,
+    // the PS/2 mouse uses line 4 of the secondary PIC
+    Mouse = PIC_1_OFFSET + MOUSE_IRQ,
+    Pic2Spurious = PIC_1_OFFSET + PIC_2_SPURIOUS_IRQ,
+    // raised by the local APIC itself, acknowledged to it instead of the PICs
+    ApicTimer = APIC_TIMER_VECTOR,
+    Reschedule = RESCHEDULE_VECTOR,
+    TlbShootdown = TLB_SHOOTDOWN_VECTOR,
+    ApicSpurious = apic::SPURIOUS_INTERRUPT_VECTOR
 }
 
 impl InterruptIndex {
@@ -40,54 +156,568 @@ lazy_static! {
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_handler);
+        idt[InterruptIndex::Pic1Spurious.as_usize()].set_handler_fn(pic_1_spurious_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_handler);
+        idt[InterruptIndex::Pic2Spurious.as_usize()].set_handler_fn(pic_2_spurious_handler);
+        idt[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_handler);
+        idt[InterruptIndex::Reschedule.as_usize()].set_handler_fn(reschedule_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_handler);
+        for (group, stubs) in DYNAMIC_STUBS.iter().enumerate() {
+            for (offset, &stub) in stubs.iter().enumerate() {
+                let vector = usize::from(DYNAMIC_VECTOR_BASE) + group * STUB_GROUP_SIZE + offset;
+                if fixed_owner(vector as u8).is_none() {
+                    idt[vector].set_handler_fn(stub);
+                }
+            }
+        }
+        // the one gate user code may raise itself
+        idt[usize::from(syscall_abi::INT_VECTOR)].set_handler_fn(syscall::interrupt_entry())
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.divide_error.set_handler_fn(fault_handler(divide_error_entry));
+        idt.invalid_opcode.set_handler_fn(fault_handler(invalid_opcode_entry));
+        idt.stack_segment_fault.set_handler_fn(fault_handler_with_error_code(stack_segment_fault_entry));
+        idt.general_protection_fault.set_handler_fn(fault_handler_with_error_code(general_protection_fault_entry));
+        idt.x87_floating_point.set_handler_fn(fault_handler(x87_floating_point_entry));
+        idt.simd_floating_point.set_handler_fn(fault_handler(simd_floating_point_entry));
 
         // unsafe because the the caller must ensure that the used index is valid and not already used for another exception.
         // The CPU will switch to the double fault stack whenever a double fault occurs. Thus, we are able to catch all double faults, including kernel stack overflows.
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // an NMI can't be masked, it may arrive while the kernel stack is bad
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(gdt::NMI_IST_INDEX);
+            // a kernel stack overflow shows up as a page fault on the guard page, the handler can't push onto it
+            let page_fault_handler = mem::transmute::<unsafe extern "C" fn(), PageFaultHandlerFunc>(page_fault_entry);
+            idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
         }
         idt
     };
 }
 
-lazy_static! {
-    static ref KEYBOARD : Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-}
-
 pub fn init_idt() {
     IDT.load();
+}
+
+/**
+ * Remaps the PICs to their vectors and enables the hardware interrupts.
+ * With the `apic` feature, and the local and I/O APICs up, the PICs are masked instead
+ * and the enabled IRQ lines are routed through the I/O APIC to the same vectors,
+ * unless this CPU's x2APIC ID is too large for the I/O APIC to reach it.
+ */
+pub fn init_pics() {
+    // remapped either way, so even their spurious interrupts don't look like exceptions
     unsafe { PICS.lock().initialize(); }
+    if cfg!(feature = "apic") && apic::is_available() && ioapic::is_available() && ioapic::can_route_here() {
+        use x86_64::instructions::port::Port;
+
+        let _pics = PICS.lock();
+        for &port in [PIC_1_DATA_PORT, PIC_2_DATA_PORT].iter() {
+            let mut port: Port<u8> = Port::new(port);
+            unsafe { port.write(ALL_LINES) };
+        }
+        IO_APIC_ROUTING.store(true, Ordering::Relaxed);
+        let enabled = ENABLED_IRQS.load(Ordering::Relaxed);
+        for irq in (0..16).filter(|irq| enabled & 1 << irq != 0) {
+            let _ = ioapic::route_irq(irq, PIC_1_OFFSET + irq);
+        }
+    }
     x86_64::instructions::interrupts::enable();
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+/**
+ * Tells whether the IRQs come through the I/O APIC instead of the PICs, see init_pics().
+ */
+pub fn io_apic_routing() -> bool {
+    IO_APIC_ROUTING.load(Ordering::Relaxed)
+}
+
+/**
+ * Returns how many times each vector was raised since boot, indexed by vector, including the exceptions.
+ * Spurious PIC interrupts are counted on their lines as well, see spurious_interrupts().
+ */
+pub fn stats() -> [u64; VECTORS] {
+    let mut stats = [0; VECTORS];
+    for (count, counter) in stats.iter_mut().zip(COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    stats
+}
+
+fn count(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+extern "x86-interrupt" fn timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Timer.as_u8());
+    timer_tick();
     eoi(InterruptIndex::Timer.as_u8());
+    scheduler::tick();
 }
 
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{DecodedKey};
+extern "x86-interrupt" fn apic_timer_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::ApicTimer.as_u8());
+    timer_tick();
+    apic::eoi();
+    scheduler::tick();
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+/**
+ * Handles another CPU asking this one to reschedule, switching to the next ready thread if there is one.
+ */
+extern "x86-interrupt" fn reschedule_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Reschedule.as_u8());
+    apic::eoi();
+    scheduler::schedule();
+}
+
+/**
+ * Handles another CPU asking this one to drop its cached translations after the page tables changed.
+ */
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::TlbShootdown.as_u8());
+    x86_64::instructions::tlb::flush_all();
+    apic::eoi();
+}
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+/**
+ * Handles the local APIC's spurious interrupts, raised when an interrupt went away before it could be delivered.
+ * Nothing is in service, so there is nothing to acknowledge.
+ */
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    count(InterruptIndex::ApicSpurious.as_u8());
+}
+
+/**
+ * Hands out a free vector whose interrupts run the given handler, for a device to raise, e.g. through MSI,
+ * or for the kernel to raise with a self IPI. The owner is what reservations() shows for it.
+ * The vector is acknowledged to the local APIC, so it is not for lines that come through the PICs.
+ */
+pub fn allocate_vector(owner: &'static str, handler: Handler) -> Result<u8, VectorError> {
+    let mut handlers = DYNAMIC_HANDLERS.lock();
+    let index = (0..DYNAMIC_VECTORS)
+        .find(|&index| handlers[index].is_none() && fixed_owner(DYNAMIC_VECTOR_BASE + index as u8).is_none())
+        .ok_or(VectorError::NoFreeVector)?;
+    handlers[index] = Some((owner, handler));
+    Ok(DYNAMIC_VECTOR_BASE + index as u8)
+}
+
+pub fn free_vector(vector: u8) -> Result<(), VectorError> {
+    let mut handlers = DYNAMIC_HANDLERS.lock();
+    let slot = usize::from(vector).checked_sub(usize::from(DYNAMIC_VECTOR_BASE))
+        .and_then(|index| handlers.get_mut(index))
+        .filter(|slot| slot.is_some())
+        .ok_or(VectorError::NotAllocated(vector))?;
+    *slot = None;
+    Ok(())
+}
+
+/**
+ * Returns who has the given vector, the kernel for the fixed ones or a driver for the allocated ones, None if it is free.
+ */
+pub fn vector_owner(vector: u8) -> Option<&'static str> {
+    fixed_owner(vector).or_else(|| {
+        let index = usize::from(vector.checked_sub(DYNAMIC_VECTOR_BASE)?);
+        DYNAMIC_HANDLERS.lock()[index].map(|(owner, _)| owner)
+    })
+}
+
+/**
+ * Returns every taken vector with its owner, in order, for debugging conflicts.
+ */
+pub fn reservations() -> impl Iterator<Item = (u8, &'static str)> {
+    (0..=u8::max_value()).filter_map(|vector| vector_owner(vector).map(|owner| (vector, owner)))
+}
+
+/**
+ * Returns the owner of the vectors that are never given out.
+ */
+fn fixed_owner(vector: u8) -> Option<&'static str> {
+    match vector {
+        vector if vector < EXCEPTION_VECTORS => Some("exception"),
+        vector if vector < DYNAMIC_VECTOR_BASE => Some("PIC"),
+        APIC_TIMER_VECTOR => Some("APIC timer"),
+        RESCHEDULE_VECTOR => Some("reschedule IPI"),
+        TLB_SHOOTDOWN_VECTOR => Some("TLB shootdown IPI"),
+        apic::SPURIOUS_INTERRUPT_VECTOR => Some("APIC spurious"),
+        syscall_abi::INT_VECTOR => Some("system call"),
+        _ => None
+    }
+}
+
+/**
+ * Generates a handler for each dynamic vector, as the IDT can't tell a handler which vector it was called for.
+ * Each group of 16 goes in a module of its own, so the stubs' names only have to differ within it.
+ */
+macro_rules! dynamic_stubs {
+    ($($group:ident => $base:expr),*) => {
+        $(
+            mod $group {
+                use super::{dispatch_dynamic, HandlerFunc, InterruptGs, InterruptStackFrame, STUB_GROUP_SIZE};
+
+                dynamic_stubs!(@group $base;
+                    stub_0 => 0x0, stub_1 => 0x1, stub_2 => 0x2, stub_3 => 0x3,
+                    stub_4 => 0x4, stub_5 => 0x5, stub_6 => 0x6, stub_7 => 0x7,
+                    stub_8 => 0x8, stub_9 => 0x9, stub_a => 0xA, stub_b => 0xB,
+                    stub_c => 0xC, stub_d => 0xD, stub_e => 0xE, stub_f => 0xF
+                );
             }
-        }
+        )*
+        const DYNAMIC_STUBS: [[HandlerFunc; STUB_GROUP_SIZE]; STUB_GROUPS] = [$($group::STUBS),*];
+    };
+    (@group $base:expr; $($stub:ident => $offset:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $stub(stack_frame: &mut InterruptStackFrame) {
+                let _gs = InterruptGs::enter(stack_frame);
+                dispatch_dynamic($base + $offset);
+            }
+        )*
+        pub(super) const STUBS: [HandlerFunc; STUB_GROUP_SIZE] = [$($stub),*];
+    };
+}
+
+dynamic_stubs!(
+    vectors_3x => 0x30, vectors_4x => 0x40, vectors_5x => 0x50, vectors_6x => 0x60,
+    vectors_7x => 0x70, vectors_8x => 0x80, vectors_9x => 0x90, vectors_ax => 0xA0,
+    vectors_bx => 0xB0, vectors_cx => 0xC0, vectors_dx => 0xD0, vectors_ex => 0xE0,
+    vectors_fx => 0xF0
+);
+
+fn dispatch_dynamic(vector: u8) {
+    count(vector);
+    // copied, so the handler may free or allocate vectors
+    let handler = DYNAMIC_HANDLERS.lock()[usize::from(vector - DYNAMIC_VECTOR_BASE)];
+    if let Some((_, handler)) = handler {
+        handler();
+    }
+    apic::eoi();
+}
+
+/**
+ * Handles IRQ7, which the primary PIC raises when an interrupt went away before the CPU took it.
+ * Such an interrupt is not in service, so it must not be acknowledged, that could end another one.
+ */
+extern "x86-interrupt" fn pic_1_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    count(InterruptIndex::Pic1Spurious.as_u8());
+    if in_service(PIC_1_COMMAND_PORT) & 1 << SPURIOUS_LINE == 0 {
+        SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    eoi(InterruptIndex::Pic1Spurious.as_u8());
+}
+
+/**
+ * Handles IRQ15, the secondary PIC's spurious line. A spurious interrupt from it has still reached
+ * the primary PIC through line 2 as a real one, so the primary PIC is acknowledged anyway.
+ */
+extern "x86-interrupt" fn pic_2_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    count(InterruptIndex::Pic2Spurious.as_u8());
+    if in_service(PIC_2_COMMAND_PORT) & 1 << SPURIOUS_LINE == 0 {
+        SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        acknowledge(PIC_1_COMMAND_PORT);
+        return;
     }
+    eoi(InterruptIndex::Pic2Spurious.as_u8());
+}
+
+/**
+ * Returns how many spurious interrupts the PICs raised, a steady stream of them hints at noisy hardware.
+ */
+pub fn spurious_interrupts() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/**
+ * Reads a PIC's in-service register, one bit for each line whose interrupt is being handled.
+ */
+fn in_service(command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock();
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe {
+        port.write(READ_ISR);
+        port.read()
+    }
+}
+
+/**
+ * Ends the interrupt in service on a single PIC.
+ */
+fn acknowledge(command_port: u16) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock();
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe { port.write(END_OF_INTERRUPT) };
+}
+
+/**
+ * Counts a timer tick, whatever raised it, see time::clock_source().
+ */
+fn timer_tick() {
+    time::tick();
+    status_bar::set_uptime(time::uptime_ms() / 1000);
+    keyboard::tick();
+}
+
+extern "x86-interrupt" fn keyboard_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Keyboard.as_u8());
+    use x86_64::instructions::port::Port;
+
+    // decoding is left to kernel context, see keyboard::read_key()
+    let mut port = Port::new(i8042::DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+    keyboard::push_scancode(scancode);
 
     eoi(InterruptIndex::Keyboard.as_u8());
 }
 
+extern "x86-interrupt" fn mouse_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Mouse.as_u8());
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(i8042::DATA_PORT);
+    let byte: u8 = unsafe { port.read() };
+    mouse::receive(byte);
+
+    eoi(InterruptIndex::Mouse.as_u8());
+}
+
+extern "x86-interrupt" fn rtc_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Rtc.as_u8());
+    rtc::interrupt();
+    eoi(InterruptIndex::Rtc.as_u8());
+}
+
+extern "x86-interrupt" fn com1_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(InterruptIndex::Com1.as_u8());
+    serial::receive_pending();
+    eoi(InterruptIndex::Com1.as_u8());
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("Breakpoint exception occurred: {:#?}", stack_frame);
+    let _gs = InterruptGs::enter(stack_frame);
+    count(BREAKPOINT_VECTOR);
+    if gdbstub::is_attached() {
+        gdbstub::breakpoint(stack_frame);
+    } else {
+        info!("Breakpoint exception occurred: {:#?}", stack_frame);
+    }
+}
+
+/**
+ * Handles debug exceptions, raised after every instruction while the trap flag is set, i.e. when gdb single steps.
+ */
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    count(DEBUG_VECTOR);
+    if gdbstub::is_attached() {
+        gdbstub::debug(stack_frame);
+    } else {
+        info!("Debug exception occurred: {:#?}", stack_frame);
+    }
+}
+
+/**
+ * Handles non-maskable interrupts, raised by the chipset on hardware errors, or by a watchdog.
+ * It can interrupt code holding any lock, so the state is dumped to the debug console, which takes none,
+ * and the kernel carries on.
+ */
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    use core::fmt::Write;
+    use x86_64::instructions::port::Port;
+    use x86_64::registers::control::Cr3;
+
+    count(NMI_VECTOR);
+    let mut control: Port<u8> = Port::new(SYSTEM_CONTROL_PORT);
+    let status = unsafe { control.read() };
+    let reason = match status {
+        status if status & NMI_PARITY_ERROR != 0 => "memory parity or system error",
+        status if status & NMI_CHANNEL_CHECK != 0 => "I/O channel check",
+        _ => "unknown"
+    };
+    let (level_4_table, _) = Cr3::read();
+    let mut console = DebugCon;
+    let _ = writeln!(console, "NMI #{}: {}", COUNTS[usize::from(NMI_VECTOR)].load(Ordering::Relaxed), reason);
+    let _ = writeln!(console, "{:#?}", stack_frame);
+    let _ = writeln!(console, "CR2: {:#018x} CR3: {:#018x}", Cr2::read().as_u64(), level_4_table.start_address().as_u64());
+}
+
+/**
+ * Handles the faults user code can cause, called by their entry stubs with the interrupted registers: raises the
+ * fault's signal in the faulting thread's process if it came from ring 3, and panics if it came from the kernel,
+ * see signal::fault(). GS is on the CPU's block already.
+ */
+#[no_mangle]
+extern "C" fn fault_dispatch(registers: &Registers, vector: u64, error_code: u64, stack_frame: &mut InterruptStackFrame) {
+    let vector = vector as u8;
+    count(vector);
+    if vector == PAGE_FAULT_VECTOR {
+        page_fault(registers, PageFaultErrorCode::from_bits_truncate(error_code), stack_frame);
+        return;
+    }
+    let (name, signal, error_code) = match vector {
+        DIVIDE_ERROR_VECTOR => ("Divide Error", SIGFPE, None),
+        INVALID_OPCODE_VECTOR => ("Invalid Opcode", SIGILL, None),
+        X87_FLOATING_POINT_VECTOR => ("x87 Floating Point Exception", SIGFPE, None),
+        SIMD_FLOATING_POINT_VECTOR => ("SIMD Floating Point Exception", SIGFPE, None),
+        STACK_SEGMENT_FAULT_VECTOR => ("Stack Segment Fault", SIGBUS, Some(error_code)),
+        GENERAL_PROTECTION_FAULT_VECTOR => ("General Protection Fault", SIGSEGV, Some(error_code)),
+        _ => unreachable!("no fault stub for vector {}", vector)
+    };
+    signal::fault(signal, name, registers, stack_frame);
+    panic_screen::record_exception(name, stack_frame, error_code);
+    match error_code {
+        Some(error_code) => panic!(
+            "{} occurred at {:#x}, error code {:#x}", name, stack_frame.instruction_pointer.as_u64(), error_code
+        ),
+        None => panic!("{} occurred at {:#x}", name, stack_frame.instruction_pointer.as_u64())
+    }
+}
+
+/**
+ * Handles page faults. Writes to copy-on-write pages and touches of reserved pages are resolved, see the cow and demand
+ * modules. Any other fault raises SIGSEGV if it came from user code, and is a bug if it came from the kernel.
+ * The faulting address is in CR2, which the panic screen shows.
+ */
+fn page_fault(registers: &Registers, error_code: PageFaultErrorCode, stack_frame: &mut InterruptStackFrame) {
+    let address = Cr2::read();
+    if cow::handle_fault(address, error_code) || demand::handle_fault(address, error_code) {
+        return;
+    }
+    // a system call handed a bad pointer fails instead
+    if user_access::fixup(stack_frame) {
+        return;
+    }
+    signal::fault(SIGSEGV, "Page Fault", registers, stack_frame);
+    panic_screen::record_exception("Page Fault", stack_frame, Some(error_code.bits()));
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && stack::is_guard_page(address) {
+        panic!("Stack overflow: Page Fault on the guard page at {:#x}: {:?}", address.as_u64(), error_code);
+    }
+    panic!("Page Fault occurred at {:#x}: {:?}", address.as_u64(), error_code);
+}
+
+/**
+ * Turns a fault's entry stub into its IDT handler, the stub returns with iretq, like an x86-interrupt function.
+ */
+fn fault_handler(stub: unsafe extern "C" fn()) -> HandlerFunc {
+    unsafe { mem::transmute::<unsafe extern "C" fn(), HandlerFunc>(stub) }
+}
+
+fn fault_handler_with_error_code(stub: unsafe extern "C" fn()) -> HandlerFuncWithErrCode {
+    unsafe { mem::transmute::<unsafe extern "C" fn(), HandlerFuncWithErrCode>(stub) }
+}
+
+extern "C" {
+    fn divide_error_entry();
+    fn invalid_opcode_entry();
+    fn stack_segment_fault_entry();
+    fn general_protection_fault_entry();
+    fn page_fault_entry();
+    fn x87_floating_point_entry();
+    fn simd_floating_point_entry();
+}
+
+// x86-interrupt functions don't show a handler the registers of the code they interrupted, but a signal handler has to
+// go back to them, so the faults user code can cause come in through these stubs instead. Each pushes a zero where
+// the CPU pushes no error code, and its vector; the common part swaps GS when the fault came from ring 3, pushes
+// the registers in the layout of syscall::Registers, with RIP, RFLAGS and RSP copied from the CPU's frame,
+// and calls fault_dispatch() with them, the vector, the error code and the frame.
+// The CPU aligns the stack to 16 bytes before pushing its frame, the 25 words on it after that need one more for the call.
+global_asm!("
+.att_syntax prefix
+.macro fault_stub name, vector, error_code
+.global \\name
+\\name:
+.if \\error_code == 0
+    pushq $0
+.endif
+    pushq $\\vector
+    jmp fault_entry
+.endm
+    fault_stub divide_error_entry, 0, 0
+    fault_stub invalid_opcode_entry, 6, 0
+    fault_stub stack_segment_fault_entry, 12, 1
+    fault_stub general_protection_fault_entry, 13, 1
+    fault_stub page_fault_entry, 14, 1
+    fault_stub x87_floating_point_entry, 16, 0
+    fault_stub simd_floating_point_entry, 19, 0
+fault_entry:
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    cld
+    pushq 40(%rsp)
+    pushq 40(%rsp)
+    pushq 32(%rsp)
+    pushq %r11
+    pushq %rcx
+    pushq %rax
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %r10
+    pushq %r8
+    pushq %r9
+    pushq %rbx
+    pushq %rbp
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, %rdi
+    movq 144(%rsp), %rsi
+    movq 152(%rsp), %rdx
+    leaq 160(%rsp), %rcx
+    subq $8, %rsp
+    call fault_dispatch
+    addq $8, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbp
+    popq %rbx
+    popq %r9
+    popq %r8
+    popq %r10
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rax
+    popq %rcx
+    popq %r11
+    addq $40, %rsp
+    testb $3, 8(%rsp)
+    jz 2f
+    swapgs
+2:
+    iretq
+");
+
+/**
+ * Handles machine checks, raised when the CPU detects a hardware error it can't correct.
+ * It can't be returned from.
+ */
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    count(MACHINE_CHECK_VECTOR);
+    panic_screen::record_exception("Machine Check", stack_frame, None);
+    panic!("Machine Check occurred, stopping kernel...");
 }
 
 /**
@@ -119,11 +749,92 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFra
  * This function is diverging because the x86_64 architecture does not permit returning from a double fault exception.
  */
 extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: u64) -> !{
-    panic!("Double Fault occurred: \n{:#?},\n, error code: {:#?} stopping kernel...", _error_code, stack_frame);
+    count(DOUBLE_FAULT_VECTOR);
+    panic_screen::record_exception("Double Fault", stack_frame, Some(_error_code));
+    // a page fault handler that overflowed its own stack ends up here, CR2 still has the address
+    let address = Cr2::read();
+    if stack::is_guard_page(address) {
+        panic!("Double Fault, likely from a stack overflow onto the guard page at {:#x}, stopping kernel...", address.as_u64());
+    }
+    panic!("Double Fault occurred, stopping kernel...");
+}
+
+/**
+ * Keeps the given IRQ line from reaching the CPU, e.g. the PIT's once the local APIC's timer ticks instead.
+ */
+pub fn mask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    ENABLED_IRQS.fetch_and(!(1 << irq), Ordering::Relaxed);
+    if io_apic_routing() {
+        let _ = ioapic::mask_irq(irq);
+        return;
+    }
+    let _pics = PICS.lock();
+    let (port, line) = if irq < 8 { (PIC_1_DATA_PORT, irq) } else { (PIC_2_DATA_PORT, irq - 8) };
+    let mut port: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = port.read();
+        port.write(mask | 1 << line);
+    }
+}
+
+/**
+ * Lets the given IRQ line through the PICs, lines 8-15 being on the secondary one.
+ * The firmware may leave lines masked that it had no use for.
+ */
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    ENABLED_IRQS.fetch_or(1 << irq, Ordering::Relaxed);
+    if io_apic_routing() {
+        let _ = ioapic::route_irq(irq, PIC_1_OFFSET + irq);
+        return;
+    }
+    // serializes the mask updates
+    let _pics = PICS.lock();
+    let lines = if irq < 8 {
+        [(PIC_1_DATA_PORT, irq), (PIC_1_DATA_PORT, irq)]
+    } else {
+        // the secondary PIC's interrupts reach the CPU through line 2 of the primary one
+        [(PIC_2_DATA_PORT, irq - 8), (PIC_1_DATA_PORT, 2)]
+    };
+    for &(port, line) in lines.iter() {
+        let mut port: Port<u8> = Port::new(port);
+        unsafe {
+            let mask = port.read();
+            port.write(mask & !(1 << line));
+        }
+    }
+}
+
+/**
+ * Enables interrupts and halts until the next one, with no window in between for it to come in and be missed:
+ * sti takes effect only after the instruction following it.
+ */
+pub fn enable_and_hlt() {
+    unsafe { interrupts_enable_and_hlt() };
 }
 
 fn eoi(index : u8) {
+    if io_apic_routing() {
+        apic::eoi();
+        return;
+    }
     unsafe {
         PICS.lock().notify_end_of_interrupt(index);
     }
-}
\ No newline at end of file
+}
+
+extern "C" {
+    fn interrupts_enable_and_hlt();
+}
+
+global_asm!("
+.att_syntax prefix
+.global interrupts_enable_and_hlt
+interrupts_enable_and_hlt:
+    sti
+    hlt
+    ret
+");
@@ -0,0 +1,216 @@
+use crate::acpi::{self, AcpiError};
+use crate::apic;
+use crate::paging::{self, PagingError};
+use crate::sync::IrqMutex;
+use core::ptr;
+use x86_64::PhysAddr;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+// the MADT's entries follow the header, the local APIC's address and some flags
+const MADT_ENTRIES_OFFSET: usize = 44;
+// entry types, each entry starts with its type and length
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+
+// an ISA IRQ's polarity and trigger mode in an override's flags, 3 meaning active low and level triggered
+const POLARITY_MASK: u16 = 0x3;
+const TRIGGER_SHIFT: u16 = 2;
+const ACTIVE_LOW: u16 = 0x3;
+const LEVEL_TRIGGERED: u16 = 0x3;
+
+// registers are selected through IOREGSEL, then read or written through IOWIN
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+const REGISTERS_SIZE: u64 = 0x20;
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+// version register: the index of the last redirection entry
+const MAX_ENTRY_SHIFT: u32 = 16;
+
+// redirection entry bits, the destination APIC ID is in the top byte of the upper half
+const ENTRY_ACTIVE_LOW: u32 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u32 = 1 << 15;
+const ENTRY_MASKED: u32 = 1 << 16;
+const DESTINATION_SHIFT: u32 = 24;
+// the highest APIC ID that fits the destination, x2APIC ones above it would need interrupt remapping
+const MAX_DESTINATION: u32 = 0xFF;
+
+const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    Acpi(AcpiError),
+    // the MADT lists no I/O APIC
+    NotPresent,
+    NotAvailable,
+    // the IRQ's global system interrupt is not on the I/O APIC
+    InvalidIrq(u8),
+    // this CPU's x2APIC ID does not fit a redirection entry's destination
+    InvalidDestination(u32),
+    Paging(PagingError)
+}
+
+impl From<AcpiError> for IoApicError {
+    fn from(error: AcpiError) -> IoApicError {
+        IoApicError::Acpi(error)
+    }
+}
+
+impl From<PagingError> for IoApicError {
+    fn from(error: PagingError) -> IoApicError {
+        IoApicError::Paging(error)
+    }
+}
+
+/**
+ * Where an ISA IRQ comes in on the I/O APIC, and how. Without an override in the MADT,
+ * it is the input with the same number, active high and edge triggered.
+ */
+#[derive(Debug, Clone, Copy)]
+struct IsaIrq {
+    global_interrupt: u32,
+    active_low: bool,
+    level_triggered: bool
+}
+
+struct IoApic {
+    // the registers' virtual address, 0 until init()
+    base: u64,
+    // the first global system interrupt it handles, and how many
+    interrupt_base: u32,
+    entries: u32,
+    isa_irqs: [IsaIrq; ISA_IRQS]
+}
+
+static IO_APIC: IrqMutex<IoApic> = IrqMutex::new(IoApic {
+    base: 0,
+    interrupt_base: 0,
+    entries: 0,
+    isa_irqs: [IsaIrq {
+        global_interrupt: 0,
+        active_low: false,
+        level_triggered: false
+    }; ISA_IRQS]
+});
+
+impl IoApic {
+    /**
+     * Returns the redirection entry an ISA IRQ comes in on.
+     */
+    fn entry(&self, irq: u8) -> Result<(u32, IsaIrq), IoApicError> {
+        if self.base == 0 {
+            return Err(IoApicError::NotAvailable);
+        }
+        let isa_irq = *self.isa_irqs.get(usize::from(irq)).ok_or(IoApicError::InvalidIrq(irq))?;
+        let entry = isa_irq.global_interrupt.wrapping_sub(self.interrupt_base);
+        if entry >= self.entries {
+            return Err(IoApicError::InvalidIrq(irq));
+        }
+        Ok((entry, isa_irq))
+    }
+}
+
+/**
+ * Finds the first I/O APIC and the ISA IRQ overrides in the MADT, and masks all of its inputs.
+ * Needs acpi::init() first.
+ */
+pub fn init() -> Result<(), IoApicError> {
+    let madt = acpi::find_table(MADT_SIGNATURE)?;
+    let mut io_apic = IO_APIC.lock();
+    for (irq, isa_irq) in io_apic.isa_irqs.iter_mut().enumerate() {
+        isa_irq.global_interrupt = irq as u32;
+    }
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    let mut address = None;
+    while offset + 2 <= madt.len() {
+        let (kind, length) = (madt[offset], usize::from(madt[offset + 1]));
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + length];
+        match kind {
+            ENTRY_IO_APIC if address.is_none() && length >= 12 => {
+                address = Some(acpi::read_u32(entry, 4));
+                io_apic.interrupt_base = acpi::read_u32(entry, 8);
+            }
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE if length >= 10 => {
+                let irq = usize::from(entry[3]);
+                let flags = u16::from_le_bytes([entry[8], entry[9]]);
+                if irq < ISA_IRQS {
+                    io_apic.isa_irqs[irq] = IsaIrq {
+                        global_interrupt: acpi::read_u32(entry, 4),
+                        active_low: flags & POLARITY_MASK == ACTIVE_LOW,
+                        level_triggered: flags >> TRIGGER_SHIFT & POLARITY_MASK == LEVEL_TRIGGERED
+                    };
+                }
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+
+    let address = address.ok_or(IoApicError::NotPresent)?;
+    let base = paging::map_mmio(PhysAddr::new(u64::from(address)), REGISTERS_SIZE)?.as_u64();
+    io_apic.base = base;
+    io_apic.entries = (read(base, VERSION) >> MAX_ENTRY_SHIFT & 0xFF) + 1;
+    for entry in 0..io_apic.entries {
+        write(base, REDIRECTION_TABLE + entry * 2, ENTRY_MASKED);
+    }
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    IO_APIC.lock().base != 0
+}
+
+/**
+ * Tells whether IRQs can be sent to this CPU: its APIC ID has to fit a redirection entry's destination.
+ */
+pub fn can_route_here() -> bool {
+    apic::id() <= MAX_DESTINATION
+}
+
+/**
+ * Sends an ISA IRQ to this CPU's local APIC as the given vector. Fails on a CPU whose APIC ID is above 255.
+ */
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), IoApicError> {
+    if !can_route_here() {
+        return Err(IoApicError::InvalidDestination(apic::id()));
+    }
+    let io_apic = IO_APIC.lock();
+    let (entry, isa_irq) = io_apic.entry(irq)?;
+    let mut low = u32::from(vector);
+    if isa_irq.active_low {
+        low |= ENTRY_ACTIVE_LOW;
+    }
+    if isa_irq.level_triggered {
+        low |= ENTRY_LEVEL_TRIGGERED;
+    }
+    // the destination goes in first, the entry is unmasked by writing its lower half
+    write(io_apic.base, REDIRECTION_TABLE + entry * 2 + 1, apic::id() << DESTINATION_SHIFT);
+    write(io_apic.base, REDIRECTION_TABLE + entry * 2, low);
+    Ok(())
+}
+
+pub fn mask_irq(irq: u8) -> Result<(), IoApicError> {
+    let io_apic = IO_APIC.lock();
+    let (entry, _) = io_apic.entry(irq)?;
+    let register = REDIRECTION_TABLE + entry * 2;
+    write(io_apic.base, register, read(io_apic.base, register) | ENTRY_MASKED);
+    Ok(())
+}
+
+fn read(base: u64, register: u32) -> u32 {
+    unsafe {
+        ptr::write_volatile((base as usize + REGISTER_SELECT) as *mut u32, register);
+        ptr::read_volatile((base as usize + REGISTER_WINDOW) as *const u32)
+    }
+}
+
+fn write(base: u64, register: u32, value: u32) {
+    unsafe {
+        ptr::write_volatile((base as usize + REGISTER_SELECT) as *mut u32, register);
+        ptr::write_volatile((base as usize + REGISTER_WINDOW) as *mut u32, value);
+    }
+}
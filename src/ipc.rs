@@ -0,0 +1,287 @@
+use crate::process::{self, Pid};
+use crate::sync::IrqMutex;
+use crate::wait_queue::WaitQueue;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+
+/**
+ * The size of a message's data, every message carries exactly this much.
+ */
+pub const MESSAGE_DATA_SIZE: usize = 48;
+// messages a port holds before send() fails with Full
+const PORT_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortId(u64);
+
+impl PortId {
+    pub fn new(id: u64) -> PortId {
+        PortId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    NoSuchPort,
+    // another port has the name
+    NameTaken,
+    // only a port's owner receives from it, and hands it on
+    NotOwner,
+    // the port holds PORT_CAPACITY messages already
+    Full,
+    // the port was closed, e.g. as its owner exited
+    Closed
+}
+
+/**
+ * A message: fixed size data, who sent it, and optionally a port whose ownership it hands over.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    // filled in by send(), None from kernel threads
+    pub sender: Option<Pid>,
+    pub handle: Option<PortId>,
+    pub data: [u8; MESSAGE_DATA_SIZE]
+}
+
+impl Message {
+    pub fn new(data: [u8; MESSAGE_DATA_SIZE]) -> Message {
+        Message {
+            sender: None,
+            handle: None,
+            data
+        }
+    }
+}
+
+/**
+ * What ports() tells about a port.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub id: PortId,
+    pub name: Option<String>,
+    pub owner: Option<Pid>,
+    pub queued: usize
+}
+
+/**
+ * A queue of messages: anyone who knows its ID sends to it, its owner receives from it, blocking while it is empty.
+ */
+struct Port {
+    id: PortId,
+    name: Option<String>,
+    // None for the kernel's, and while its ownership is in flight in a message
+    owner: IrqMutex<Option<Pid>>,
+    // set while a message hands it over, no one receives from it meanwhile
+    in_flight: AtomicBool,
+    messages: IrqMutex<VecDeque<Message>>,
+    closed: AtomicBool,
+    receivers: WaitQueue
+}
+
+struct PortTable {
+    ports: BTreeMap<PortId, Arc<Port>>,
+    names: BTreeMap<String, PortId>,
+    next_id: u64
+}
+
+lazy_static! {
+    static ref PORTS: IrqMutex<PortTable> = IrqMutex::new(PortTable {
+        ports: BTreeMap::new(),
+        names: BTreeMap::new(),
+        next_id: 1
+    });
+}
+
+/**
+ * Makes a port owned by the calling thread's process, the kernel's for kernel threads, found by lookup()
+ * under the name if it has one. An unnamed one is only known to those it is told to, e.g. for replies.
+ */
+pub fn create(name: Option<&str>) -> Result<PortId, IpcError> {
+    let owner = process::current();
+    let mut table = PORTS.lock();
+    if let Some(name) = name {
+        if table.names.contains_key(name) {
+            return Err(IpcError::NameTaken);
+        }
+    }
+    let id = PortId(table.next_id);
+    table.next_id += 1;
+    if let Some(name) = name {
+        table.names.insert(String::from(name), id);
+    }
+    table.ports.insert(id, Arc::new(Port {
+        id,
+        name: name.map(String::from),
+        owner: IrqMutex::new(owner),
+        in_flight: AtomicBool::new(false),
+        messages: IrqMutex::new(VecDeque::new()),
+        closed: AtomicBool::new(false),
+        receivers: WaitQueue::new()
+    }));
+    Ok(id)
+}
+
+/**
+ * Returns the port with the given name.
+ */
+pub fn lookup(name: &str) -> Option<PortId> {
+    PORTS.lock().names.get(name).copied()
+}
+
+/**
+ * Queues a message on a port, without waiting: fails with Full if the port holds too many.
+ * A handle in the message must be a port the caller owns, which is its receiver's once it receives the message.
+ */
+pub fn send(id: PortId, mut message: Message) -> Result<(), IpcError> {
+    let port = port(id)?;
+    let sender = process::current();
+    message.sender = sender;
+    let handle = match message.handle {
+        Some(handle) => Some(self::port(handle)?),
+        None => None
+    };
+    if let Some(handle) = &handle {
+        if *handle.owner.lock() != sender || handle.in_flight.swap(true, Ordering::AcqRel) {
+            return Err(IpcError::NotOwner);
+        }
+    }
+    let queued = {
+        let mut messages = port.messages.lock();
+        if port.closed.load(Ordering::Acquire) {
+            Err(IpcError::Closed)
+        } else if messages.len() >= PORT_CAPACITY {
+            Err(IpcError::Full)
+        } else {
+            messages.push_back(message);
+            Ok(())
+        }
+    };
+    if let Some(handle) = handle {
+        match queued {
+            Ok(()) => *handle.owner.lock() = None,
+            Err(_) => handle.in_flight.store(false, Ordering::Release)
+        }
+    }
+    queued?;
+    port.receivers.wake_one();
+    Ok(())
+}
+
+/**
+ * Takes the oldest message of a port the caller owns, blocking until there is one. The caller becomes the owner of
+ * the port the message hands over, if any. Fails with Closed once the port is closed and empty.
+ */
+pub fn receive(id: PortId) -> Result<Message, IpcError> {
+    take(id, true).and_then(|message| message.ok_or(IpcError::Closed))
+}
+
+/**
+ * Like receive(), but returns None instead of blocking while the port is empty.
+ */
+pub fn try_receive(id: PortId) -> Result<Option<Message>, IpcError> {
+    take(id, false)
+}
+
+/**
+ * Closes a port the caller owns and forgets it: queued messages are dropped, handing their ports back to the
+ * kernel, receivers waiting on it fail with Closed, and later calls with NoSuchPort.
+ */
+pub fn close(id: PortId) -> Result<(), IpcError> {
+    let port = port(id)?;
+    check_owner(&port)?;
+    close_port(&port);
+    Ok(())
+}
+
+/**
+ * Closes the ports a process owns, as it exits.
+ */
+pub(crate) fn close_owned(pid: Pid) {
+    let owned: Vec<Arc<Port>> = PORTS.lock().ports.values()
+        .filter(|port| *port.owner.lock() == Some(pid) && !port.in_flight.load(Ordering::Acquire))
+        .cloned()
+        .collect();
+    for port in owned {
+        close_port(&port);
+    }
+}
+
+/**
+ * Lists the ports, by ID.
+ */
+pub fn ports() -> Vec<PortInfo> {
+    PORTS.lock().ports.values().map(|port| PortInfo {
+        id: port.id,
+        name: port.name.clone(),
+        owner: *port.owner.lock(),
+        queued: port.messages.lock().len()
+    }).collect()
+}
+
+fn port(id: PortId) -> Result<Arc<Port>, IpcError> {
+    PORTS.lock().ports.get(&id).cloned().ok_or(IpcError::NoSuchPort)
+}
+
+fn check_owner(port: &Port) -> Result<(), IpcError> {
+    if port.in_flight.load(Ordering::Acquire) || *port.owner.lock() != process::current() {
+        return Err(IpcError::NotOwner);
+    }
+    Ok(())
+}
+
+/**
+ * Takes a message off a port the caller owns, waiting for one if asked to, None if the port is closed or,
+ * without waiting, empty. Hands the message's port to the caller.
+ */
+fn take(id: PortId, block: bool) -> Result<Option<Message>, IpcError> {
+    let port = port(id)?;
+    check_owner(&port)?;
+    let mut message = None;
+    port.receivers.wait_until(|| {
+        message = port.messages.lock().pop_front();
+        message.is_some() || !block || port.closed.load(Ordering::Acquire)
+    });
+    if let Some(handle) = message.and_then(|message| message.handle) {
+        if let Ok(handle) = self::port(handle) {
+            *handle.owner.lock() = process::current();
+            handle.in_flight.store(false, Ordering::Release);
+        }
+    }
+    Ok(message)
+}
+
+fn close_port(port: &Port) {
+    {
+        let mut table = PORTS.lock();
+        table.ports.remove(&port.id);
+        if let Some(name) = &port.name {
+            table.names.remove(name);
+        }
+    }
+    port.closed.store(true, Ordering::Release);
+    let dropped: Vec<Message> = port.messages.lock().drain(..).collect();
+    // the ports in flight in them go back to the kernel, as no one received them
+    for handle in dropped.iter().filter_map(|message| message.handle) {
+        if let Ok(handle) = self::port(handle) {
+            handle.in_flight.store(false, Ordering::Release);
+        }
+    }
+    port.receivers.wake_all();
+}
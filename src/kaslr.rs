@@ -0,0 +1,104 @@
+use crate::time;
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::random::RdRand;
+
+// the regions move by a multiple of 2 MiB below 64 GiB, which keeps each in its level 4 entry
+const SLIDE_ALIGN: u64 = 2 * 1024 * 1024;
+const MAX_SLIDE: u64 = 64 * 1024 * 1024 * 1024;
+const REGIONS: usize = 3;
+
+/**
+ * The parts of the kernel's address space that move, by a slide each.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Heap,
+    Stacks,
+    Vmm
+}
+
+/**
+ * Where the entropy for the slides came from.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    RdRand,
+    // the TSC's low bits at boot, weak, but different each time
+    Tsc
+}
+
+/**
+ * The slides init() picked, for the boot log and for symbolizing addresses.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    // the bootloader loads the kernel at the addresses it is linked at, and its code is not position independent,
+    // so the image itself does not move and this is always 0
+    pub kernel_offset: u64,
+    pub heap_slide: u64,
+    pub stacks_slide: u64,
+    pub vmm_slide: u64,
+    pub source: EntropySource
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KASLR from {:?}: kernel +{:#x}, heap +{:#x}, stacks +{:#x}, vmm +{:#x}",
+            self.source, self.kernel_offset, self.heap_slide, self.stacks_slide, self.vmm_slide)
+    }
+}
+
+static LAYOUT: Once<Layout> = Once::new();
+
+/**
+ * Picks the slides, once. Must run before anything maps the heap, stacks or vmm regions, i.e. before heap::init().
+ */
+pub fn init() -> Layout {
+    *LAYOUT.call_once(|| {
+        let (mut entropy, source) = match RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+            Some(random) => (random, EntropySource::RdRand),
+            None => (time::read_tsc(), EntropySource::Tsc)
+        };
+        let mut slides = [0; REGIONS];
+        for slide in slides.iter_mut() {
+            *slide = mix(&mut entropy) % (MAX_SLIDE / SLIDE_ALIGN) * SLIDE_ALIGN;
+        }
+        Layout {
+            kernel_offset: 0,
+            heap_slide: slides[0],
+            stacks_slide: slides[1],
+            vmm_slide: slides[2],
+            source
+        }
+    })
+}
+
+/**
+ * Returns the slides, None before init().
+ */
+pub fn layout() -> Option<Layout> {
+    LAYOUT.r#try().copied()
+}
+
+/**
+ * Returns how far a region is moved from its base address, 0 before init().
+ */
+pub fn slide(region: Region) -> u64 {
+    layout().map_or(0, |layout| match region {
+        Region::Heap => layout.heap_slide,
+        Region::Stacks => layout.stacks_slide,
+        Region::Vmm => layout.vmm_slide
+    })
+}
+
+/**
+ * Takes the next number of a splitmix64 sequence, so one seed gives unrelated slides.
+ */
+fn mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut value = *state;
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,522 @@
+use crate::hotkey;
+use crate::i8042::{self, I8042Error, ScancodeSet};
+use crate::sync::IrqMutex;
+use crate::time;
+use crate::status_bar;
+use crate::trace;
+use crate::wait_queue::WaitQueue;
+use core::cell::UnsafeCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, Keyboard, KeyCode, KeyEvent, KeyState, ScancodeSet1, ScancodeSet2, layouts};
+use x86_64::instructions::interrupts;
+
+// followed by the LED byte: scroll lock in bit 0, num lock in bit 1, caps lock in bit 2
+const SET_LEDS: u8 = 0xED;
+// followed by the typematic byte: the repeat rate in bits 0-4, the delay before repeating in bits 5-6
+const SET_TYPEMATIC: u8 = 0xF3;
+// the delays the keyboard can wait before repeating, 250 ms apart
+const TYPEMATIC_DELAY_STEP: u32 = 250;
+const DEFAULT_REPEAT_DELAY: u32 = 500;
+const DEFAULT_REPEAT_RATE: u32 = 10;
+// the keyboard's answers to commands, they come in like scancodes
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+// how long the keyboard has to answer a command byte, real ones take a few milliseconds
+const ANSWER_TIMEOUT_US: u64 = 100_000;
+const POLL_INTERVAL_US: u64 = 10;
+// ANSWER before the keyboard answered
+const NO_ANSWER: usize = usize::MAX;
+// SysRq, i.e. Alt+PrintScreen, has a scancode of its own that pc_keyboard doesn't know
+const SET1_SYSRQ_PRESSED: u8 = 0x54;
+const SET1_SYSRQ_RELEASED: u8 = 0xD4;
+// in set 2 it is released with the release prefix
+const SET2_SYSRQ: u8 = 0x84;
+const SET2_RELEASE: u8 = 0xF0;
+
+// scancodes received but not decoded yet, more are dropped; a power of two, so the indices can wrap around freely
+const QUEUE_SIZE: usize = 128;
+
+/**
+ * Scancodes on their way from the keyboard interrupt handler to the decoding in kernel context.
+ */
+static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+
+// wakes the task waiting on the KeyStream when a scancode arrives
+static WAKER: AtomicWaker = AtomicWaker::new();
+// there can only be one KeyStream at a time, the waker holds a single task
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+// the threads in wait_key(), woken the same times as the waker
+static KEY_WAITERS: WaitQueue = WaitQueue::new();
+// the keyboard's answer to the command byte sent last, ACK or RESEND, handed over by the interrupt handler
+static ANSWER: AtomicUsize = AtomicUsize::new(NO_ANSWER);
+
+lazy_static! {
+    /** Decodes the scancodes in the set the PS/2 controller settled on, so it must be used after i8042::init(). */
+    static ref KEYBOARD : IrqMutex<Decoder> = IrqMutex::new(match i8042::scancode_set() {
+        ScancodeSet::Set1 => Decoder::Set1(Keyboard::new(layouts::Us104Key, ScancodeSet1)),
+        ScancodeSet::Set2 => Decoder::Set2(Keyboard::new(layouts::Us104Key, ScancodeSet2), false)
+    });
+}
+
+// pc_keyboard keeps the modifier state to itself (and ignores the Alt keys), so we track them ourselves
+static ALT_PRESSED: AtomicBool = AtomicBool::new(false);
+static SHIFT_PRESSED: AtomicBool = AtomicBool::new(false);
+static CTRL_PRESSED: AtomicBool = AtomicBool::new(false);
+static SYSRQ_HELD: AtomicBool = AtomicBool::new(false);
+// the lock keys toggle on every press
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+static NUM_LOCK: AtomicBool = AtomicBool::new(false);
+static SCROLL_LOCK: AtomicBool = AtomicBool::new(false);
+// a lock key was toggled, the LEDs are sent by read_key() once the keyboard is unlocked, see update_leds()
+static LEDS_STALE: AtomicBool = AtomicBool::new(false);
+
+// repeat held keys in software, ignoring the keyboard's own repeats
+static SOFTWARE_REPEAT: AtomicBool = AtomicBool::new(false);
+// when the held key is repeated next, in milliseconds since boot, checked by the timer interrupt
+static NEXT_REPEAT: AtomicU64 = AtomicU64::new(NO_REPEAT);
+const NO_REPEAT: u64 = u64::max_value();
+
+static REPEAT: IrqMutex<Repeat> = IrqMutex::new(Repeat {
+    delay: DEFAULT_REPEAT_DELAY as u64,
+    period: 1000 / DEFAULT_REPEAT_RATE as u64,
+    held: None
+});
+
+/**
+ * The settings of the software key repeat, in milliseconds, and the key being held for it.
+ */
+struct Repeat {
+    delay: u64,
+    period: u64,
+    held: Option<(KeyCode, DecodedKey)>
+}
+
+/**
+ * pc_keyboard's decoder for either scancode set.
+ */
+enum Decoder {
+    Set1(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    // and whether the previous byte was the release prefix
+    Set2(Keyboard<layouts::Us104Key, ScancodeSet2>, bool)
+}
+
+impl Decoder {
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        match self {
+            Decoder::Set1(keyboard) => keyboard.add_byte(scancode).ok()?,
+            Decoder::Set2(keyboard, release) => {
+                *release = scancode == SET2_RELEASE;
+                keyboard.add_byte(scancode).ok()?
+            }
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Decoder::Set1(keyboard) => keyboard.process_keyevent(event),
+            Decoder::Set2(keyboard, _) => keyboard.process_keyevent(event)
+        }
+    }
+
+    /**
+     * Returns whether SysRq is pressed or released by the scancode, if it is SysRq at all.
+     */
+    fn sysrq(&self, scancode: u8) -> Option<bool> {
+        match (self, scancode) {
+            (Decoder::Set1(_), SET1_SYSRQ_PRESSED) => Some(true),
+            (Decoder::Set1(_), SET1_SYSRQ_RELEASED) => Some(false),
+            (Decoder::Set2(_, release), SET2_SYSRQ) => Some(!release),
+            _ => None
+        }
+    }
+}
+
+/**
+ * The modifier keys held down and the lock keys turned on.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool
+}
+
+/**
+ * A fixed-size queue taking no lock, for a single producer, the interrupt handler, and any number of consumers.
+ */
+struct ScancodeQueue {
+    // a slot is only written while it is outside head..tail, and only read while inside
+    scancodes: UnsafeCell<[u8; QUEUE_SIZE]>,
+    // the next scancode is read from slot head % QUEUE_SIZE, written to slot tail % QUEUE_SIZE
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize
+}
+
+unsafe impl Sync for ScancodeQueue {}
+
+impl ScancodeQueue {
+    const fn new() -> ScancodeQueue {
+        ScancodeQueue {
+            scancodes: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0)
+        }
+    }
+
+    fn push(&self, scancode: u8) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == QUEUE_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { (*self.scancodes.get())[tail % QUEUE_SIZE] = scancode };
+        // publishes the scancode
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            if head == self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let scancode = unsafe { (*self.scancodes.get())[head % QUEUE_SIZE] };
+            // another consumer may have taken it meanwhile
+            match self.head.compare_exchange_weak(head, head.wrapping_add(1), Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => return Some(scancode),
+                Err(current) => head = current
+            }
+        }
+    }
+}
+
+/**
+ * Queues a scancode read from the keyboard, or hands an answer to a command over to send_command(), called by its
+ * interrupt handler. Takes no lock but the waiting threads'.
+ */
+pub(crate) fn push_scancode(scancode: u8) {
+    // answers to the commands send_command() waits for, not keys
+    if scancode == ACK || scancode == RESEND {
+        ANSWER.store(usize::from(scancode), Ordering::Release);
+        return;
+    }
+    SCANCODES.push(scancode);
+    WAKER.wake();
+    KEY_WAITERS.wake_all();
+}
+
+/**
+ * Decodes the queued scancodes up to the next key, which is returned, or until the queue is empty.
+ * Hotkeys, see the hotkey module, are handled on the way and not returned.
+ * Must not be called from an interrupt handler.
+ */
+pub fn read_key() -> Option<DecodedKey> {
+    let key = next_key();
+    update_leds();
+    key
+}
+
+fn next_key() -> Option<DecodedKey> {
+    while let Some(scancode) = SCANCODES.pop() {
+        trace!("scancode {:#04x}", scancode);
+        if let Some(key) = decode(scancode) {
+            return Some(key);
+        }
+    }
+    repeated_key()
+}
+
+/**
+ * Blocks the calling thread until the next key, see read_key(). Must not be called from an interrupt handler.
+ */
+pub fn wait_key() -> DecodedKey {
+    loop {
+        if let Some(key) = read_key() {
+            return key;
+        }
+        // a hotkey only is no key, wait for more then
+        KEY_WAITERS.wait_until(|| !SCANCODES.is_empty() || time::uptime_ms() >= NEXT_REPEAT.load(Ordering::Relaxed));
+    }
+}
+
+/**
+ * Sets how long a key has to be held before it repeats, and how many times a second it repeats then.
+ * The keyboard only knows delays of 250 to 1000 ms and rates of 2 to 30 a second, the nearest ones are taken.
+ * The software repeat, if enabled, follows the given values, as precisely as the timer ticks allow.
+ * Interrupts must be enabled, see send_command().
+ */
+pub fn set_repeat_rate(delay_ms: u32, rate: u32) -> Result<(), I8042Error> {
+    let rate = rate.max(1);
+    {
+        let mut repeat = REPEAT.lock();
+        repeat.delay = u64::from(delay_ms);
+        repeat.period = u64::from(1000 / rate);
+    }
+
+    let delay = (delay_ms + TYPEMATIC_DELAY_STEP / 2) / TYPEMATIC_DELAY_STEP;
+    let delay = delay.max(1).min(4) as u8 - 1;
+    send_command(SET_TYPEMATIC)?;
+    send_command(delay << 5 | typematic_rate(rate))
+}
+
+/**
+ * Returns the typematic rate bits for the rate nearest to the given one.
+ * The keyboard repeats 240 / ((8 + bits 0-2) * 2^bits 3-4) times a second.
+ */
+fn typematic_rate(rate: u32) -> u8 {
+    // in tenths, to tell the slow rates apart
+    let wanted = rate * 10;
+    (0..32u8)
+        .min_by_key(|&bits| {
+            let actual = 2400 / ((8 + u32::from(bits & 0x07)) << (bits >> 3));
+            (actual as i32 - wanted as i32).abs()
+        })
+        .unwrap_or(0)
+}
+
+/**
+ * Repeats held keys in software instead of relying on the keyboard, for emulators and
+ * keyboards that ignore the typematic command. The keyboard's own repeats are dropped meanwhile.
+ */
+pub fn set_software_repeat(enabled: bool) {
+    SOFTWARE_REPEAT.store(enabled, Ordering::Relaxed);
+    REPEAT.lock().held = None;
+    NEXT_REPEAT.store(NO_REPEAT, Ordering::Relaxed);
+}
+
+/**
+ * Wakes the KeyStream when the held key is due to repeat, called by the timer interrupt.
+ */
+pub(crate) fn tick() {
+    if time::uptime_ms() >= NEXT_REPEAT.load(Ordering::Relaxed) {
+        WAKER.wake();
+        KEY_WAITERS.wake_all();
+    }
+}
+
+/**
+ * Returns the held key again if it is due to repeat.
+ */
+fn repeated_key() -> Option<DecodedKey> {
+    let now = time::uptime_ms();
+    if now < NEXT_REPEAT.load(Ordering::Relaxed) {
+        return None;
+    }
+    let repeat = REPEAT.lock();
+    let (_, key) = repeat.held?;
+    NEXT_REPEAT.store(now + repeat.period, Ordering::Relaxed);
+    Some(key)
+}
+
+/**
+ * Keeps track of the held key for the software repeat.
+ * Returns false for the keyboard's own repeats, which are to be dropped.
+ */
+fn track_held_key(event: &KeyEvent, key: Option<DecodedKey>) -> bool {
+    if !SOFTWARE_REPEAT.load(Ordering::Relaxed) {
+        return true;
+    }
+    let mut repeat = REPEAT.lock();
+    let held = repeat.held.map(|(code, _)| code);
+    match event.state {
+        KeyState::Down if held == Some(event.code) => false,
+        KeyState::Down => {
+            if let Some(key) = key {
+                repeat.held = Some((event.code, key));
+                NEXT_REPEAT.store(time::uptime_ms() + repeat.delay, Ordering::Relaxed);
+            }
+            true
+        }
+        KeyState::Up => {
+            if held == Some(event.code) {
+                repeat.held = None;
+                NEXT_REPEAT.store(NO_REPEAT, Ordering::Relaxed);
+            }
+            true
+        }
+    }
+}
+
+/**
+ * Returns the current state of the modifier and lock keys, as of the keys decoded so far.
+ */
+pub fn modifiers() -> Modifiers {
+    Modifiers {
+        shift: SHIFT_PRESSED.load(Ordering::Relaxed),
+        ctrl: CTRL_PRESSED.load(Ordering::Relaxed),
+        alt: ALT_PRESSED.load(Ordering::Relaxed),
+        caps_lock: CAPS_LOCK.load(Ordering::Relaxed),
+        num_lock: NUM_LOCK.load(Ordering::Relaxed),
+        scroll_lock: SCROLL_LOCK.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * Returns how many scancodes were lost because nobody decoded them in time.
+ */
+pub fn dropped_scancodes() -> usize {
+    SCANCODES.dropped.load(Ordering::Relaxed)
+}
+
+/**
+ * The keys typed, for async code: `while let Some(key) = keys.next().await`.
+ * The stream never ends. Only one can exist at a time, it can be created again once dropped.
+ */
+pub struct KeyStream {
+    _private: ()
+}
+
+impl KeyStream {
+    pub fn new() -> KeyStream {
+        if STREAM_TAKEN.swap(true, Ordering::Acquire) {
+            panic!("KeyStream::new() called while another KeyStream exists");
+        }
+        KeyStream {
+            _private: ()
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> KeyStream {
+        KeyStream::new()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = read_key() {
+            return Poll::Ready(Some(key));
+        }
+
+        WAKER.register(context.waker());
+        // a scancode may have come in before the waker was registered
+        match read_key() {
+            Some(key) => {
+                WAKER.take();
+                Poll::Ready(Some(key))
+            }
+            None => Poll::Pending
+        }
+    }
+}
+
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        STREAM_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+fn decode(scancode: u8) -> Option<DecodedKey> {
+    let mut keyboard = KEYBOARD.lock();
+    if let Some(pressed) = keyboard.sysrq(scancode) {
+        SYSRQ_HELD.store(pressed, Ordering::Relaxed);
+        // lets the decoder forget a release prefix
+        keyboard.add_byte(scancode);
+        return None;
+    }
+    let key_event = keyboard.add_byte(scancode)?;
+    trace!("key {:?} {:?}", key_event.code, key_event.state);
+    match find_hotkey(&key_event) {
+        Some(handler) => {
+            // handlers take the screen locks and may register hotkeys, the keyboard is not needed for them
+            drop(keyboard);
+            handler();
+            None
+        }
+        None => {
+            let key = keyboard.process_keyevent(key_event.clone());
+            if track_held_key(&key_event, key) { key } else { None }
+        }
+    }
+}
+
+/**
+ * Updates the tracked modifiers and returns the handler of the hotkey the event completes, if any.
+ * Modifier events are never consumed, pc_keyboard still needs to see them.
+ */
+fn find_hotkey(event: &KeyEvent) -> Option<hotkey::Handler> {
+    let pressed = event.state == KeyState::Down;
+    match event.code {
+        KeyCode::AltLeft | KeyCode::AltRight => ALT_PRESSED.store(pressed, Ordering::Relaxed),
+        KeyCode::ShiftLeft | KeyCode::ShiftRight => SHIFT_PRESSED.store(pressed, Ordering::Relaxed),
+        KeyCode::ControlLeft | KeyCode::ControlRight => CTRL_PRESSED.store(pressed, Ordering::Relaxed),
+        KeyCode::CapsLock if pressed => toggle_lock(&CAPS_LOCK),
+        KeyCode::NumpadLock if pressed => toggle_lock(&NUM_LOCK),
+        KeyCode::ScrollLock if pressed => toggle_lock(&SCROLL_LOCK),
+        key if pressed => return hotkey::find(key, &modifiers(), SYSRQ_HELD.load(Ordering::Relaxed)),
+        _ => {}
+    }
+    None
+}
+
+fn toggle_lock(lock: &AtomicBool) {
+    lock.fetch_xor(true, Ordering::Relaxed);
+    let modifiers = modifiers();
+    status_bar::set_lock_states(modifiers.caps_lock, modifiers.num_lock, modifiers.scroll_lock);
+    // the keyboard is locked, with interrupts off, its answer to the LED command could not come in now
+    LEDS_STALE.store(true, Ordering::Relaxed);
+}
+
+/**
+ * Sends the lock states to the LEDs if a lock key was toggled since they were sent last.
+ * With interrupts off they stay stale until a later call.
+ */
+fn update_leds() {
+    if !interrupts::are_enabled() || !LEDS_STALE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let modifiers = modifiers();
+    let _ = set_leds(modifiers.caps_lock, modifiers.num_lock, modifiers.scroll_lock);
+}
+
+/**
+ * Turns the keyboard's lock LEDs on or off. They are kept in sync with the lock keys by themselves.
+ * Interrupts must be enabled, see send_command().
+ */
+pub fn set_leds(caps_lock: bool, num_lock: bool, scroll_lock: bool) -> Result<(), I8042Error> {
+    let leds = (scroll_lock as u8) | (num_lock as u8) << 1 | (caps_lock as u8) << 2;
+    send_command(SET_LEDS)?;
+    send_command(leds)
+}
+
+/**
+ * Sends a command, or a command's data byte, to the keyboard and waits for it to acknowledge the byte, the next one
+ * may be lost otherwise. The answer comes through the interrupt handler, so interrupts must be enabled and the
+ * keyboard not locked, or this times out. Fails with Resend if the keyboard did not take the byte.
+ */
+fn send_command(byte: u8) -> Result<(), I8042Error> {
+    ANSWER.store(NO_ANSWER, Ordering::Release);
+    i8042::send_to_first_port(byte)?;
+    match wait_for_answer()? {
+        ACK => Ok(()),
+        _ => Err(I8042Error::Resend)
+    }
+}
+
+fn wait_for_answer() -> Result<u8, I8042Error> {
+    for _ in 0..ANSWER_TIMEOUT_US / POLL_INTERVAL_US {
+        let answer = ANSWER.load(Ordering::Acquire);
+        if answer != NO_ANSWER {
+            return Ok(answer as u8);
+        }
+        time::busy_wait_us(POLL_INTERVAL_US);
+    }
+    Err(I8042Error::Timeout)
+}
@@ -0,0 +1,131 @@
+use crate::logger::{Level, Record, Sink, Timestamp};
+use crate::sync::IrqMutex;
+use core::fmt::{self, Write};
+
+// how many messages are kept, the oldest ones are overwritten first
+const KLOG_ENTRIES: usize = 128;
+// longer messages are cut off
+const MAX_MESSAGE_LENGTH: usize = 120;
+
+/**
+ * A logged message as kept in the kernel log.
+ */
+#[derive(Clone, Copy)]
+pub struct Message {
+    // counts every message ever logged, so readers can tell what they have missed
+    pub sequence: u64,
+    pub level: Level,
+    // milliseconds since boot
+    pub timestamp: u64,
+    text: [u8; MAX_MESSAGE_LENGTH],
+    len: usize
+}
+
+impl Message {
+    const fn empty() -> Message {
+        Message {
+            sequence: 0,
+            level: Level::Info,
+            timestamp: 0,
+            text: [0; MAX_MESSAGE_LENGTH],
+            len: 0
+        }
+    }
+
+    /**
+     * Returns the module the message comes from and the message itself, as "module: message".
+     */
+    pub fn text(&self) -> &str {
+        // only ever cut at a character boundary
+        unsafe { core::str::from_utf8_unchecked(&self.text[..self.len]) }
+    }
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for character in text.chars() {
+            let mut encoded = [0; 4];
+            let encoded = character.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > MAX_MESSAGE_LENGTH {
+                break;
+            }
+            self.text[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:<5} {}", Timestamp(self.timestamp), self.level, self.text())
+    }
+}
+
+/**
+ * The kernel log: the latest messages, whatever sinks they went to, so they can be replayed
+ * after they scrolled off the screen, e.g. by a dmesg command or after a crash.
+ */
+static KLOG: IrqMutex<Klog> = IrqMutex::new(Klog {
+    messages: [Message::empty(); KLOG_ENTRIES],
+    next_sequence: 0
+});
+
+struct Klog {
+    messages: [Message; KLOG_ENTRIES],
+    // the sequence number of the next message, it goes to slot next_sequence % KLOG_ENTRIES
+    next_sequence: u64
+}
+
+/**
+ * Keeps every message in the kernel log.
+ */
+pub struct KlogSink;
+
+impl Sink for KlogSink {
+    fn name(&self) -> &'static str {
+        "klog"
+    }
+
+    fn log(&self, record: &Record) {
+        let mut klog = KLOG.lock();
+        let sequence = klog.next_sequence;
+        let message = &mut klog.messages[(sequence % KLOG_ENTRIES as u64) as usize];
+        *message = Message {
+            sequence,
+            level: record.level,
+            timestamp: record.timestamp,
+            ..Message::empty()
+        };
+        let _ = write!(message, "{}: {}", record.module, record.args);
+        klog.next_sequence += 1;
+    }
+}
+
+/**
+ * Calls `each` for the kept messages with a sequence number of at least `since`, oldest first,
+ * and returns the sequence number the next message will get, to continue from later.
+ * Messages that were already overwritten are skipped.
+ * The log is locked meanwhile, so `each` must not log.
+ */
+pub fn read<F: FnMut(&Message)>(since: u64, mut each: F) -> u64 {
+    let klog = KLOG.lock();
+    let oldest = klog.next_sequence.saturating_sub(KLOG_ENTRIES as u64);
+    for sequence in since.max(oldest)..klog.next_sequence {
+        each(&klog.messages[(sequence % KLOG_ENTRIES as u64) as usize]);
+    }
+    klog.next_sequence
+}
+
+/**
+ * Writes all the kept messages to the given sink, one per line.
+ */
+pub fn dump<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    let mut result = Ok(());
+    read(0, |message| {
+        if result.is_ok() {
+            result = writeln!(out, "{}", message);
+        }
+    });
+    result
+}
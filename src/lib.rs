@@ -2,11 +2,147 @@
 // TODO: remove the annotation when it is stable
 #![no_std]
 #![feature(abi_x86_interrupt)]
+// the syscall entry stub is written in assembly
+#![feature(global_asm)]
+// the heap panics when it runs out, see heap.rs
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
+
+pub mod acpi;
+pub mod address_space;
+pub mod ansi;
+pub mod apic;
+pub mod block;
+pub mod boot;
+pub mod buddy;
+pub mod console;
+pub mod cow;
+pub mod cp437;
+pub mod debug_heap;
+pub mod debugcon;
+pub mod demand;
+pub mod dma;
+pub mod early_console;
+pub mod elf;
+pub mod fat;
+pub mod fpu;
+pub mod frame_allocator;
+pub mod gdbstub;
+pub mod heap;
+pub mod hotkey;
+pub mod hpet;
+pub mod i8042;
+pub mod initrd;
 pub mod interrupts;
+pub mod ipc;
+pub mod ioapic;
+pub mod kaslr;
+pub mod keyboard;
+pub mod klog;
+pub mod logger;
+pub mod memory;
+pub mod mouse;
+pub mod msi;
 pub mod vga_buffer;
 pub mod gdt;
+pub mod paging;
+pub mod panic_screen;
+pub mod pci;
+pub mod percpu;
+pub mod pipe;
+pub mod power;
+pub mod process;
+pub mod program;
+pub mod ramfs;
+pub mod rtc;
+pub mod sched;
+pub mod scheduler;
+pub mod serial;
+pub mod signal;
+pub mod slab;
+pub mod stack;
+pub mod status_bar;
+pub mod sync;
+pub mod sys;
+pub mod syscall;
+pub mod syscall_abi;
+pub mod thread;
+pub mod time;
+pub mod timer;
+pub mod user;
+pub mod user_access;
+pub mod vfs;
+pub mod vmm;
+pub mod vt;
+pub mod wait_queue;
+
+use bootloader::BootInfo;
 
-pub fn init() {
-    gdt::init();
-    interrupts::init_idt();
+/**
+ * Brings up the kernel stage by stage, reporting each on the screen.
+ */
+pub fn init(boot_info: &'static BootInfo) {
+    boot::banner();
+    boot::stage("VGA", || {
+        vga_buffer::set_background_mode(vga_buffer::DEFAULT_BACKGROUND_MODE);
+        status_bar::redraw();
+    });
+    boot::stage("memory", || memory::init(boot_info));
+    let _ = boot::try_stage("frames", frame_allocator::init);
+    boot::stage("paging", paging::init);
+    // before anything is mapped in the regions it moves
+    boot::stage("KASLR", || {
+        kaslr::init();
+    });
+    let _ = boot::try_stage("heap", heap::init);
+    // a missing serial port is not fatal, the console is on the screen
+    let _ = boot::try_stage("serial", serial::init);
+    let _ = boot::try_stage("log", logger::init);
+    info!("{}, {} frames free", memory::summary(), frame_allocator::free_frames());
+    for region in memory::memory_map() {
+        debug!("{:#012x}-{:#012x} {:?}", region.range.start_addr(), region.range.end_addr(), region.region_type);
+    }
+    debug!("kernel mapped with {} 2 MiB pages", paging::kernel_huge_pages());
+    if let Some(layout) = kaslr::layout() {
+        info!("{}", layout);
+    }
+    // the IDT's interrupt stacks are in the TSS, there is no going on without it
+    boot::try_stage("GDT", gdt::init).expect("no GDT for the boot CPU");
+    boot::stage("IDT", interrupts::init_idt);
+    boot::stage("protections", || {
+        let protections = memory::enable_protections();
+        info!("paging protections: {:?}", protections);
+    });
+    let _ = boot::try_stage("FPU", fpu::init);
+    let _ = boot::try_stage("per-CPU", percpu::init);
+    let _ = boot::try_stage("syscall", syscall::init);
+    let _ = boot::try_stage("system calls", sys::init);
+    // the root file system, until there are disks
+    let _ = boot::try_stage("ramfs", ramfs::init);
+    // the files that ship with the kernel, into the ramfs
+    #[cfg(feature = "initrd")]
+    let _ = boot::try_stage("initrd", initrd::init);
+    let _ = boot::try_stage("scheduler", scheduler::init);
+    // polled with interrupts still off; without a controller there is just no keyboard
+    let _ = boot::try_stage("PS/2", i8042::init);
+    let _ = boot::try_stage("mouse", mouse::init);
+    // the local APIC's timer, or else the HPET, takes over the timer interrupt from the PIT where there is one
+    let _ = boot::try_stage("ACPI", acpi::init);
+    let _ = boot::try_stage("HPET", hpet::init);
+    let _ = boot::try_stage("APIC", apic::init);
+    // the IRQs are routed through it instead of the PICs at the PIC stage
+    #[cfg(feature = "apic")]
+    let _ = boot::try_stage("I/O APIC", ioapic::init);
+    let _ = boot::try_stage("timer", time::init);
+    let _ = boot::try_stage("TSC", time::calibrate_tsc);
+    let _ = boot::try_stage("RTC", rtc::init);
+    boot::stage("PIC", interrupts::init_pics);
+    // before gdb, waiting for it would be counted as boot time
+    let timings = boot::timings();
+    println!("\n{}", timings);
+    debug_println!("{}", timings);
+    // waits here until gdb attaches
+    #[cfg(feature = "gdbstub")]
+    let _ = boot::try_stage("gdb", gdbstub::init);
 }
\ No newline at end of file
@@ -0,0 +1,436 @@
+use crate::debugcon::DebugCon;
+use crate::klog::KlogSink;
+use crate::serial;
+use crate::sync::IrqMutex;
+use crate::time;
+use crate::vga_buffer::{self, ColorCode, Colors};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+// how many sinks can be registered at the same time
+const MAX_SINKS: usize = 4;
+// how many modules can have their own level, and how long their paths can be
+const MAX_MODULE_FILTERS: usize = 8;
+const MAX_MODULE_LENGTH: usize = 48;
+
+/**
+ * The severity of a log message, the most severe first.
+ * A sink set to a level gets the messages of that level and the more severe ones.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE"
+        }
+    }
+
+    fn color(self) -> ColorCode {
+        match self {
+            Level::Error => ColorCode::new(Colors::LightRed, Colors::Black),
+            Level::Warn => ColorCode::new(Colors::Yellow, Colors::Black),
+            Level::Info => ColorCode::new(Colors::LightCyan, Colors::Black),
+            Level::Debug => ColorCode::new(Colors::LightGray, Colors::Black),
+            Level::Trace => ColorCode::new(Colors::DarkGray, Colors::Black)
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = LoggerError;
+
+    /**
+     * Parses a level name, in any case, e.g. "debug".
+     */
+    fn from_str(name: &str) -> Result<Level, LoggerError> {
+        [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace].iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or(LoggerError::UnknownLevel)
+    }
+}
+
+impl From<log::Level> for Level {
+    fn from(level: log::Level) -> Level {
+        match level {
+            log::Level::Error => Level::Error,
+            log::Level::Warn => Level::Warn,
+            log::Level::Info => Level::Info,
+            log::Level::Debug => Level::Debug,
+            log::Level::Trace => Level::Trace
+        }
+    }
+}
+
+impl From<Level> for log::LevelFilter {
+    fn from(level: Level) -> log::LevelFilter {
+        match level {
+            Level::Error => log::LevelFilter::Error,
+            Level::Warn => log::LevelFilter::Warn,
+            Level::Info => log::LevelFilter::Info,
+            Level::Debug => log::LevelFilter::Debug,
+            Level::Trace => log::LevelFilter::Trace
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/**
+ * A log message on its way to the sinks.
+ */
+pub struct Record<'a> {
+    pub level: Level,
+    // the module the message comes from, e.g. visage::interrupts
+    pub module: &'a str,
+    // milliseconds since boot at the time of logging
+    pub timestamp: u64,
+    pub args: fmt::Arguments<'a>
+}
+
+/**
+ * Milliseconds since boot, shown as seconds, e.g. `[   12.345]`.
+ */
+pub struct Timestamp(pub u64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>5}.{:03}]", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/**
+ * A destination for log messages. Sinks are called with no lock of the logger held,
+ * so they may take their own locks, but must not log themselves.
+ */
+pub trait Sink: Sync {
+    /**
+     * Identifies the sink for set_level() and remove_sink().
+     */
+    fn name(&self) -> &'static str;
+
+    fn log(&self, record: &Record);
+}
+
+/**
+ * Prints messages on the screen, in the color of their level.
+ */
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn log(&self, record: &Record) {
+        vga_buffer::_print_colored(
+            record.level.color(),
+            format_args!("{} [{}] {}\n", Timestamp(record.timestamp), record.level, record.args)
+        );
+    }
+}
+
+/**
+ * Sends messages to COM1, along with the module they come from.
+ */
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn log(&self, record: &Record) {
+        serial::_print(format_args!("{} {:<5} {}: {}\n", Timestamp(record.timestamp), record.level, record.module, record.args));
+    }
+}
+
+/**
+ * Sends messages to the emulator's debug console, along with the module they come from.
+ */
+pub struct DebugConSink;
+
+impl Sink for DebugConSink {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn log(&self, record: &Record) {
+        let _ = writeln!(DebugCon, "{} {:<5} {}: {}", Timestamp(record.timestamp), record.level, record.module, record.args);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoggerError {
+    TooManySinks,
+    UnknownSink,
+    TooManyModuleFilters,
+    ModuleTooLong,
+    UnknownModule,
+    UnknownLevel,
+    // a module filter not written as module=level
+    InvalidFilter
+}
+
+type SinkEntry = Option<(&'static dyn Sink, Level)>;
+
+/**
+ * The registered sinks with the least severe level each of them gets.
+ * By default the screen gets everything from Info up, the serial port from Debug up, and the kernel log everything.
+ */
+static SINKS: IrqMutex<[SinkEntry; MAX_SINKS]> = IrqMutex::new([
+    Some((&VgaSink, Level::Info)),
+    Some((&SerialSink, Level::Debug)),
+    Some((&KlogSink, Level::Trace)),
+    None
+]);
+
+/**
+ * The least severe level logged from a module and its submodules, or None if nothing is.
+ */
+#[derive(Clone, Copy)]
+struct ModuleFilter {
+    module: [u8; MAX_MODULE_LENGTH],
+    len: usize,
+    level: Option<Level>
+}
+
+impl ModuleFilter {
+    fn module(&self) -> &str {
+        // only ever filled from a whole str
+        unsafe { core::str::from_utf8_unchecked(&self.module[..self.len]) }
+    }
+
+    /**
+     * Tells whether the filter is for the given module or one of its parents.
+     */
+    fn matches(&self, module: &str) -> bool {
+        let prefix = self.module();
+        module.starts_with(prefix) && (module.len() == prefix.len() || module[prefix.len()..].starts_with("::"))
+    }
+}
+
+/**
+ * The modules with a level of their own, on top of the levels of the sinks.
+ * The filter with the longest module path matching a message decides whether it is logged at all.
+ */
+static MODULE_FILTERS: IrqMutex<[Option<ModuleFilter>; MAX_MODULE_FILTERS]> = IrqMutex::new([None; MAX_MODULE_FILTERS]);
+
+/**
+ * Registers a sink to get the messages of the given level and the more severe ones.
+ */
+pub fn add_sink(sink: &'static dyn Sink, level: Level) -> Result<(), LoggerError> {
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().find(|entry| entry.is_none()) {
+        Some(entry) => {
+            *entry = Some((sink, level));
+            update_max_level(&sinks);
+            Ok(())
+        }
+        None => Err(LoggerError::TooManySinks)
+    }
+}
+
+pub fn remove_sink(name: &str) -> Result<(), LoggerError> {
+    let mut sinks = SINKS.lock();
+    let entry = find(&mut sinks, name)?;
+    *entry = None;
+    update_max_level(&sinks);
+    Ok(())
+}
+
+/**
+ * Changes the least severe level the named sink gets.
+ */
+pub fn set_level(name: &str, level: Level) -> Result<(), LoggerError> {
+    let mut sinks = SINKS.lock();
+    let entry = find(&mut sinks, name)?;
+    if let Some((_, sink_level)) = entry {
+        *sink_level = level;
+    }
+    update_max_level(&sinks);
+    Ok(())
+}
+
+/**
+ * Limits the messages of a module and its submodules to the given level and the more severe ones,
+ * e.g. `set_module_level("visage::interrupts", Some(Level::Debug))`. None silences the module.
+ * More specific modules can be set to a different level, e.g. to keep a submodule more verbose than its parent.
+ */
+pub fn set_module_level(module: &str, level: Option<Level>) -> Result<(), LoggerError> {
+    if module.len() > MAX_MODULE_LENGTH {
+        return Err(LoggerError::ModuleTooLong);
+    }
+    let mut filters = MODULE_FILTERS.lock();
+    let existing = filters.iter().position(|filter| filter.map_or(false, |filter| filter.module() == module));
+    let slot = existing.or_else(|| filters.iter().position(|filter| filter.is_none()))
+        .ok_or(LoggerError::TooManyModuleFilters)?;
+
+    let mut filter = ModuleFilter {
+        module: [0; MAX_MODULE_LENGTH],
+        len: module.len(),
+        level
+    };
+    filter.module[..module.len()].copy_from_slice(module.as_bytes());
+    filters[slot] = Some(filter);
+    Ok(())
+}
+
+/**
+ * Removes the module's own level, it gets the level of its parent or of the sinks again.
+ */
+pub fn clear_module_level(module: &str) -> Result<(), LoggerError> {
+    let mut filters = MODULE_FILTERS.lock();
+    let filter = filters.iter_mut()
+        .find(|filter| filter.map_or(false, |filter| filter.module() == module))
+        .ok_or(LoggerError::UnknownModule)?;
+    *filter = None;
+    Ok(())
+}
+
+/**
+ * Sets module levels from a comma separated list of module=level pairs, where level can also be "off",
+ * e.g. "visage::interrupts=warn,visage::vga_buffer=debug", as given on the kernel command line.
+ * The pairs before an invalid one are kept.
+ */
+pub fn parse_module_levels(filters: &str) -> Result<(), LoggerError> {
+    for filter in filters.split(',').map(str::trim).filter(|filter| !filter.is_empty()) {
+        let mut parts = filter.splitn(2, '=');
+        let module = parts.next().unwrap_or("").trim();
+        let level = parts.next().ok_or(LoggerError::InvalidFilter)?.trim();
+        if module.is_empty() {
+            return Err(LoggerError::InvalidFilter);
+        }
+        let level = if level.eq_ignore_ascii_case("off") { None } else { Some(level.parse()?) };
+        set_module_level(module, level)?;
+    }
+    Ok(())
+}
+
+/**
+ * Tells whether a message of the given level from the given module passes the module filters.
+ */
+fn module_enabled(module: &str, level: Level) -> bool {
+    let filters = MODULE_FILTERS.lock();
+    let filter = filters.iter().flatten()
+        .filter(|filter| filter.matches(module))
+        .max_by_key(|filter| filter.len);
+    match filter {
+        Some(filter) => filter.level.map_or(false, |filter_level| level <= filter_level),
+        None => true
+    }
+}
+
+/**
+ * Lets the `log` crate drop the messages no sink wants before they are even formatted.
+ */
+fn update_max_level(sinks: &[SinkEntry; MAX_SINKS]) {
+    let max_level = sinks.iter().flatten().map(|&(_, level)| log::LevelFilter::from(level)).max();
+    log::set_max_level(max_level.unwrap_or(log::LevelFilter::Off));
+}
+
+/**
+ * Routes the messages logged through the `log` crate, by dependencies, to the sinks, tagged with their target.
+ */
+struct LogFacade;
+
+impl log::Log for LogFacade {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            _log(Level::from(record.level()), record.target(), *record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG_FACADE: LogFacade = LogFacade;
+
+/**
+ * Installs the logger behind the `log` crate's macros. Can only be done once.
+ */
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOG_FACADE)?;
+    update_max_level(&SINKS.lock());
+    Ok(())
+}
+
+fn find<'a>(sinks: &'a mut [SinkEntry; MAX_SINKS], name: &str) -> Result<&'a mut SinkEntry, LoggerError> {
+    sinks.iter_mut()
+        .find(|entry| entry.map_or(false, |(sink, _)| sink.name() == name))
+        .ok_or(LoggerError::UnknownSink)
+}
+
+/**
+ * Logs a message with the given level.
+ */
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::logger::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::logger::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::logger::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::logger::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::logger::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::logger::Level::Trace, $($arg)*));
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if !module_enabled(module, level) {
+        return;
+    }
+    // copied, so the sinks run without the lock held
+    let sinks = *SINKS.lock();
+    let record = Record {
+        level,
+        module,
+        timestamp: time::uptime_ms(),
+        args
+    };
+
+    for &(sink, sink_level) in sinks.iter().flatten() {
+        if level <= sink_level {
+            sink.log(&record);
+        }
+    }
+}
@@ -1,24 +1,45 @@
 #![no_std]
 #![no_main]
 
+use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
-use visage::println;
+use pc_keyboard::DecodedKey;
+use visage::{early_println, info, print};
+use visage::interrupts;
+use visage::keyboard;
+use visage::rtc;
+use visage::timer;
 use x86_64;
 
 /* Kernel entry point.
-* Extern "C" for telling the compiler to use the C calling convention (at this time Rust has unspecified calling convention)
-* no_mangle attribute disables the function name mangling, so the linker can find it by default.
+* entry_point! exports it as _start with the C calling convention and no name mangling, so the linker finds it,
+* and checks its signature, which a bare extern "C" fn _start could get wrong.
+* The bootloader passes a BootInfo, telling among others where it mapped the physical memory and the memory map.
 * The ! return type means this is a diverging function: not allowed to ever return.
 * This is required because the entry point is not called by any function, but invoked directly by the bootloader.
 * Instead of returning, shutting down the machine could be a reasonable action, since there's nothing left to do if a freestanding binary returns.
 * For now, we fulfill the requirement by looping endlessly. */
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    println!("visage {}", "0.0.1");
-    visage::init();
-    println!("kernel is running...");
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // nothing is set up yet, only the early console can be used
+    early_println!("visage: entered kernel_main");
+    visage::init(boot_info);
+    match rtc::boot_time() {
+        Some(date) => info!("kernel is running since {}", date),
+        None => info!("kernel is running...")
+    }
     loop {
-        x86_64::instructions::hlt();
+        // echo what is typed
+        while let Some(key) = keyboard::read_key() {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => print!("{:?}", key),
+            }
+        }
+        timer::run_pending();
+        // a key pressed right before the hlt is only noticed at the next interrupt, a timer tick at the latest
+        interrupts::enable_and_hlt();
     }
 }
 
@@ -29,7 +50,7 @@ pub extern "C" fn _start() -> ! {
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
+    visage::panic_screen::show(_info);
     loop {
         x86_64::instructions::hlt();
     }
@@ -0,0 +1,275 @@
+use bootloader::BootInfo;
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use core::fmt;
+use crate::frame_allocator;
+use crate::heap::{self, HeapStats};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+// CPUID: the highest leaves, leaf 7 EBX has SMEP and SMAP, leaf 0x80000001 EDX has NX
+const CPUID_MAX_LEAF: u32 = 0x0;
+const CPUID_EXTENDED_FEATURES: u32 = 0x7;
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+const CPUID_AMD_FEATURES: u32 = 0x8000_0001;
+const CPUID_NX: u32 = 1 << 20;
+
+const KIB: u64 = 1024;
+
+// where the bootloader mapped the whole physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// the bootloader's map of the physical memory, the boot info stays mapped for good
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+/**
+ * The parts of the kernel that take frames from the frame allocator, for stats().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    PageTables,
+    Heap,
+    Slab,
+    Stacks,
+    Buddy,
+    Vmm,
+    // the frames behind address spaces' demand-filled and copy-on-write pages
+    UserPages,
+    Dma
+}
+
+pub const SUBSYSTEMS: usize = 8;
+
+impl Subsystem {
+    pub const ALL: [Subsystem; SUBSYSTEMS] = [
+        Subsystem::PageTables,
+        Subsystem::Heap,
+        Subsystem::Slab,
+        Subsystem::Stacks,
+        Subsystem::Buddy,
+        Subsystem::Vmm,
+        Subsystem::UserPages,
+        Subsystem::Dma
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::PageTables => "page tables",
+            Subsystem::Heap => "heap",
+            Subsystem::Slab => "slab",
+            Subsystem::Stacks => "stacks",
+            Subsystem::Buddy => "buddy",
+            Subsystem::Vmm => "vmm",
+            Subsystem::UserPages => "user pages",
+            Subsystem::Dma => "DMA"
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FRAMES: AtomicU64 = AtomicU64::new(0);
+// the frames each subsystem holds, by Subsystem
+static SUBSYSTEM_FRAMES: [AtomicU64; SUBSYSTEMS] = [NO_FRAMES; SUBSYSTEMS];
+
+/**
+ * Takes the offset the bootloader mapped the physical memory at and its memory map,
+ * before anything reads firmware tables or device registers, or allocates memory.
+ */
+pub fn init(boot_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
+}
+
+/**
+ * Returns the regions of the physical memory, in address order, empty before init().
+ */
+pub fn memory_map() -> &'static [MemoryRegion] {
+    match MEMORY_MAP.r#try() {
+        Some(memory_map) => memory_map,
+        None => &[]
+    }
+}
+
+/**
+ * How much of the physical memory is free, taken at boot, or off limits.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemorySummary {
+    pub usable_bytes: u64,
+    pub usable_regions: usize,
+    // the kernel, its stack, the page tables, the bootloader and the boot info
+    pub in_use_bytes: u64,
+    pub in_use_regions: usize,
+    // the firmware's, ACPI's, bad memory and holes
+    pub reserved_bytes: u64,
+    pub reserved_regions: usize
+}
+
+impl fmt::Display for MemorySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory: {} KiB usable in {} regions, {} KiB in use in {}, {} KiB reserved in {}",
+            self.usable_bytes / KIB, self.usable_regions,
+            self.in_use_bytes / KIB, self.in_use_regions,
+            self.reserved_bytes / KIB, self.reserved_regions)
+    }
+}
+
+/**
+ * Sums up the memory map by what the regions are for.
+ */
+pub fn summary() -> MemorySummary {
+    let mut summary = MemorySummary::default();
+    for region in memory_map() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        let (bytes, regions) = match region.region_type {
+            MemoryRegionType::Empty => continue,
+            MemoryRegionType::Usable => (&mut summary.usable_bytes, &mut summary.usable_regions),
+            MemoryRegionType::InUse
+            | MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::FrameZero
+            | MemoryRegionType::Package => (&mut summary.in_use_bytes, &mut summary.in_use_regions),
+            _ => (&mut summary.reserved_bytes, &mut summary.reserved_regions)
+        };
+        *bytes += size;
+        *regions += 1;
+    }
+    summary
+}
+
+/**
+ * Live memory use, see stats().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    // the frames of memory, the usable ones and those taken at boot, and how many are taken or free
+    pub total_frames: u64,
+    pub used_frames: u64,
+    pub free_frames: u64,
+    pub heap: HeapStats,
+    // the frames each subsystem holds, by Subsystem, the rest of the used ones were taken at boot or by no subsystem
+    pub subsystem_frames: [u64; SUBSYSTEMS]
+}
+
+impl MemoryStats {
+    /**
+     * Returns how much of the memory is taken, in percent.
+     */
+    pub fn used_percent(&self) -> u64 {
+        if self.total_frames == 0 { 0 } else { self.used_frames * 100 / self.total_frames }
+    }
+}
+
+/**
+ * Shows the stats a line each, as a meminfo command would.
+ */
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const FRAME_KIB: u64 = 4;
+        writeln!(f, "total: {} KiB", self.total_frames * FRAME_KIB)?;
+        writeln!(f, "used: {} KiB ({}%)", self.used_frames * FRAME_KIB, self.used_percent())?;
+        writeln!(f, "free: {} KiB", self.free_frames * FRAME_KIB)?;
+        writeln!(f, "heap: {} of {} bytes in use", self.heap.used, self.heap.size)?;
+        for (subsystem, frames) in Subsystem::ALL.iter().zip(self.subsystem_frames.iter()) {
+            writeln!(f, "{}: {} KiB", subsystem.name(), frames * FRAME_KIB)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Returns how much physical memory is taken, how much of the heap, and by which subsystems.
+ */
+pub fn stats() -> MemoryStats {
+    let used_frames = frame_allocator::used_frames();
+    let free_frames = frame_allocator::free_frames();
+    let mut subsystem_frames = [0; SUBSYSTEMS];
+    for (frames, count) in subsystem_frames.iter_mut().zip(SUBSYSTEM_FRAMES.iter()) {
+        *frames = count.load(Ordering::Relaxed);
+    }
+    MemoryStats {
+        total_frames: used_frames + free_frames,
+        used_frames,
+        free_frames,
+        heap: heap::stats(),
+        subsystem_frames
+    }
+}
+
+/**
+ * Counts frames a subsystem took from the frame allocator.
+ */
+pub fn frames_taken(subsystem: Subsystem, frames: u64) {
+    SUBSYSTEM_FRAMES[subsystem as usize].fetch_add(frames, Ordering::Relaxed);
+}
+
+/**
+ * Counts frames a subsystem gave back to the frame allocator.
+ */
+pub fn frames_freed(subsystem: Subsystem, frames: u64) {
+    SUBSYSTEM_FRAMES[subsystem as usize].fetch_sub(frames, Ordering::Relaxed);
+}
+
+/**
+ * Returns the virtual address a physical one can be reached at, through the bootloader's mapping of the physical memory.
+ * It covers everything up to the highest address in the memory map, which includes the usual MMIO ranges below 4 GiB.
+ */
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    VirtAddr::new(address.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/**
+ * Returns the physical address behind one in the bootloader's mapping of the physical memory, see phys_to_virt().
+ */
+pub fn virt_to_phys(address: VirtAddr) -> PhysAddr {
+    PhysAddr::new(address.as_u64() - PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/**
+ * Turns on the paging protections the CPU has: non-executable pages (EFER.NXE), faults on the kernel executing (SMEP)
+ * or touching (SMAP) user pages, and on the kernel writing read-only pages (CR0.WP).
+ * They only matter for pages whose flags ask for them, so this changes nothing for the bootloader's mappings.
+ */
+pub fn enable_protections() -> Protections {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    let max_leaf = unsafe { __cpuid(CPUID_MAX_LEAF) }.eax;
+    let max_extended_leaf = unsafe { __cpuid(CPUID_MAX_EXTENDED_LEAF) }.eax;
+    let extended_features = if max_leaf >= CPUID_EXTENDED_FEATURES {
+        unsafe { __cpuid_count(CPUID_EXTENDED_FEATURES, 0) }.ebx
+    } else {
+        0
+    };
+    let protections = Protections {
+        nx: max_extended_leaf >= CPUID_AMD_FEATURES && unsafe { __cpuid(CPUID_AMD_FEATURES) }.edx & CPUID_NX != 0,
+        smep: extended_features & CPUID_SMEP != 0,
+        smap: extended_features & CPUID_SMAP != 0
+    };
+    unsafe {
+        if protections.nx {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr4::update(|flags| {
+            flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, protections.smep);
+            flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, protections.smap);
+        });
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    protections
+}
+
+/**
+ * The protections enable_protections() could turn on, CR0.WP is there on every CPU.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    pub nx: bool,
+    pub smep: bool,
+    pub smap: bool
+}
@@ -0,0 +1,243 @@
+use crate::i8042::{self, I8042Error};
+use crate::interrupts;
+use crate::sync::IrqMutex;
+
+// mouse commands, each answered with ACK
+const RESET: u8 = 0xFF;
+const SET_DEFAULTS: u8 = 0xF6;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const GET_DEVICE_ID: u8 = 0xF2;
+const ENABLE_REPORTING: u8 = 0xF4;
+const ACK: u8 = 0xFA;
+const SELF_TEST_PASSED: u8 = 0xAA;
+// setting these sample rates in a row turns an IntelliMouse's wheel on, it then reports this ID
+const INTELLIMOUSE_KNOCK: [u8; 3] = [200, 100, 80];
+const INTELLIMOUSE_ID: u8 = 3;
+
+// first packet byte: the buttons, a bit that is always set, then the signs and overflows of the movement
+const LEFT_BUTTON: u8 = 0x01;
+const RIGHT_BUTTON: u8 = 0x02;
+const MIDDLE_BUTTON: u8 = 0x04;
+const ALWAYS_ONE: u8 = 0x08;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+const X_OVERFLOW: u8 = 0x40;
+const Y_OVERFLOW: u8 = 0x80;
+
+// movement counts per text cell
+const COUNTS_PER_CELL: i32 = 8;
+const COLUMNS: i32 = 80;
+const ROWS: i32 = 25;
+// events not read yet, more are dropped
+const EVENT_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    // the PS/2 controller has no second port
+    NoSecondPort,
+    Controller(I8042Error),
+    // the mouse didn't acknowledge a command or failed its self test
+    UnexpectedAnswer(u8)
+}
+
+impl From<I8042Error> for MouseError {
+    fn from(error: I8042Error) -> MouseError {
+        MouseError::Controller(error)
+    }
+}
+
+/**
+ * The buttons held down.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool
+}
+
+/**
+ * A movement packet: how far the mouse moved, right and down being positive, how far the wheel turned
+ * and the buttons held down afterwards.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: Buttons
+}
+
+const NO_EVENT: MouseEvent = MouseEvent {
+    dx: 0,
+    dy: 0,
+    wheel: 0,
+    buttons: Buttons {
+        left: false,
+        right: false,
+        middle: false
+    }
+};
+
+static MOUSE: IrqMutex<Mouse> = IrqMutex::new(Mouse {
+    packet: [0; 4],
+    received: 0,
+    packet_size: 3,
+    x: COLUMNS * COUNTS_PER_CELL / 2,
+    y: ROWS * COUNTS_PER_CELL / 2,
+    buttons: Buttons {
+        left: false,
+        right: false,
+        middle: false
+    },
+    events: [NO_EVENT; EVENT_QUEUE_SIZE],
+    first_event: 0,
+    event_count: 0,
+    dropped: 0
+});
+
+struct Mouse {
+    packet: [u8; 4],
+    // bytes of the current packet received so far
+    received: usize,
+    // 4 with a wheel, 3 otherwise
+    packet_size: usize,
+    // the position in movement counts, kept within the screen
+    x: i32,
+    y: i32,
+    buttons: Buttons,
+    events: [MouseEvent; EVENT_QUEUE_SIZE],
+    first_event: usize,
+    event_count: usize,
+    dropped: usize
+}
+
+impl Mouse {
+    fn add_byte(&mut self, byte: u8) {
+        // a packet starts with the always set bit, anything else means the bytes got out of step
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received == self.packet_size {
+            self.received = 0;
+            self.decode();
+        }
+    }
+
+    fn decode(&mut self) {
+        let [flags, x, y, extra] = self.packet;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return;
+        }
+        let dx = i16::from(x) - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+        // the mouse counts up as it moves away from the user, the screen's rows count down
+        let dy = -(i16::from(y) - if flags & Y_SIGN != 0 { 0x100 } else { 0 });
+        // the wheel movement is a signed 4 bit number
+        let wheel = if self.packet_size == 4 { ((extra << 4) as i8) >> 4 } else { 0 };
+
+        self.buttons = Buttons {
+            left: flags & LEFT_BUTTON != 0,
+            right: flags & RIGHT_BUTTON != 0,
+            middle: flags & MIDDLE_BUTTON != 0
+        };
+        self.x = (self.x + i32::from(dx)).max(0).min(COLUMNS * COUNTS_PER_CELL - 1);
+        self.y = (self.y + i32::from(dy)).max(0).min(ROWS * COUNTS_PER_CELL - 1);
+
+        if self.event_count == EVENT_QUEUE_SIZE {
+            self.dropped += 1;
+            return;
+        }
+        self.events[(self.first_event + self.event_count) % EVENT_QUEUE_SIZE] = MouseEvent {
+            dx,
+            dy,
+            wheel,
+            buttons: self.buttons
+        };
+        self.event_count += 1;
+    }
+}
+
+/**
+ * Resets the mouse on the PS/2 controller's second port, turns its wheel on if it is an IntelliMouse,
+ * and starts its movement reports through IRQ12.
+ * Must run after i8042::init() and with interrupts disabled, the answers are polled for.
+ */
+pub fn init() -> Result<(), MouseError> {
+    if !i8042::has_second_port() {
+        return Err(MouseError::NoSecondPort);
+    }
+    i8042::enable_second_port()?;
+
+    command(RESET)?;
+    match i8042::read_response()? {
+        SELF_TEST_PASSED => {}
+        answer => return Err(MouseError::UnexpectedAnswer(answer))
+    }
+    // the device ID follows the self test
+    i8042::read_response()?;
+    command(SET_DEFAULTS)?;
+
+    for &rate in INTELLIMOUSE_KNOCK.iter() {
+        command(SET_SAMPLE_RATE)?;
+        command(rate)?;
+    }
+    command(GET_DEVICE_ID)?;
+    if i8042::read_response()? == INTELLIMOUSE_ID {
+        MOUSE.lock().packet_size = 4;
+    }
+
+    command(ENABLE_REPORTING)?;
+    i8042::enable_second_port_interrupt()?;
+    interrupts::unmask_irq(interrupts::MOUSE_IRQ);
+    Ok(())
+}
+
+/**
+ * Returns the text cell the mouse points at, as column and row.
+ */
+pub fn position() -> (usize, usize) {
+    let mouse = MOUSE.lock();
+    ((mouse.x / COUNTS_PER_CELL) as usize, (mouse.y / COUNTS_PER_CELL) as usize)
+}
+
+pub fn buttons() -> Buttons {
+    MOUSE.lock().buttons
+}
+
+/**
+ * Returns the oldest movement not read yet, if there is one.
+ */
+pub fn read_event() -> Option<MouseEvent> {
+    let mut mouse = MOUSE.lock();
+    if mouse.event_count == 0 {
+        return None;
+    }
+    let event = mouse.events[mouse.first_event];
+    mouse.first_event = (mouse.first_event + 1) % EVENT_QUEUE_SIZE;
+    mouse.event_count -= 1;
+    Some(event)
+}
+
+/**
+ * Returns how many movements were lost because nobody read them in time.
+ */
+pub fn dropped_events() -> usize {
+    MOUSE.lock().dropped
+}
+
+/**
+ * Takes a byte from the mouse, called by its interrupt handler.
+ */
+pub(crate) fn receive(byte: u8) {
+    MOUSE.lock().add_byte(byte);
+}
+
+fn command(byte: u8) -> Result<(), MouseError> {
+    i8042::send_to_second_port(byte)?;
+    match i8042::read_response()? {
+        ACK => Ok(()),
+        answer => Err(MouseError::UnexpectedAnswer(answer))
+    }
+}
@@ -0,0 +1,158 @@
+use crate::apic;
+use crate::interrupts::{self, Handler, VectorError};
+use crate::paging::{self, PagingError};
+use crate::pci::{self, PciDevice};
+use core::ptr;
+use x86_64::PhysAddr;
+
+// capability IDs
+const MSI_CAPABILITY: u8 = 0x05;
+const MSIX_CAPABILITY: u8 = 0x11;
+
+// MSI capability: message control, then the address, its upper half if it has 64 bits, and the data
+const MSI_CONTROL: u8 = 2;
+const MSI_ADDRESS: u8 = 4;
+const MSI_ADDRESS_HIGH: u8 = 8;
+const MSI_DATA_32: u8 = 8;
+const MSI_DATA_64: u8 = 12;
+const MSI_ENABLE: u16 = 1 << 0;
+// how many vectors the function may use, as a power of two, only one is given out here
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0x7 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+
+// MSI-X capability: message control, then where the vector table is, a BAR and an offset in it
+const MSIX_CONTROL: u8 = 2;
+const MSIX_TABLE: u8 = 4;
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_BAR_MASK: u32 = 0x7;
+// each table entry has the address, its upper half, the data and a mask bit
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 4;
+const MSIX_ENTRY_DATA: usize = 8;
+const MSIX_ENTRY_CONTROL: usize = 12;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// a message is a write to the local APICs' address range, with the destination APIC ID in it, the vector being the data
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+const DESTINATION_SHIFT: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    // the messages go to the local APIC, which is not enabled
+    NoApic,
+    // the function has no MSI or MSI-X capability
+    NotSupported,
+    // the function has fewer MSI-X table entries
+    InvalidEntry(u16),
+    // the MSI-X table is in an I/O BAR
+    InvalidBar,
+    Vector(VectorError),
+    Paging(PagingError)
+}
+
+impl From<VectorError> for MsiError {
+    fn from(error: VectorError) -> MsiError {
+        MsiError::Vector(error)
+    }
+}
+
+impl From<PagingError> for MsiError {
+    fn from(error: PagingError) -> MsiError {
+        MsiError::Paging(error)
+    }
+}
+
+/**
+ * Gives the function a vector running the handler and enables its MSI, which also turns its legacy interrupt line off.
+ * Returns the vector, to be freed with interrupts::free_vector() after disable_msi().
+ */
+pub fn enable_msi(device: &PciDevice, handler: Handler) -> Result<u8, MsiError> {
+    if !apic::is_available() {
+        return Err(MsiError::NoApic);
+    }
+    let capability = device.find_capability(MSI_CAPABILITY).ok_or(MsiError::NotSupported)?;
+    let vector = interrupts::allocate_vector("MSI", handler)?;
+
+    let control = device.read_u16(capability + MSI_CONTROL);
+    device.write_u32(capability + MSI_ADDRESS, message_address());
+    let data = if control & MSI_64_BIT != 0 {
+        device.write_u32(capability + MSI_ADDRESS_HIGH, 0);
+        MSI_DATA_64
+    } else {
+        MSI_DATA_32
+    };
+    device.write_u16(capability + data, u16::from(vector));
+    device.write_u16(capability + MSI_CONTROL, (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE);
+    device.set_command(device.command() | pci::COMMAND_INTERRUPT_DISABLE | pci::COMMAND_BUS_MASTER);
+    Ok(vector)
+}
+
+pub fn disable_msi(device: &PciDevice) -> Result<(), MsiError> {
+    let capability = device.find_capability(MSI_CAPABILITY).ok_or(MsiError::NotSupported)?;
+    let control = device.read_u16(capability + MSI_CONTROL);
+    device.write_u16(capability + MSI_CONTROL, control & !MSI_ENABLE);
+    Ok(())
+}
+
+/**
+ * Gives an entry of the function's MSI-X table a vector running the handler, and enables MSI-X.
+ * Each entry is a separate interrupt source, e.g. a queue of an NVMe or virtio device.
+ * Returns the vector, to be freed with interrupts::free_vector() after the entry is masked.
+ */
+pub fn enable_msix(device: &PciDevice, entry: u16, handler: Handler) -> Result<u8, MsiError> {
+    if !apic::is_available() {
+        return Err(MsiError::NoApic);
+    }
+    let (capability, entry_address) = msix_entry(device, entry)?;
+    let control = device.read_u16(capability + MSIX_CONTROL);
+    let vector = interrupts::allocate_vector("MSI-X", handler)?;
+    // the table is only reachable with memory decoding on, and the function masked while it changes
+    device.set_command(device.command() | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER | pci::COMMAND_INTERRUPT_DISABLE);
+    device.write_u16(capability + MSIX_CONTROL, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+    unsafe {
+        write(entry_address + MSIX_ENTRY_ADDRESS, message_address());
+        write(entry_address + MSIX_ENTRY_ADDRESS_HIGH, 0);
+        write(entry_address + MSIX_ENTRY_DATA, u32::from(vector));
+        write(entry_address + MSIX_ENTRY_CONTROL, 0);
+    }
+    device.write_u16(capability + MSIX_CONTROL, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+    Ok(vector)
+}
+
+/**
+ * Masks an entry of the function's MSI-X table, so it stops raising its vector.
+ */
+pub fn mask_msix(device: &PciDevice, entry: u16) -> Result<(), MsiError> {
+    let (_, entry_address) = msix_entry(device, entry)?;
+    unsafe { write(entry_address + MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED) };
+    Ok(())
+}
+
+/**
+ * Returns the offset of the function's MSI-X capability and the virtual address of an entry of its table.
+ */
+fn msix_entry(device: &PciDevice, entry: u16) -> Result<(u8, usize), MsiError> {
+    let capability = device.find_capability(MSIX_CAPABILITY).ok_or(MsiError::NotSupported)?;
+    if entry > device.read_u16(capability + MSIX_CONTROL) & MSIX_TABLE_SIZE_MASK {
+        return Err(MsiError::InvalidEntry(entry));
+    }
+    let table = device.read_u32(capability + MSIX_TABLE);
+    let bar = device.memory_bar((table & MSIX_BAR_MASK) as u8).ok_or(MsiError::InvalidBar)?;
+    let entry_address = bar + u64::from(table & !MSIX_BAR_MASK) + u64::from(entry) * MSIX_ENTRY_SIZE as u64;
+    let entry_address = paging::map_mmio(PhysAddr::new(entry_address), MSIX_ENTRY_SIZE as u64)?.as_u64();
+    Ok((capability, entry_address as usize))
+}
+
+/**
+ * Returns the address messages are written to so they reach this CPU.
+ */
+fn message_address() -> u32 {
+    MESSAGE_ADDRESS | apic::id() << DESTINATION_SHIFT
+}
+
+unsafe fn write(address: usize, value: u32) {
+    ptr::write_volatile(address as *mut u32, value);
+}
@@ -0,0 +1,370 @@
+use crate::frame_allocator::{self, GlobalFrameAllocator};
+use crate::memory::{self, Subsystem};
+use crate::sync::IrqMutex;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Mapper, MapperAllSizes, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+    PageTableIndex, PhysFrame, Size2MiB, Size4KiB, UnusedPhysFrame};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateError, TranslateResult, UnmapError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    NotInitialized,
+    // a page table was needed, but there was no free frame for it
+    FrameAllocationFailed,
+    PageAlreadyMapped,
+    PageNotMapped,
+    // the page is part of a 2 MiB or 1 GiB page, which can't be changed a 4 KiB page at a time
+    HugePage,
+    InvalidFrameAddress(PhysAddr),
+    // the page is in a level 4 entry an address space shares with the kernel
+    KernelPage
+}
+
+impl From<MapToError> for PagingError {
+    fn from(error: MapToError) -> PagingError {
+        match error {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::HugePage,
+            MapToError::PageAlreadyMapped => PagingError::PageAlreadyMapped
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(error: UnmapError) -> PagingError {
+        match error {
+            UnmapError::ParentEntryHugePage => PagingError::HugePage,
+            UnmapError::PageNotMapped => PagingError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(address) => PagingError::InvalidFrameAddress(address)
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(error: FlagUpdateError) -> PagingError {
+        match error {
+            FlagUpdateError::PageNotMapped => PagingError::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => PagingError::HugePage
+        }
+    }
+}
+
+impl From<TranslateError> for PagingError {
+    fn from(error: TranslateError) -> PagingError {
+        match error {
+            TranslateError::PageNotMapped => PagingError::PageNotMapped,
+            TranslateError::ParentEntryHugePage => PagingError::HugePage,
+            TranslateError::InvalidFrameAddress(address) => PagingError::InvalidFrameAddress(address)
+        }
+    }
+}
+
+// the active page tables, reached through the physical memory mapping
+static MAPPER: IrqMutex<Option<OffsetPageTable<'static>>> = IrqMutex::new(None);
+// the kernel's level 4 table, the one the bootloader left in CR3
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+// how many of the kernel's 4 KiB mappings init() merged into 2 MiB pages
+static KERNEL_HUGE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// the flags the CPU sets by itself, which don't keep mappings from being merged
+const CPU_SET_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::ACCESSED.bits() | PageTableFlags::DIRTY.bits()
+);
+const PAGES_PER_HUGE_PAGE: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+/**
+ * Finds the active level 4 table through CR3, and merges the 4 KiB mappings of the kernel's own part of the address
+ * space into 2 MiB pages wherever 512 of them line up, see promote(). The bootloader already maps the physical memory
+ * with 2 MiB pages. Needs memory::init(), and frame_allocator::init() for mapping pages that need new page tables.
+ */
+pub fn init() {
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    let level_4_address = memory::phys_to_virt(level_4_frame.start_address());
+    let physical_memory_offset = memory::phys_to_virt(PhysAddr::new(0));
+    // the table is only ever reached through MAPPER
+    let level_4_table = unsafe { &mut *level_4_address.as_mut_ptr::<PageTable>() };
+    let mut mapper = MAPPER.lock();
+    *mapper = Some(unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) });
+
+    // the kernel's code, data and stack are all in the level 4 entry this function is in
+    let kernel = VirtAddr::new(init as *const () as u64);
+    let first = Page::from_page_table_indices_2mib(kernel.p4_index(), PageTableIndex::new(0), PageTableIndex::new(0));
+    let mut merged = 0;
+    for page in Page::range(first, first + 512 * 512) {
+        if let Some(entry) = level_2_entry(page) {
+            if merge(entry) {
+                merged += 1;
+            }
+        }
+    }
+    tlb::flush_all();
+    KERNEL_HUGE_PAGES.store(merged, Ordering::Relaxed);
+}
+
+/**
+ * Returns the frame of the kernel's level 4 table, which MAPPER changes, None before init().
+ */
+pub fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
+
+/**
+ * Returns how many 2 MiB pages init() merged the kernel's mappings into.
+ */
+pub fn kernel_huge_pages() -> usize {
+    KERNEL_HUGE_PAGES.load(Ordering::Relaxed)
+}
+
+/**
+ * Maps a page to a frame and flushes it from the TLB.
+ *
+ * # Safety
+ *
+ * The frame must not be in use elsewhere, e.g. one from frame_allocator::allocate_frame(), or a device's registers.
+ */
+pub unsafe fn map_to(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    mapper.map_to(page, UnusedPhysFrame::new(frame), flags, &mut GlobalFrameAllocator)?.flush();
+    Ok(())
+}
+
+/**
+ * Removes a page's mapping and flushes it from the TLB. Returns the frame it was mapped to, which is not freed.
+ */
+pub fn unmap(page: Page) -> Result<PhysFrame, PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/**
+ * Maps a 2 MiB page to a 2 MiB frame and flushes it from the TLB.
+ *
+ * # Safety
+ *
+ * The frame must not be in use elsewhere, e.g. one from frame_allocator::allocate_huge_frame().
+ */
+pub unsafe fn map_huge(page: Page<Size2MiB>, frame: PhysFrame<Size2MiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    mapper.map_to(page, UnusedPhysFrame::new(frame), flags, &mut GlobalFrameAllocator)?.flush();
+    Ok(())
+}
+
+pub fn unmap_huge(page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/**
+ * Turns a 2 MiB page into 512 4 KiB ones with the same flags, mapped to the same memory, so they can be
+ * changed one at a time. Takes a frame for their page table.
+ */
+pub fn split_huge_page(page: Page<Size2MiB>) -> Result<(), PagingError> {
+    let mapper = MAPPER.lock();
+    if mapper.is_none() {
+        return Err(PagingError::NotInitialized);
+    }
+    let entry = level_2_entry(page).ok_or(PagingError::PageNotMapped)?;
+    split(entry)?;
+    tlb::flush(page.start_address());
+    Ok(())
+}
+
+/**
+ * Turns the 512 4 KiB pages of a 2 MiB page back into one, if they map a 2 MiB frame in order with the same flags.
+ * Frees their page table. Returns whether they did.
+ */
+pub fn promote(page: Page<Size2MiB>) -> Result<bool, PagingError> {
+    let mapper = MAPPER.lock();
+    if mapper.is_none() {
+        return Err(PagingError::NotInitialized);
+    }
+    let merged = level_2_entry(page).map_or(false, merge);
+    if merged {
+        let first = Page::<Size4KiB>::containing_address(page.start_address());
+        for small in Page::range(first, first + PAGES_PER_HUGE_PAGE) {
+            tlb::flush(small.start_address());
+        }
+    }
+    Ok(merged)
+}
+
+/**
+ * Changes the flags of a mapped page and flushes it from the TLB.
+ */
+pub fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    mapper.update_flags(page, flags)?.flush();
+    Ok(())
+}
+
+/**
+ * Returns the physical address a virtual one is mapped to, whatever the size of its page.
+ */
+pub fn translate_addr(address: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref()?.translate_addr(address)
+}
+
+/**
+ * Makes a device's registers reachable, uncached, at the address memory::phys_to_virt() gives for them,
+ * mapping the pages the bootloader's physical memory mapping does not cover. Returns that address.
+ * Registers inside a 2 MiB page of that mapping get it split, inside a 1 GiB one they are left as they are.
+ */
+pub fn map_mmio(address: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+    set_cache_mode(address, size, CacheMode::Uncached)
+}
+
+/**
+ * How the CPU caches a page, through its PWT and PCD bits with the default PAT.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncached
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+        }
+    }
+}
+
+/**
+ * Sets how physical memory is cached at the address memory::phys_to_virt() gives for it, like map_mmio(),
+ * and returns that address. Lines already in the caches are not written back.
+ */
+pub fn set_cache_mode(address: PhysAddr, size: u64, mode: CacheMode) -> Result<VirtAddr, PagingError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | mode.flags();
+    let start = PhysFrame::<Size4KiB>::containing_address(address);
+    let end = PhysFrame::<Size4KiB>::containing_address(address + size.max(1) - 1u64);
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+    for frame in PhysFrame::range_inclusive(start, end) {
+        let page = Page::<Size4KiB>::containing_address(memory::phys_to_virt(frame.start_address()));
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Frame4KiB { .. } => mapper.update_flags(page, flags)?.flush(),
+            // the registers get 4 KiB pages of their own, the rest of the 2 MiB stays as it was
+            TranslateResult::Frame2MiB { .. } => {
+                let entry = level_2_entry(Page::containing_address(page.start_address())).ok_or(PagingError::PageNotMapped)?;
+                split(entry)?;
+                tlb::flush(page.start_address());
+                mapper.update_flags(page, flags)?.flush();
+            }
+            TranslateResult::Frame1GiB { .. } => {}
+            TranslateResult::PageNotMapped => unsafe {
+                mapper.map_to(page, UnusedPhysFrame::new(frame), flags, &mut GlobalFrameAllocator)?.flush()
+            },
+            TranslateResult::InvalidFrameAddress(address) => return Err(PagingError::InvalidFrameAddress(address))
+        }
+    }
+    Ok(memory::phys_to_virt(address))
+}
+
+
+/**
+ * Returns the level 2 entry of a 2 MiB page, whether it maps the page or points to a page table, or None if there is
+ * no level 2 table for it. MAPPER must be locked, nothing else changes the tables.
+ */
+fn level_2_entry(page: Page<Size2MiB>) -> Option<&'static mut PageTableEntry> {
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_entry = &table(level_4_frame.start_address())[page.p4_index()];
+    if level_4_entry.is_unused() {
+        return None;
+    }
+    let level_3_entry = &table(level_4_entry.addr())[page.p3_index()];
+    if level_3_entry.is_unused() || level_3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    Some(&mut table(level_3_entry.addr())[page.p2_index()])
+}
+
+/**
+ * Points a level 2 entry mapping a 2 MiB page to a new page table mapping the same memory 4 KiB at a time.
+ * The TLB still needs flushing.
+ */
+fn split(entry: &mut PageTableEntry) -> Result<(), PagingError> {
+    let flags = entry.flags();
+    if entry.is_unused() || !flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(PagingError::PageNotMapped);
+    }
+    // the 2 MiB entry's PAT bit is in the address bits, and never set here
+    let start = entry.addr().align_down(Size2MiB::SIZE);
+    let frame = frame_allocator::allocate_frame().ok_or(PagingError::FrameAllocationFailed)?;
+    memory::frames_taken(Subsystem::PageTables, 1);
+    let small_flags = flags & !PageTableFlags::HUGE_PAGE;
+    let small_pages = table(frame.start_address());
+    for (index, small) in small_pages.iter_mut().enumerate() {
+        small.set_addr(start + index as u64 * Size4KiB::SIZE, small_flags);
+    }
+    // the 4 KiB entries have the restrictions, the table's entry only passes on what they may allow
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_frame(frame, table_flags);
+    Ok(())
+}
+
+/**
+ * Points a level 2 entry straight at the 2 MiB frame its page table maps, if it maps all of one in order with the
+ * same flags, and frees the table. The TLB still needs flushing.
+ */
+fn merge(entry: &mut PageTableEntry) -> bool {
+    let table_flags = entry.flags();
+    if entry.is_unused() || table_flags.contains(PageTableFlags::HUGE_PAGE) {
+        return false;
+    }
+    let small_pages = table(entry.addr());
+    let first = &small_pages[0];
+    let start = first.addr();
+    // bit 7 of a 4 KiB entry is PAT rather than the huge page flag
+    let flags = first.flags() - CPU_SET_FLAGS;
+    if first.is_unused() || !start.is_aligned(Size2MiB::SIZE) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return false;
+    }
+    let in_order = small_pages.iter().enumerate().all(|(index, small)| {
+        !small.is_unused() && small.addr() == start + index as u64 * Size4KiB::SIZE && small.flags() - CPU_SET_FLAGS == flags
+    });
+    if !in_order {
+        return false;
+    }
+
+    // a huge page only has its own flags, so it takes the restrictions the table's entry added
+    let mut huge_flags = flags | PageTableFlags::HUGE_PAGE;
+    if !table_flags.contains(PageTableFlags::WRITABLE) {
+        huge_flags.remove(PageTableFlags::WRITABLE);
+    }
+    if !table_flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        huge_flags.remove(PageTableFlags::USER_ACCESSIBLE);
+    }
+    huge_flags |= table_flags & PageTableFlags::NO_EXECUTE;
+    let table_frame = PhysFrame::containing_address(entry.addr());
+    entry.set_addr(start, huge_flags);
+    // the bootloader's tables go to the allocator too, nothing else points at them
+    if frame_allocator::free_frame(table_frame).is_ok() {
+        memory::frames_freed(Subsystem::PageTables, 1);
+    }
+    true
+}
+
+fn table(address: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(address).as_mut_ptr::<PageTable>() }
+}
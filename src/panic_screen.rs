@@ -0,0 +1,73 @@
+use crate::debugcon::DebugCon;
+use crate::sync::IrqMutex;
+use crate::vga_buffer::{ColorCode, Colors, PanicWriter};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::rflags;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+
+// with the `blink` feature the white background shows up as light gray, under blinking text
+const PANIC_COLOR: ColorCode = ColorCode::new(Colors::Red, Colors::White);
+
+/**
+ * The exception that is about to bring the kernel down, saved by its handler right before it panics,
+ * so the panic screen can show where the CPU was.
+ */
+static EXCEPTION: IrqMutex<Option<Exception>> = IrqMutex::new(None);
+
+struct Exception {
+    name: &'static str,
+    frame: InterruptStackFrameValue,
+    error_code: Option<u64>
+}
+
+/**
+ * Saves the state of a fatal exception for the panic screen. Call it from the handler before panicking.
+ */
+pub fn record_exception(name: &'static str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    *EXCEPTION.lock() = Some(Exception {
+        name,
+        frame: (*stack_frame).clone(),
+        error_code
+    });
+}
+
+/**
+ * Takes over the display and shows everything we know about the panic:
+ * the panic message, the exception stack frame if the panic comes from an exception handler,
+ * and the control registers most useful for figuring out what went wrong.
+ * Interrupts are turned off for good, nothing else may draw on the screen after this.
+ * No lock is waited for, so the panic is shown even if it hit while the console was locked.
+ */
+pub fn show(info: &PanicInfo) {
+    x86_64::instructions::interrupts::disable();
+    // before anything else, in case drawing the screen goes wrong too
+    let _ = writeln!(DebugCon, "KERNEL PANIC: {}", info);
+
+    let mut writer = unsafe { PanicWriter::new(PANIC_COLOR) };
+    writer.clear();
+    writer.hide_cursor();
+
+    let _ = writeln!(writer, " KERNEL PANIC\n");
+    let _ = writeln!(writer, " {}\n", info);
+
+    // a panic inside record_exception() would leave it locked
+    let exception = EXCEPTION.try_lock();
+    if let Some(exception) = exception.as_ref().and_then(|exception| exception.as_ref()) {
+        match exception.error_code {
+            Some(error_code) => {
+                let _ = writeln!(writer, " Exception: {} (error code {:#x})", exception.name, error_code);
+            }
+            None => {
+                let _ = writeln!(writer, " Exception: {}", exception.name);
+            }
+        }
+        let _ = writeln!(writer, " {:#?}\n", exception.frame);
+    }
+
+    let (level_4_table, _) = Cr3::read();
+    let _ = writeln!(writer, " CR2:    {:#018x}", Cr2::read().as_u64());
+    let _ = writeln!(writer, " CR3:    {:#018x}", level_4_table.start_address().as_u64());
+    let _ = writeln!(writer, " RFLAGS: {:?}", rflags::read());
+}
@@ -0,0 +1,177 @@
+use crate::sync::IrqMutex;
+use x86_64::instructions::port::Port;
+
+// configuration mechanism 1: the address of a configuration dword goes out here, then the dword is read or written there
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// configuration header fields
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const HEADER_TYPE: u8 = 0x0E;
+const BAR_0: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+// no device answers at an address with this vendor
+const NO_VENDOR: u16 = 0xFFFF;
+// header type: the device has more than one function
+const MULTI_FUNCTION: u8 = 0x80;
+// status: there is a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+// the low two bits of the capability pointers are reserved
+const CAPABILITY_POINTER_MASK: u8 = 0xFC;
+
+// command bits
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+// keeps the device from raising its legacy interrupt line, for when it uses MSI
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+// BAR bits: I/O space, and for memory ones whether they take two BARs for a 64 bit address
+const BAR_IO_SPACE: u32 = 0x1;
+const BAR_TYPE_MASK: u32 = 0x6;
+const BAR_TYPE_64: u32 = 0x4;
+const BAR_MEMORY_MASK: u32 = !0xF;
+
+const BUSES: u16 = 256;
+const DEVICES: u8 = 32;
+const FUNCTIONS: u8 = 8;
+
+// serializes the address and data port pairs
+static CONFIG: IrqMutex<()> = IrqMutex::new(());
+
+/**
+ * A function of a device on the PCI bus, addressed by its location.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8
+}
+
+impl PciDevice {
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        // the status half of the dword is cleared by writing ones, so it is written as 0
+        self.write_u32(COMMAND, u32::from(command));
+    }
+
+    /**
+     * Returns the physical address of a memory BAR, None for an I/O one.
+     */
+    pub fn memory_bar(&self, index: u8) -> Option<u64> {
+        let register = BAR_0 + index * 4;
+        let bar = self.read_u32(register);
+        if bar & BAR_IO_SPACE != 0 {
+            return None;
+        }
+        let mut address = u64::from(bar & BAR_MEMORY_MASK);
+        if bar & BAR_TYPE_MASK == BAR_TYPE_64 {
+            address |= u64::from(self.read_u32(register + 4)) << 32;
+        }
+        Some(address)
+    }
+
+    /**
+     * Returns the configuration space offset of the capability with the given ID, e.g. MSI's.
+     */
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return None;
+        }
+        let mut pointer = self.read_u8(CAPABILITIES_POINTER) & CAPABILITY_POINTER_MASK;
+        // a broken list could loop, there is room for at most 48 capabilities
+        for _ in 0..48 {
+            if pointer == 0 {
+                return None;
+            }
+            if self.read_u8(pointer) == id {
+                return Some(pointer);
+            }
+            pointer = self.read_u8(pointer + 1) & CAPABILITY_POINTER_MASK;
+        }
+        None
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let _config = CONFIG.lock();
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA_PORT);
+        unsafe {
+            address.write(self.address(offset));
+            data.read()
+        }
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset & !3) & !(0xFFFF << shift);
+        self.write_u32(offset & !3, dword | u32::from(value) << shift);
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let _config = CONFIG.lock();
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA_PORT);
+        unsafe {
+            address.write(self.address(offset));
+            data.write(value);
+        }
+    }
+
+    fn address(&self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & !3)
+    }
+}
+
+/**
+ * Scans the buses for the first function with the given vendor and device IDs.
+ */
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    for bus in 0..BUSES {
+        for device in 0..DEVICES {
+            let first = PciDevice {
+                bus: bus as u8,
+                device,
+                function: 0
+            };
+            if first.vendor_id() == NO_VENDOR {
+                continue;
+            }
+            let functions = if first.read_u8(HEADER_TYPE) & MULTI_FUNCTION != 0 { FUNCTIONS } else { 1 };
+            for function in 0..functions {
+                let candidate = PciDevice { function, ..first };
+                if candidate.vendor_id() == vendor_id && candidate.device_id() == device_id {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+    None
+}
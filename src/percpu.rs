@@ -0,0 +1,147 @@
+use crate::gdt::{self, GdtError, MAX_CPUS};
+use crate::user;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/**
+ * A CPU's block, which its GS base points at while it runs the kernel. Assembly reaches the fields
+ * at fixed offsets from GS: the CPU's index at 0, the kernel stack at 8, and the user stack during a system call at 16.
+ */
+#[repr(C)]
+pub(crate) struct CpuBlock {
+    cpu: AtomicU64,
+    pub(crate) kernel_stack: AtomicU64,
+    user_stack: AtomicU64
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BLOCK: CpuBlock = CpuBlock {
+    cpu: AtomicU64::new(0),
+    kernel_stack: AtomicU64::new(0),
+    user_stack: AtomicU64::new(0)
+};
+static BLOCKS: [CpuBlock; MAX_CPUS] = [NO_BLOCK; MAX_CPUS];
+// GS is only worth reading once the boot CPU set it up, before that everything runs on CPU 0
+static READY: AtomicBool = AtomicBool::new(false);
+
+/**
+ * A variable with a value for each CPU, see per_cpu!. Each CPU reaches its own with get().
+ */
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS]
+}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpu<T> {
+        PerCpu { values }
+    }
+
+    /**
+     * Returns the calling CPU's value. Another CPU may reach it through get_for(), so it is shared like a static.
+     */
+    pub fn get(&self) -> &T {
+        &self.values[current()]
+    }
+
+    pub fn get_for(&self, cpu: usize) -> Option<&T> {
+        self.values.get(cpu)
+    }
+}
+
+/**
+ * Declares a static with a value for each CPU, each starting as the given constant:
+ * `per_cpu!(static COUNTER: AtomicU64 = AtomicU64::new(0));`, read with `this_cpu!(COUNTER)`.
+ */
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $visibility:vis static $name:ident: $type:ty = $init:expr) => {
+        $(#[$attr])*
+        $visibility static $name: $crate::percpu::PerCpu<$type> = {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: $type = $init;
+            $crate::percpu::PerCpu::new([INIT; $crate::gdt::MAX_CPUS])
+        };
+    };
+}
+
+/**
+ * Returns the calling CPU's value of a static declared with per_cpu!.
+ */
+#[macro_export]
+macro_rules! this_cpu {
+    ($name:path) => {
+        $crate::percpu::PerCpu::get(&$name)
+    };
+}
+
+/**
+ * Points the calling CPU's GS base at its block, and the kernel GS base, which swapgs swaps in, at nothing for user code.
+ * Needs gdt::init() on this CPU first.
+ */
+pub fn init() -> Result<(), GdtError> {
+    let cpu = gdt::cpu_index()?;
+    let block = &BLOCKS[cpu];
+    block.cpu.store(cpu as u64, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(block));
+    KernelGsBase::write(VirtAddr::new(0));
+    READY.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/**
+ * Returns the calling CPU's index in the per-CPU tables, read through GS.
+ */
+pub fn current() -> usize {
+    if !READY.load(Ordering::Relaxed) {
+        return 0;
+    }
+    unsafe { percpu_cpu() as usize }
+}
+
+/**
+ * Returns the calling CPU's block, for the code setting up what the assembly finds in it.
+ */
+pub(crate) fn block() -> &'static CpuBlock {
+    &BLOCKS[current()]
+}
+
+/**
+ * Makes GS point at the CPU's block in an interrupt handler, which it doesn't if the interrupt came from user code:
+ * swapgs then, and once more when the guard is dropped, before the handler returns.
+ */
+pub struct InterruptGs {
+    swapped: bool
+}
+
+impl InterruptGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> InterruptGs {
+        let swapped = user::from_user(stack_frame);
+        if swapped {
+            unsafe { x86_64::instructions::segmentation::swap_gs() };
+        }
+        InterruptGs { swapped }
+    }
+}
+
+impl Drop for InterruptGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { x86_64::instructions::segmentation::swap_gs() };
+        }
+    }
+}
+
+extern "C" {
+    fn percpu_cpu() -> u64;
+}
+
+global_asm!("
+.att_syntax prefix
+.global percpu_cpu
+percpu_cpu:
+    movq %gs:0, %rax
+    ret
+");
@@ -0,0 +1,175 @@
+use crate::sync::IrqMutex;
+use crate::wait_queue::WaitQueue;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/**
+ * How many bytes a pipe holds before writers block.
+ */
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    // every read end is closed, no one would read what is written
+    BrokenPipe
+}
+
+/**
+ * The bytes in a pipe, in a ring.
+ */
+struct Ring {
+    bytes: [u8; PIPE_CAPACITY],
+    // where the oldest byte is
+    start: usize,
+    length: usize
+}
+
+impl Ring {
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let count = buffer.len().min(self.length);
+        for byte in buffer[..count].iter_mut() {
+            *byte = self.bytes[self.start];
+            self.start = (self.start + 1) % PIPE_CAPACITY;
+        }
+        self.length -= count;
+        count
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> usize {
+        let count = buffer.len().min(PIPE_CAPACITY - self.length);
+        for &byte in buffer[..count].iter() {
+            self.bytes[(self.start + self.length) % PIPE_CAPACITY] = byte;
+            self.length += 1;
+        }
+        count
+    }
+}
+
+struct Pipe {
+    ring: IrqMutex<Ring>,
+    // the open ends of each kind, a reader sees the end of the data once there are no writers
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    // waiting for bytes, and for room
+    read_waiters: WaitQueue,
+    write_waiters: WaitQueue
+}
+
+/**
+ * The read end of a pipe. Clones are more read ends of the same pipe, e.g. for a fork()ed child.
+ */
+pub struct PipeReader {
+    pipe: Arc<Pipe>
+}
+
+/**
+ * The write end of a pipe. Clones are more write ends of the same pipe.
+ */
+pub struct PipeWriter {
+    pipe: Arc<Pipe>
+}
+
+/**
+ * Makes a pipe, returns its ends: what is written to the one is read from the other, in order.
+ * Both block, the reader while the pipe is empty, the writer while it is full.
+ */
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        ring: IrqMutex::new(Ring {
+            bytes: [0; PIPE_CAPACITY],
+            start: 0,
+            length: 0
+        }),
+        readers: AtomicUsize::new(1),
+        writers: AtomicUsize::new(1),
+        read_waiters: WaitQueue::new(),
+        write_waiters: WaitQueue::new()
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /**
+     * Reads what is in the pipe, up to the buffer's length, waiting until there is something.
+     * Returns how many bytes it read, 0 at the end of the data: the pipe is empty and every write end closed.
+     */
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        let pipe = &self.pipe;
+        let mut count = 0;
+        pipe.read_waiters.wait_until(|| {
+            count = pipe.ring.lock().read(buffer);
+            count > 0 || pipe.writers.load(Ordering::Acquire) == 0
+        });
+        if count > 0 {
+            pipe.write_waiters.wake_all();
+        }
+        count
+    }
+}
+
+impl PipeWriter {
+    /**
+     * Writes the whole buffer into the pipe, waiting for room as often as it takes.
+     * Fails with BrokenPipe once every read end is closed, what was written before may not be read.
+     */
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, PipeError> {
+        let pipe = &self.pipe;
+        let mut written = 0;
+        while written < buffer.len() {
+            let before = written;
+            let mut broken = false;
+            // back as soon as some of it is in, for the readers to take
+            pipe.write_waiters.wait_until(|| {
+                broken = pipe.readers.load(Ordering::Acquire) == 0;
+                if !broken {
+                    written += pipe.ring.lock().write(&buffer[written..]);
+                }
+                broken || written > before
+            });
+            if broken {
+                return Err(PipeError::BrokenPipe);
+            }
+            pipe.read_waiters.wake_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> PipeReader {
+        self.pipe.readers.fetch_add(1, Ordering::AcqRel);
+        PipeReader { pipe: self.pipe.clone() }
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> PipeWriter {
+        self.pipe.writers.fetch_add(1, Ordering::AcqRel);
+        PipeWriter { pipe: self.pipe.clone() }
+    }
+}
+
+/**
+ * Closes the read end, writers fail with BrokenPipe once the last is closed.
+ */
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        if self.pipe.readers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.pipe.write_waiters.wake_all();
+        }
+    }
+}
+
+/**
+ * Closes the write end, readers see the end of the data once the last is closed.
+ */
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        if self.pipe.writers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.pipe.read_waiters.wake_all();
+        }
+    }
+}
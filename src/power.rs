@@ -0,0 +1,32 @@
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+
+// the PS/2 controller's command port, and the command that pulses the CPU's reset line
+const CONTROLLER_COMMAND_PORT: u16 = 0x64;
+const INPUT_BUFFER_FULL: u8 = 0x02;
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+/**
+ * Restarts the machine through the keyboard controller's reset line.
+ * Where there is none, the CPU is reset with a triple fault: any exception with an empty IDT does it.
+ */
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let mut controller: Port<u8> = Port::new(CONTROLLER_COMMAND_PORT);
+    unsafe {
+        while controller.read() & INPUT_BUFFER_FULL != 0 {}
+        controller.write(PULSE_RESET_LINE);
+
+        let empty_idt = DescriptorTablePointer {
+            limit: 0,
+            base: 0
+        };
+        x86_64::instructions::tables::lidt(&empty_idt);
+    }
+    x86_64::instructions::interrupts::int3();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
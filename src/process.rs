@@ -0,0 +1,478 @@
+use crate::address_space::AddressSpace;
+use crate::elf::{self, ElfError, Image};
+use crate::ipc;
+use crate::paging::PagingError;
+use crate::pipe::{PipeReader, PipeWriter};
+use crate::scheduler::{self, SchedulerError, ThreadId, ThreadState};
+use crate::signal::SignalState;
+use crate::sync::IrqMutex;
+use crate::syscall_abi::SIGCHLD;
+use crate::vfs::File;
+use crate::wait_queue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use lazy_static::lazy_static;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub fn new(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    // there is no process with the given PID
+    NoSuchProcess,
+    // the process has exited, it gets no new threads
+    Exited,
+    // exec() while other threads of the process still run, in the address space it would replace
+    Busy,
+    // the calling thread is a kernel thread, it has no process
+    NotAProcess,
+    // wait() for a process that has no such children
+    NoChildren,
+    Elf(ElfError),
+    Paging(PagingError),
+    Scheduler(SchedulerError)
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(error: ElfError) -> ProcessError {
+        ProcessError::Elf(error)
+    }
+}
+
+impl From<PagingError> for ProcessError {
+    fn from(error: PagingError) -> ProcessError {
+        ProcessError::Paging(error)
+    }
+}
+
+impl From<SchedulerError> for ProcessError {
+    fn from(error: SchedulerError) -> ProcessError {
+        ProcessError::Scheduler(error)
+    }
+}
+
+// the most descriptors a process has open
+const MAX_FILES: usize = 64;
+// standard input, output and error
+const STANDARD_FILES: usize = 3;
+
+/**
+ * What a file descriptor refers to.
+ */
+#[derive(Clone)]
+pub enum Descriptor {
+    // the screen, write only
+    Console,
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    // a file open through the VFS
+    File(Arc<dyn File>)
+}
+
+/**
+ * The open files of a process, by file descriptor. A clone shares the files, e.g. for a fork()ed child.
+ */
+#[derive(Clone, Default)]
+pub struct FileTable {
+    descriptors: Vec<Option<Descriptor>>
+}
+
+impl FileTable {
+    pub fn new() -> FileTable {
+        FileTable {
+            descriptors: Vec::new()
+        }
+    }
+
+    /**
+     * Makes a table with standard input, output and error on the console.
+     */
+    pub fn with_console() -> FileTable {
+        FileTable {
+            descriptors: vec![Some(Descriptor::Console); STANDARD_FILES]
+        }
+    }
+
+    /**
+     * Opens a descriptor on the lowest free file descriptor, returns it, None if there are MAX_FILES open already.
+     */
+    pub fn insert(&mut self, descriptor: Descriptor) -> Option<usize> {
+        match self.descriptors.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.descriptors[fd] = Some(descriptor);
+                Some(fd)
+            }
+            None if self.descriptors.len() < MAX_FILES => {
+                self.descriptors.push(Some(descriptor));
+                Some(self.descriptors.len() - 1)
+            }
+            None => None
+        }
+    }
+
+    /**
+     * Opens a descriptor on the given file descriptor, closing what was open on it.
+     * Returns what was, or Err with the descriptor back if the file descriptor is MAX_FILES or above.
+     */
+    pub fn insert_at(&mut self, fd: usize, descriptor: Descriptor) -> Result<Option<Descriptor>, Descriptor> {
+        if fd >= MAX_FILES {
+            return Err(descriptor);
+        }
+        if self.descriptors.len() <= fd {
+            self.descriptors.resize(fd + 1, None);
+        }
+        Ok(self.descriptors[fd].replace(descriptor))
+    }
+
+    pub fn get(&self, fd: usize) -> Option<&Descriptor> {
+        self.descriptors.get(fd)?.as_ref()
+    }
+
+    /**
+     * Closes a file descriptor, returns what was open on it.
+     */
+    pub fn remove(&mut self, fd: usize) -> Option<Descriptor> {
+        self.descriptors.get_mut(fd)?.take()
+    }
+}
+
+/**
+ * A program and everything it owns: an address space of its own, its threads, which all run in it,
+ * its open files, its signals, and once it exited, its exit status. An exited process is a zombie, with nothing but its exit
+ * status left, until its parent wait()s for it.
+ */
+pub struct Process {
+    pid: Pid,
+    // None for the processes the kernel started, and the orphans of exited ones
+    parent: Option<Pid>,
+    name: String,
+    // None once it exited
+    address_space: Option<AddressSpace>,
+    threads: Vec<ThreadId>,
+    files: FileTable,
+    signals: SignalState,
+    // None while it runs
+    exit_status: Option<i32>
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /**
+     * Returns the process' address space, None once it exited.
+     */
+    pub fn address_space(&mut self) -> Option<&mut AddressSpace> {
+        self.address_space.as_mut()
+    }
+
+    pub fn threads(&self) -> &[ThreadId] {
+        &self.threads
+    }
+
+    pub fn files(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+
+    pub fn signals(&mut self) -> &mut SignalState {
+        &mut self.signals
+    }
+
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+}
+
+/**
+ * What processes() tells about a process.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub threads: usize,
+    pub exit_status: Option<i32>
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Box<Process>>,
+    next_pid: u64
+}
+
+// woken whenever a process exits, for the parents waiting on their children
+static EXITED: WaitQueue = WaitQueue::new();
+
+lazy_static! {
+    static ref PROCESSES: IrqMutex<ProcessTable> = IrqMutex::new(ProcessTable {
+        processes: BTreeMap::new(),
+        // PID 0 is no process, the kernel itself
+        next_pid: 1
+    });
+}
+
+/**
+ * Makes a process with an empty address space, but for the kernel's mappings, and no threads yet.
+ * Its standard input, output and error are on the console.
+ */
+pub fn create(name: &str, parent: Option<Pid>) -> Result<Pid, ProcessError> {
+    let address_space = AddressSpace::new()?;
+    let mut table = PROCESSES.lock();
+    if let Some(parent) = parent {
+        if !table.processes.contains_key(&parent) {
+            return Err(ProcessError::NoSuchProcess);
+        }
+    }
+    let pid = Pid(table.next_pid);
+    table.next_pid += 1;
+    table.processes.insert(pid, Box::new(Process {
+        pid,
+        parent,
+        name: String::from(name),
+        address_space: Some(address_space),
+        threads: Vec::new(),
+        files: FileTable::with_console(),
+        signals: SignalState::new(),
+        exit_status: None
+    }));
+    Ok(pid)
+}
+
+/**
+ * Makes a child of a process, with the same name, a copy-on-write copy of its address space, see
+ * AddressSpace::fork(), its open files and signal actions, but no threads yet.
+ */
+pub fn fork(pid: Pid) -> Result<Pid, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let parent = table.processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    if parent.exit_status.is_some() {
+        return Err(ProcessError::Exited);
+    }
+    let address_space = parent.address_space.as_mut().ok_or(ProcessError::Exited)?.fork()?;
+    let name = parent.name.clone();
+    let files = parent.files.clone();
+    let signals = parent.signals.fork();
+    let child = Pid(table.next_pid);
+    table.next_pid += 1;
+    table.processes.insert(child, Box::new(Process {
+        pid: child,
+        parent: Some(pid),
+        name,
+        address_space: Some(address_space),
+        threads: Vec::new(),
+        files,
+        signals,
+        exit_status: None
+    }));
+    Ok(child)
+}
+
+/**
+ * Replaces the program of the calling thread's process with an ELF executable, loaded into a new address space,
+ * which the thread moves to. The old one is freed, there is no going back to it once this succeeded.
+ * The process is renamed after the program, its signal handlers go back to the default actions. Returns where to start it, see user::push_arguments() for its stack.
+ */
+pub fn exec(name: &str, file: &[u8]) -> Result<Image, ProcessError> {
+    let thread = scheduler::current().ok_or(ProcessError::NotAProcess)?;
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    let mut space = AddressSpace::new()?;
+    let image = elf::load(&mut space, file)?;
+    let level_4 = space.level_4_frame();
+    let old = {
+        let mut table = PROCESSES.lock();
+        let process = table.processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        let others = process.threads.iter().any(|&other| {
+            other != thread && scheduler::state(other).map_or(false, |state| state != ThreadState::Exited)
+        });
+        if others {
+            return Err(ProcessError::Busy);
+        }
+        if process.exit_status.is_some() {
+            return Err(ProcessError::Exited);
+        }
+        process.name = String::from(name);
+        process.signals.exec();
+        process.address_space.replace(space)
+    };
+    scheduler::set_address_space(Some(level_4));
+    // not active anymore, freed outside the table's lock
+    drop(old);
+    Ok(image)
+}
+
+/**
+ * Starts a thread of the process, running entry(argument) in its address space.
+ */
+pub fn spawn_thread(pid: Pid, entry: fn(usize), argument: usize) -> Result<ThreadId, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let process = table.processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    if process.exit_status.is_some() {
+        return Err(ProcessError::Exited);
+    }
+    let level_4 = process.address_space.as_ref().ok_or(ProcessError::Exited)?.level_4_frame();
+    let thread = scheduler::spawn_in(level_4, entry, argument)?;
+    process.threads.push(thread);
+    Ok(thread)
+}
+
+/**
+ * Runs the closure on a process, returns what it returned, None if there is no such process.
+ * The process table is locked meanwhile.
+ */
+pub fn with<R, F: FnOnce(&mut Process) -> R>(pid: Pid, f: F) -> Option<R> {
+    PROCESSES.lock().processes.get_mut(&pid).map(|process| f(process))
+}
+
+/**
+ * Records the exit status of a process. Its threads and address space stay until it is removed.
+ */
+pub fn set_exit_status(pid: Pid, status: i32) -> Result<(), ProcessError> {
+    with(pid, |process| process.exit_status = Some(status)).ok_or(ProcessError::NoSuchProcess)
+}
+
+/**
+ * Ends a process with the given exit status: its threads are killed, but for the calling one, which has to
+ * scheduler::exit() next if it is one of them, and its address space and open files are freed.
+ * The ports it owns are closed. It stays a zombie until its parent wait()s for it, which gets a SIGCHLD,
+ * a process the kernel started goes right away.
+ * Its children are orphaned, nothing waits for them anymore.
+ */
+pub fn exit(pid: Pid, status: i32) -> Result<(), ProcessError> {
+    let current = scheduler::current();
+    let (space, threads, files) = {
+        let mut table = PROCESSES.lock();
+        let process = table.processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        if process.exit_status.is_some() {
+            return Err(ProcessError::Exited);
+        }
+        process.exit_status = Some(status);
+        let space = process.address_space.take();
+        let threads = mem::take(&mut process.threads);
+        let files = mem::take(&mut process.files);
+        let parent = process.parent;
+        // orphans that exited already can't be waited for, nor can the process itself
+        let mut gone: Vec<Pid> = table.processes.values()
+            .filter(|child| child.parent == Some(pid) && child.exit_status.is_some())
+            .map(|child| child.pid)
+            .collect();
+        if parent.is_none() {
+            gone.push(pid);
+        }
+        for child in table.processes.values_mut().filter(|child| child.parent == Some(pid)) {
+            child.parent = None;
+        }
+        for gone in gone {
+            table.processes.remove(&gone);
+        }
+        if let Some(parent) = parent.and_then(|parent| table.processes.get_mut(&parent)) {
+            // ignored by default, it never ends the parent
+            parent.signals.raise(SIGCHLD);
+        }
+        (space, threads, files)
+    };
+    for &thread in threads.iter().filter(|&&thread| Some(thread) != current) {
+        scheduler::kill(thread);
+    }
+    if current.map_or(false, |current| threads.contains(&current)) {
+        // the thread is on its way out, in the kernel's tables, as its own are freed
+        scheduler::set_address_space(None);
+    }
+    drop(space);
+    drop(files);
+    ipc::close_owned(pid);
+    EXITED.wake_all();
+    Ok(())
+}
+
+/**
+ * Waits for a child of a process to exit, the given one or any, and reaps it: takes the zombie out of the table.
+ * Returns its PID and exit status, or None without blocking, if no child exited yet.
+ */
+pub fn wait(parent: Pid, child: Option<Pid>, block: bool) -> Result<Option<(Pid, i32)>, ProcessError> {
+    let mut reaped = Ok(None);
+    EXITED.wait_until(|| {
+        reaped = reap(parent, child);
+        !block || reaped != Ok(None)
+    });
+    reaped
+}
+
+/**
+ * Takes an exited child of a process out of the table, the given one or any, None if they all still run.
+ */
+fn reap(parent: Pid, child: Option<Pid>) -> Result<Option<(Pid, i32)>, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let mut children = table.processes.values()
+        .filter(|process| process.parent == Some(parent) && child.map_or(true, |child| process.pid == child))
+        .peekable();
+    if children.peek().is_none() {
+        return Err(ProcessError::NoChildren);
+    }
+    let exited = children.find_map(|process| process.exit_status.map(|status| (process.pid, status)));
+    if let Some((pid, _)) = exited {
+        table.processes.remove(&pid);
+    }
+    Ok(exited)
+}
+
+/**
+ * Takes a process out of the table, freeing its address space. None of its threads may run anymore.
+ */
+pub fn remove(pid: Pid) -> Result<(), ProcessError> {
+    let process = PROCESSES.lock().processes.remove(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    // the address space is freed outside the table's lock
+    drop(process);
+    Ok(())
+}
+
+/**
+ * Returns the process of the calling thread, None for kernel threads.
+ */
+pub fn current() -> Option<Pid> {
+    let thread = scheduler::current()?;
+    PROCESSES.lock().processes.values().find(|process| process.threads.contains(&thread)).map(|process| process.pid)
+}
+
+/**
+ * Lists the processes, by PID.
+ */
+pub fn processes() -> Vec<ProcessInfo> {
+    PROCESSES.lock().processes.values().map(|process| ProcessInfo {
+        pid: process.pid,
+        parent: process.parent,
+        name: process.name.clone(),
+        threads: process.threads.len(),
+        exit_status: process.exit_status
+    }).collect()
+}
@@ -0,0 +1,46 @@
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramError {
+    // there is a program by that name already
+    AlreadyRegistered,
+    EmptyName
+}
+
+lazy_static! {
+    /** The executables exec() can run, by name, built into the kernel until there are files to load them from. */
+    static ref PROGRAMS: IrqMutex<BTreeMap<String, &'static [u8]>> = IrqMutex::new(BTreeMap::new());
+}
+
+/**
+ * Makes an ELF executable runnable by exec() under the given name.
+ */
+pub fn register(name: &str, file: &'static [u8]) -> Result<(), ProgramError> {
+    if name.is_empty() {
+        return Err(ProgramError::EmptyName);
+    }
+    let mut programs = PROGRAMS.lock();
+    if programs.contains_key(name) {
+        return Err(ProgramError::AlreadyRegistered);
+    }
+    programs.insert(String::from(name), file);
+    Ok(())
+}
+
+/**
+ * Returns the executable registered under the name.
+ */
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.lock().get(name).copied()
+}
+
+/**
+ * Lists the names of the registered programs, in order.
+ */
+pub fn programs() -> Vec<String> {
+    PROGRAMS.lock().keys().cloned().collect()
+}
@@ -0,0 +1,280 @@
+use crate::heap;
+use crate::sync::IrqMutex;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError, MAX_NAME};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// a file's bytes are in extents of this size, growing it adds extents instead of copying what is there
+const EXTENT_SIZE: usize = 4096;
+// the extents of all the files of a ramfs together, the heap panics when it runs out and the rest of the kernel
+// needs it too
+const MAX_EXTENT_BYTES: u64 = heap::HEAP_SIZE / 4;
+const ROOT_INODE: u64 = 1;
+
+/**
+ * A file system on the heap: directories and regular files, gone when it is dropped. The files' contents can take
+ * up to MAX_EXTENT_BYTES of the heap, writes past that fail with NoSpace.
+ * The kernel mounts one at / at boot, see init(), a writable namespace before there are disks.
+ */
+pub struct RamFs {
+    root: Arc<RamInode>
+}
+
+impl RamFs {
+    pub fn new() -> RamFs {
+        let shared = Arc::new(Shared {
+            next_inode: AtomicU64::new(ROOT_INODE + 1),
+            extent_bytes: AtomicU64::new(0)
+        });
+        RamFs {
+            root: Arc::new(RamInode::new(ROOT_INODE, shared, FileType::Directory))
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> RamFs {
+        RamFs::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Contents {
+    // the extents are EXTENT_SIZE each, but for the last, which the size may end before
+    File { extents: Vec<Vec<u8>>, size: u64 },
+    Directory(BTreeMap<String, Arc<RamInode>>)
+}
+
+/**
+ * What the inodes of a file system share.
+ */
+struct Shared {
+    // for the inodes made in a directory
+    next_inode: AtomicU64,
+    // what the extents of all the files take, up to MAX_EXTENT_BYTES
+    extent_bytes: AtomicU64
+}
+
+struct RamInode {
+    inode: u64,
+    shared: Arc<Shared>,
+    contents: IrqMutex<Contents>
+}
+
+impl RamInode {
+    fn new(inode: u64, shared: Arc<Shared>, file_type: FileType) -> RamInode {
+        let contents = match file_type {
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            _ => Contents::File {
+                extents: Vec::new(),
+                size: 0
+            }
+        };
+        RamInode {
+            inode,
+            shared,
+            contents: IrqMutex::new(contents)
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => entries.is_empty(),
+            Contents::File { .. } => false
+        }
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &*self.contents.lock() {
+            Contents::File { size, .. } => (FileType::Regular, *size),
+            Contents::Directory(_) => (FileType::Directory, 0)
+        };
+        Metadata {
+            file_type,
+            size,
+            inode: self.inode
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let contents = self.contents.lock();
+        let (extents, size) = match &*contents {
+            Contents::File { extents, size } => (extents, *size),
+            Contents::Directory(_) => return Err(VfsError::IsADirectory)
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        let mut done = 0;
+        while done < count {
+            let position = offset as usize + done;
+            let extent = &extents[position / EXTENT_SIZE];
+            let start = position % EXTENT_SIZE;
+            let chunk = (EXTENT_SIZE - start).min(count - done);
+            buffer[done..done + chunk].copy_from_slice(&extent[start..start + chunk]);
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut contents = self.contents.lock();
+        let (extents, size) = match &mut *contents {
+            Contents::File { extents, size } => (extents, size),
+            Contents::Directory(_) => return Err(VfsError::IsADirectory)
+        };
+        let end = offset.checked_add(buffer.len() as u64).ok_or(VfsError::NoSpace)?;
+        // a write past the end leaves zeros in the gap
+        resize(extents, end.max(*size), &self.shared)?;
+        *size = end.max(*size);
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset as usize + done;
+            let extent = &mut extents[position / EXTENT_SIZE];
+            let start = position % EXTENT_SIZE;
+            let chunk = (EXTENT_SIZE - start).min(buffer.len() - done);
+            extent[start..start + chunk].copy_from_slice(&buffer[done..done + chunk]);
+            done += chunk;
+        }
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), VfsError> {
+        match &mut *self.contents.lock() {
+            Contents::File { extents, size } => {
+                resize(extents, new_size, &self.shared)?;
+                // what was cut off reads as zeros if the file grows again
+                let end = (new_size as usize).saturating_sub(extents.len().saturating_sub(1) * EXTENT_SIZE);
+                if let Some(last) = extents.last_mut() {
+                    for byte in last[end..].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                *size = new_size;
+                Ok(())
+            }
+            Contents::Directory(_) => Err(VfsError::IsADirectory)
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => {
+                let entry = entries.get(name).ok_or(VfsError::NotFound)?;
+                Ok(entry.clone())
+            }
+            Contents::File { .. } => Err(VfsError::NotADirectory)
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.len() > MAX_NAME {
+            return Err(VfsError::InvalidPath);
+        }
+        if file_type == FileType::Device {
+            return Err(VfsError::NotSupported);
+        }
+        let mut contents = self.contents.lock();
+        let entries = match &mut *contents {
+            Contents::Directory(entries) => entries,
+            Contents::File { .. } => return Err(VfsError::NotADirectory)
+        };
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let inode = self.shared.next_inode.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(RamInode::new(inode, self.shared.clone(), file_type));
+        entries.insert(String::from(name), entry.clone());
+        Ok(entry)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let mut contents = self.contents.lock();
+        let entries = match &mut *contents {
+            Contents::Directory(entries) => entries,
+            Contents::File { .. } => return Err(VfsError::NotADirectory)
+        };
+        let entry = entries.get(name).ok_or(VfsError::NotFound)?;
+        // a directory's lock is taken before its entries'
+        if entry.metadata().file_type == FileType::Directory && !entry.is_empty_directory() {
+            return Err(VfsError::NotEmpty);
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => Ok(entries.iter().nth(index).map(|(name, entry)| {
+                let metadata = entry.metadata();
+                DirEntry {
+                    name: name.clone(),
+                    file_type: metadata.file_type,
+                    inode: metadata.inode
+                }
+            })),
+            Contents::File { .. } => Err(VfsError::NotADirectory)
+        }
+    }
+}
+
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        // removed, and closed by the last one who had it open
+        if let Contents::File { extents, .. } = &*self.contents.lock() {
+            let bytes = (extents.len() * EXTENT_SIZE) as u64;
+            self.shared.extent_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+/**
+ * Makes a file's extents hold exactly the given size, adding zeroed ones or dropping those past it.
+ * Fails with NoSpace, changing nothing, if the file system's extents would take more than MAX_EXTENT_BYTES.
+ */
+fn resize(extents: &mut Vec<Vec<u8>>, size: u64, shared: &Shared) -> Result<(), VfsError> {
+    let count = size.checked_add(EXTENT_SIZE as u64 - 1).ok_or(VfsError::NoSpace)? / EXTENT_SIZE as u64;
+    let current = extents.len() as u64;
+    if count > current {
+        let added = (count - current) * EXTENT_SIZE as u64;
+        // taken before the extents are allocated, so writes to other files can't overdraw it meanwhile
+        let mut used = shared.extent_bytes.load(Ordering::Relaxed);
+        loop {
+            let total = used.checked_add(added).filter(|&total| total <= MAX_EXTENT_BYTES).ok_or(VfsError::NoSpace)?;
+            match shared.extent_bytes.compare_exchange_weak(used, total, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => used = actual
+            }
+        }
+        while (extents.len() as u64) < count {
+            extents.push(vec![0; EXTENT_SIZE]);
+        }
+    } else {
+        extents.truncate(count as usize);
+        shared.extent_bytes.fetch_sub((current - count) * EXTENT_SIZE as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/**
+ * Mounts an empty ramfs at /, the root file system until there are disks. Needs the heap.
+ */
+pub fn init() -> Result<(), VfsError> {
+    vfs::mount("/", Arc::new(RamFs::new()))
+}
@@ -0,0 +1,311 @@
+use crate::interrupts;
+use crate::sync::IrqMutex;
+use crate::time::{self, ClockSource, DateTime, Instant};
+use crate::timer::Handler;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// the CMOS register to access is selected here, then read or written through the data port
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+// the top bit of the address port masks NMIs, they are masked while the CMOS is accessed
+const NMI_DISABLE: u8 = 0x80;
+// selected afterwards with NMIs unmasked again, reading it does nothing
+const STATUS_D: u8 = 0x0D;
+
+// clock registers, each alarm register follows the one it is compared to
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+// reading it tells which interrupts were raised and acknowledges them, IRQ8 stays quiet until it is read
+const STATUS_C: u8 = 0x0C;
+
+// status A: the clock is about to change its registers, they can be half updated while this is set
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+// status A: the low 4 bits select the periodic interrupt's rate, 32768 Hz >> (rate - 1)
+const RATE_MASK: u8 = 0x0F;
+// status B and C: the periodic and alarm interrupts
+const PERIODIC_INTERRUPT: u8 = 0x40;
+const ALARM_INTERRUPT: u8 = 0x20;
+// status B: the registers are binary instead of BCD, the hours count to 23 instead of 12
+const BINARY_MODE: u8 = 0x04;
+const HOURS_24: u8 = 0x02;
+// set on the hour register after noon in 12 hour mode
+const PM: u8 = 0x80;
+
+// the clock only keeps two digits of the year, the century register is not at the same place everywhere
+const CENTURY: u16 = 2000;
+// reads tried before giving up on getting the same time twice
+const MAX_READS: usize = 8;
+// the periodic interrupt divides this clock, rates 1 and 2 don't work on every chip so 8192 Hz is the fastest
+const BASE_FREQUENCY: u32 = 32768;
+const MAX_PERIODIC_FREQUENCY: u32 = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    // the clock kept updating while it was read
+    Unstable,
+    // the registers don't make a date, e.g. the CMOS battery is flat
+    InvalidDate(DateTime),
+    // the periodic interrupt only comes at powers of two from 2 to 8192 Hz
+    InvalidFrequency(u32),
+    InvalidAlarm,
+    // the HPET drives the timer interrupt, its legacy route takes IRQ8 away from the clock
+    IrqTaken
+}
+
+// the time read at boot, and when it was read
+static BOOT_TIME: IrqMutex<Option<(DateTime, Instant)>> = IrqMutex::new(None);
+// periodic interrupts so far, and their frequency, 0 while they are off
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+// run by timer::run_pending() once the alarm interrupt came
+static ALARM: IrqMutex<Option<Handler>> = IrqMutex::new(None);
+static ALARM_RAISED: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Reads the clock once to check that it keeps a valid date, and remembers it as the boot time.
+ * Lets IRQ8 through, though the clock only raises it once the periodic interrupt or an alarm is set up.
+ */
+pub fn init() -> Result<(), RtcError> {
+    let now = now()?;
+    *BOOT_TIME.lock() = Some((now, Instant::now()));
+    // anything left raised by the firmware would hold the interrupt back
+    read_cmos(STATUS_C);
+    interrupts::unmask_irq(interrupts::RTC_IRQ);
+    Ok(())
+}
+
+/**
+ * Returns the date and time read at boot, if the clock could be read.
+ */
+pub fn boot_time() -> Option<DateTime> {
+    BOOT_TIME.lock().map(|(date, _)| date)
+}
+
+/**
+ * Returns the time read at boot along with when it was read, for DateTime::now() to count on from.
+ */
+pub(crate) fn anchor() -> Option<(DateTime, Instant)> {
+    *BOOT_TIME.lock()
+}
+
+/**
+ * Reads the current date and time from the clock.
+ */
+pub fn now() -> Result<DateTime, RtcError> {
+    // the registers are read until they come out the same twice, an update may happen between any two reads
+    let mut previous = read_registers();
+    for _ in 0..MAX_READS {
+        let registers = read_registers();
+        if registers == previous {
+            return decode(registers);
+        }
+        previous = registers;
+    }
+    Err(RtcError::Unstable)
+}
+
+/**
+ * Starts the periodic interrupt at the given frequency, a power of two from 2 to 8192 Hz.
+ * It keeps counting when the PIT is reprogrammed for something else, and wakes a halted CPU between timer ticks.
+ */
+pub fn enable_periodic(frequency: u32) -> Result<(), RtcError> {
+    if frequency < 2 || frequency > MAX_PERIODIC_FREQUENCY || !frequency.is_power_of_two() {
+        return Err(RtcError::InvalidFrequency(frequency));
+    }
+    if time::clock_source() == ClockSource::Hpet {
+        return Err(RtcError::IrqTaken);
+    }
+    // 32768 >> (rate - 1) = frequency
+    let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_cmos(STATUS_A, (read_cmos(STATUS_A) & !RATE_MASK) | rate);
+        PERIODIC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        write_cmos(STATUS_B, read_cmos(STATUS_B) | PERIODIC_INTERRUPT);
+    });
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_cmos(STATUS_B, read_cmos(STATUS_B) & !PERIODIC_INTERRUPT);
+        PERIODIC_FREQUENCY.store(0, Ordering::Relaxed);
+    });
+}
+
+/**
+ * Returns the number of periodic interrupts so far.
+ */
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/**
+ * Returns the periodic interrupt's frequency in Hz, 0 while it is off.
+ */
+pub fn periodic_frequency() -> u32 {
+    PERIODIC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/**
+ * Runs the handler when the clock next reaches the given time of day, like a timer but by the wall clock.
+ * The handler runs in kernel context from timer::run_pending(), replacing any alarm set before.
+ */
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: Handler) -> Result<(), RtcError> {
+    if hour >= 24 || minute >= 60 || second >= 60 {
+        return Err(RtcError::InvalidAlarm);
+    }
+    if time::clock_source() == ClockSource::Hpet {
+        return Err(RtcError::IrqTaken);
+    }
+    *ALARM.lock() = Some(handler);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let status_b = read_cmos(STATUS_B);
+        // the alarm is compared with the clock's registers, so it has to be in their format
+        write_cmos(SECONDS_ALARM, encode(second, status_b));
+        write_cmos(MINUTES_ALARM, encode(minute, status_b));
+        write_cmos(HOURS_ALARM, encode_hour(hour, status_b));
+        write_cmos(STATUS_B, status_b | ALARM_INTERRUPT);
+    });
+    Ok(())
+}
+
+pub fn cancel_alarm() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_cmos(STATUS_B, read_cmos(STATUS_B) & !ALARM_INTERRUPT);
+    });
+    *ALARM.lock() = None;
+    ALARM_RAISED.store(false, Ordering::Relaxed);
+}
+
+/**
+ * Acknowledges IRQ8 and counts what raised it, called by its interrupt handler.
+ */
+pub(crate) fn interrupt() {
+    let raised = read_cmos(STATUS_C);
+    if raised & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if raised & ALARM_INTERRUPT != 0 {
+        ALARM_RAISED.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Runs the alarm's handler if it went off, called by timer::run_pending().
+ */
+pub(crate) fn run_alarm() {
+    if !ALARM_RAISED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    // an alarm goes off once, it would come again the next day otherwise
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_cmos(STATUS_B, read_cmos(STATUS_B) & !ALARM_INTERRUPT);
+    });
+    let handler = ALARM.lock().take();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/**
+ * Reads the clock registers and status B, after waiting for an update in progress to finish.
+ */
+fn read_registers() -> [u8; 7] {
+    // an update takes about 2 ms, the flag is set for at most that long
+    while read_cmos(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    [
+        read_cmos(SECONDS),
+        read_cmos(MINUTES),
+        read_cmos(HOURS),
+        read_cmos(DAY),
+        read_cmos(MONTH),
+        read_cmos(YEAR),
+        read_cmos(STATUS_B)
+    ]
+}
+
+fn decode(registers: [u8; 7]) -> Result<DateTime, RtcError> {
+    let [second, minute, hour, day, month, year, status_b] = registers;
+    let binary = status_b & BINARY_MODE != 0;
+    let value = |register: u8| if binary { register } else { from_bcd(register) };
+
+    let pm = hour & PM != 0;
+    let mut hour = value(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let date = DateTime {
+        year: CENTURY + u16::from(value(year)),
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minute),
+        second: value(second)
+    };
+    let valid = (1..=12).contains(&date.month) && (1..=31).contains(&date.day)
+        && date.hour < 24 && date.minute < 60 && date.second < 60;
+    if valid {
+        Ok(date)
+    } else {
+        Err(RtcError::InvalidDate(date))
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & BINARY_MODE != 0 { value } else { to_bcd(value) }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & HOURS_24 != 0 {
+        return encode(hour, status_b);
+    }
+    // midnight and noon are 12
+    let twelve_hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+    let pm = if hour >= 12 { PM } else { 0 };
+    encode(twelve_hour, status_b) | pm
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    // the register selection and the read must not be split by an interrupt handler using the CMOS
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        address.write(NMI_DISABLE | register);
+        let value = data.read();
+        address.write(STATUS_D);
+        value
+    })
+}
+
+fn write_cmos(register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        address.write(NMI_DISABLE | register);
+        data.write(value);
+        address.write(STATUS_D);
+    })
+}
@@ -0,0 +1,2 @@
+// the scheduler's statistics, for ps and top style commands, under the name they are asked for by
+pub use crate::scheduler::{stats, threads, SchedulerStats, ThreadInfo, ThreadState};
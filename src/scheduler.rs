@@ -0,0 +1,711 @@
+use crate::fpu::FpuState;
+use crate::gdt::{self, GdtError, MAX_CPUS};
+use crate::paging;
+use crate::percpu;
+use crate::stack::{self, Stack, StackError};
+use crate::sync::IrqMutex;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+
+// 16 KiB, above a guard page like every kernel stack
+const THREAD_STACK_PAGES: u64 = 4;
+// how many timer ticks a thread of each priority runs before the next ready one of the same priority gets the CPU,
+// 100, 50 and 20 ms at the default 100 Hz, see set_time_slice()
+const PRIORITIES: usize = 3;
+const DEFAULT_TIME_SLICES: [u64; PRIORITIES] = [10, 5, 2];
+// what context_switch pops for a new thread: R15, R14, R13, R12, RBX, RBP, RFLAGS and the return address
+const INITIAL_FRAME_WORDS: usize = 8;
+// RFLAGS with only the always set bit 1, interrupts stay off until run_thread()
+const INITIAL_RFLAGS: u64 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/**
+ * A thread runs only while no thread of a higher priority is ready, and takes the CPU from a lower priority one
+ * at the next tick. Threads of the same priority take turns, each for its priority's time slice.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // e.g. interrupt bottom halves, which must not wait behind anything
+    Realtime,
+    Normal,
+    // background work for when nothing else is ready
+    Idle
+}
+
+impl Priority {
+    pub const ALL: [Priority; PRIORITIES] = [Priority::Realtime, Priority::Normal, Priority::Idle];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    // waiting in the ready queue
+    Ready,
+    // on a CPU
+    Running,
+    // waiting to be woken, see wait_queue.rs
+    Blocked,
+    // done, its stack is freed at a later switch, once nothing runs on it
+    Exited
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerError {
+    // init() did not run on the CPU yet
+    NotStarted,
+    // there is no thread with the given ID, or it has exited
+    NoSuchThread,
+    // a time slice must be at least a tick
+    InvalidTimeSlice,
+    Stack(StackError),
+    Gdt(GdtError)
+}
+
+impl From<StackError> for SchedulerError {
+    fn from(error: StackError) -> SchedulerError {
+        SchedulerError::Stack(error)
+    }
+}
+
+impl From<GdtError> for SchedulerError {
+    fn from(error: GdtError) -> SchedulerError {
+        SchedulerError::Gdt(error)
+    }
+}
+
+/**
+ * What threads() tells about a thread.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub state: ThreadState,
+    pub priority: Priority,
+    // the timer ticks it ran for
+    pub ticks: u64,
+    // the nanoseconds it ran for, by the clock at its switches, the running ones' up to now
+    pub run_time_ns: u64,
+    // how often it was switched in, and how often out while it could still run, by a tick or a higher priority thread
+    pub switches: u64,
+    pub preemptions: u64,
+    // the CPU running it, None unless it is Running
+    pub cpu: Option<usize>
+}
+
+/**
+ * What stats() tells about the scheduler: how busy it is and what each thread does, e.g. for a ps or top command.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerStats {
+    // context switches since init(), on all CPUs
+    pub switches: u64,
+    // the threads in each priority's ready queue, in the order of Priority::ALL
+    pub queued: [usize; PRIORITIES],
+    // the threads in each state, the idle threads among them
+    pub running: usize,
+    pub ready: usize,
+    pub blocked: usize,
+    pub exited: usize,
+    // the ticks the CPUs spent in their idle threads
+    pub idle_ticks: u64,
+    pub threads: Vec<ThreadInfo>
+}
+
+/**
+ * A kernel thread. While it is switched out, its registers are on its own stack, under the saved stack pointer:
+ * the interrupted ones pushed by the interrupt handler it was switched out of, the callee-saved ones by context_switch.
+ */
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    priority: Priority,
+    // None for the code a CPU booted into, which runs on the stack the bootloader or the CPU's startup gave it
+    stack: Option<Stack>,
+    // what RSP0 is set to while the thread runs, the stack an interrupt from ring 3 lands on
+    kernel_stack_top: VirtAddr,
+    // the level 4 table of the address space of the thread's process, None to run in the kernel's
+    address_space: Option<PhysFrame>,
+    // RSP while switched out
+    stack_pointer: u64,
+    // set while a CPU runs on the thread's stack, cleared by context_switch once its RSP is saved: until then it is
+    // not taken from the ready queue by another CPU, nor reaped
+    on_cpu: AtomicBool,
+    // ticks left of the time slice while running
+    ticks_left: u64,
+    // ticks it ran for in all
+    ticks: u64,
+    // nanoseconds it ran for up to its last switch out, and when it was last switched in
+    run_time_ns: u64,
+    switched_in_ns: u64,
+    // see ThreadInfo
+    switches: u64,
+    preemptions: u64,
+    fpu: FpuState
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    // a queue for each priority, by Priority::index()
+    ready: [VecDeque<ThreadId>; PRIORITIES],
+    // the thread each CPU runs, None until init() on it
+    current: [Option<ThreadId>; MAX_CPUS],
+    // each CPU's idle thread, which it runs when no other thread is ready, never in the ready queues
+    idle: [Option<ThreadId>; MAX_CPUS],
+    next_id: u64,
+    // context switches on all CPUs
+    switches: u64
+}
+
+// by Priority::index()
+static TIME_SLICES: [AtomicU64; PRIORITIES] = [
+    AtomicU64::new(DEFAULT_TIME_SLICES[0]),
+    AtomicU64::new(DEFAULT_TIME_SLICES[1]),
+    AtomicU64::new(DEFAULT_TIME_SLICES[2])
+];
+
+lazy_static! {
+    static ref SCHEDULER: IrqMutex<Scheduler> = IrqMutex::new(Scheduler {
+        threads: BTreeMap::new(),
+        ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        current: [None; MAX_CPUS],
+        idle: [None; MAX_CPUS],
+        next_id: 0,
+        switches: 0
+    });
+}
+
+impl Scheduler {
+    fn add(&mut self, stack: Option<Stack>, kernel_stack_top: VirtAddr, state: ThreadState, priority: Priority) -> &mut Thread {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        let thread = Box::new(Thread {
+            id,
+            state,
+            priority,
+            stack,
+            kernel_stack_top,
+            address_space: None,
+            stack_pointer: 0,
+            on_cpu: AtomicBool::new(state == ThreadState::Running),
+            ticks_left: time_slice(priority),
+            ticks: 0,
+            run_time_ns: 0,
+            switched_in_ns: 0,
+            switches: 0,
+            preemptions: 0,
+            fpu: FpuState::new()
+        });
+        self.threads.entry(id).or_insert(thread)
+    }
+
+    /**
+     * Counts a tick against the calling CPU's thread, returns whether its time slice is over
+     * or a thread of a higher priority is ready.
+     */
+    fn tick(&mut self) -> bool {
+        let current = match self.current[percpu::current()] {
+            Some(current) => current,
+            None => return false
+        };
+        let thread = match self.threads.get_mut(&current) {
+            Some(thread) => thread,
+            None => return false
+        };
+        thread.ticks += 1;
+        thread.ticks_left = thread.ticks_left.saturating_sub(1);
+        let priority = thread.priority;
+        if self.idle.contains(&Some(current)) {
+            return self.ready.iter().any(|queue| !queue.is_empty());
+        }
+        thread.ticks_left == 0 || self.ready[..priority.index()].iter().any(|queue| !queue.is_empty())
+    }
+
+    /**
+     * Takes the first thread of the highest priority ready, of at least the given priority.
+     * A thread another CPU has just switched out is queued before its stack pointer is saved, it is passed over.
+     */
+    fn next(&mut self, lowest: Priority) -> Option<ThreadId> {
+        let threads = &self.threads;
+        let switched_out = |id: &ThreadId| {
+            threads.get(id).map_or(true, |thread| !thread.on_cpu.load(Ordering::Acquire))
+        };
+        self.ready[..=lowest.index()].iter_mut().find_map(|queue| {
+            let position = queue.iter().position(switched_out)?;
+            queue.remove(position)
+        })
+    }
+
+    /**
+     * Makes the next ready thread the calling CPU's, putting the current one back in the queue unless it exited.
+     * Returns where to save the current thread's stack pointer, its on_cpu flag to clear after that,
+     * and the stack pointer to switch to, or None to keep running the current thread.
+     */
+    fn switch(&mut self) -> Option<(*mut u64, *const AtomicBool, u64)> {
+        let cpu = percpu::current();
+        let current_id = self.current[cpu]?;
+        self.reap();
+        let idle = self.idle[cpu];
+        let is_idle = idle == Some(current_id);
+        let (running, priority) = match self.threads.get(&current_id) {
+            Some(current) => (current.state == ThreadState::Running && !is_idle, current.priority),
+            None => (false, Priority::Idle)
+        };
+        // a running thread only makes way for one of at least its priority, the idle thread for any
+        let lowest = if running { priority } else { Priority::Idle };
+        // a blocked or exited thread makes way for the idle thread at least
+        let next_id = match self.next(lowest).or(if running || is_idle { None } else { idle }) {
+            Some(next_id) => next_id,
+            None => {
+                // the thread goes on with a fresh slice
+                if let Some(current) = self.threads.get_mut(&current_id) {
+                    current.ticks_left = time_slice(current.priority);
+                }
+                return None;
+            }
+        };
+
+        let now = time::now_ns();
+        let current = self.threads.get_mut(&current_id)?;
+        current.run_time_ns += now.saturating_sub(current.switched_in_ns);
+        if current.state == ThreadState::Running {
+            current.preemptions += 1;
+            current.state = ThreadState::Ready;
+            if !is_idle {
+                self.ready[priority.index()].push_back(current_id);
+            }
+        }
+        // the kernel itself leaves these registers alone, see fpu.rs
+        current.fpu.save();
+        let save_to = &mut current.stack_pointer as *mut u64;
+        let on_cpu = &current.on_cpu as *const AtomicBool;
+
+        let next = self.threads.get_mut(&next_id)?;
+        next.state = ThreadState::Running;
+        next.on_cpu.store(true, Ordering::Relaxed);
+        next.ticks_left = time_slice(next.priority);
+        next.switched_in_ns = now;
+        next.switches += 1;
+        next.fpu.restore();
+        // interrupts and system calls from ring 3 land on the thread's stack
+        let _ = gdt::set_kernel_stack(next.kernel_stack_top);
+        percpu::block().kernel_stack.store(next.kernel_stack_top.as_u64(), Ordering::Relaxed);
+        // kernel threads run in the kernel's tables, which every address space shares
+        if let Some(level_4) = next.address_space.or_else(paging::kernel_level_4_frame) {
+            if Cr3::read().0 != level_4 {
+                unsafe { Cr3::write(level_4, Cr3Flags::empty()) };
+            }
+        }
+        let stack_pointer = next.stack_pointer;
+        self.current[cpu] = Some(next_id);
+        self.switches += 1;
+        Some((save_to, on_cpu, stack_pointer))
+    }
+
+    /**
+     * Describes a thread, with the time it ran for up to now if it is running.
+     */
+    fn info(&self, thread: &Thread, now: u64) -> ThreadInfo {
+        let cpu = self.current.iter().position(|&current| current == Some(thread.id));
+        let running = match cpu {
+            Some(_) => now.saturating_sub(thread.switched_in_ns),
+            None => 0
+        };
+        ThreadInfo {
+            id: thread.id,
+            state: thread.state,
+            priority: thread.priority,
+            ticks: thread.ticks,
+            run_time_ns: thread.run_time_ns + running,
+            switches: thread.switches,
+            preemptions: thread.preemptions,
+            cpu: cpu.filter(|_| thread.state == ThreadState::Running)
+        }
+    }
+
+    /**
+     * Forgets the exited threads no CPU runs anymore and frees their stacks.
+     */
+    fn reap(&mut self) {
+        let current = self.current;
+        let exited: Vec<ThreadId> = self.threads.values()
+            .filter(|thread| thread.state == ThreadState::Exited && !current.contains(&Some(thread.id)))
+            .filter(|thread| !thread.on_cpu.load(Ordering::Acquire))
+            .map(|thread| thread.id)
+            .collect();
+        for id in exited {
+            if let Some(stack) = self.threads.remove(&id).and_then(|thread| thread.stack) {
+                // nothing runs on it, its thread was switched out for good
+                let _ = unsafe { stack::free(stack) };
+            }
+        }
+    }
+}
+
+/**
+ * Makes the code running on the calling CPU its first thread, which the timer then switches with the ones spawn()ed,
+ * and starts the CPU's idle thread. Needs the heap, gdt::init() and percpu::init() on this CPU first.
+ */
+pub fn init() -> Result<(), SchedulerError> {
+    let kernel_stack_top = gdt::kernel_stack_top()?;
+    let (idle_stack, idle_stack_pointer) = new_stack(idle, 0)?;
+    let cpu = percpu::current();
+    let mut scheduler = SCHEDULER.lock();
+    let boot = scheduler.add(None, kernel_stack_top, ThreadState::Running, Priority::Normal);
+    boot.switched_in_ns = time::now_ns();
+    let id = boot.id;
+    scheduler.current[cpu] = Some(id);
+    let idle = scheduler.add(Some(idle_stack), idle_stack.top(), ThreadState::Ready, Priority::Idle);
+    idle.stack_pointer = idle_stack_pointer.as_u64();
+    let idle = idle.id;
+    scheduler.idle[cpu] = Some(idle);
+    Ok(())
+}
+
+/**
+ * Starts a kernel thread running entry(argument) on a stack of its own, with interrupts enabled.
+ * It is queued behind the ready threads of its priority, and exits when entry returns. thread::spawn() takes closures.
+ */
+pub fn spawn(entry: fn(usize), argument: usize, priority: Priority) -> Result<ThreadId, SchedulerError> {
+    start(entry, argument, priority, None)
+}
+
+/**
+ * Like spawn(), for a thread of normal priority running in a process' address space, given by its level 4 table,
+ * which must outlive the thread.
+ */
+pub(crate) fn spawn_in(level_4: PhysFrame, entry: fn(usize), argument: usize) -> Result<ThreadId, SchedulerError> {
+    start(entry, argument, Priority::Normal, Some(level_4))
+}
+
+fn start(entry: fn(usize), argument: usize, priority: Priority, level_4: Option<PhysFrame>) -> Result<ThreadId, SchedulerError> {
+    let (stack, stack_pointer) = new_stack(entry, argument)?;
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.current[percpu::current()].is_none() {
+        drop(scheduler);
+        // nothing ran on it
+        let _ = unsafe { stack::free(stack) };
+        return Err(SchedulerError::NotStarted);
+    }
+    let thread = scheduler.add(Some(stack), stack.top(), ThreadState::Ready, priority);
+    thread.stack_pointer = stack_pointer.as_u64();
+    thread.address_space = level_4;
+    let id = thread.id;
+    scheduler.ready[priority.index()].push_back(id);
+    Ok(id)
+}
+
+/**
+ * Maps a new thread's stack, with the frame context_switch pops the first time on it, returning into thread_start
+ * with the entry in R12 and its argument in R13. Returns the stack and the thread's first stack pointer.
+ */
+fn new_stack(entry: fn(usize), argument: usize) -> Result<(Stack, VirtAddr), SchedulerError> {
+    let stack = stack::allocate(THREAD_STACK_PAGES)?;
+    let start = scheduler_thread_start as unsafe extern "C" fn() as usize as u64;
+    let frame = [0, 0, argument as u64, entry as usize as u64, 0, 0, INITIAL_RFLAGS, start];
+    let stack_pointer = stack.top() - (INITIAL_FRAME_WORDS * mem::size_of::<u64>()) as u64;
+    unsafe { (stack_pointer.as_mut_ptr() as *mut [u64; INITIAL_FRAME_WORDS]).write(frame) };
+    Ok((stack, stack_pointer))
+}
+
+/**
+ * What a CPU runs when no other thread is ready: it halts until an interrupt, which may have made one ready.
+ * Interrupts stay disabled from the check to the hlt, so a wake-up can't slip in between.
+ */
+fn idle(_: usize) {
+    loop {
+        interrupts::disable();
+        schedule();
+        crate::interrupts::enable_and_hlt();
+    }
+}
+
+/**
+ * Moves a thread to another priority. A ready one goes to the back of the new priority's queue,
+ * a running one keeps the CPU until the next tick.
+ */
+pub fn set_priority(id: ThreadId, priority: Priority) -> Result<(), SchedulerError> {
+    let mut scheduler = SCHEDULER.lock();
+    let thread = scheduler.threads.get_mut(&id)
+        .filter(|thread| thread.state != ThreadState::Exited)
+        .ok_or(SchedulerError::NoSuchThread)?;
+    let previous = mem::replace(&mut thread.priority, priority);
+    // the idle threads are never queued
+    if let Some(position) = scheduler.ready[previous.index()].iter().position(|&ready| ready == id) {
+        scheduler.ready[previous.index()].remove(position);
+        scheduler.ready[priority.index()].push_back(id);
+    }
+    Ok(())
+}
+
+/**
+ * Returns how many timer ticks a thread of the given priority runs before the next one of the same priority.
+ */
+pub fn time_slice(priority: Priority) -> u64 {
+    TIME_SLICES[priority.index()].load(Ordering::Relaxed)
+}
+
+/**
+ * Changes the time slice of a priority, taking effect from each thread's next turn.
+ */
+pub fn set_time_slice(priority: Priority, ticks: u64) -> Result<(), SchedulerError> {
+    if ticks == 0 {
+        return Err(SchedulerError::InvalidTimeSlice);
+    }
+    TIME_SLICES[priority.index()].store(ticks, Ordering::Relaxed);
+    Ok(())
+}
+
+/**
+ * Switches the calling CPU to the next ready thread of the highest priority, if there is one of at least the current
+ * thread's, which goes to the back of its queue.
+ * The timer calls it when a time slice is over, another CPU's reschedule IPI too.
+ */
+pub fn schedule() {
+    interrupts::without_interrupts(|| {
+        let switch = SCHEDULER.lock().switch();
+        if let Some((save_to, on_cpu, stack_pointer)) = switch {
+            // the lock is released, the next thread may take it right away
+            unsafe { scheduler_context_switch(save_to, on_cpu, stack_pointer) };
+        }
+    });
+}
+
+/**
+ * Counts a timer tick against the running thread, switching to the next one once its time slice is over,
+ * or right away for a ready thread of a higher priority.
+ * Called by the timer interrupt handlers, after the end of interrupt, as the next thread may not return there for a while.
+ */
+pub fn tick() {
+    let expired = SCHEDULER.lock().tick();
+    if expired {
+        schedule();
+    }
+}
+
+/**
+ * Ends the calling thread. Its stack is freed once another thread runs.
+ */
+pub fn exit() -> ! {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(current) = scheduler.current[percpu::current()] {
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.state = ThreadState::Exited;
+            }
+        }
+    }
+    loop {
+        schedule();
+        // nothing else was ready, wait for something to be
+        interrupts::enable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/**
+ * Ends another thread: it leaves the ready queues, or the wait queue it blocked in, and never runs again.
+ * A thread another CPU runs stops at that CPU's next switch. Returns whether the thread was still going.
+ */
+pub(crate) fn kill(id: ThreadId) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    let thread = match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state != ThreadState::Exited => thread,
+        _ => return false
+    };
+    thread.state = ThreadState::Exited;
+    let priority = thread.priority;
+    scheduler.ready[priority.index()].retain(|&ready| ready != id);
+    true
+}
+
+/**
+ * Marks the calling thread blocked, so the next switch leaves it out of the ready queues until wake().
+ * The caller switches away with schedule() next, interrupts disabled in between, so a wake-up can't come first.
+ * Returns the thread, None before init().
+ */
+pub(crate) fn block_current() -> Option<ThreadId> {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current[percpu::current()]?;
+    let thread = scheduler.threads.get_mut(&current)?;
+    thread.state = ThreadState::Blocked;
+    Some(current)
+}
+
+/**
+ * Makes a blocked thread ready again, behind the others of its priority, or just running if it has not
+ * switched away yet. Returns whether the thread was blocked.
+ */
+pub fn wake(id: ThreadId) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    let running = scheduler.current.contains(&Some(id));
+    let thread = match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state == ThreadState::Blocked => thread,
+        _ => return false
+    };
+    if running {
+        thread.state = ThreadState::Running;
+    } else {
+        thread.state = ThreadState::Ready;
+        let priority = thread.priority;
+        scheduler.ready[priority.index()].push_back(id);
+    }
+    true
+}
+
+/**
+ * Moves the calling thread to another address space, given by its level 4 table, None for the kernel's,
+ * switching to it right away. The space must outlive the thread, or the next move.
+ */
+pub(crate) fn set_address_space(level_4: Option<PhysFrame>) {
+    let mut scheduler = SCHEDULER.lock();
+    let current = match scheduler.current[percpu::current()] {
+        Some(current) => current,
+        None => return
+    };
+    if let Some(thread) = scheduler.threads.get_mut(&current) {
+        thread.address_space = level_4;
+    }
+    if let Some(level_4) = level_4.or_else(paging::kernel_level_4_frame) {
+        if Cr3::read().0 != level_4 {
+            unsafe { Cr3::write(level_4, Cr3Flags::empty()) };
+        }
+    }
+}
+
+/**
+ * Returns the state of a thread, None once it has been reaped.
+ */
+pub fn state(id: ThreadId) -> Option<ThreadState> {
+    SCHEDULER.lock().threads.get(&id).map(|thread| thread.state)
+}
+
+/**
+ * Returns the state of the calling thread, None before init().
+ */
+pub fn current_state() -> Option<ThreadState> {
+    let scheduler = SCHEDULER.lock();
+    let current = scheduler.current[percpu::current()]?;
+    scheduler.threads.get(&current).map(|thread| thread.state)
+}
+
+/**
+ * Returns the thread the calling CPU runs, None before init().
+ */
+pub fn current() -> Option<ThreadId> {
+    SCHEDULER.lock().current[percpu::current()]
+}
+
+/**
+ * Lists the threads that have not been reaped yet, by ID.
+ */
+pub fn threads() -> Vec<ThreadInfo> {
+    let now = time::now_ns();
+    let scheduler = SCHEDULER.lock();
+    scheduler.threads.values().map(|thread| scheduler.info(thread, now)).collect()
+}
+
+/**
+ * Returns the scheduler's counters, run queues and threads, all taken at once.
+ */
+pub fn stats() -> SchedulerStats {
+    let now = time::now_ns();
+    let scheduler = SCHEDULER.lock();
+    let threads: Vec<ThreadInfo> = scheduler.threads.values().map(|thread| scheduler.info(thread, now)).collect();
+    let count = |state| threads.iter().filter(|thread| thread.state == state).count();
+    let mut queued = [0; PRIORITIES];
+    for (queued, queue) in queued.iter_mut().zip(scheduler.ready.iter()) {
+        *queued = queue.len();
+    }
+    SchedulerStats {
+        switches: scheduler.switches,
+        queued,
+        running: count(ThreadState::Running),
+        ready: count(ThreadState::Ready),
+        blocked: count(ThreadState::Blocked),
+        exited: count(ThreadState::Exited),
+        idle_ticks: threads.iter()
+            .filter(|thread| scheduler.idle.contains(&Some(thread.id)))
+            .map(|thread| thread.ticks)
+            .sum(),
+        threads
+    }
+}
+
+/**
+ * Where a new thread starts, on its own stack with interrupts still disabled from the switch.
+ */
+#[no_mangle]
+extern "C" fn scheduler_run_thread(entry: usize, argument: usize) -> ! {
+    // spawn() put a fn(usize) there
+    let entry: fn(usize) = unsafe { mem::transmute(entry) };
+    interrupts::enable();
+    entry(argument);
+    exit()
+}
+
+extern "C" {
+    fn scheduler_context_switch(save_to: *mut u64, on_cpu: *const AtomicBool, stack_pointer: u64);
+    fn scheduler_thread_start();
+}
+
+// context_switch pushes the callee-saved registers and RFLAGS, the System V ABI has the caller save the rest,
+// leaves the stack pointer in *save_to (RDI), clears the on_cpu flag at RSI, after which another CPU may switch to
+// the thread, and pops the same from the stack at RDX.
+// A new thread's stack holds a frame returning into thread_start, 16 byte aligned for its call.
+global_asm!("
+.att_syntax prefix
+.global scheduler_context_switch
+scheduler_context_switch:
+    pushfq
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%rdi)
+    movb $0, (%rsi)
+    movq %rdx, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    popfq
+    ret
+
+.global scheduler_thread_start
+scheduler_thread_start:
+    movq %r12, %rdi
+    movq %r13, %rsi
+    call scheduler_run_thread
+    ud2
+");
@@ -0,0 +1,261 @@
+use crate::sync::IrqMutex;
+use core::fmt;
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+// The UART's registers, as offsets from its base port.
+// The first two are the divisor latch instead while the divisor latch access bit of the line control register is set.
+const DATA_REGISTER: u16 = 0;
+const INTERRUPT_ENABLE_REGISTER: u16 = 1;
+const FIFO_CONTROL_REGISTER: u16 = 2;
+const LINE_CONTROL_REGISTER: u16 = 3;
+const MODEM_CONTROL_REGISTER: u16 = 4;
+const LINE_STATUS_REGISTER: u16 = 5;
+
+// the UART's clock divided by 16, the divisor is taken from this
+const MAX_BAUD_RATE: u32 = 115200;
+const DIVISOR_LATCH_ACCESS: u8 = 0x80;
+// 8 data bits, no parity, 1 stop bit
+const EIGHT_N_ONE: u8 = 0x03;
+// enable and clear both FIFOs, interrupt when 14 bytes are waiting
+const FIFO_ENABLE_CLEAR_14: u8 = 0xC7;
+// DTR, RTS and OUT2, the latter gates the UART's interrupt line
+const MODEM_READY: u8 = 0x0B;
+// like MODEM_READY but looped back to the receiver, to see whether a UART is there at all
+const MODEM_LOOPBACK: u8 = 0x1E;
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+const TRANSMIT_EMPTY: u8 = 0x20;
+const DATA_READY: u8 = 0x01;
+const RECEIVED_DATA_INTERRUPT: u8 = 0x01;
+// bytes received but not read yet, more are dropped
+const RECEIVE_BUFFER_SIZE: usize = 256;
+
+lazy_static! {
+    /** The first serial port, set up for 115200 baud 8N1 on first use.
+    * With QEMU's -serial stdio, whatever is written to it shows up in the terminal running QEMU.
+    */
+    pub static ref SERIAL1 : IrqMutex<SerialPort> = IrqMutex::new({
+        let mut port = SerialPort::new(COM1);
+        let _ = port.init(DEFAULT_BAUD_RATE);
+        port
+    });
+}
+
+/**
+ * Bytes received on COM1, filled by its interrupt handler.
+ */
+static RECEIVED: IrqMutex<ReceiveBuffer> = IrqMutex::new(ReceiveBuffer {
+    bytes: [0; RECEIVE_BUFFER_SIZE],
+    start: 0,
+    len: 0,
+    dropped: 0
+});
+
+struct ReceiveBuffer {
+    bytes: [u8; RECEIVE_BUFFER_SIZE],
+    // index of the oldest byte
+    start: usize,
+    len: usize,
+    dropped: usize
+}
+
+impl ReceiveBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.len == RECEIVE_BUFFER_SIZE {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes[(self.start + self.len) % RECEIVE_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % RECEIVE_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    // nothing answered the loopback test
+    NotPresent,
+    InvalidBaudRate(u32)
+}
+
+/**
+ * A 16550 compatible UART, driven by polling.
+ */
+pub struct SerialPort {
+    base: u16,
+    // set by a successful init(), sending to a missing UART would wait forever for its transmitter
+    present: bool
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort {
+            base,
+            present: false
+        }
+    }
+
+    /**
+     * Sets the UART up for the given baud rate with 8N1 framing and the FIFOs enabled, with its interrupts turned off.
+     * Checks that the UART is there by sending a byte to itself in loopback mode.
+     */
+    pub fn init(&mut self, baud_rate: u32) -> Result<(), SerialError> {
+        if baud_rate == 0 || baud_rate > MAX_BAUD_RATE || MAX_BAUD_RATE % baud_rate != 0 {
+            return Err(SerialError::InvalidBaudRate(baud_rate));
+        }
+        let divisor = (MAX_BAUD_RATE / baud_rate) as u16;
+
+        self.write_register(INTERRUPT_ENABLE_REGISTER, 0x00);
+        self.write_register(LINE_CONTROL_REGISTER, DIVISOR_LATCH_ACCESS);
+        self.write_register(DATA_REGISTER, (divisor & 0xFF) as u8);
+        self.write_register(INTERRUPT_ENABLE_REGISTER, (divisor >> 8) as u8);
+        self.write_register(LINE_CONTROL_REGISTER, EIGHT_N_ONE);
+        self.write_register(FIFO_CONTROL_REGISTER, FIFO_ENABLE_CLEAR_14);
+
+        self.write_register(MODEM_CONTROL_REGISTER, MODEM_LOOPBACK);
+        self.write_register(DATA_REGISTER, LOOPBACK_TEST_BYTE);
+        self.present = self.read_register(DATA_REGISTER) == LOOPBACK_TEST_BYTE;
+        self.write_register(MODEM_CONTROL_REGISTER, MODEM_READY);
+
+        if self.present {
+            Ok(())
+        } else {
+            Err(SerialError::NotPresent)
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /**
+     * Waits until the transmitter can take a byte, then sends it. Does nothing if the UART is missing.
+     */
+    pub fn send(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        while self.read_register(LINE_STATUS_REGISTER) & TRANSMIT_EMPTY == 0 {}
+        self.write_register(DATA_REGISTER, byte);
+    }
+
+    /**
+     * Returns a received byte if one is waiting, without blocking.
+     */
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.present && self.read_register(LINE_STATUS_REGISTER) & DATA_READY != 0 {
+            Some(self.read_register(DATA_REGISTER))
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Makes the UART raise its interrupt whenever a byte arrives.
+     */
+    pub fn enable_receive_interrupt(&mut self) {
+        if self.present {
+            self.write_register(INTERRUPT_ENABLE_REGISTER, RECEIVED_DATA_INTERRUPT);
+        }
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.read() }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.write(value) }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for byte in text.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Sets up COM1, reporting whether there is one, and starts receiving on it through IRQ4.
+ */
+pub fn init() -> Result<(), SerialError> {
+    let mut port = SERIAL1.lock();
+    port.init(DEFAULT_BAUD_RATE)?;
+    port.enable_receive_interrupt();
+    crate::interrupts::unmask_irq(crate::interrupts::COM1_IRQ);
+    Ok(())
+}
+
+/**
+ * Returns the next byte received on COM1, if there is one.
+ */
+pub fn read_byte() -> Option<u8> {
+    RECEIVED.lock().pop()
+}
+
+/**
+ * Waits for the next byte received on COM1. The CPU is halted in the meantime.
+ * Interrupts must be enabled, or this never returns.
+ */
+pub fn wait_byte() -> u8 {
+    loop {
+        if let Some(byte) = read_byte() {
+            return byte;
+        }
+        // a byte arriving right before the hlt is only noticed at the next interrupt, a timer tick at the latest
+        x86_64::instructions::hlt();
+    }
+}
+
+/**
+ * Returns how many received bytes were lost because nobody read them in time.
+ */
+pub fn dropped_bytes() -> usize {
+    RECEIVED.lock().dropped
+}
+
+/**
+ * Moves the bytes waiting in the UART to the receive buffer, called by the COM1 interrupt handler.
+ */
+pub(crate) fn receive_pending() {
+    let mut port = SERIAL1.lock();
+    let mut received = RECEIVED.lock();
+    while let Some(byte) = port.try_receive() {
+        received.push(byte);
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    SERIAL1.lock().write_fmt(args).unwrap();
+}
@@ -0,0 +1,404 @@
+use crate::process::{self, Pid, ProcessError};
+use crate::scheduler;
+use crate::syscall::Registers;
+use crate::syscall_abi::{SIGCHLD, SIGCONT, SIGKILL, SIGNAL_EXIT_BASE, SIGSEGV};
+use crate::user;
+use crate::user_access::{self, UserAccessError, USER_END};
+use crate::warn;
+use core::convert::TryInto;
+use core::mem;
+use core::slice;
+use x86_64::VirtAddr;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/**
+ * Signal numbers go from 1 up to below this, a bit each in a u32.
+ */
+pub const SIGNAL_COUNT: usize = 32;
+// the bytes under the interrupted stack pointer the System V ABI lets functions use without moving it
+const RED_ZONE: u64 = 128;
+const STACK_ALIGNMENT: u64 = 16;
+// a handler's frame on the user stack: the restorer it returns into, then what sigreturn() reads back, the interrupted
+// registers, the blocked signals from before and the signal's number
+const FRAME_SIZE: usize = 8 + mem::size_of::<Registers>() + 16;
+const BLOCKED_OFFSET: usize = mem::size_of::<Registers>();
+const SIGNAL_OFFSET: usize = BLOCKED_OFFSET + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    // not a signal's number, or SIGKILL, whose action can't be changed
+    InvalidSignal,
+    // a handler or its restorer outside user memory
+    BadAddress,
+    // sigreturn() found no frame it could read at the stack pointer
+    BadFrame,
+    Process(ProcessError)
+}
+
+impl From<ProcessError> for SignalError {
+    fn from(error: ProcessError) -> SignalError {
+        SignalError::Process(error)
+    }
+}
+
+/**
+ * What a signal does to a process once delivered.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // ends the process, or nothing for the signals ignored by default
+    Default,
+    Ignore,
+    Handler(Handler)
+}
+
+/**
+ * A function of the process that handles a signal, called with the signal's number, and the code it returns into,
+ * which calls sigreturn().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handler {
+    pub entry: VirtAddr,
+    pub restorer: VirtAddr
+}
+
+/**
+ * The signals of a process: which are pending, which are blocked, and what each does.
+ * A fork()ed child gets a copy, but for the pending ones.
+ */
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    // raised but not delivered yet, a bit per signal number
+    pending: u32,
+    // not delivered while set, a handler's signal is while it runs
+    blocked: u32,
+    actions: [Action; SIGNAL_COUNT]
+}
+
+impl SignalState {
+    pub fn new() -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; SIGNAL_COUNT]
+        }
+    }
+
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u32 {
+        self.blocked
+    }
+
+    /**
+     * Returns what a signal does, the default action for numbers that are no signal's.
+     */
+    pub fn action(&self, signal: u64) -> Action {
+        self.actions.get(signal as usize).copied().unwrap_or(Action::Default)
+    }
+
+    /**
+     * Records a signal sent to the process. Returns whether it ends the process right away: SIGKILL always does,
+     * as does any other signal left to its default action of ending it, unless blocked. Ignored signals are dropped,
+     * the rest stay pending until a thread of the process returns to user code, see deliver().
+     */
+    pub fn raise(&mut self, signal: u64) -> bool {
+        if !is_signal(signal) {
+            return false;
+        }
+        if signal == SIGKILL {
+            return true;
+        }
+        match self.action(signal) {
+            Action::Ignore => false,
+            Action::Default if ignored_by_default(signal) => false,
+            Action::Default if self.blocked & bit(signal) == 0 => true,
+            _ => {
+                self.pending |= bit(signal);
+                false
+            }
+        }
+    }
+
+    /**
+     * Sets what a signal does, returns what it did. A pending signal that is ignored now is dropped.
+     */
+    pub fn set_action(&mut self, signal: u64, action: Action) -> Result<Action, SignalError> {
+        if !is_signal(signal) || signal == SIGKILL {
+            return Err(SignalError::InvalidSignal);
+        }
+        let old = mem::replace(&mut self.actions[signal as usize], action);
+        let ignored = match action {
+            Action::Ignore => true,
+            Action::Default => ignored_by_default(signal),
+            Action::Handler(_) => false
+        };
+        if ignored {
+            self.pending &= !bit(signal);
+        }
+        Ok(old)
+    }
+
+    /**
+     * Returns the copy a fork()ed child starts with: the same actions and blocked signals, none pending.
+     */
+    pub fn fork(&self) -> SignalState {
+        SignalState {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /**
+     * Resets the handlers for exec(), as they are gone with the old program: their signals get the default action
+     * back. Ignored signals stay ignored.
+     */
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let Action::Handler(_) = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    /**
+     * Takes the lowest pending signal that is not blocked and does something, dropping those that are ignored
+     * meanwhile. Returns it with its action, and the blocked signals from before, see handle().
+     */
+    fn take(&mut self) -> Option<(u64, Action, u32)> {
+        loop {
+            let deliverable = self.pending & !self.blocked;
+            if deliverable == 0 {
+                return None;
+            }
+            let signal = u64::from(deliverable.trailing_zeros());
+            self.pending &= !bit(signal);
+            match self.action(signal) {
+                Action::Ignore => continue,
+                Action::Default if ignored_by_default(signal) => continue,
+                Action::Default => return Some((signal, Action::Default, self.blocked)),
+                action => return Some((signal, action, self.handle(signal)))
+            }
+        }
+    }
+
+    /**
+     * Blocks a signal while its handler runs, returns the blocked signals from before, for sigreturn() to restore.
+     */
+    fn handle(&mut self, signal: u64) -> u32 {
+        let blocked = self.blocked;
+        self.blocked |= bit(signal);
+        blocked
+    }
+
+    /**
+     * Restores the blocked signals as sigreturn() reads them back, SIGKILL can't be among them.
+     */
+    fn restore_blocked(&mut self, blocked: u32) {
+        self.blocked = blocked & !bit(SIGKILL) & !1;
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> SignalState {
+        SignalState::new()
+    }
+}
+
+/**
+ * Sends a signal to a process, see SignalState::raise() for what becomes of it. Signal 0 only checks the process
+ * is there and running. Does not return if the signal ends the calling thread's own process.
+ * A thread blocked in a system call only sees a pending signal once the call returns.
+ */
+pub fn send(pid: Pid, signal: u64) -> Result<(), SignalError> {
+    if signal != 0 && !is_signal(signal) {
+        return Err(SignalError::InvalidSignal);
+    }
+    let own = process::current() == Some(pid);
+    let ends = process::with(pid, |process| {
+        if process.exit_status().is_some() {
+            return Err(ProcessError::Exited);
+        }
+        Ok(signal != 0 && process.signals().raise(signal))
+    }).ok_or(ProcessError::NoSuchProcess)??;
+    if ends {
+        let _ = process::exit(pid, exit_status(signal));
+        if own {
+            scheduler::exit();
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Sets what a signal does to the calling thread's process, returns what it did.
+ */
+pub fn set_action(signal: u64, action: Action) -> Result<Action, SignalError> {
+    if let Action::Handler(handler) = action {
+        if handler.entry.as_u64() >= USER_END || handler.restorer.as_u64() >= USER_END {
+            return Err(SignalError::BadAddress);
+        }
+    }
+    let pid = process::current().ok_or(ProcessError::NotAProcess)?;
+    process::with(pid, |process| process.signals().set_action(signal, action)).ok_or(ProcessError::NoSuchProcess)?
+}
+
+/**
+ * Ends the calling thread's process as killed by the signal, and the thread with it.
+ */
+pub fn terminate(signal: u64) -> ! {
+    if let Some(pid) = process::current() {
+        let _ = process::exit(pid, exit_status(signal));
+    }
+    scheduler::exit();
+}
+
+/**
+ * Returns the exit status of a process killed by the signal.
+ */
+pub fn exit_status(signal: u64) -> i32 {
+    SIGNAL_EXIT_BASE + signal as i32
+}
+
+/**
+ * Delivers a pending signal to the calling thread's process, on its way back to user code with the registers:
+ * a handler gets a frame on the stack and the registers point at it, the default action ends the process.
+ * A process whose stack can't take the frame is killed with SIGSEGV. System calls call it as they return.
+ */
+pub(crate) fn deliver(registers: &mut Registers) {
+    let pid = match process::current() {
+        Some(pid) => pid,
+        None => return
+    };
+    let (signal, action, blocked) = match process::with(pid, |process| process.signals().take()).flatten() {
+        Some(taken) => taken,
+        None => return
+    };
+    match action {
+        Action::Handler(handler) => {
+            if enter_handler(registers, signal, handler, blocked).is_err() {
+                terminate(SIGSEGV);
+            }
+        }
+        // take() skips the ignored ones
+        _ => terminate(signal)
+    }
+}
+
+/**
+ * Goes back to where a handler interrupted the calling thread, for sigreturn(): reads the registers and blocked
+ * signals from the frame at the stack pointer, as the handler's return into the restorer left it.
+ * Only the RFLAGS bits user code may set itself are taken over.
+ */
+pub(crate) fn return_from_handler(registers: &mut Registers) -> Result<(), SignalError> {
+    let pid = process::current().ok_or(ProcessError::NotAProcess)?;
+    let frame = VirtAddr::try_new(registers.rsp).map_err(|_| SignalError::BadFrame)?;
+    // the restorer's address is popped already
+    let mut bytes = [0; FRAME_SIZE - 8];
+    user_access::copy_from_user(&mut bytes, frame).map_err(|_| SignalError::BadFrame)?;
+    let mut saved = Registers::default();
+    unsafe { registers_bytes(&mut saved) }.copy_from_slice(&bytes[..BLOCKED_OFFSET]);
+    // sysretq faults in ring 0 on a non-canonical RIP
+    if saved.rip >= USER_END {
+        return Err(SignalError::BadFrame);
+    }
+    saved.rflags = user::user_rflags(saved.rflags);
+    let blocked = u64::from_le_bytes(bytes[BLOCKED_OFFSET..SIGNAL_OFFSET].try_into().unwrap_or_default());
+    process::with(pid, |process| process.signals().restore_blocked(blocked as u32)).ok_or(ProcessError::NoSuchProcess)?;
+    *registers = saved;
+    Ok(())
+}
+
+/**
+ * Turns an exception in user code into a signal for the calling thread's process, instead of a kernel panic.
+ * If the process handles the signal, the thread goes on in the handler, with the interrupted registers, as the
+ * exception's entry stub saved them, in its frame, so returning from the handler goes back to them.
+ * Blocked, ignored or left to the default action, the signal ends the process, as the faulting instruction would
+ * only fault again.
+ * Returns if the exception came from the kernel, which is a bug. Exception handlers call it first, with GS on the
+ * CPU's block.
+ */
+pub(crate) fn fault(signal: u64, name: &str, registers: &Registers, stack_frame: &InterruptStackFrame) {
+    if !user::from_user(stack_frame) {
+        return;
+    }
+    let pid = process::current();
+    let handler = pid.and_then(|pid| process::with(pid, |process| {
+        let signals = process.signals();
+        match signals.action(signal) {
+            Action::Handler(handler) if signals.blocked() & bit(signal) == 0 => Some((handler, signals.handle(signal))),
+            _ => None
+        }
+    })).flatten();
+    if let Some((handler, blocked)) = handler {
+        let mut registers = *registers;
+        let top = frame_address(registers.rsp);
+        // the frame must not fault, the page fault handler would start over on the stack it runs on
+        if top.map_or(false, |top| user_access::is_resident(top, FRAME_SIZE as u64))
+            && enter_handler(&mut registers, signal, handler, blocked).is_ok()
+        {
+            user::resume(&registers);
+        }
+    }
+    warn!(
+        "{} in user code at {:#x}, in thread {:?}, killing process {:?} with signal {}",
+        name,
+        stack_frame.instruction_pointer.as_u64(),
+        scheduler::current(),
+        pid,
+        signal
+    );
+    terminate(signal);
+}
+
+/**
+ * Pushes a handler's frame under the red zone of the user stack in the registers, and points them at the handler:
+ * the signal's number in RDI, the restorer as the return address, the stack aligned as after a call.
+ */
+fn enter_handler(registers: &mut Registers, signal: u64, handler: Handler, blocked: u32) -> Result<(), UserAccessError> {
+    let top = frame_address(registers.rsp).ok_or(UserAccessError::BadAddress)?;
+    let mut bytes = [0; FRAME_SIZE];
+    bytes[..8].copy_from_slice(&handler.restorer.as_u64().to_le_bytes());
+    bytes[8..8 + BLOCKED_OFFSET].copy_from_slice(unsafe { registers_bytes(registers) });
+    bytes[8 + BLOCKED_OFFSET..8 + SIGNAL_OFFSET].copy_from_slice(&u64::from(blocked).to_le_bytes());
+    bytes[8 + SIGNAL_OFFSET..].copy_from_slice(&signal.to_le_bytes());
+    user_access::copy_to_user(top, &bytes)?;
+    registers.rip = handler.entry.as_u64();
+    registers.rsp = top.as_u64();
+    registers.rdi = signal;
+    // the System V ABI has functions start with the direction flag clear
+    registers.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    Ok(())
+}
+
+/**
+ * Returns where a handler's frame goes under a user stack pointer, None if it doesn't fit in user memory.
+ */
+fn frame_address(stack_pointer: u64) -> Option<VirtAddr> {
+    let top = stack_pointer.checked_sub(RED_ZONE + FRAME_SIZE as u64)? & !(STACK_ALIGNMENT - 1);
+    // the return address is pushed, as by a call from an aligned stack
+    let top = top.checked_sub(8)?;
+    VirtAddr::try_new(top).ok().filter(|top| top.as_u64() + (FRAME_SIZE as u64) <= USER_END)
+}
+
+/**
+ * Returns the registers as bytes, as a frame stores them. They are all u64, any bytes are valid.
+ */
+unsafe fn registers_bytes(registers: &mut Registers) -> &mut [u8] {
+    slice::from_raw_parts_mut(registers as *mut Registers as *mut u8, mem::size_of::<Registers>())
+}
+
+fn is_signal(signal: u64) -> bool {
+    signal > 0 && signal < SIGNAL_COUNT as u64
+}
+
+fn ignored_by_default(signal: u64) -> bool {
+    signal == SIGCHLD || signal == SIGCONT
+}
+
+fn bit(signal: u64) -> u32 {
+    1 << signal
+}
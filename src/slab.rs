@@ -0,0 +1,280 @@
+use crate::frame_allocator;
+use crate::memory::{self, Subsystem};
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr::{self, NonNull};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// each slab is one frame, reached through the mapping of the physical memory, with its header at the start
+const SLAB_SIZE: usize = 4096;
+const MAX_CACHES: usize = 32;
+
+// the caches that have had a slab, for caches()
+static CACHES: IrqMutex<[Option<&'static SlabCache>; MAX_CACHES]> = IrqMutex::new([None; MAX_CACHES]);
+
+/**
+ * A cache of fixed-size objects, e.g. task structs or network buffers, carved out of frames of their own,
+ * so they don't fragment the heap and only contend for the cache's own lock.
+ * Meant to be a static, e.g. `static TASKS: SlabCache = SlabCache::new("task", 256, 16);`.
+ */
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    slabs: IrqMutex<Slabs>
+}
+
+/**
+ * How a cache is doing, see SlabCache::stats().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    // objects handed out and not freed yet
+    pub objects_in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    // allocations that found no free frame for a new slab
+    pub failures: u64
+}
+
+// the header at the start of a slab's frame, its objects follow
+struct Slab {
+    previous: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize
+}
+
+// a free object holds the next free one of its slab
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+/**
+ * The slabs of a cache: those with free objects, those without, and one left over with none in use,
+ * kept so an object going back and forth does not allocate and free a frame every time.
+ */
+struct Slabs {
+    partial: *mut Slab,
+    full: *mut Slab,
+    empty: *mut Slab,
+    count: usize,
+    objects_in_use: usize,
+    allocations: u64,
+    frees: u64,
+    failures: u64
+}
+
+// the slabs are only reached through their cache's lock
+unsafe impl Send for Slabs {}
+
+impl SlabCache {
+    /**
+     * The alignment must be a power of two. Objects too big for a slab next to its header can't be allocated.
+     */
+    pub const fn new(name: &'static str, object_size: usize, align: usize) -> SlabCache {
+        SlabCache {
+            name,
+            object_size,
+            align,
+            slabs: IrqMutex::new(Slabs {
+                partial: ptr::null_mut(),
+                full: ptr::null_mut(),
+                empty: ptr::null_mut(),
+                count: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+                failures: 0
+            })
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /**
+     * Takes a free object, its contents are whatever was left in it. Returns None without a free frame for a new slab.
+     */
+    pub fn allocate(&'static self) -> Option<NonNull<u8>> {
+        let (first, stride, count) = self.layout();
+        let mut slabs = self.slabs.lock();
+        if slabs.partial.is_null() {
+            // the empty slab kept by free() is counted already
+            let slab = if !slabs.empty.is_null() {
+                mem::replace(&mut slabs.empty, ptr::null_mut())
+            } else {
+                let slab = match self.new_slab(first, stride, count) {
+                    Some(slab) => slab,
+                    None => {
+                        slabs.failures += 1;
+                        return None;
+                    }
+                };
+                if slabs.count == 0 {
+                    register(self);
+                }
+                slabs.count += 1;
+                slab
+            };
+            unsafe { push(&mut slabs.partial, slab) };
+        }
+
+        let slab = slabs.partial;
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                unlink(&mut slabs.partial, slab);
+                push(&mut slabs.full, slab);
+            }
+            slabs.objects_in_use += 1;
+            slabs.allocations += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    /**
+     * Gives back an object allocate() handed out. A slab with no objects in use goes back to the frame allocator,
+     * unless it is the only one left over.
+     *
+     * # Safety
+     *
+     * The object must be from this cache and not be used anymore.
+     */
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let (_, _, count) = self.layout();
+        let object = object.as_ptr() as *mut FreeObject;
+        let slab = (object as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let mut slabs = self.slabs.lock();
+        if (*slab).in_use == count {
+            unlink(&mut slabs.full, slab);
+            push(&mut slabs.partial, slab);
+        }
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        slabs.objects_in_use -= 1;
+        slabs.frees += 1;
+
+        if (*slab).in_use == 0 {
+            unlink(&mut slabs.partial, slab);
+            if slabs.empty.is_null() {
+                slabs.empty = slab;
+            } else {
+                slabs.count -= 1;
+                let address = memory::virt_to_phys(VirtAddr::new(slab as u64));
+                // the frame came from allocate_frame() in new_slab()
+                if frame_allocator::free_frame(PhysFrame::containing_address(address)).is_ok() {
+                    memory::frames_freed(Subsystem::Slab, 1);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (_, _, objects_per_slab) = self.layout();
+        let slabs = self.slabs.lock();
+        CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            objects_per_slab,
+            slabs: slabs.count,
+            objects_in_use: slabs.objects_in_use,
+            allocations: slabs.allocations,
+            frees: slabs.frees,
+            failures: slabs.failures
+        }
+    }
+
+    /**
+     * Returns where the first object is in a slab, how far apart the objects are, and how many fit.
+     */
+    fn layout(&self) -> (usize, usize, usize) {
+        let align = self.align.max(mem::align_of::<Slab>());
+        let first = align_up(mem::size_of::<Slab>(), align);
+        let stride = align_up(self.object_size.max(mem::size_of::<FreeObject>()), align);
+        let count = SLAB_SIZE.saturating_sub(first) / stride;
+        (first, stride, count)
+    }
+
+    /**
+     * Takes a frame and chains all of its objects into the free list.
+     */
+    fn new_slab(&self, first: usize, stride: usize, count: usize) -> Option<*mut Slab> {
+        if count == 0 {
+            return None;
+        }
+        let frame = frame_allocator::allocate_frame()?;
+        memory::frames_taken(Subsystem::Slab, 1);
+        let base = memory::phys_to_virt(frame.start_address()).as_u64() as usize;
+        let slab = base as *mut Slab;
+        unsafe {
+            let mut free = ptr::null_mut();
+            for index in (0..count).rev() {
+                let object = (base + first + index * stride) as *mut FreeObject;
+                object.write(FreeObject { next: free });
+                free = object;
+            }
+            slab.write(Slab {
+                previous: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0
+            });
+        }
+        Some(slab)
+    }
+}
+
+/**
+ * Returns the statistics of every cache that has had a slab.
+ */
+pub fn caches() -> Vec<CacheStats> {
+    // copied out, so no cache's lock is taken under CACHES'
+    let caches = *CACHES.lock();
+    caches.iter().filter_map(|cache| cache.map(SlabCache::stats)).collect()
+}
+
+fn register(cache: &'static SlabCache) {
+    let mut caches = CACHES.lock();
+    if caches.iter().any(|entry| entry.map_or(false, |entry| ptr::eq(entry, cache))) {
+        return;
+    }
+    if let Some(entry) = caches.iter_mut().find(|entry| entry.is_none()) {
+        *entry = Some(cache);
+    }
+}
+
+unsafe fn push(list: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).previous = ptr::null_mut();
+    (*slab).next = *list;
+    if !list.is_null() {
+        (**list).previous = slab;
+    }
+    *list = slab;
+}
+
+unsafe fn unlink(list: &mut *mut Slab, slab: *mut Slab) {
+    let (previous, next) = ((*slab).previous, (*slab).next);
+    if previous.is_null() {
+        *list = next;
+    } else {
+        (*previous).next = next;
+    }
+    if !next.is_null() {
+        (*next).previous = previous;
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
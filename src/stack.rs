@@ -0,0 +1,131 @@
+use crate::frame_allocator;
+use crate::kaslr::{self, Region};
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, PagingError};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+// the stacks get virtual addresses of their own, far from the heap, and are never moved or reused,
+// the range moves up by the KASLR slide
+const STACKS_START: u64 = 0x_5555_0000_0000;
+const STACKS_SIZE: u64 = 0x_0001_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+// where the next stack's guard page goes, from the start of the range
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    // there are no free frames left for the stack
+    NoMemory,
+    // the stacks' virtual address range is used up
+    NoAddressSpace,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for StackError {
+    fn from(error: PagingError) -> StackError {
+        StackError::Paging(error)
+    }
+}
+
+/**
+ * A kernel stack, with an unmapped guard page right below it, so running off its bottom page faults
+ * at an address is_guard_page() recognizes, instead of overwriting whatever is there.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack {
+    bottom: VirtAddr,
+    top: VirtAddr
+}
+
+impl Stack {
+    /**
+     * Returns where the stack starts, it grows down from there.
+     */
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+}
+
+/**
+ * Maps a stack of the given number of 4 KiB pages on fresh frames, above a guard page. Needs paging::init().
+ */
+pub fn allocate(pages: u64) -> Result<Stack, StackError> {
+    let size = (pages + 1) * PAGE_SIZE;
+    let offset = NEXT.fetch_add(size, Ordering::Relaxed);
+    if offset + size > STACKS_SIZE {
+        return Err(StackError::NoAddressSpace);
+    }
+    let guard = start() + offset;
+    let bottom = VirtAddr::new(guard + PAGE_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let start = Page::containing_address(bottom);
+    for (mapped, page) in Page::range(start, start + pages).enumerate() {
+        if let Err(error) = map_fresh(page, flags) {
+            // the pages mapped so far go back, the addresses stay taken
+            let _ = unmap_range(start, start + mapped as u64);
+            return Err(error);
+        }
+    }
+    Ok(Stack {
+        bottom,
+        top: bottom + pages * PAGE_SIZE
+    })
+}
+
+/**
+ * Maps a page of a stack on a fresh frame, which goes back to the allocator if the mapping fails.
+ */
+fn map_fresh(page: Page, flags: PageTableFlags) -> Result<(), StackError> {
+    let frame = frame_allocator::allocate_frame().ok_or(StackError::NoMemory)?;
+    // the frame is fresh from the allocator
+    if let Err(error) = unsafe { paging::map_to(page, frame, flags) } {
+        let _ = frame_allocator::free_frame(frame);
+        return Err(error.into());
+    }
+    memory::frames_taken(Subsystem::Stacks, 1);
+    Ok(())
+}
+
+/**
+ * Unmaps a stack and frees its frames. Its addresses are not handed out again, so a stale pointer into it faults.
+ *
+ * # Safety
+ *
+ * Nothing may run on the stack anymore.
+ */
+pub unsafe fn free(stack: Stack) -> Result<(), StackError> {
+    unmap_range(Page::containing_address(stack.bottom), Page::containing_address(stack.top))
+}
+
+/**
+ * Unmaps the pages from start up to end and frees their frames, which came from map_fresh().
+ */
+fn unmap_range(start: Page, end: Page) -> Result<(), StackError> {
+    for page in Page::range(start, end) {
+        if frame_allocator::free_frame(paging::unmap(page)?).is_ok() {
+            memory::frames_freed(Subsystem::Stacks, 1);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Tells whether a page fault at the given address, on a page that was not present, ran into a stack's guard page,
+ * i.e. off the bottom of a stack. Takes no locks, so it is safe in the fault handlers.
+ */
+pub fn is_guard_page(address: VirtAddr) -> bool {
+    // everything unmapped in the range is a guard page or a freed stack
+    let start = start();
+    (start..start + NEXT.load(Ordering::Relaxed).min(STACKS_SIZE)).contains(&address.as_u64())
+}
+
+fn start() -> u64 {
+    STACKS_START + kaslr::slide(Region::Stacks)
+}
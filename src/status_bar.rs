@@ -0,0 +1,139 @@
+use crate::memory;
+use crate::sync::IrqMutex;
+use crate::vga_buffer::{ColorCode, Colors, ScreenChar, DISPLAY};
+use core::fmt::{self, Write};
+
+/**
+ * Number of display rows the status bar takes at the top of the screen, the writers use the rows below it.
+ */
+pub const ROWS: usize = 1;
+
+const STATUS_COLOR: ColorCode = ColorCode::new(Colors::Black, Colors::LightGray);
+// wide enough for every text mode
+const MAX_COLUMNS: usize = 80;
+
+/**
+ * What the status bar shows, kept up to date by the timer interrupt, the keyboard and the terminal switching.
+ */
+static STATUS: IrqMutex<Status> = IrqMutex::new(Status {
+    uptime: 0,
+    memory_used: 0,
+    terminal: 0,
+    caps_lock: false,
+    num_lock: false,
+    scroll_lock: false
+});
+
+struct Status {
+    // seconds
+    uptime: u64,
+    // percent of the usable memory
+    memory_used: u64,
+    terminal: usize,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool
+}
+
+/**
+ * Shows the given virtual terminal as the active one.
+ */
+pub fn set_terminal(terminal: usize) {
+    update(|status| status.terminal = terminal);
+}
+
+pub fn set_lock_states(caps_lock: bool, num_lock: bool, scroll_lock: bool) {
+    update(|status| {
+        status.caps_lock = caps_lock;
+        status.num_lock = num_lock;
+        status.scroll_lock = scroll_lock;
+    });
+}
+
+/**
+ * Shows the given uptime in seconds, the timer interrupt calls it on every tick. Only redraws when the uptime changed,
+ * along with how much memory is in use then.
+ */
+pub fn set_uptime(seconds: u64) {
+    let mut status = STATUS.lock();
+    if status.uptime != seconds {
+        status.uptime = seconds;
+        status.memory_used = memory::stats().used_percent();
+        draw(&status);
+    }
+}
+
+/**
+ * Draws the status bar again, e.g. after something else was drawn over it.
+ */
+pub fn redraw() {
+    update(|_| {});
+}
+
+fn update<F: FnOnce(&mut Status)>(change: F) {
+    let mut status = STATUS.lock();
+    change(&mut status);
+    draw(&status);
+}
+
+fn draw(status: &Status) {
+    let mut left = Line::new();
+    let _ = write!(left, " VT{}  uptime: {}s  mem: {}%", status.terminal + 1, status.uptime, status.memory_used);
+
+    let mut right = Line::new();
+    for &(on, name) in [(status.caps_lock, "CAPS"), (status.num_lock, "NUM"), (status.scroll_lock, "SCROLL")].iter() {
+        if on {
+            let _ = write!(right, " {}", name);
+        }
+    }
+    let _ = right.write_str(" ");
+
+    let mut display = DISPLAY.lock();
+    let (width, _) = display.dimensions();
+    let width = width.min(MAX_COLUMNS);
+    let right_start = width.saturating_sub(right.len);
+    for col in 0..width {
+        let byte = if col >= right_start {
+            right.bytes[col - right_start]
+        } else if col < left.len {
+            left.bytes[col]
+        } else {
+            b' '
+        };
+        display.put_char(0, col, ScreenChar {
+            ascii_char: byte,
+            color_code: STATUS_COLOR
+        });
+    }
+    display.flush();
+}
+
+/**
+ * A line of ASCII text formatted in place, whatever does not fit is cut off.
+ */
+struct Line {
+    bytes: [u8; MAX_COLUMNS],
+    len: usize
+}
+
+impl Line {
+    fn new() -> Line {
+        Line {
+            bytes: [b' '; MAX_COLUMNS],
+            len: 0
+        }
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for byte in text.bytes() {
+            if self.len == MAX_COLUMNS {
+                break;
+            }
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/**
+ * A spinlock that keeps interrupts disabled while it is held.
+ * With a plain spinlock, an interrupt handler taking a lock the interrupted code already holds spins forever.
+ * Every lock shared with interrupt handlers should be one of these.
+ * Interrupts are restored to their previous state when the guard is dropped, so locks can be nested.
+ */
+pub struct IrqMutex<T> {
+    inner: Mutex<T>
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    // dropped before the interrupts are restored, fields are dropped in declaration order
+    guard: MutexGuard<'a, T>,
+    _interrupts: RestoreInterrupts
+}
+
+struct RestoreInterrupts(bool);
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex {
+            inner: Mutex::new(value)
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts = RestoreInterrupts::disable();
+        IrqMutexGuard {
+            guard: self.inner.lock(),
+            _interrupts: interrupts
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts = RestoreInterrupts::disable();
+        self.inner.try_lock().map(|guard| IrqMutexGuard {
+            guard,
+            _interrupts: interrupts
+        })
+    }
+
+    /**
+     * Releases the lock no matter who holds it.
+     *
+     * # Safety
+     *
+     * Only meant for code that will never return to the holder, like the panic handler.
+     */
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl RestoreInterrupts {
+    fn disable() -> RestoreInterrupts {
+        let enabled = interrupts::are_enabled();
+        if enabled {
+            interrupts::disable();
+        }
+        RestoreInterrupts(enabled)
+    }
+}
+
+impl Drop for RestoreInterrupts {
+    fn drop(&mut self) {
+        if self.0 {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
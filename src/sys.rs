@@ -0,0 +1,634 @@
+use crate::elf::ElfError;
+use crate::heap;
+use crate::ipc::{self, IpcError, Message, PortId, MESSAGE_DATA_SIZE};
+use crate::print;
+use crate::pipe::{self, PIPE_CAPACITY};
+use crate::process::{self, Descriptor, Pid, ProcessError};
+use crate::program;
+use crate::scheduler;
+use crate::signal::{self, Action, Handler, SignalError};
+use crate::syscall::{self, Registers, SyscallError};
+use crate::syscall_abi::{self, DIRENT_INODE_OFFSET, DIRENT_NAME_OFFSET, DIRENT_SIZE, DIRENT_TYPE_OFFSET, DT_CHR,
+    DT_DIR, DT_REG, E2BIG, EAGAIN, EBADF, EBUSY, ECHILD, EEXIST, EFAULT, EINVAL, EIO, EISDIR, EMFILE, ENAMETOOLONG,
+    ENOENT, ENOEXEC, ENOMEM, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EPIPE, EROFS, ESRCH, IPC_NOWAIT, MESSAGE_DATA_OFFSET,
+    MESSAGE_HANDLE_OFFSET, MESSAGE_SENDER_OFFSET, MESSAGE_SIZE, NO_HANDLE, O_ACCMODE, O_APPEND, O_CREAT, O_RDONLY,
+    O_RDWR, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_END, SEEK_SET, SIGPIPE, SIGSEGV, SIG_DFL, SIG_IGN, SYS_CLOSE, SYS_DUP2,
+    SYS_EXEC, SYS_EXIT, SYS_FORK, SYS_KILL, SYS_LSEEK, SYS_OPEN, SYS_PIPE, SYS_PORT_CLOSE, SYS_PORT_CREATE,
+    SYS_PORT_LOOKUP, SYS_READ, SYS_READDIR, SYS_RECEIVE, SYS_SEND, SYS_SIGACTION, SYS_SIGRETURN, SYS_SLEEP,
+    SYS_WAITPID, SYS_WRITE, WNOHANG};
+use crate::time;
+use crate::user;
+use crate::user_access::{self, UserAccessError};
+use crate::vfs::{self, FileType, OpenOptions, SeekFrom, VfsError};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::mem;
+use x86_64::VirtAddr;
+
+// a longer write or file read is cut short, the program does the rest with another
+const MAX_WRITE: u64 = 64 * 1024;
+const MAX_READ: u64 = 64 * 1024;
+// exec()'s limits, its arguments have to fit the new program's stack
+const MAX_PATH: usize = 256;
+const MAX_ARGUMENTS: usize = 32;
+const MAX_ARGUMENT_LENGTH: usize = 256;
+// a file exec() runs is read onto the heap whole, a bigger one fails with E2BIG
+const MAX_EXECUTABLE_SIZE: usize = heap::HEAP_SIZE as usize / 2;
+const MAX_PORT_NAME: usize = 64;
+// waitpid()'s PID for any child
+const ANY_CHILD: i64 = -1;
+
+/**
+ * Registers the system calls' handlers under the numbers in syscall_abi.
+ */
+pub fn init() -> Result<(), SyscallError> {
+    syscall::register(SYS_EXIT, sys_exit)?;
+    syscall::register(SYS_WRITE, sys_write)?;
+    syscall::register(SYS_SLEEP, sys_sleep)?;
+    syscall::register(SYS_FORK, sys_fork)?;
+    syscall::register(SYS_EXEC, sys_exec)?;
+    syscall::register(SYS_WAITPID, sys_waitpid)?;
+    syscall::register(SYS_PORT_CREATE, sys_port_create)?;
+    syscall::register(SYS_PORT_LOOKUP, sys_port_lookup)?;
+    syscall::register(SYS_SEND, sys_send)?;
+    syscall::register(SYS_RECEIVE, sys_receive)?;
+    syscall::register(SYS_PORT_CLOSE, sys_port_close)?;
+    syscall::register(SYS_READ, sys_read)?;
+    syscall::register(SYS_PIPE, sys_pipe)?;
+    syscall::register(SYS_CLOSE, sys_close)?;
+    syscall::register(SYS_DUP2, sys_dup2)?;
+    syscall::register(SYS_KILL, sys_kill)?;
+    syscall::register(SYS_SIGACTION, sys_sigaction)?;
+    syscall::register(SYS_SIGRETURN, sys_sigreturn)?;
+    syscall::register(SYS_OPEN, sys_open)?;
+    syscall::register(SYS_LSEEK, sys_lseek)?;
+    syscall::register(SYS_READDIR, sys_readdir)?;
+    Ok(())
+}
+
+/**
+ * exit(status): ends the calling thread's process with the status, and the thread with it.
+ */
+fn sys_exit(registers: &mut Registers) -> u64 {
+    if let Some(pid) = process::current() {
+        let _ = process::exit(pid, registers.rdi as i32);
+    }
+    scheduler::exit();
+}
+
+/**
+ * write(fd, buffer, length): the console prints the buffer on the screen, a pipe takes all of it, blocking while full,
+ * a file what its file system can. A broken pipe raises SIGPIPE as well.
+ */
+fn sys_write(registers: &mut Registers) -> u64 {
+    result(write(registers.rdi, registers.rsi, registers.rdx.min(MAX_WRITE)))
+}
+
+fn write(fd: u64, buffer: u64, length: u64) -> Result<u64, u64> {
+    let descriptor = descriptor(fd)?;
+    let mut bytes = vec![0; length as usize];
+    user_access::copy_from_user(&mut bytes, user_pointer(buffer)?).map_err(|_| EFAULT)?;
+    match descriptor {
+        Descriptor::Console => print!("{}", String::from_utf8_lossy(&bytes)),
+        Descriptor::PipeWriter(writer) => {
+            if writer.write(&bytes).is_err() {
+                // ends the process, unless handled or ignored, and then the write fails
+                if let Some(pid) = process::current() {
+                    let _ = signal::send(pid, SIGPIPE);
+                }
+                return Err(EPIPE);
+            }
+        }
+        Descriptor::File(file) => return Ok(vfs::write(&*file, &bytes).map_err(vfs_error)? as u64),
+        Descriptor::PipeReader(_) => return Err(EBADF)
+    }
+    Ok(length)
+}
+
+/**
+ * read(fd, buffer, length): a pipe gives what it has, up to its capacity at a time, a file from its position on.
+ * The console can't be read yet.
+ */
+fn sys_read(registers: &mut Registers) -> u64 {
+    result(read(registers.rdi, registers.rsi, registers.rdx))
+}
+
+fn read(fd: u64, buffer: u64, length: u64) -> Result<u64, u64> {
+    let descriptor = descriptor(fd)?;
+    let buffer = user_pointer(buffer)?;
+    let limit = match descriptor {
+        Descriptor::PipeReader(_) => PIPE_CAPACITY as u64,
+        _ => MAX_READ
+    };
+    let mut bytes = vec![0; length.min(limit) as usize];
+    let count = match descriptor {
+        Descriptor::PipeReader(reader) => reader.read(&mut bytes),
+        Descriptor::File(file) => vfs::read(&*file, &mut bytes).map_err(vfs_error)?,
+        _ => return Err(EBADF)
+    };
+    // what was read is lost if it can't be stored
+    user_access::copy_to_user(buffer, &bytes[..count]).map_err(|_| EFAULT)?;
+    Ok(count as u64)
+}
+
+/**
+ * pipe(fds)
+ */
+fn sys_pipe(registers: &mut Registers) -> u64 {
+    result(make_pipe(registers.rdi))
+}
+
+fn make_pipe(fds: u64) -> Result<u64, u64> {
+    let destination = user_pointer(fds)?;
+    let pid = process::current().ok_or(EINVAL)?;
+    let (reader, writer) = pipe::pipe();
+    let opened = process::with(pid, |process| {
+        let files = process.files();
+        let read_fd = files.insert(Descriptor::PipeReader(reader))?;
+        match files.insert(Descriptor::PipeWriter(writer)) {
+            Some(write_fd) => Some((read_fd, write_fd)),
+            None => {
+                files.remove(read_fd);
+                None
+            }
+        }
+    }).ok_or(EINVAL)?;
+    let (read_fd, write_fd) = opened.ok_or(EMFILE)?;
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+    if user_access::copy_to_user(destination, &bytes).is_err() {
+        let _ = process::with(pid, |process| {
+            process.files().remove(read_fd);
+            process.files().remove(write_fd);
+        });
+        return Err(EFAULT);
+    }
+    Ok(0)
+}
+
+/**
+ * open(path, flags)
+ */
+fn sys_open(registers: &mut Registers) -> u64 {
+    result(open(registers.rdi, registers.rsi))
+}
+
+fn open(path: u64, flags: u64) -> Result<u64, u64> {
+    if flags & !(O_ACCMODE | O_CREAT | O_TRUNC | O_APPEND) != 0 {
+        return Err(EINVAL);
+    }
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(EINVAL)
+    };
+    let options = OpenOptions {
+        read,
+        write,
+        create: flags & O_CREAT != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0
+    };
+    let path = read_string(path, MAX_PATH)?;
+    let pid = process::current().ok_or(EINVAL)?;
+    let file = vfs::open(&path, options).map_err(vfs_error)?;
+    let fd = process::with(pid, |process| process.files().insert(Descriptor::File(file))).ok_or(EINVAL)?;
+    fd.map(|fd| fd as u64).ok_or(EMFILE)
+}
+
+/**
+ * lseek(fd, offset, whence)
+ */
+fn sys_lseek(registers: &mut Registers) -> u64 {
+    result(lseek(registers.rdi, registers.rsi as i64, registers.rdx))
+}
+
+fn lseek(fd: u64, offset: i64, whence: u64) -> Result<u64, u64> {
+    let file = match descriptor(fd)? {
+        Descriptor::File(file) => file,
+        // pipes and the console have no position
+        _ => return Err(EINVAL)
+    };
+    let from = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(EINVAL)
+    };
+    vfs::seek(&*file, from).map_err(vfs_error)
+}
+
+/**
+ * readdir(fd, entry)
+ */
+fn sys_readdir(registers: &mut Registers) -> u64 {
+    result(readdir(registers.rdi, registers.rsi))
+}
+
+fn readdir(fd: u64, destination: u64) -> Result<u64, u64> {
+    let file = match descriptor(fd)? {
+        Descriptor::File(file) => file,
+        _ => return Err(ENOTDIR)
+    };
+    let destination = user_pointer(destination)?;
+    let entry = match vfs::readdir(&*file).map_err(vfs_error)? {
+        Some(entry) => entry,
+        None => return Ok(0)
+    };
+    let mut bytes = [0; DIRENT_SIZE];
+    let file_type = match entry.file_type {
+        FileType::Regular => DT_REG,
+        FileType::Directory => DT_DIR,
+        FileType::Device => DT_CHR
+    };
+    bytes[DIRENT_INODE_OFFSET..DIRENT_INODE_OFFSET + 8].copy_from_slice(&entry.inode.to_le_bytes());
+    bytes[DIRENT_TYPE_OFFSET] = file_type;
+    // the VFS keeps names to MAX_NAME bytes, the last byte stays the NUL
+    let name = entry.name.as_bytes();
+    let length = name.len().min(DIRENT_SIZE - DIRENT_NAME_OFFSET - 1);
+    bytes[DIRENT_NAME_OFFSET..DIRENT_NAME_OFFSET + length].copy_from_slice(&name[..length]);
+    // the entry is taken, it is lost if it can't be stored
+    user_access::copy_to_user(destination, &bytes).map_err(|_| EFAULT)?;
+    Ok(1)
+}
+
+/**
+ * close(fd): the last file descriptor on a pipe's end closes the end.
+ */
+fn sys_close(registers: &mut Registers) -> u64 {
+    let fd = registers.rdi;
+    let closed = process::current().and_then(|pid| {
+        process::with(pid, |process| process.files().remove(fd as usize)).flatten()
+    });
+    match closed {
+        // dropped outside the process table's lock, which may wake the other end's waiters
+        Some(descriptor) => {
+            drop(descriptor);
+            0
+        }
+        None => syscall_abi::error(EBADF)
+    }
+}
+
+/**
+ * dup2(fd, new_fd)
+ */
+fn sys_dup2(registers: &mut Registers) -> u64 {
+    result(dup2(registers.rdi, registers.rsi))
+}
+
+fn dup2(fd: u64, new_fd: u64) -> Result<u64, u64> {
+    let descriptor = descriptor(fd)?;
+    let pid = process::current().ok_or(EINVAL)?;
+    let replaced = process::with(pid, |process| process.files().insert_at(new_fd as usize, descriptor)).ok_or(EINVAL)?;
+    // what was open there is closed outside the process table's lock
+    drop(replaced.map_err(|_| EBADF)?);
+    Ok(new_fd)
+}
+
+/**
+ * sleep(ms): blocks the calling thread for at least the given time.
+ */
+fn sys_sleep(registers: &mut Registers) -> u64 {
+    time::sleep_ms(registers.rdi);
+    0
+}
+
+/**
+ * fork(): starts the child with the caller's registers, but for the 0 it returns, in a thread of its own.
+ */
+fn sys_fork(registers: &mut Registers) -> u64 {
+    result(fork(registers))
+}
+
+fn fork(registers: &Registers) -> Result<u64, u64> {
+    let pid = process::current().ok_or(EINVAL)?;
+    let child = process::fork(pid).map_err(process_error)?;
+    let mut child_registers = *registers;
+    child_registers.rax = 0;
+    if let Err(error) = user::resume_in(child, child_registers) {
+        let _ = process::remove(child);
+        return Err(process_error(error));
+    }
+    Ok(child.as_u64())
+}
+
+/**
+ * exec(path, argv): the new program starts when the system call returns, as the registers now point at it.
+ */
+fn sys_exec(registers: &mut Registers) -> u64 {
+    result(exec(registers))
+}
+
+fn exec(registers: &mut Registers) -> Result<u64, u64> {
+    let name = read_string(registers.rdi, MAX_PATH)?;
+    let arguments = read_arguments(registers.rsi)?;
+    // a built in program by its name, or else a file by its absolute path
+    let loaded;
+    let file = match program::find(&name) {
+        Some(file) => file,
+        None if name.starts_with('/') => {
+            // read onto the heap whole, which panics when it runs out
+            let size = vfs::metadata(&name).map_err(vfs_error)?.size;
+            if size > MAX_EXECUTABLE_SIZE as u64 {
+                return Err(E2BIG);
+            }
+            if size >= heap::stats().free as u64 {
+                return Err(ENOMEM);
+            }
+            loaded = vfs::read_all(&name, MAX_EXECUTABLE_SIZE).map_err(|error| match error {
+                // it grew meanwhile
+                VfsError::NoSpace => E2BIG,
+                error => vfs_error(error)
+            })?;
+            &loaded[..]
+        }
+        None => return Err(ENOENT)
+    };
+    let image = process::exec(&name, file).map_err(process_error)?;
+    // the old program is gone, a new one that can't get its arguments can only be killed
+    let stack_pointer = match user::push_arguments(image.stack_pointer, &arguments) {
+        Ok(stack_pointer) => stack_pointer,
+        Err(_) => signal::terminate(SIGSEGV)
+    };
+    user::reset(registers, image.entry, stack_pointer);
+    Ok(0)
+}
+
+/**
+ * waitpid(pid, status, options): reaps the child, and stores its exit status where status points, unless it is NULL.
+ */
+fn sys_waitpid(registers: &mut Registers) -> u64 {
+    result(waitpid(registers.rdi as i64, registers.rsi, registers.rdx))
+}
+
+fn waitpid(child: i64, status: u64, options: u64) -> Result<u64, u64> {
+    let pid = process::current().ok_or(EINVAL)?;
+    let child = match child {
+        ANY_CHILD => None,
+        child if child > 0 => Some(Pid::new(child as u64)),
+        // no process groups
+        _ => return Err(EINVAL)
+    };
+    if options & !WNOHANG != 0 {
+        return Err(EINVAL);
+    }
+    let (child, exit_status) = match process::wait(pid, child, options & WNOHANG == 0).map_err(process_error)? {
+        Some(exited) => exited,
+        None => return Ok(0)
+    };
+    if status != 0 {
+        user_access::copy_to_user(user_pointer(status)?, &exit_status.to_le_bytes()).map_err(|_| EFAULT)?;
+    }
+    Ok(child.as_u64())
+}
+
+/**
+ * port_create(name): the port belongs to the calling process, and is closed when it exits.
+ */
+fn sys_port_create(registers: &mut Registers) -> u64 {
+    result(port_create(registers.rdi))
+}
+
+fn port_create(name: u64) -> Result<u64, u64> {
+    let name = match name {
+        0 => None,
+        name => Some(read_string(name, MAX_PORT_NAME)?)
+    };
+    let port = ipc::create(name.as_ref().map(String::as_str)).map_err(ipc_error)?;
+    Ok(port.as_u64())
+}
+
+/**
+ * port_lookup(name)
+ */
+fn sys_port_lookup(registers: &mut Registers) -> u64 {
+    result(read_string(registers.rdi, MAX_PORT_NAME).and_then(|name| {
+        ipc::lookup(&name).map(PortId::as_u64).ok_or(ENOENT)
+    }))
+}
+
+/**
+ * send(port, message)
+ */
+fn sys_send(registers: &mut Registers) -> u64 {
+    result(send(PortId::new(registers.rdi), registers.rsi))
+}
+
+fn send(port: PortId, message: u64) -> Result<u64, u64> {
+    let mut bytes = [0; MESSAGE_SIZE];
+    user_access::copy_from_user(&mut bytes, user_pointer(message)?).map_err(|_| EFAULT)?;
+    let mut data = [0; MESSAGE_DATA_SIZE];
+    data.copy_from_slice(&bytes[MESSAGE_DATA_OFFSET..MESSAGE_DATA_OFFSET + MESSAGE_DATA_SIZE]);
+    let mut message = Message::new(data);
+    message.handle = match u64_at(&bytes, MESSAGE_HANDLE_OFFSET) {
+        NO_HANDLE => None,
+        handle => Some(PortId::new(handle))
+    };
+    ipc::send(port, message).map_err(ipc_error)?;
+    Ok(0)
+}
+
+/**
+ * receive(port, message, options)
+ */
+fn sys_receive(registers: &mut Registers) -> u64 {
+    result(receive(PortId::new(registers.rdi), registers.rsi, registers.rdx))
+}
+
+fn receive(port: PortId, destination: u64, options: u64) -> Result<u64, u64> {
+    if options & !IPC_NOWAIT != 0 {
+        return Err(EINVAL);
+    }
+    let destination = user_pointer(destination)?;
+    let message = if options & IPC_NOWAIT != 0 {
+        ipc::try_receive(port).map_err(ipc_error)?.ok_or(EAGAIN)?
+    } else {
+        ipc::receive(port).map_err(ipc_error)?
+    };
+    let mut bytes = [0; MESSAGE_SIZE];
+    let sender = message.sender.map_or(0, Pid::as_u64);
+    let handle = message.handle.map_or(NO_HANDLE, PortId::as_u64);
+    bytes[MESSAGE_SENDER_OFFSET..MESSAGE_SENDER_OFFSET + 8].copy_from_slice(&sender.to_le_bytes());
+    bytes[MESSAGE_HANDLE_OFFSET..MESSAGE_HANDLE_OFFSET + 8].copy_from_slice(&handle.to_le_bytes());
+    bytes[MESSAGE_DATA_OFFSET..MESSAGE_DATA_OFFSET + MESSAGE_DATA_SIZE].copy_from_slice(&message.data);
+    // the message is taken, it is lost if it can't be stored
+    user_access::copy_to_user(destination, &bytes).map_err(|_| EFAULT)?;
+    Ok(0)
+}
+
+/**
+ * port_close(port)
+ */
+fn sys_port_close(registers: &mut Registers) -> u64 {
+    result(ipc::close(PortId::new(registers.rdi)).map(|_| 0).map_err(ipc_error))
+}
+
+/**
+ * kill(pid, signal)
+ */
+fn sys_kill(registers: &mut Registers) -> u64 {
+    result(kill(registers.rdi as i64, registers.rsi))
+}
+
+fn kill(pid: i64, signal: u64) -> Result<u64, u64> {
+    // no process groups
+    if pid <= 0 {
+        return Err(EINVAL);
+    }
+    signal::send(Pid::new(pid as u64), signal).map_err(signal_error)?;
+    Ok(0)
+}
+
+/**
+ * sigaction(signal, handler, restorer)
+ */
+fn sys_sigaction(registers: &mut Registers) -> u64 {
+    result(sigaction(registers.rdi, registers.rsi, registers.rdx))
+}
+
+fn sigaction(number: u64, handler: u64, restorer: u64) -> Result<u64, u64> {
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        // a handler with nowhere to return to
+        _ if restorer == 0 => return Err(EINVAL),
+        entry => Action::Handler(Handler {
+            entry: user_pointer(entry)?,
+            restorer: user_pointer(restorer)?
+        })
+    };
+    match signal::set_action(number, action).map_err(signal_error)? {
+        Action::Default => Ok(SIG_DFL),
+        Action::Ignore => Ok(SIG_IGN),
+        Action::Handler(handler) => Ok(handler.entry.as_u64())
+    }
+}
+
+/**
+ * sigreturn(): returns the interrupted RAX, as the registers are the interrupted ones now. A process whose handler's
+ * frame is gone is killed with SIGSEGV.
+ */
+fn sys_sigreturn(registers: &mut Registers) -> u64 {
+    if signal::return_from_handler(registers).is_err() {
+        signal::terminate(SIGSEGV);
+    }
+    registers.rax
+}
+
+/**
+ * Returns what a file descriptor of the calling process refers to, a copy, so it can block without the process
+ * table locked.
+ */
+fn descriptor(fd: u64) -> Result<Descriptor, u64> {
+    let pid = process::current().ok_or(EBADF)?;
+    process::with(pid, |process| process.files().get(fd as usize).cloned()).flatten().ok_or(EBADF)
+}
+
+/**
+ * Copies in a NUL terminated string of at most the given length.
+ */
+fn read_string(address: u64, max_length: usize) -> Result<String, u64> {
+    let mut buffer = vec![0; max_length + 1];
+    let length = user_access::strncpy_from_user(&mut buffer, user_pointer(address)?).map_err(|error| match error {
+        UserAccessError::TooLong => ENAMETOOLONG,
+        _ => EFAULT
+    })?;
+    buffer.truncate(length);
+    String::from_utf8(buffer).map_err(|_| EINVAL)
+}
+
+/**
+ * Copies in a NULL terminated array of pointers to strings, an argv.
+ */
+fn read_arguments(address: u64) -> Result<Vec<String>, u64> {
+    let mut arguments = Vec::new();
+    loop {
+        let pointer_address = address.checked_add((arguments.len() * mem::size_of::<u64>()) as u64).ok_or(EFAULT)?;
+        let mut pointer = [0; 8];
+        user_access::copy_from_user(&mut pointer, user_pointer(pointer_address)?).map_err(|_| EFAULT)?;
+        let pointer = u64::from_le_bytes(pointer);
+        if pointer == 0 {
+            return Ok(arguments);
+        }
+        if arguments.len() == MAX_ARGUMENTS {
+            return Err(E2BIG);
+        }
+        let argument = read_string(pointer, MAX_ARGUMENT_LENGTH).map_err(|error| match error {
+            ENAMETOOLONG => E2BIG,
+            error => error
+        })?;
+        arguments.push(argument);
+    }
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+/**
+ * Takes a pointer a program passed, which may be anything, even outside the canonical address ranges.
+ */
+fn user_pointer(address: u64) -> Result<VirtAddr, u64> {
+    VirtAddr::try_new(address).map_err(|_| EFAULT)
+}
+
+/**
+ * Turns a handler's result into what the system call returns, see syscall_abi::error().
+ */
+fn result(result: Result<u64, u64>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(number) => syscall_abi::error(number)
+    }
+}
+
+fn process_error(error: ProcessError) -> u64 {
+    match error {
+        ProcessError::NoSuchProcess | ProcessError::Exited => ESRCH,
+        ProcessError::Busy => EBUSY,
+        ProcessError::NotAProcess => EINVAL,
+        ProcessError::NoChildren => ECHILD,
+        ProcessError::Elf(ElfError::NoMemory) | ProcessError::Paging(_) => ENOMEM,
+        ProcessError::Elf(_) => ENOEXEC,
+        ProcessError::Scheduler(_) => EAGAIN
+    }
+}
+
+fn signal_error(error: SignalError) -> u64 {
+    match error {
+        SignalError::InvalidSignal => EINVAL,
+        SignalError::BadAddress | SignalError::BadFrame => EFAULT,
+        SignalError::Process(error) => process_error(error)
+    }
+}
+
+fn vfs_error(error: VfsError) -> u64 {
+    match error {
+        VfsError::NotFound | VfsError::NotMounted => ENOENT,
+        VfsError::NotADirectory => ENOTDIR,
+        VfsError::IsADirectory => EISDIR,
+        VfsError::AlreadyExists => EEXIST,
+        VfsError::InvalidPath | VfsError::InvalidSeek | VfsError::NotSupported => EINVAL,
+        VfsError::NotEmpty => ENOTEMPTY,
+        VfsError::ReadOnly => EROFS,
+        VfsError::AccessDenied => EBADF,
+        VfsError::NoSpace => ENOSPC,
+        VfsError::Busy => EBUSY,
+        VfsError::Io => EIO
+    }
+}
+
+fn ipc_error(error: IpcError) -> u64 {
+    match error {
+        IpcError::NoSuchPort => EBADF,
+        IpcError::NameTaken => EEXIST,
+        IpcError::NotOwner => EPERM,
+        IpcError::Full => EAGAIN,
+        IpcError::Closed => EPIPE
+    }
+}
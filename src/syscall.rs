@@ -0,0 +1,269 @@
+use crate::gdt::{self, GdtError};
+use crate::sync::IrqMutex;
+use crate::percpu;
+use crate::signal;
+use crate::syscall_abi::{self, ENOSYS};
+use core::sync::atomic::Ordering;
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::HandlerFunc;
+
+// the MSRs syscall takes its target from: the code selectors, the entry point, and the RFLAGS bits it clears
+const STAR_MSR: u32 = 0xC000_0081;
+const LSTAR_MSR: u32 = 0xC000_0082;
+const SFMASK_MSR: u32 = 0xC000_0084;
+// STAR: syscall loads CS from the first selector and SS 8 above it,
+// sysret loads SS 8 above the second and CS 16 above it, see the GDT's layout
+const STAR_SYSCALL_SHIFT: u64 = 32;
+const STAR_SYSRET_SHIFT: u64 = 48;
+const SELECTOR_SIZE: u16 = 8;
+
+const MAX_SYSCALLS: usize = 64;
+/**
+ * What a system call returns when there is no handler for its number.
+ */
+pub const NO_SYSCALL: u64 = syscall_abi::error(ENOSYS);
+
+/**
+ * Runs a system call, its number and arguments are in the caller's registers, see Registers::arguments().
+ * The return value goes back to the program in RAX. The other registers go back as the handler leaves them.
+ */
+pub type Handler = fn(&mut Registers) -> u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    Gdt(GdtError),
+    InvalidNumber(usize),
+    AlreadyRegistered(usize)
+}
+
+impl From<GdtError> for SyscallError {
+    fn from(error: GdtError) -> SyscallError {
+        SyscallError::Gdt(error)
+    }
+}
+
+static HANDLERS: IrqMutex<[Option<Handler>; MAX_SYSCALLS]> = IrqMutex::new([None; MAX_SYSCALLS]);
+
+/**
+ * The user registers the entry stubs save, in the order they push them, so the last one pushed comes first.
+ * They are all restored on the way back, where the user code goes on and with what stack, too.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    // syscall leaves RIP and RFLAGS in them, so they are lost for it, int 0x80 keeps them
+    pub rcx: u64,
+    pub r11: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64
+}
+
+impl Registers {
+    /**
+     * Returns a system call's six arguments.
+     */
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/**
+ * Enables the syscall instruction on the calling CPU, see syscall_abi for the calling convention.
+ * Needs gdt::init() and percpu::init() on this CPU first.
+ */
+pub fn init() -> Result<(), SyscallError> {
+    let kernel_stack = gdt::kernel_stack_top()?;
+    percpu::block().kernel_stack.store(kernel_stack.as_u64(), Ordering::Relaxed);
+
+    let kernel_code = u64::from(gdt::kernel_code_selector().0);
+    // the user data selector, with the user code selector right above it
+    let user_base = u64::from(gdt::user_data_selector().0 - SELECTOR_SIZE);
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+        Msr::new(STAR_MSR).write(kernel_code << STAR_SYSCALL_SHIFT | user_base << STAR_SYSRET_SHIFT);
+        let entry: unsafe extern "C" fn() = syscall_entry;
+        Msr::new(LSTAR_MSR).write(entry as usize as u64);
+        // the handler starts with interrupts off until it is on the kernel stack, and with a sane direction flag
+        let mask = RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK;
+        Msr::new(SFMASK_MSR).write(mask.bits());
+    }
+    Ok(())
+}
+
+/**
+ * Makes a system call number run the given handler.
+ */
+pub fn register(number: usize, handler: Handler) -> Result<(), SyscallError> {
+    let mut handlers = HANDLERS.lock();
+    let slot = handlers.get_mut(number).ok_or(SyscallError::InvalidNumber(number))?;
+    if slot.is_some() {
+        return Err(SyscallError::AlreadyRegistered(number));
+    }
+    *slot = Some(handler);
+    Ok(())
+}
+
+/**
+ * The entry of int 0x80, for the IDT. It takes the same registers as syscall, and runs the same handlers,
+ * for programs that can't use the instruction. The IDT entry must allow ring 3.
+ */
+pub(crate) fn interrupt_entry() -> HandlerFunc {
+    // the stub returns with iretq, like an x86-interrupt function, and takes no arguments but the stack frame
+    unsafe { core::mem::transmute::<unsafe extern "C" fn(), HandlerFunc>(syscall_interrupt_entry) }
+}
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+}
+
+/**
+ * Called by the entry stubs on the kernel stack, with interrupts enabled again. A pending signal is delivered
+ * before the registers go back, see signal::deliver().
+ */
+#[no_mangle]
+extern "C" fn syscall_dispatch(registers: &mut Registers) {
+    let number = registers.rax as usize;
+    // copied, so a handler may register others
+    let handler = HANDLERS.lock().get(number).copied().flatten();
+    registers.rax = match handler {
+        Some(handler) => handler(registers),
+        None => NO_SYSCALL
+    };
+    // on the way back to user code
+    signal::deliver(registers);
+}
+
+// syscall leaves the user RSP alone, so the stub switches to the kernel stack, kept in the CPU's block, before anything
+// is pushed, see percpu::CpuBlock. GS stays on the block until the way back out.
+// The eighteen pushes keep the stack 16 byte aligned for the call, the kernel stack's top is.
+global_asm!("
+.att_syntax prefix
+.global syscall_entry
+syscall_entry:
+    swapgs
+    movq %rsp, %gs:16
+    movq %gs:8, %rsp
+    pushq %gs:16
+    pushq %r11
+    pushq %rcx
+    pushq %r11
+    pushq %rcx
+    pushq %rax
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %r10
+    pushq %r8
+    pushq %r9
+    pushq %rbx
+    pushq %rbp
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    sti
+    movq %rsp, %rdi
+    call syscall_dispatch
+    cli
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbp
+    popq %rbx
+    popq %r9
+    popq %r8
+    popq %r10
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rax
+    addq $16, %rsp
+    popq %rcx
+    popq %r11
+    popq %rsp
+    swapgs
+    sysretq
+");
+
+// int 0x80 arrives on the thread's kernel stack through the TSS' RSP0, with the CPU's interrupt frame on it,
+// so the stub copies the frame's RSP, RFLAGS and RIP into the syscall stub's layout, and back again on the way out,
+// and swaps GS when it came from ring 3. The frame's five words and the padding keep the stack 16 byte aligned.
+// The direction flag is cleared as SFMASK does for syscall, the caller may have set it, and Rust code and the rep movsb
+// of user_access assume it clear.
+global_asm!("
+.att_syntax prefix
+.global syscall_interrupt_entry
+syscall_interrupt_entry:
+    testb $3, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    cld
+    subq $8, %rsp
+    pushq 32(%rsp)
+    pushq 32(%rsp)
+    pushq 24(%rsp)
+    pushq %r11
+    pushq %rcx
+    pushq %rax
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %r10
+    pushq %r8
+    pushq %r9
+    pushq %rbx
+    pushq %rbp
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    sti
+    movq %rsp, %rdi
+    call syscall_dispatch
+    cli
+    movq 120(%rsp), %rax
+    movq %rax, 152(%rsp)
+    movq 128(%rsp), %rax
+    movq %rax, 168(%rsp)
+    movq 136(%rsp), %rax
+    movq %rax, 176(%rsp)
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbp
+    popq %rbx
+    popq %r9
+    popq %r8
+    popq %r10
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rax
+    popq %rcx
+    popq %r11
+    addq $32, %rsp
+    testb $3, 8(%rsp)
+    jz 2f
+    swapgs
+2:
+    iretq
+");
@@ -0,0 +1,173 @@
+// The system call ABI, shared with user programs, so it depends on nothing else of the kernel.
+//
+// A program puts the system call number in RAX and up to six arguments in RDI, RSI, RDX, R10, R8 and R9, as on
+// Linux, then runs syscall, or int 0x80 where that is not available. The result comes back in RAX: a value
+// from 0 up on success, an error number negated on failure, see is_error(). The syscall instruction overwrites
+// RCX and R11, int 0x80 leaves them alone, every other register is preserved either way.
+
+// the vector of the int 0x80 entry
+pub const INT_VECTOR: u8 = 0x80;
+
+// exit(status) ends the calling process with the status, all its threads, it does not return
+pub const SYS_EXIT: usize = 0;
+// write(fd, buffer, length) writes to a file descriptor, returns how many bytes it wrote; a process starts with
+// standard input (0), output (1) and error (2) on the console
+pub const SYS_WRITE: usize = 1;
+// sleep(ms) blocks the calling thread for at least the given number of milliseconds, returns 0
+pub const SYS_SLEEP: usize = 2;
+// fork() starts a copy of the calling process, with a copy-on-write copy of its memory and the calling thread's
+// registers, returns the child's PID, and 0 in the child
+pub const SYS_FORK: usize = 3;
+// exec(path, argv) replaces the calling process' program with the one by that name, and its argv, a NULL terminated
+// array of strings; the process keeps its PID and must have no other threads, does not return on success
+pub const SYS_EXEC: usize = 4;
+// waitpid(pid, status, options) waits for the child with the PID, or any for -1, to exit, stores its exit status
+// as an i32 where status points unless it is NULL, and returns its PID, or 0 with WNOHANG if none exited yet
+pub const SYS_WAITPID: usize = 5;
+
+// port_create(name) makes a port the calling process owns, named by the NUL terminated string, or unnamed for NULL,
+// returns its ID
+pub const SYS_PORT_CREATE: usize = 6;
+// port_lookup(name) returns the ID of the port with the name
+pub const SYS_PORT_LOOKUP: usize = 7;
+// send(port, message) queues the message on the port without waiting, returns 0; a handle in it hands the caller's
+// port over to the receiver
+pub const SYS_SEND: usize = 8;
+// receive(port, message, options) stores the oldest message of a port the calling process owns where message points,
+// waiting for one unless options has IPC_NOWAIT, returns 0
+pub const SYS_RECEIVE: usize = 9;
+// port_close(port) closes a port the calling process owns, returns 0
+pub const SYS_PORT_CLOSE: usize = 10;
+// read(fd, buffer, length) reads up to length bytes from a file descriptor, waiting until there are any, returns how
+// many it read, 0 at the end of the file
+pub const SYS_READ: usize = 11;
+// pipe(fds) makes a pipe, stores the file descriptors of its read and write ends as two i32 where fds points,
+// returns 0; reads return 0 once all write ends are closed, writes fail with EPIPE once all read ends are
+pub const SYS_PIPE: usize = 12;
+// close(fd) closes a file descriptor, returns 0
+pub const SYS_CLOSE: usize = 13;
+// dup2(fd, new_fd) opens what fd refers to on new_fd as well, closing what was open there, returns new_fd
+pub const SYS_DUP2: usize = 14;
+// kill(pid, signal) sends a signal to the process with the PID, returns 0; signal 0 only checks it is there
+pub const SYS_KILL: usize = 15;
+// sigaction(signal, handler, restorer) sets what a signal does to the calling process: SIG_DFL, SIG_IGN, or a handler
+// called as handler(signal) on the interrupted stack, which returns into restorer, code that calls sigreturn() with
+// the stack as the handler left it; returns the previous handler, SIG_DFL or SIG_IGN
+pub const SYS_SIGACTION: usize = 16;
+// sigreturn() goes back to where a signal handler interrupted the process, with the registers it had, does not return
+pub const SYS_SIGRETURN: usize = 17;
+// open(path, flags) opens the file at the absolute path, returns its file descriptor; flags has one of O_RDONLY,
+// O_WRONLY and O_RDWR, and any of O_CREAT, O_TRUNC and O_APPEND
+pub const SYS_OPEN: usize = 18;
+// lseek(fd, offset, whence) moves an open file's position to the offset from SEEK_SET, SEEK_CUR or SEEK_END,
+// returns the new position
+pub const SYS_LSEEK: usize = 19;
+// readdir(fd, entry) stores the next entry of an open directory where entry points, returns 1, or 0 after the last
+pub const SYS_READDIR: usize = 20;
+
+// waitpid()'s option not to wait
+pub const WNOHANG: u64 = 1;
+// receive()'s option not to wait
+pub const IPC_NOWAIT: u64 = 1;
+
+// a message, MESSAGE_SIZE bytes: the sender's PID, which send() fills in, at MESSAGE_SENDER_OFFSET,
+// the handed over port at MESSAGE_HANDLE_OFFSET, NO_HANDLE for none, both u64, and the data at MESSAGE_DATA_OFFSET
+pub const MESSAGE_SIZE: usize = 64;
+pub const MESSAGE_SENDER_OFFSET: usize = 0;
+pub const MESSAGE_HANDLE_OFFSET: usize = 8;
+pub const MESSAGE_DATA_OFFSET: usize = 16;
+pub const NO_HANDLE: u64 = 0;
+
+// open()'s flags, the same as Linux's
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0x40;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+// lseek()'s whence
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// a directory entry, DIRENT_SIZE bytes: the inode number, a u64, at DIRENT_INODE_OFFSET, the type, a u8 of
+// DT_REG, DT_DIR or DT_CHR, at DIRENT_TYPE_OFFSET, and the NUL terminated name at DIRENT_NAME_OFFSET
+pub const DIRENT_SIZE: usize = 272;
+pub const DIRENT_INODE_OFFSET: usize = 0;
+pub const DIRENT_TYPE_OFFSET: usize = 8;
+pub const DIRENT_NAME_OFFSET: usize = 16;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+// sigaction()'s handlers for the signal's default action, and for ignoring it
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+// the signal numbers, the same as Linux's, up to 31; SIGCHLD and SIGCONT are ignored by default, the others end the
+// process, which exits with status SIGNAL_EXIT_BASE plus the signal's number, as shells report it
+pub const SIGHUP: u64 = 1;
+pub const SIGINT: u64 = 2;
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGTRAP: u64 = 5;
+pub const SIGABRT: u64 = 6;
+pub const SIGBUS: u64 = 7;
+pub const SIGFPE: u64 = 8;
+// can't be handled, ignored or blocked
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGPIPE: u64 = 13;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+// the error numbers, the same as Linux's
+pub const ENOENT: u64 = 2;
+pub const EPERM: u64 = 1;
+pub const ESRCH: u64 = 3;
+pub const EIO: u64 = 5;
+pub const E2BIG: u64 = 7;
+pub const ENOEXEC: u64 = 8;
+pub const EBADF: u64 = 9;
+pub const ECHILD: u64 = 10;
+pub const EAGAIN: u64 = 11;
+pub const ENOMEM: u64 = 12;
+pub const EFAULT: u64 = 14;
+pub const EBUSY: u64 = 16;
+pub const EEXIST: u64 = 17;
+pub const ENOTDIR: u64 = 20;
+pub const EISDIR: u64 = 21;
+pub const EINVAL: u64 = 22;
+pub const EMFILE: u64 = 24;
+pub const ENOSPC: u64 = 28;
+pub const EROFS: u64 = 30;
+pub const EPIPE: u64 = 32;
+pub const ENAMETOOLONG: u64 = 36;
+pub const ENOSYS: u64 = 38;
+pub const ENOTEMPTY: u64 = 39;
+// a result above this, seen as signed, is an error
+const MAX_ERROR: u64 = 4095;
+
+/**
+ * Returns the result of a system call failing with the given error number.
+ */
+pub const fn error(number: u64) -> u64 {
+    number.wrapping_neg()
+}
+
+/**
+ * Tells whether a system call's result is an error, and which one.
+ */
+pub fn is_error(result: u64) -> Option<u64> {
+    let number = result.wrapping_neg();
+    if number != 0 && number <= MAX_ERROR {
+        Some(number)
+    } else {
+        None
+    }
+}
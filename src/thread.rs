@@ -0,0 +1,105 @@
+use crate::scheduler::{self, Priority, SchedulerError, ThreadId};
+use crate::sync::IrqMutex;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+/**
+ * What a spawned thread runs, boxed once more for a thin pointer to pass through scheduler::spawn().
+ */
+type Main = Box<dyn FnOnce() + Send>;
+
+/**
+ * Owns a thread started with spawn(), to wait for it and take what it returned. Dropping it detaches the thread,
+ * which runs on regardless.
+ */
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    // None until the thread returns
+    result: Arc<IrqMutex<Option<T>>>
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /**
+     * Tells whether the thread has returned, so join() would not wait.
+     */
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().is_some()
+    }
+
+    /**
+     * Waits for the thread to return, yielding the CPU meanwhile, and returns what it returned.
+     */
+    pub fn join(self) -> T {
+        loop {
+            if let Some(result) = self.result.lock().take() {
+                return result;
+            }
+            yield_now();
+        }
+    }
+}
+
+/**
+ * Runs the closure in a new kernel thread, on a stack of its own, e.g. for background work off the boot path.
+ * The thread is queued behind the ready ones and gets the CPU at a timer tick or a yield_now().
+ * Needs scheduler::init() on the calling CPU.
+ */
+pub fn spawn<F, T>(main: F) -> Result<JoinHandle<T>, SchedulerError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static
+{
+    spawn_with_priority(Priority::default(), main)
+}
+
+/**
+ * Like spawn(), with a priority other than the normal one.
+ */
+pub fn spawn_with_priority<F, T>(priority: Priority, main: F) -> Result<JoinHandle<T>, SchedulerError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static
+{
+    let result = Arc::new(IrqMutex::new(None));
+    let thread_result = result.clone();
+    let main: Main = Box::new(move || {
+        let value = main();
+        *thread_result.lock() = Some(value);
+    });
+    let main = Box::into_raw(Box::new(main));
+    match scheduler::spawn(run, main as usize, priority) {
+        Ok(id) => Ok(JoinHandle { id, result }),
+        Err(error) => {
+            // the thread never started, the closure is still ours
+            drop(unsafe { Box::from_raw(main) });
+            Err(error)
+        }
+    }
+}
+
+/**
+ * Gives the CPU to the next ready thread of at least the calling thread's priority, if there is one.
+ * The calling thread goes to the back of its queue.
+ */
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+/**
+ * Returns the calling thread's ID, None before scheduler::init().
+ */
+pub fn current() -> Option<ThreadId> {
+    scheduler::current()
+}
+
+/**
+ * The entry of every spawned thread, the argument is the Main spawn() boxed.
+ */
+fn run(main: usize) {
+    let main = unsafe { Box::from_raw(main as *mut Main) };
+    main();
+}
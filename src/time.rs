@@ -0,0 +1,486 @@
+use crate::apic::{self, ApicError, TimerMode};
+use crate::hpet::{self, HpetError};
+use crate::interrupts;
+use crate::rtc;
+use crate::wait_queue::WaitQueue;
+use crate::warn;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+// the 8253/8254 PIT's input clock in Hz, divided down for the timer interrupt
+pub(crate) const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// channel 0, divisor sent low byte first, mode 2 (rate generator), binary counting
+const CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+// channel 2, count sent low byte first, mode 0 (output goes high when the count runs out), binary counting
+const CHANNEL_2_ONE_SHOT: u8 = 0xB0;
+// channel 2 is the PC speaker's, its gate and output are in the system control port
+const SYSTEM_CONTROL_PORT: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 0x01;
+const SPEAKER_DATA: u8 = 0x02;
+const CHANNEL_2_OUTPUT: u8 = 0x20;
+// the longest count channel 2 can run down at once
+const MAX_ONE_SHOT_COUNT: u64 = 0xFFFF;
+// the largest divisor, written as 0; the firmware leaves the PIT at it, about 18.2 Hz
+const MAX_DIVISOR: u32 = 65536;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// the TSC and the APIC timer are timed over this many PIT counts, about 10 ms, the TSC a few times to leave out the slowest runs
+pub(crate) const CALIBRATION_COUNT: u16 = 11932;
+const CALIBRATION_ROUNDS: usize = 3;
+// CPUID: leaf 1 EDX tells whether there is a TSC, leaf 0x8000_0007 EDX whether it runs at a constant rate
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_TSC: u32 = 1 << 4;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+const SECONDS_PER_DAY: u64 = 86400;
+// the Gregorian calendar repeats every 400 years, and 1970-01-01 is this many days after 0000-03-01
+const DAYS_PER_ERA: u64 = 146_097;
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+
+/**
+ * The timer interrupt frequency set up at boot.
+ */
+pub const DEFAULT_TIMER_FREQUENCY: u32 = 100;
+
+// timer interrupts since the interrupts were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// the threads in sleep_ms(), woken at every tick to check their deadline
+static SLEEPERS: WaitQueue = WaitQueue::new();
+// the time since the interrupts were enabled, summed up tick by tick so changing the frequency keeps it right
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(MAX_DIVISOR as u64 * NANOS_PER_SECOND / PIT_FREQUENCY as u64);
+// the TSC's rate, 0 until it is calibrated, and its value at that point
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+// what now_ns() returned just before the TSC was calibrated, so it doesn't jump back
+static TSC_START_NANOS: AtomicU64 = AtomicU64::new(0);
+static INVARIANT_TSC: AtomicBool = AtomicBool::new(false);
+// what drives the timer interrupt
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+
+/**
+ * What raises the timer interrupt.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Pit,
+    Hpet,
+    // the local APIC's timer, straight to the CPU without going through the PICs
+    ApicTimer
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    // the PIT can only divide its clock by 1 to 65536
+    InvalidFrequency(u32),
+    Hpet(HpetError),
+    Apic(ApicError),
+    // the CPU has no time stamp counter
+    NoTsc
+}
+
+/**
+ * A point in monotonic time, from now_ns(), for measuring how long something took or waiting until a deadline.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(now_ns())
+    }
+
+    /**
+     * Returns the time since the earlier instant, zero if it is not earlier.
+     */
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        nanos(duration).and_then(|nanos| self.0.checked_add(nanos)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        nanos(duration).and_then(|nanos| self.0.checked_sub(nanos)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("instant overflowed")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("instant went before boot")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/**
+ * A calendar date and time, as kept by the real-time clock, usually the local time.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+impl DateTime {
+    /**
+     * Returns the current date and time, counted on from the RTC's reading at boot by the monotonic clock,
+     * so it doesn't have to wait for the RTC. None if the RTC could not be read.
+     */
+    pub fn now() -> Option<DateTime> {
+        let (boot_time, read_at) = rtc::anchor()?;
+        Some(boot_time + read_at.elapsed())
+    }
+
+    /**
+     * Returns the seconds since 1970-01-01 00:00:00.
+     */
+    pub fn seconds_since_epoch(&self) -> u64 {
+        let days = days_from_civil(u64::from(self.year), u64::from(self.month), u64::from(self.day));
+        days * SECONDS_PER_DAY + u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second)
+    }
+
+    pub fn from_seconds_since_epoch(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl Add<Duration> for DateTime {
+    type Output = DateTime;
+
+    fn add(self, duration: Duration) -> DateTime {
+        DateTime::from_seconds_since_epoch(self.seconds_since_epoch() + duration.as_secs())
+    }
+}
+
+impl Sub<Duration> for DateTime {
+    type Output = DateTime;
+
+    fn sub(self, duration: Duration) -> DateTime {
+        DateTime::from_seconds_since_epoch(self.seconds_since_epoch().saturating_sub(duration.as_secs()))
+    }
+}
+
+impl Sub<DateTime> for DateTime {
+    type Output = Duration;
+
+    /**
+     * Returns the time between the two, zero if the other one is later.
+     */
+    fn sub(self, earlier: DateTime) -> Duration {
+        Duration::from_secs(self.seconds_since_epoch().saturating_sub(earlier.seconds_since_epoch()))
+    }
+}
+
+/**
+ * Sets the timer interrupt up at the default frequency, raised by the local APIC's timer if apic::init() enabled it,
+ * by the HPET if hpet::init() found one, by the PIT otherwise.
+ */
+pub fn init() -> Result<(), TimeError> {
+    let sources = [
+        (ClockSource::ApicTimer, apic::is_available()),
+        (ClockSource::Hpet, hpet::is_available()),
+        (ClockSource::Pit, true)
+    ];
+    let mut result = Ok(());
+    for &(source, _) in sources.iter().filter(|&&(_, available)| available) {
+        CLOCK_SOURCE.store(source as u8, Ordering::Relaxed);
+        result = set_frequency(DEFAULT_TIMER_FREQUENCY);
+        if result.is_ok() {
+            break;
+        }
+    }
+    // the PIT keeps raising IRQ0 whatever it is set to, it only has to be kept out when nothing else uses the line
+    if clock_source() == ClockSource::ApicTimer {
+        interrupts::mask_irq(interrupts::TIMER_IRQ);
+    }
+    result
+}
+
+/**
+ * Returns what raises the timer interrupt.
+ */
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        source if source == ClockSource::Hpet as u8 => ClockSource::Hpet,
+        source if source == ClockSource::ApicTimer as u8 => ClockSource::ApicTimer,
+        _ => ClockSource::Pit
+    }
+}
+
+/**
+ * Reprograms the timer to interrupt the given number of times a second, as close as it gets.
+ * With the PIT anything from 19 Hz up to its clock works, though a few hundred to a thousand is sensible.
+ */
+pub fn set_frequency(frequency: u32) -> Result<(), TimeError> {
+    if frequency == 0 || frequency > PIT_FREQUENCY {
+        return Err(TimeError::InvalidFrequency(frequency));
+    }
+    let period = NANOS_PER_SECOND / u64::from(frequency);
+    match clock_source() {
+        ClockSource::Hpet => {
+            let tick_nanos = x86_64::instructions::interrupts::without_interrupts(|| hpet::start_periodic(period))
+                .map_err(TimeError::Hpet)?;
+            TICK_NANOS.store(tick_nanos, Ordering::Relaxed);
+            return Ok(());
+        }
+        ClockSource::ApicTimer => {
+            let tick_nanos = x86_64::instructions::interrupts::without_interrupts(|| {
+                apic::start_timer(interrupts::APIC_TIMER_VECTOR, period, TimerMode::Periodic)
+            }).map_err(TimeError::Apic)?;
+            TICK_NANOS.store(tick_nanos, Ordering::Relaxed);
+            return Ok(());
+        }
+        ClockSource::Pit => {}
+    }
+    let divisor = (PIT_FREQUENCY + frequency / 2) / frequency;
+    if divisor > MAX_DIVISOR {
+        return Err(TimeError::InvalidFrequency(frequency));
+    }
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    // the two halves of the divisor must not be split by a tick reading the new period
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            command.write(CHANNEL_0_RATE_GENERATOR);
+            channel_0.write(divisor as u8);
+            channel_0.write((divisor >> 8) as u8);
+        }
+        TICK_NANOS.store(u64::from(divisor) * NANOS_PER_SECOND / u64::from(PIT_FREQUENCY), Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/**
+ * Returns the timer interrupt frequency in Hz, rounded.
+ */
+pub fn frequency() -> u32 {
+    let tick_nanos = TICK_NANOS.load(Ordering::Relaxed);
+    ((NANOS_PER_SECOND + tick_nanos / 2) / tick_nanos) as u32
+}
+
+/**
+ * Returns the number of timer interrupts so far.
+ */
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/**
+ * Returns the milliseconds since the interrupts were enabled, as precise as a tick.
+ */
+pub fn uptime_ms() -> u64 {
+    UPTIME_NANOS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/**
+ * Counts a timer interrupt, called by its handler.
+ */
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(TICK_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+    SLEEPERS.wake_all();
+}
+
+/**
+ * Measures the TSC's rate against the PIT, after which now_ns() and busy_wait_us() go by the TSC.
+ * Must run with interrupts disabled, an interrupt during a measurement would make the TSC look faster.
+ */
+pub fn calibrate_tsc() -> Result<(), TimeError> {
+    if unsafe { __cpuid(CPUID_FEATURES) }.edx & CPUID_TSC == 0 {
+        return Err(TimeError::NoTsc);
+    }
+    let invariant = unsafe { __cpuid(CPUID_MAX_EXTENDED) }.eax >= CPUID_POWER_MANAGEMENT
+        && unsafe { __cpuid(CPUID_POWER_MANAGEMENT) }.edx & CPUID_INVARIANT_TSC != 0;
+    INVARIANT_TSC.store(invariant, Ordering::Relaxed);
+    if !invariant {
+        warn!("the TSC is not invariant, it may change its rate with the CPU's power state");
+    }
+
+    let mut cycles = u64::max_value();
+    for _ in 0..CALIBRATION_ROUNDS {
+        let start = read_tsc();
+        count_down(CALIBRATION_COUNT);
+        cycles = cycles.min(read_tsc().wrapping_sub(start));
+    }
+    TSC_START_NANOS.store(now_ns(), Ordering::Relaxed);
+    TSC_START.store(read_tsc(), Ordering::Relaxed);
+    TSC_FREQUENCY.store(cycles * u64::from(PIT_FREQUENCY) / u64::from(CALIBRATION_COUNT), Ordering::Relaxed);
+    Ok(())
+}
+
+/**
+ * Returns the TSC's rate in Hz, 0 if it is not calibrated.
+ */
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/**
+ * Tells whether the TSC runs at the same rate whatever the CPU's power state, so it is a reliable clock.
+ */
+pub fn invariant_tsc() -> bool {
+    INVARIANT_TSC.load(Ordering::Relaxed)
+}
+
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/**
+ * Returns monotonic nanoseconds since boot, with the TSC's resolution once it is calibrated.
+ * Before that, or without a TSC, it goes by the HPET's counter if there is one, by timer ticks otherwise.
+ */
+pub fn now_ns() -> u64 {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return if hpet::is_available() { hpet::nanos() } else { UPTIME_NANOS.load(Ordering::Relaxed) };
+    }
+    let cycles = read_tsc().wrapping_sub(TSC_START.load(Ordering::Relaxed));
+    // 128 bits so it takes centuries to overflow
+    TSC_START_NANOS.load(Ordering::Relaxed) + (u128::from(cycles) * u128::from(NANOS_PER_SECOND) / u128::from(frequency)) as u64
+}
+
+/**
+ * Waits at least the given number of milliseconds, blocking the calling thread, so others run meanwhile.
+ * With interrupts disabled, e.g. during boot, it busy waits instead.
+ */
+pub fn sleep_ms(ms: u64) {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        busy_wait_us(ms * 1000);
+        return;
+    }
+    // a tick may be just about to happen, so the wait starts at the next one
+    let deadline = uptime_ms() + ms + 1;
+    SLEEPERS.wait_until(|| uptime_ms() >= deadline);
+}
+
+/**
+ * Spins for at least the given number of microseconds, timed by the TSC once it is calibrated, by the PIT's channel 2 before.
+ * Works before the timer interrupt is set up and with interrupts disabled, for hardware that needs short delays.
+ */
+pub fn busy_wait_us(us: u64) {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency != 0 {
+        let start = read_tsc();
+        let cycles = us * frequency / 1_000_000;
+        while read_tsc().wrapping_sub(start) < cycles {}
+        return;
+    }
+    let mut count = us * u64::from(PIT_FREQUENCY) / 1_000_000 + 1;
+    while count > 0 {
+        let chunk = count.min(MAX_ONE_SHOT_COUNT);
+        count_down(chunk as u16);
+        count -= chunk;
+    }
+}
+
+/**
+ * Runs channel 2 down from the given count and waits until it reaches zero.
+ */
+pub(crate) fn count_down(count: u16) {
+    let mut control: Port<u8> = Port::new(SYSTEM_CONTROL_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_2: Port<u8> = Port::new(PIT_CHANNEL_2);
+    unsafe {
+        // gate off while loading, the speaker stays silent
+        let gate = control.read() & !(CHANNEL_2_GATE | SPEAKER_DATA);
+        control.write(gate);
+        command.write(CHANNEL_2_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        control.write(gate | CHANNEL_2_GATE);
+        while control.read() & CHANNEL_2_OUTPUT == 0 {}
+        control.write(gate);
+    }
+}
+
+/**
+ * Returns a duration in nanoseconds, if it fits in 64 bits, i.e. is shorter than 584 years.
+ */
+fn nanos(duration: Duration) -> Option<u64> {
+    let nanos = duration.as_nanos();
+    if nanos > u128::from(u64::max_value()) {
+        None
+    } else {
+        Some(nanos as u64)
+    }
+}
+
+/**
+ * Returns the days since 1970-01-01 of a date in the Gregorian calendar, from 1970 on.
+ * Years are counted from March, so the leap day comes last.
+ */
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS
+}
+
+/**
+ * The inverse of days_from_civil(), returns the year, month and day.
+ */
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + UNIX_EPOCH_DAYS;
+    let era = days / DAYS_PER_ERA;
+    let day_of_era = days % DAYS_PER_ERA;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
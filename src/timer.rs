@@ -0,0 +1,213 @@
+use crate::rtc;
+use crate::sync::IrqMutex;
+use crate::time;
+
+// slots of the wheel, one per tick, timers further away than a turn wait in their slot for the right one
+const WHEEL_SIZE: usize = 64;
+// how many timers can be pending at the same time
+const MAX_TIMERS: usize = 32;
+
+/**
+ * Called in kernel context, from run_pending(), with no timer lock held, so it may start or cancel timers.
+ */
+pub type Handler = fn();
+
+/**
+ * Identifies a started timer for cancel(). Stays invalid once the timer has run out or was cancelled,
+ * even if its slot is reused.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    TooManyTimers,
+    // the timer has already run out or was cancelled
+    NotRunning
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    // None while the slot is free
+    handler: Option<Handler>,
+    // the tick it runs at
+    expires: u64,
+    // ticks between runs, 0 for a one-shot timer
+    period: u64,
+    // the next timer in the same wheel slot
+    next: Option<usize>,
+    generation: u32
+}
+
+const FREE_TIMER: Timer = Timer {
+    handler: None,
+    expires: 0,
+    period: 0,
+    next: None,
+    generation: 0
+};
+
+static WHEEL: IrqMutex<Wheel> = IrqMutex::new(Wheel {
+    slots: [None; WHEEL_SIZE],
+    timers: [FREE_TIMER; MAX_TIMERS],
+    processed: 0
+});
+
+struct Wheel {
+    // the first timer of each slot's list, a timer goes in the slot of the tick it expires at
+    slots: [Option<usize>; WHEEL_SIZE],
+    timers: [Timer; MAX_TIMERS],
+    // the last tick whose timers have all been run
+    processed: u64
+}
+
+impl Wheel {
+    fn add(&mut self, expires: u64, period: u64, handler: Handler) -> Result<TimerId, TimerError> {
+        let index = self.timers.iter().position(|timer| timer.handler.is_none()).ok_or(TimerError::TooManyTimers)?;
+        let timer = &mut self.timers[index];
+        timer.handler = Some(handler);
+        timer.expires = expires;
+        timer.period = period;
+        let id = TimerId {
+            index,
+            generation: timer.generation
+        };
+        self.link(index);
+        Ok(id)
+    }
+
+    fn remove(&mut self, id: TimerId) -> Result<(), TimerError> {
+        let timer = self.timers.get(id.index).ok_or(TimerError::NotRunning)?;
+        if timer.handler.is_none() || timer.generation != id.generation {
+            return Err(TimerError::NotRunning);
+        }
+        self.free(id.index);
+        Ok(())
+    }
+
+    fn free(&mut self, index: usize) {
+        self.unlink(index);
+        let timer = &mut self.timers[index];
+        timer.handler = None;
+        timer.generation = timer.generation.wrapping_add(1);
+    }
+
+    fn link(&mut self, index: usize) {
+        let slot = slot_of(self.timers[index].expires);
+        self.timers[index].next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let slot = slot_of(self.timers[index].expires);
+        let mut previous: Option<usize> = None;
+        let mut current = self.slots[slot];
+        while let Some(at) = current {
+            if at == index {
+                let next = self.timers[at].next;
+                match previous {
+                    Some(previous) => self.timers[previous].next = next,
+                    None => self.slots[slot] = next
+                }
+                return;
+            }
+            previous = current;
+            current = self.timers[at].next;
+        }
+    }
+
+    /**
+     * Finds a timer in the given tick's slot that is due by then, those waiting for a later turn stay.
+     */
+    fn find_due(&self, tick: u64) -> Option<usize> {
+        let mut current = self.slots[slot_of(tick)];
+        while let Some(index) = current {
+            if self.timers[index].expires <= tick {
+                return Some(index);
+            }
+            current = self.timers[index].next;
+        }
+        None
+    }
+
+    /**
+     * Takes the next timer due by the given tick off the wheel, a periodic one goes back at its next expiry.
+     */
+    fn take_due(&mut self, now: u64) -> Option<Handler> {
+        // every slot comes up once in the last turn, and a timer found there is due from any earlier turn too
+        self.processed = self.processed.max(now.saturating_sub(WHEEL_SIZE as u64));
+        while self.processed < now {
+            let tick = self.processed + 1;
+            if let Some(index) = self.find_due(tick) {
+                let timer = self.timers[index];
+                if timer.period == 0 {
+                    self.free(index);
+                } else {
+                    self.unlink(index);
+                    // periods missed while nobody ran the timers are skipped instead of run in a burst
+                    let missed = now.saturating_sub(timer.expires) / timer.period;
+                    self.timers[index].expires = timer.expires + (missed + 1) * timer.period;
+                    self.link(index);
+                }
+                return timer.handler;
+            }
+            self.processed = tick;
+        }
+        None
+    }
+}
+
+/**
+ * Runs the handler once, about the given number of milliseconds from now, rounded up to whole timer ticks.
+ */
+pub fn after(ms: u64, handler: Handler) -> Result<TimerId, TimerError> {
+    WHEEL.lock().add(time::ticks() + ms_to_ticks(ms), 0, handler)
+}
+
+/**
+ * Runs the handler every given number of milliseconds, rounded up to whole timer ticks, until it is cancelled.
+ */
+pub fn every(ms: u64, handler: Handler) -> Result<TimerId, TimerError> {
+    let period = ms_to_ticks(ms);
+    WHEEL.lock().add(time::ticks() + period, period, handler)
+}
+
+/**
+ * Stops a timer before it runs (again).
+ */
+pub fn cancel(id: TimerId) -> Result<(), TimerError> {
+    WHEEL.lock().remove(id)
+}
+
+/**
+ * Runs the handlers of the timers that are due, one at a time with the lock released, and that of the RTC alarm if it went off.
+ * The timer interrupt only counts ticks, this has to be called from kernel context, e.g. the main loop,
+ * so the handlers may take their time and any lock. Timers started by a handler run at the next call at the earliest.
+ */
+pub fn run_pending() {
+    rtc::run_alarm();
+    let now = time::ticks();
+    loop {
+        // the guard is dropped at the end of the statement, before the handler runs
+        let due = WHEEL.lock().take_due(now);
+        match due {
+            Some(handler) => handler(),
+            None => break
+        }
+    }
+}
+
+/**
+ * Converts milliseconds to timer ticks at the current frequency, at least one.
+ */
+fn ms_to_ticks(ms: u64) -> u64 {
+    let frequency = u64::from(time::frequency());
+    ((ms * frequency + 999) / 1000).max(1)
+}
+
+fn slot_of(tick: u64) -> usize {
+    (tick % WHEEL_SIZE as u64) as usize
+}
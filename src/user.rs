@@ -0,0 +1,208 @@
+use crate::elf::Image;
+use crate::gdt;
+use crate::process::{self, Pid, ProcessError};
+use crate::scheduler::ThreadId;
+use crate::syscall::Registers;
+use crate::user_access::{self, UserAccessError};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+// interrupts enabled, and the always set bit 1
+const USER_RFLAGS: u64 = 0x202;
+// the RFLAGS bits user code may set itself: the status flags, the trap and the direction flag
+const USER_RFLAGS_MASK: u64 = 0xDD5;
+// the System V stack's argv and envp terminators and AT_NULL auxiliary vector entry, under the strings
+const STACK_TERMINATORS: usize = 4;
+const STACK_ALIGNMENT: u64 = 16;
+// the low bits of a selector: the privilege level it was loaded with, 3 for user code
+const RPL_MASK: u64 = 0x3;
+const USER_RPL: u64 = 0x3;
+
+/**
+ * Starts a thread of the process running a loaded executable in ring 3, at its entry point with its stack.
+ */
+pub fn start(pid: Pid, image: Image) -> Result<ThreadId, ProcessError> {
+    let image = Box::into_raw(Box::new(image));
+    process::spawn_thread(pid, run_image, image as usize).map_err(|error| {
+        // the thread never started, the image is still ours
+        drop(unsafe { Box::from_raw(image) });
+        error
+    })
+}
+
+/**
+ * Starts a thread of the process going on in ring 3 with the given registers, e.g. a fork()ed child's copy of
+ * its parent's. It starts with the FPU registers clean.
+ */
+pub fn resume_in(pid: Pid, registers: Registers) -> Result<ThreadId, ProcessError> {
+    let registers = Box::into_raw(Box::new(registers));
+    process::spawn_thread(pid, run_registers, registers as usize).map_err(|error| {
+        // the thread never started, the registers are still ours
+        drop(unsafe { Box::from_raw(registers) });
+        error
+    })
+}
+
+/**
+ * Points a system call's saved registers at a new program, for exec(): at its entry point with its stack,
+ * every other register cleared.
+ */
+pub fn reset(registers: &mut Registers, entry: VirtAddr, stack_pointer: VirtAddr) {
+    *registers = Registers {
+        rip: entry.as_u64(),
+        rsp: stack_pointer.as_u64(),
+        rflags: USER_RFLAGS,
+        ..Registers::default()
+    };
+}
+
+/**
+ * Puts a program's arguments on its stack, below the stack pointer elf::load() gave, the way the System V ABI has
+ * _start find them: argc, the argv pointers and a NULL, an empty environment and auxiliary vector, the strings above.
+ * Returns the stack pointer to start with. The process' address space has to be the active one.
+ */
+pub fn push_arguments(stack_pointer: VirtAddr, arguments: &[String]) -> Result<VirtAddr, UserAccessError> {
+    let mut top = stack_pointer.as_u64();
+    let mut words = Vec::with_capacity(1 + arguments.len() + STACK_TERMINATORS);
+    words.push(arguments.len() as u64);
+    for argument in arguments {
+        let length = argument.len() as u64 + 1;
+        top = top.checked_sub(length).ok_or(UserAccessError::BadAddress)?;
+        user_access::copy_to_user(VirtAddr::new(top), argument.as_bytes())?;
+        user_access::copy_to_user(VirtAddr::new(top + length - 1), &[0])?;
+        words.push(top);
+    }
+    words.resize(words.len() + STACK_TERMINATORS, 0);
+    let size = (words.len() * mem::size_of::<u64>()) as u64;
+    let bottom = top.checked_sub(size).ok_or(UserAccessError::BadAddress)? & !(STACK_ALIGNMENT - 1);
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+    user_access::copy_to_user(VirtAddr::new(bottom), &bytes)?;
+    Ok(VirtAddr::new(bottom))
+}
+
+/**
+ * Leaves the kernel for user code at the given address, with the given stack, in the address space of the calling
+ * thread. Interrupts and exceptions come back on the thread's kernel stack through the TSS' RSP0, system calls on the
+ * same stack through the CPU's block.
+ * The general purpose registers are cleared, nothing of the kernel's leaks into ring 3. SMEP and SMAP, where
+ * memory::enable_protections() turned them on, keep the kernel from running and touching user pages meanwhile.
+ */
+pub fn enter(entry: VirtAddr, stack_pointer: VirtAddr) -> ! {
+    let code = u64::from(gdt::user_code_selector().0);
+    let data = u64::from(gdt::user_data_selector().0);
+    unsafe { user_enter(entry.as_u64(), stack_pointer.as_u64(), code, data, USER_RFLAGS) }
+}
+
+/**
+ * Leaves the kernel for user code with all the given registers, in the address space of the calling thread.
+ * Only the RFLAGS bits user code may set itself are taken over, interrupts are enabled.
+ */
+pub fn resume(registers: &Registers) -> ! {
+    let mut registers = *registers;
+    registers.rflags = user_rflags(registers.rflags);
+    let code = u64::from(gdt::user_code_selector().0);
+    let data = u64::from(gdt::user_data_selector().0);
+    unsafe { user_resume(&registers, code, data) }
+}
+
+/**
+ * Returns the RFLAGS user code may go on with: only the bits it may set itself are taken from the given ones,
+ * interrupts are enabled. Registers user code handed in, e.g. to sigreturn(), go through it.
+ */
+pub fn user_rflags(rflags: u64) -> u64 {
+    rflags & USER_RFLAGS_MASK | USER_RFLAGS
+}
+
+/**
+ * Tells whether an exception came from user code.
+ */
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & RPL_MASK == USER_RPL
+}
+
+/**
+ * The entry of the threads start() spawns, the argument is the Image it boxed.
+ */
+fn run_image(image: usize) {
+    let image = unsafe { Box::from_raw(image as *mut Image) };
+    enter(image.entry, image.stack_pointer);
+}
+
+/**
+ * The entry of the threads resume_in() spawns, the argument is the Registers it boxed.
+ */
+fn run_registers(registers: usize) {
+    let registers = unsafe { Box::from_raw(registers as *mut Registers) };
+    resume(&registers);
+}
+
+extern "C" {
+    fn user_enter(entry: u64, stack_pointer: u64, code: u64, data: u64, rflags: u64) -> !;
+    fn user_resume(registers: *const Registers, code: u64, data: u64) -> !;
+}
+
+// builds the frame iretq pops, SS, RSP, RFLAGS, CS and RIP, from the arguments in RDI, RSI, RDX, RCX and R8,
+// clears the registers, and swaps in the user GS base with interrupts off, so no handler sees it in ring 0
+global_asm!("
+.att_syntax prefix
+.global user_enter
+user_enter:
+    cli
+    pushq %rcx
+    pushq %rsi
+    pushq %r8
+    pushq %rdx
+    pushq %rdi
+    xorl %eax, %eax
+    xorl %ebx, %ebx
+    xorl %ecx, %ecx
+    xorl %edx, %edx
+    xorl %esi, %esi
+    xorl %edi, %edi
+    xorl %ebp, %ebp
+    xorl %r8d, %r8d
+    xorl %r9d, %r9d
+    xorl %r10d, %r10d
+    xorl %r11d, %r11d
+    xorl %r12d, %r12d
+    xorl %r13d, %r13d
+    xorl %r14d, %r14d
+    xorl %r15d, %r15d
+    swapgs
+    iretq
+");
+
+// builds the iretq frame from the Registers in RDI and the selectors in RSI and RDX, then loads the registers,
+// RDI last, as it points at them, see syscall::Registers for their offsets
+global_asm!("
+.att_syntax prefix
+.global user_resume
+user_resume:
+    cli
+    pushq %rdx
+    pushq 136(%rdi)
+    pushq 128(%rdi)
+    pushq %rsi
+    pushq 120(%rdi)
+    movq 0(%rdi), %r15
+    movq 8(%rdi), %r14
+    movq 16(%rdi), %r13
+    movq 24(%rdi), %r12
+    movq 32(%rdi), %rbp
+    movq 40(%rdi), %rbx
+    movq 48(%rdi), %r9
+    movq 56(%rdi), %r8
+    movq 64(%rdi), %r10
+    movq 72(%rdi), %rdx
+    movq 80(%rdi), %rsi
+    movq 96(%rdi), %rax
+    movq 104(%rdi), %rcx
+    movq 112(%rdi), %r11
+    movq 88(%rdi), %rdi
+    swapgs
+    iretq
+");
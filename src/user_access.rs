@@ -0,0 +1,185 @@
+use crate::cow;
+use crate::demand;
+use crate::process;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+const PAGE_SIZE: u64 = 4096;
+/**
+ * The end of the lower half, user pointers must be below it, the kernel's mappings above it are in every address space.
+ */
+pub const USER_END: u64 = 0x_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    // the range is not all in the user half, or wraps around
+    BadAddress,
+    // a page of the range is not mapped for user code, or not writable for copy_to_user()
+    NotMapped,
+    // the copy faulted anyway, the mapping went away meanwhile
+    Fault,
+    // strncpy_from_user() found no terminating NUL in the buffer's length
+    TooLong,
+    // the calling thread is a kernel thread, it has no user memory
+    NoProcess
+}
+
+/**
+ * Copies from the calling process' memory into a kernel buffer, the whole buffer's length.
+ */
+pub fn copy_from_user(destination: &mut [u8], source: VirtAddr) -> Result<(), UserAccessError> {
+    check(source, destination.len() as u64, false)?;
+    copy(destination.as_mut_ptr(), source.as_ptr(), destination.len())
+}
+
+/**
+ * Copies a kernel buffer into the calling process' memory. Copy-on-write pages get copied on the way.
+ */
+pub fn copy_to_user(destination: VirtAddr, source: &[u8]) -> Result<(), UserAccessError> {
+    check(destination, source.len() as u64, true)?;
+    copy(destination.as_mut_ptr(), source.as_ptr(), source.len())
+}
+
+/**
+ * Copies a NUL terminated string from the calling process' memory, without the NUL, returns its length.
+ * Fails with TooLong if the buffer fills up before the NUL. Only the pages up to the NUL have to be mapped.
+ */
+pub fn strncpy_from_user(destination: &mut [u8], source: VirtAddr) -> Result<usize, UserAccessError> {
+    let mut copied = 0;
+    while copied < destination.len() {
+        let from = source.as_u64() + copied as u64;
+        if from >= USER_END {
+            return Err(UserAccessError::BadAddress);
+        }
+        // a page at a time, the string may end before the next one
+        let chunk = (PAGE_SIZE - from % PAGE_SIZE).min((destination.len() - copied) as u64) as usize;
+        let chunk = &mut destination[copied..copied + chunk];
+        copy_from_user(chunk, VirtAddr::new(from))?;
+        if let Some(length) = chunk.iter().position(|&byte| byte == 0) {
+            return Ok(copied + length);
+        }
+        copied += chunk.len();
+    }
+    Err(UserAccessError::TooLong)
+}
+
+/**
+ * Tells whether a range of the calling process' memory is all present and writable, so copy_to_user() can't fault on
+ * it, not even for a copy-on-write or demand paged page. The page fault handler checks it before it writes to user
+ * memory, another page fault would start over on its stack.
+ */
+pub(crate) fn is_resident(start: VirtAddr, length: u64) -> bool {
+    if length == 0 {
+        return true;
+    }
+    let end = match start.as_u64().checked_add(length).filter(|&end| end <= USER_END) {
+        Some(end) => end,
+        None => return false
+    };
+    let pid = match process::current() {
+        Some(pid) => pid,
+        None => return false
+    };
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    let resident = process::with(pid, |process| match process.address_space() {
+        Some(space) => Page::range_inclusive(first, last).all(|page| {
+            space.page_flags(page).map_or(false, |flags| {
+                flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE)
+            })
+        }),
+        None => false
+    });
+    resident == Some(true)
+}
+
+/**
+ * Resumes a copy that faulted on a user page at its end, so it fails instead of taking the kernel down.
+ * Returns whether the fault was in a copy. The page fault handler calls it once the fault turned out not to be
+ * a copy-on-write or demand paged one.
+ */
+pub(crate) fn fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let fault: unsafe extern "C" fn() = user_access_fault;
+    let fixup: unsafe extern "C" fn() = user_access_fixup;
+    if stack_frame.instruction_pointer.as_u64() != fault as usize as u64 {
+        return false;
+    }
+    // only the instruction pointer changes, to code that returns from the copy
+    unsafe { stack_frame.as_mut().instruction_pointer = VirtAddr::new(fixup as usize as u64) };
+    true
+}
+
+/**
+ * Checks a range against the calling process' mappings: every page has to be mapped, or reserved, for user code,
+ * and for writes writable or copy-on-write.
+ */
+fn check(start: VirtAddr, length: u64, write: bool) -> Result<(), UserAccessError> {
+    if length == 0 {
+        return Ok(());
+    }
+    let end = start.as_u64().checked_add(length).filter(|&end| end <= USER_END).ok_or(UserAccessError::BadAddress)?;
+    let pid = process::current().ok_or(UserAccessError::NoProcess)?;
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    let mapped = process::with(pid, |process| {
+        let space = match process.address_space() {
+            Some(space) => space,
+            None => return false
+        };
+        let level_4 = space.level_4_frame();
+        Page::range_inclusive(first, last).all(|page| {
+            let flags = match space.page_flags(page).or_else(|| demand::reserved_flags(level_4, page)) {
+                Some(flags) => flags,
+                None => return false
+            };
+            flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                && (!write || flags.intersects(PageTableFlags::WRITABLE | cow::COPY_ON_WRITE))
+        })
+    });
+    if mapped != Some(true) {
+        return Err(UserAccessError::NotMapped);
+    }
+    Ok(())
+}
+
+fn copy(destination: *mut u8, source: *const u8, length: usize) -> Result<(), UserAccessError> {
+    // STAC and CLAC are invalid opcodes where there is no SMAP
+    let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+    match unsafe { user_access_copy(destination, source, length, smap) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault)
+    }
+}
+
+extern "C" {
+    fn user_access_copy(destination: *mut u8, source: *const u8, length: usize, smap: bool) -> usize;
+    fn user_access_fault();
+    fn user_access_fixup();
+}
+
+// copies with rep movsb, with RFLAGS.AC set meanwhile, so SMAP lets the kernel touch user pages, and returns how many
+// bytes were left, in RCX. A fault on the rep movsb resumes at user_access_fixup, with RCX at the bytes it did not copy.
+global_asm!("
+.att_syntax prefix
+.global user_access_copy
+.global user_access_fault
+.global user_access_fixup
+user_access_copy:
+    movzbl %cl, %r8d
+    movq %rdx, %rcx
+    testl %r8d, %r8d
+    jz 1f
+    stac
+1:
+user_access_fault:
+    rep movsb
+user_access_fixup:
+    testl %r8d, %r8d
+    jz 2f
+    clac
+2:
+    movq %rcx, %rax
+    ret
+");
@@ -0,0 +1,517 @@
+use crate::sync::IrqMutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+/**
+ * The longest name a directory entry may have, in bytes.
+ */
+pub const MAX_NAME: usize = 255;
+// read_all() reads this much at a time
+const READ_CHUNK: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    // there is nothing by that name
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    // not an absolute path, or a name too long, or none, where one is needed
+    InvalidPath,
+    // a directory with entries can't be removed
+    NotEmpty,
+    // the file system can't be written
+    ReadOnly,
+    // the file was not opened for reading, or for writing
+    AccessDenied,
+    // a seek to before the start of the file
+    InvalidSeek,
+    NoSpace,
+    // the file system does not do that, e.g. make devices
+    NotSupported,
+    // something is mounted there already, or under it, for unmount()
+    Busy,
+    // no file system is mounted over the path, e.g. / before the root one is
+    NotMounted,
+    // the device under the file system failed, or what it read is corrupt
+    Io
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    // e.g. a console, whose reads and writes go to a driver
+    Device
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    // in bytes, 0 for directories and devices
+    pub size: u64,
+    // a number for the inode, unique in its file system
+    pub inode: u64
+}
+
+/**
+ * What readdir() tells about an entry of a directory.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub inode: u64
+}
+
+/**
+ * A file, directory or device of a file system, found by lookup() and path. Implementations lock what they share
+ * themselves. What an inode can't do fails with NotSupported by default, the VFS checks the type of an inode before
+ * it reads a directory or looks up in a file, so only a directory implements lookup(), create(), remove()
+ * and readdir(), only a file or device read_at(), write_at() and truncate().
+ */
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /**
+     * Reads from the given byte offset into the buffer, returns how many bytes it read, 0 at the end of the file.
+     */
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Writes the buffer at the given byte offset, growing the file if it goes past its end, returns how many bytes
+     * it wrote.
+     */
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Cuts the file to the given size, or grows it with zeros.
+     */
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Returns the entry of the directory with the given name.
+     */
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Makes an empty file or directory in the directory, returns it. Fails with AlreadyExists if the name is taken.
+     */
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Takes an entry out of the directory, a directory only if it is empty. Those who have it open keep it.
+     */
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /**
+     * Returns the entry of the directory at the given index, None past the last one. Without "." and "..".
+     */
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+/**
+ * A tree of inodes the VFS mounts at a path.
+ */
+pub trait FileSystem: Send + Sync {
+    /**
+     * Names the kind of file system, e.g. for mounts().
+     */
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/**
+ * Where seek() moves an open file to.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+/**
+ * An open file, with a position of its own, which reads and writes go on from. Whoever shares the Arc,
+ * e.g. a fork()ed child through its file table, shares the position.
+ */
+pub trait File: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, VfsError>;
+
+    /**
+     * Moves the position, returns the new one.
+     */
+    fn seek(&self, from: SeekFrom) -> Result<u64, VfsError>;
+
+    /**
+     * Returns the next entry of a directory, None after the last one.
+     */
+    fn readdir(&self) -> Result<Option<DirEntry>, VfsError>;
+}
+
+/**
+ * How open() opens a file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    // make a regular file if there is none
+    pub create: bool,
+    // cut the file to 0 bytes, it has to be opened for writing
+    pub truncate: bool,
+    // every write goes to the end of the file
+    pub append: bool
+}
+
+impl OpenOptions {
+    pub fn read_only() -> OpenOptions {
+        OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        }
+    }
+}
+
+/**
+ * What mounts() tells about a mounted file system.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub path: String,
+    pub file_system: &'static str
+}
+
+struct Mount {
+    // the names on the way from the root, none for the root itself
+    path: Vec<String>,
+    file_system: Arc<dyn FileSystem>
+}
+
+lazy_static! {
+    static ref MOUNTS: IrqMutex<Vec<Mount>> = IrqMutex::new(Vec::new());
+}
+
+/**
+ * An inode open as a File.
+ */
+struct InodeFile {
+    inode: Arc<dyn Inode>,
+    options: OpenOptions,
+    // the byte offset of a file, the index of the next entry of a directory
+    position: IrqMutex<u64>
+}
+
+impl File for InodeFile {
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.options.read {
+            return Err(VfsError::AccessDenied);
+        }
+        if self.inode.metadata().file_type == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        // not locked meanwhile, the inode may block, concurrent reads may read the same bytes
+        let position = *self.position.lock();
+        let count = self.inode.read_at(position, buffer)?;
+        *self.position.lock() = position + count as u64;
+        Ok(count)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        if !self.options.write {
+            return Err(VfsError::AccessDenied);
+        }
+        let metadata = self.inode.metadata();
+        if metadata.file_type == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let position = if self.options.append { metadata.size } else { *self.position.lock() };
+        let count = self.inode.write_at(position, buffer)?;
+        *self.position.lock() = position + count as u64;
+        Ok(count)
+    }
+
+    fn seek(&self, from: SeekFrom) -> Result<u64, VfsError> {
+        let mut position = self.position.lock();
+        let (base, offset) = match from {
+            SeekFrom::Start(offset) => {
+                *position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (*position, offset),
+            SeekFrom::End(offset) => (self.inode.metadata().size, offset)
+        };
+        let moved = if offset < 0 {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        } else {
+            base.checked_add(offset as u64)
+        };
+        *position = moved.ok_or(VfsError::InvalidSeek)?;
+        Ok(*position)
+    }
+
+    fn readdir(&self) -> Result<Option<DirEntry>, VfsError> {
+        if self.inode.metadata().file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let index = *self.position.lock();
+        let entry = self.inode.readdir(index as usize)?;
+        if entry.is_some() {
+            *self.position.lock() = index + 1;
+        }
+        Ok(entry)
+    }
+}
+
+/**
+ * Mounts a file system at an absolute path, over a directory of the file system mounted there, or at / first.
+ * Its root is what the path leads to from then on, what the directory had is hidden until unmount().
+ */
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let components = components(path)?;
+    if !components.is_empty() && lookup(path)?.metadata().file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == components) {
+        return Err(VfsError::Busy);
+    }
+    mounts.push(Mount {
+        path: components.into_iter().map(String::from).collect(),
+        file_system
+    });
+    Ok(())
+}
+
+/**
+ * Unmounts the file system mounted at the path, returns it. Fails with Busy while others are mounted under it.
+ * Files open on it stay open.
+ */
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let components = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|mount| mount.path == components).ok_or(VfsError::NotMounted)?;
+    if mounts.iter().any(|mount| mount.path.len() > components.len() && mount.path.starts_with(&mounts[index].path)) {
+        return Err(VfsError::Busy);
+    }
+    Ok(mounts.remove(index).file_system)
+}
+
+/**
+ * Lists the mounted file systems, in the order they were mounted.
+ */
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().iter().map(|mount| MountInfo {
+        path: format_path(&mount.path),
+        file_system: mount.file_system.name()
+    }).collect()
+}
+
+/**
+ * Returns the inode an absolute path leads to. "." and ".." go by the path alone, ".." of / is / itself.
+ */
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    resolve(&components(path)?)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, VfsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+/**
+ * Opens the file or directory at an absolute path. A directory can only be opened for reading, with readdir().
+ */
+pub fn open(path: &str, options: OpenOptions) -> Result<Arc<dyn File>, VfsError> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(VfsError::NotFound) if options.create => match create(path, FileType::Regular) {
+            // made meanwhile
+            Err(VfsError::AlreadyExists) => lookup(path)?,
+            created => created?
+        },
+        Err(error) => return Err(error)
+    };
+    if inode.metadata().file_type == FileType::Directory && (options.write || options.truncate) {
+        return Err(VfsError::IsADirectory);
+    }
+    if options.truncate {
+        if !options.write {
+            return Err(VfsError::AccessDenied);
+        }
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(InodeFile {
+        inode,
+        options,
+        position: IrqMutex::new(0)
+    }))
+}
+
+/**
+ * Reads from an open file at its position, returns how many bytes it read, 0 at the end of the file.
+ */
+pub fn read(file: &dyn File, buffer: &mut [u8]) -> Result<usize, VfsError> {
+    file.read(buffer)
+}
+
+/**
+ * Writes to an open file at its position, or at its end if it was opened to append, returns how many bytes it wrote.
+ */
+pub fn write(file: &dyn File, buffer: &[u8]) -> Result<usize, VfsError> {
+    file.write(buffer)
+}
+
+pub fn seek(file: &dyn File, from: SeekFrom) -> Result<u64, VfsError> {
+    file.seek(from)
+}
+
+/**
+ * Returns the next entry of an open directory, None after the last one.
+ */
+pub fn readdir(file: &dyn File) -> Result<Option<DirEntry>, VfsError> {
+    file.readdir()
+}
+
+/**
+ * Reads a whole file at an absolute path, e.g. a program to run. Fails with NoSpace if it has more than limit bytes,
+ * or grows past it meanwhile, what is read is on the heap, which panics when it runs out.
+ */
+pub fn read_all(path: &str, limit: usize) -> Result<Vec<u8>, VfsError> {
+    let file = open(path, OpenOptions::read_only())?;
+    let size = file.metadata().size;
+    if size > limit as u64 {
+        return Err(VfsError::NoSpace);
+    }
+    let mut bytes = Vec::with_capacity(size as usize);
+    let mut chunk = vec![0; READ_CHUNK];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(bytes),
+            count if bytes.len() + count > limit => return Err(VfsError::NoSpace),
+            count => bytes.extend_from_slice(&chunk[..count])
+        }
+    }
+}
+
+/**
+ * Makes an empty file or directory at an absolute path, in the directory the path leads to but for the last name.
+ */
+pub fn create(path: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+    let (parent, name) = parent(path)?;
+    parent.create(name, file_type)
+}
+
+pub fn create_dir(path: &str) -> Result<(), VfsError> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+/**
+ * Removes the file, or empty directory, at an absolute path. Fails with Busy for a mount point.
+ */
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let components = components(path)?;
+    if MOUNTS.lock().iter().any(|mount| mount.path == components) {
+        return Err(VfsError::Busy);
+    }
+    let (parent, name) = parent(path)?;
+    parent.remove(name)
+}
+
+/**
+ * Splits an absolute path into its names, dropping the empty ones and ".", and taking ".." back out with the name
+ * before it.
+ */
+fn components(path: &str) -> Result<Vec<&str>, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name if name.len() > MAX_NAME => return Err(VfsError::InvalidPath),
+            name => components.push(name)
+        }
+    }
+    Ok(components)
+}
+
+/**
+ * Walks from the root of the file system mounted deepest over the path down to what it leads to.
+ */
+fn resolve(components: &[&str]) -> Result<Arc<dyn Inode>, VfsError> {
+    let (depth, file_system) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts.iter()
+            .filter(|mount| {
+                mount.path.len() <= components.len()
+                    && mount.path.iter().zip(components).all(|(mounted, name)| mounted.as_str() == *name)
+            })
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(VfsError::NotMounted)?;
+        (mount.path.len(), mount.file_system.clone())
+    };
+    // the file systems are called without the mount table locked, they may block
+    let mut inode = file_system.root();
+    for name in &components[depth..] {
+        if inode.metadata().file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/**
+ * Returns the directory a path's last name is in, and the name.
+ */
+fn parent(path: &str) -> Result<(Arc<dyn Inode>, &str), VfsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(VfsError::InvalidPath)?;
+    let parent = resolve(&components)?;
+    if parent.metadata().file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+fn format_path(components: &[String]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+    let mut path = String::new();
+    for name in components {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
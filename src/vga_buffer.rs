@@ -1,10 +1,101 @@
+use crate::ansi::{Action, Params, Parser};
+use crate::console::ConsoleBackend;
+use crate::cp437;
+use crate::status_bar;
+use crate::sync::IrqMutex;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, PageTable, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size4KiB, UnusedPhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
 
+// Buffers are sized for the largest text mode supported, writers use as much of them as the current mode shows.
 const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+const BUFFER_HEIGHT: usize = 50;
+// how many lines are kept after they scrolled off the top, 4 screens in the default 80x25 mode
+const SCROLLBACK_LINES: usize = 100;
+const DEFAULT_TAB_WIDTH: usize = 8;
+const BACKSPACE: u8 = 0x08;
+// the ■ glyph, shown for everything the font cannot display
+const UNPRINTABLE: u8 = 0xFE;
+// one bit per row in VgaTextBuffer::dirty_rows
+const ALL_ROWS: u64 = (1 << BUFFER_HEIGHT) - 1;
+
+// The VGA register groups are programmed through index/data port pairs: the register number goes to the address port,
+// then its value can be read or written through the data port right after it.
+const CRTC_ADDRESS_PORT: u16 = 0x3D4;
+const SEQUENCER_ADDRESS_PORT: u16 = 0x3C4;
+const GRAPHICS_CONTROLLER_ADDRESS_PORT: u16 = 0x3CE;
+const MAX_SCAN_LINE_REGISTER: u8 = 0x09;
+const CURSOR_START_REGISTER: u8 = 0x0A;
+const CURSOR_END_REGISTER: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0E;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0F;
+// bit 5 of the cursor start register turns the cursor off
+const CURSOR_DISABLE_BIT: u8 = 0x20;
+const MAP_MASK_REGISTER: u8 = 0x02;
+const CHARACTER_MAP_SELECT_REGISTER: u8 = 0x03;
+const MEMORY_MODE_REGISTER: u8 = 0x04;
+const READ_MAP_SELECT_REGISTER: u8 = 0x04;
+const GRAPHICS_MODE_REGISTER: u8 = 0x05;
+const MISCELLANEOUS_REGISTER: u8 = 0x06;
+// The attribute controller has a single port for both the register number and the value, a flip-flop tells them apart.
+// Reading the input status register resets the flip-flop, so the next write is taken as a register number.
+const ATTRIBUTE_CONTROLLER_PORT: u16 = 0x3C0;
+const ATTRIBUTE_CONTROLLER_READ_PORT: u16 = 0x3C1;
+const INPUT_STATUS_1_PORT: u16 = 0x3DA;
+const ATTRIBUTE_MODE_CONTROL_REGISTER: u8 = 0x10;
+const BLINK_ENABLE_BIT: u8 = 0x08;
+// has to be set along with the register number, or the attribute controller stops driving the display
+const PALETTE_ADDRESS_SOURCE: u8 = 0x20;
+
+// the legacy video memory window, the text buffer lives at 0xB8000 inside it
+const VIDEO_MEMORY_START: u64 = 0xA0000;
+const VIDEO_MEMORY_END: u64 = 0xC0000;
+// While the font plane is mapped in, it appears at the start of the window, with 32 bytes reserved for each glyph.
+const FONT_PLANE: u64 = 0xA0000;
+const GLYPH_SIZE: usize = 32;
+// The BIOS font lives in font block 0, the 8 scanline font generated for 80x50 goes to block 1, 16 KiB after it.
+const FONT_BLOCK_1_OFFSET: usize = 0x4000;
+const FONT_BLOCK_0_SELECT: u8 = 0x00;
+const FONT_BLOCK_1_SELECT: u8 = 0x05;
+
+/**
+ * The text modes the writer can switch between.
+ * Both modes have 400 scanlines, so switching only changes the height of a character cell and the font,
+ * the display timings stay the same.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    // the mode set up by the bootloader, 9x16 cells
+    Text80x25,
+    // 8 scanline high cells, twice as many rows
+    Text80x50
+}
+
+impl TextMode {
+    pub fn columns(self) -> usize {
+        80
+    }
+
+    pub fn rows(self) -> usize {
+        match self {
+            TextMode::Text80x25 => 25,
+            TextMode::Text80x50 => 50
+        }
+    }
+
+    fn char_height(self) -> u8 {
+        match self {
+            TextMode::Text80x25 => 16,
+            TextMode::Text80x50 => 8
+        }
+    }
+}
 
 lazy_static! {
     /** A global writer instance used by print!() and println!() macros.
@@ -14,15 +105,36 @@ lazy_static! {
     * The framebuffer is just an array of 16-bit words, each 16-bit value representing the display of one character.
     * In ASCII, 8 bits are used to represent a character.
     * That gives us 8 more bits which are unused. The VGA hardware uses these to designate foreground and background colors (4 bits each).
+    * The writer uses the rows below the status bar.
     */
-    pub static ref WRITER : Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER : IrqMutex<Writer> = IrqMutex::new(TextWindow {
         column_pos : 0,
-        row_pos : 1,
-        color_code : ColorCode::new(Colors::White, Colors::Black),
-        buffer : unsafe {&mut *(0xB8000 as *mut Buffer) }
+        row_pos : 0,
+        top : status_bar::ROWS,
+        left : 0,
+        width : TextMode::Text80x25.columns(),
+        height : TextMode::Text80x25.rows() - status_bar::ROWS,
+        tab_width : DEFAULT_TAB_WIDTH,
+        color_code : DEFAULT_COLOR_CODE,
+        cells : unsafe { &mut SCREEN },
+        displayed : true,
+        ansi : Parser::new(),
+        scrollback : unsafe { &mut SCROLLBACK }
     });
+
+    /** The backend the display is drawn on, the VGA text buffer until another one is chosen with set_backend().
+    * It is shared by the writer on the display and the status bar.
+    * Lock order: WRITER, then vt's TERMINALS, then the status bar, then DISPLAY.
+    */
+    pub(crate) static ref DISPLAY : IrqMutex<&'static mut dyn ConsoleBackend> = IrqMutex::new(unsafe { &mut VGA_TEXT });
 }
 
+static mut SCREEN: OffscreenBuffer = BLANK_SCREEN;
+static mut SCROLLBACK: Scrollback = Scrollback::new();
+static mut VGA_TEXT: VgaTextBuffer = VgaTextBuffer::new();
+// the text mode the display is in, VGA_TEXT takes its dimensions from it
+static MODE: IrqMutex<TextMode> = IrqMutex::new(TextMode::Text80x25);
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -47,19 +159,113 @@ pub enum Colors {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
+
+pub const DEFAULT_COLOR_CODE: ColorCode = ColorCode::new(Colors::White, Colors::Black);
+
+// ANSI numbers its eight colors in a different order than the VGA palette
+const ANSI_COLORS: [Colors; 8] = [
+    Colors::Black, Colors::Red, Colors::Green, Colors::Brown,
+    Colors::Blue, Colors::Magenta, Colors::Cyan, Colors::LightGray
+];
+const ANSI_BRIGHT_COLORS: [Colors; 8] = [
+    Colors::DarkGray, Colors::LightRed, Colors::LightGreen, Colors::Yellow,
+    Colors::LightBlue, Colors::Pink, Colors::LightCyan, Colors::White
+];
+// the high bit of a 4 bit color selects its bright variant
+const BRIGHT_BIT: u8 = 0x08;
 
 impl ColorCode {
-    fn new(foreground: Colors, background: Colors) -> ColorCode {
+    /**
+     * All 16 colors can be used as backgrounds. In blink mode (see set_background_mode()) the bright ones
+     * show up as their dark variant, with blinking text.
+     */
+    pub const fn new(foreground: Colors, background: Colors) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode((self.0 & 0xF0) | (foreground & 0x0F))
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode((background & 0x0F) << 4 | (self.0 & 0x0F))
+    }
+
+    fn foreground(self) -> u8 {
+        self.0 & 0x0F
+    }
+
+    fn background(self) -> u8 {
+        self.0 >> 4
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
-    ascii_char: u8,
-    color_code: ColorCode
+pub struct ScreenChar {
+    pub ascii_char: u8,
+    pub color_code: ColorCode
+}
+
+/**
+ * Character cells in ordinary RAM, laid out exactly like the VGA framebuffer.
+ * Every writer draws into one of these; the writer on the display also sends what it draws to its console backend.
+ */
+pub type OffscreenBuffer = [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+const BLANK_CHAR: ScreenChar = ScreenChar {
+    ascii_char: b' ',
+    color_code: DEFAULT_COLOR_CODE
+};
+
+pub const BLANK_SCREEN: OffscreenBuffer = [[BLANK_CHAR; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+/**
+ * A ring of the lines that scrolled off the top of a screen, oldest ones overwritten first.
+ * While the user is looking at the history, only the display shows it, the writer's cells keep the live screen.
+ */
+pub struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],
+    // index of the slot the next line goes into
+    next: usize,
+    len: usize,
+    // how many lines the view is scrolled back, 0 means the live screen is shown
+    view_offset: usize
+}
+
+impl Scrollback {
+    pub const fn new() -> Scrollback {
+        Scrollback {
+            lines: [[BLANK_CHAR; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            next: 0,
+            len: 0,
+            view_offset: 0
+        }
+    }
+
+    fn push(&mut self, line: &Line) {
+        self.lines[self.next] = *line;
+        self.next = (self.next + 1) % SCROLLBACK_LINES;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        }
+    }
+
+    /**
+     * Returns the line at the given position of the history, 0 being the oldest one kept.
+     */
+    fn line(&self, index: usize) -> &Line {
+        &self.lines[(self.next + SCROLLBACK_LINES - self.len + index) % SCROLLBACK_LINES]
+    }
+}
+
+impl Default for Scrollback {
+    fn default() -> Scrollback {
+        Scrollback::new()
+    }
 }
 
 #[repr(transparent)]
@@ -67,67 +273,621 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT]
 }
 
-pub struct Writer {
+/**
+ * The console backend drawing on the VGA text buffer at 0xB8000, sized by the current text mode.
+ * Reading and writing the video memory is much slower than normal RAM, so cells are drawn into a copy in RAM,
+ * and only the rows that changed are copied to the video memory on flush.
+ */
+pub struct VgaTextBuffer {
+    memory: *mut Buffer,
+    cells: OffscreenBuffer,
+    // rows of `cells` changed since they were last copied to the video memory
+    dirty_rows: u64
+}
+
+// there is a single instance, only ever reached through the writer holding it
+unsafe impl Send for VgaTextBuffer {}
+
+impl VgaTextBuffer {
+    const fn new() -> VgaTextBuffer {
+        VgaTextBuffer {
+            memory: 0xB8000 as *mut Buffer,
+            cells: BLANK_SCREEN,
+            dirty_rows: 0
+        }
+    }
+}
+
+impl ConsoleBackend for VgaTextBuffer {
+    fn dimensions(&self) -> (usize, usize) {
+        let mode = *MODE.lock();
+        (mode.columns(), mode.rows())
+    }
+
+    fn put_char(&mut self, row: usize, column: usize, character: ScreenChar) {
+        if row < BUFFER_HEIGHT && column < BUFFER_WIDTH {
+            self.cells[row][column] = character;
+            self.dirty_rows |= 1 << row;
+        }
+    }
+
+    fn get_char(&self, row: usize, column: usize) -> ScreenChar {
+        self.cells[row.min(BUFFER_HEIGHT - 1)][column.min(BUFFER_WIDTH - 1)]
+    }
+
+    fn scroll(&mut self, lines: usize, blank: ScreenChar) {
+        let (_, height) = self.dimensions();
+        let lines = lines.min(height);
+        self.cells.copy_within(lines..height, 0);
+        for row in height - lines..height {
+            self.cells[row] = [blank; BUFFER_WIDTH];
+        }
+        self.dirty_rows = ALL_ROWS;
+    }
+
+    fn clear(&mut self, blank: ScreenChar) {
+        self.cells = [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        self.dirty_rows = ALL_ROWS;
+    }
+
+    fn set_cursor(&mut self, row: usize, column: usize) {
+        let (width, height) = self.dimensions();
+        let position = (row * width + column).min(width * height - 1) as u16;
+        crtc_write(CURSOR_LOCATION_LOW_REGISTER, (position & 0xFF) as u8);
+        crtc_write(CURSOR_LOCATION_HIGH_REGISTER, (position >> 8) as u8);
+    }
+
+    fn set_cursor_visible(&mut self, visible: bool) {
+        let start = crtc_read(CURSOR_START_REGISTER);
+        if visible {
+            crtc_write(CURSOR_START_REGISTER, start & !CURSOR_DISABLE_BIT);
+        } else {
+            crtc_write(CURSOR_START_REGISTER, start | CURSOR_DISABLE_BIT);
+        }
+    }
+
+    fn flush(&mut self) {
+        let (width, height) = self.dimensions();
+        let memory = unsafe { &mut *self.memory };
+        for row in 0..height {
+            if self.dirty_rows & (1 << row) == 0 {
+                continue;
+            }
+            for col in 0..width {
+                memory.chars[row][col].write(self.cells[row][col]);
+            }
+        }
+        self.dirty_rows = 0;
+    }
+}
+
+/**
+ * A rectangle of the display, in character cells.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub top: usize,
+    pub left: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+/**
+ * A rectangular region of the display with its own text, cursor, wrapping, scrolling and scrollback.
+ * Several windows can be on the display at the same time, e.g. a log pane above an interactive one,
+ * as long as their regions do not overlap.
+ * The global writer and the virtual terminals are windows spanning the display below the status bar.
+ */
+pub struct TextWindow {
     column_pos: usize,
     row_pos: usize,
+    // where the window's top left cell is on the display
+    top: usize,
+    left: usize,
+    width: usize,
+    height: usize,
+    // tab stops are at every multiple of this column
+    tab_width: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer
+    cells: &'static mut OffscreenBuffer,
+    // only set for windows that are on the display
+    displayed: bool,
+    ansi: Parser,
+    scrollback: &'static mut Scrollback
 }
 
-impl Writer {
+/**
+ * The full-screen text window print!() writes to.
+ */
+pub type Writer = TextWindow;
+
+impl TextWindow {
+    /**
+     * Creates a window on the display, showing its text in the given region.
+     * The window starts out blank, it does not draw anything until it is written to.
+     */
+    pub fn new(region: Region, memory: &'static mut OffscreenBuffer, scrollback: &'static mut Scrollback) -> TextWindow {
+        TextWindow {
+            column_pos: 0,
+            row_pos: 0,
+            top: region.top,
+            left: region.left,
+            width: region.width.min(BUFFER_WIDTH).max(1),
+            height: region.height.min(BUFFER_HEIGHT).max(1),
+            tab_width: DEFAULT_TAB_WIDTH,
+            color_code: DEFAULT_COLOR_CODE,
+            cells: memory,
+            displayed: true,
+            ansi: Parser::new(),
+            scrollback
+        }
+    }
+
+    /**
+     * Creates a writer drawing into RAM only, its screen is not displayed.
+     */
+    pub(crate) fn offscreen(memory: &'static mut OffscreenBuffer, scrollback: &'static mut Scrollback) -> TextWindow {
+        TextWindow {
+            column_pos: 0,
+            row_pos: 0,
+            top: status_bar::ROWS,
+            left: 0,
+            width: TextMode::Text80x25.columns(),
+            height: TextMode::Text80x25.rows() - status_bar::ROWS,
+            tab_width: DEFAULT_TAB_WIDTH,
+            color_code: DEFAULT_COLOR_CODE,
+            cells: memory,
+            displayed: false,
+            ansi: Parser::new(),
+            scrollback
+        }
+    }
+
+    /**
+     * Exchanges the screens of two writers: contents, scrollback and writing state (position, color, escape sequence state)
+     * all move over, only the display stays where it was.
+     * This is how a screen kept in RAM is brought to the display and the displayed one is put aside.
+     */
+    pub(crate) fn exchange(&mut self, other: &mut TextWindow) {
+        core::mem::swap(self, other);
+        core::mem::swap(&mut self.displayed, &mut other.displayed);
+        self.redraw();
+        other.redraw();
+    }
+
     /**
      * Writes the given text to the screen in VGA-compatible Text Mode via memory-mapped i/o.
-     * The writer will always write to the last line and shift lines up when a line is full (or \n is encountered).
+     * The writer fills the screen top to bottom, then shifts lines up when the last line is full (or \n is encountered).
+     * Lines shifted off the top are kept in the scrollback.
      * The writer will print only ASCII and Code Page 437 characters.
-     * Strings in Rust are UTF-8 by default, and might contain bytes that are unprintable.
-     * In this case, it will print the ■ character instead.
+     * Strings in Rust are UTF-8 by default, so other characters are translated to the Code Page 437 glyph showing them,
+     * or to the closest look-alike. If the font has nothing resembling them, it will print the ■ character instead.
+     * ANSI escape sequences for colors, cursor movement and erasing are interpreted instead of being printed.
+     * Carriage return goes back to the start of the line, tab advances to the next tab stop,
+     * and backspace moves one cell to the left, erasing it.
     */
     pub fn write_string(&mut self, text: &str) {
-        for byte in text.bytes() {
-            match byte {
+        self.show_live_screen();
+        for character in text.chars() {
+            if character.is_ascii() {
+                if let Some(action) = self.ansi.advance(character as u8) {
+                    self.execute(action);
+                }
+            } else {
+                // escape sequences are made of ASCII only, anything else is text to show
+                self.put_glyph(cp437::encode(character).unwrap_or(UNPRINTABLE));
+            }
+        }
+        self.flush();
+        self.update_cursor();
+    }
+
+    /**
+     * Tells whether changes to the cells have to be drawn: the writer is on the display, and the view shows the live screen.
+     */
+    fn is_live(&self) -> bool {
+        self.displayed && self.scrollback.view_offset == 0
+    }
+
+    /**
+     * Tells whether the window spans the whole display, so the display can be scrolled and cleared with it.
+     */
+    fn covers_display(&self) -> bool {
+        let (width, height) = DISPLAY.lock().dimensions();
+        self.top == 0 && self.left == 0 && self.width == width && self.height == height
+    }
+
+    /**
+     * Sends the cell at the given position to the display.
+     */
+    fn draw(&mut self, row: usize, col: usize) {
+        if self.is_live() {
+            DISPLAY.lock().put_char(self.top + row, self.left + col, self.cells[row][col]);
+        }
+    }
+
+    /**
+     * Sends all the rows to the display again.
+     * While the view is scrolled back, the rows are composed from the history and the top of the live screen.
+     */
+    fn draw_all(&mut self) {
+        if !self.displayed {
+            return;
+        }
+
+        let mut display = DISPLAY.lock();
+        let history = self.scrollback.len;
+        let first_line = history - self.scrollback.view_offset;
+        for row in 0..self.height {
+            let line = first_line + row;
+            let source = if line < history {
+                self.scrollback.line(line)
+            } else {
+                &self.cells[line - history]
+            };
+            for (col, &character) in source[..self.width].iter().enumerate() {
+                display.put_char(self.top + row, self.left + col, character);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.displayed {
+            DISPLAY.lock().flush();
+        }
+    }
+
+    fn redraw(&mut self) {
+        self.draw_all();
+        self.flush();
+        self.update_cursor();
+    }
+
+    fn execute(&mut self, action: Action) {
+        match action {
+            Action::Print(byte) => match byte {
                 // printable ASCII byte or newline
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
+                b'\r' => self.column_pos = 0,
+                b'\t' => self.tab(),
+                BACKSPACE => self.backspace(),
                 // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+                _ => self.write_byte(UNPRINTABLE),
+            },
+            Action::SelectGraphicRendition(params) => self.select_graphic_rendition(params),
+            Action::CursorUp(count) => self.row_pos = self.row_pos.saturating_sub(count),
+            Action::CursorDown(count) => self.row_pos = (self.row_pos + count).min(self.height - 1),
+            Action::CursorForward(count) => self.column_pos = (self.column_pos + count).min(self.width - 1),
+            Action::CursorBack(count) => self.column_pos = self.column_pos.saturating_sub(count),
+            Action::CursorPosition { row, column } => {
+                self.row_pos = (row - 1).min(self.height - 1);
+                self.column_pos = (column - 1).min(self.width - 1);
             }
+            Action::EraseInDisplay(mode) => {
+                let cursor = self.row_pos * self.width + self.column_pos;
+                match mode {
+                    0 => self.blank_cells(cursor, self.width * self.height),
+                    1 => self.blank_cells(0, cursor + 1),
+                    _ => self.blank_cells(0, self.width * self.height)
+                }
+            }
+            Action::EraseInLine(mode) => {
+                let line_start = self.row_pos * self.width;
+                let cursor = line_start + self.column_pos;
+                match mode {
+                    0 => self.blank_cells(cursor, line_start + self.width),
+                    1 => self.blank_cells(line_start, cursor + 1),
+                    _ => self.blank_cells(line_start, line_start + self.width)
+                }
+            }
+        }
+    }
+
+    /**
+     * Applies the SGR (Select Graphic Rendition) parameters: 0 resets, 1 and 22 toggle the bright foreground,
+     * 30-37/90-97 set the foreground, 40-47/100-107 set the background, 39 and 49 restore the default colors.
+     */
+    fn select_graphic_rendition(&mut self, params: Params) {
+        if params.is_empty() {
+            self.color_code = DEFAULT_COLOR_CODE;
+            return;
+        }
+
+        for &param in params.iter() {
+            let color = self.color_code;
+            self.color_code = match param {
+                0 => DEFAULT_COLOR_CODE,
+                1 => color.with_foreground(color.foreground() | BRIGHT_BIT),
+                22 => color.with_foreground(color.foreground() & !BRIGHT_BIT),
+                30..=37 => color.with_foreground(ANSI_COLORS[param - 30] as u8),
+                39 => color.with_foreground(DEFAULT_COLOR_CODE.foreground()),
+                40..=47 => color.with_background(ANSI_COLORS[param - 40] as u8),
+                49 => color.with_background(DEFAULT_COLOR_CODE.background()),
+                90..=97 => color.with_foreground(ANSI_BRIGHT_COLORS[param - 90] as u8),
+                100..=107 => color.with_background(ANSI_BRIGHT_COLORS[param - 100] as u8),
+                _ => color
+            };
+        }
+    }
+
+    /**
+     * Blanks the cells in the [from, to) range, counted row by row from the top left corner.
+     */
+    fn blank_cells(&mut self, from: usize, to: usize) {
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code
+        };
+
+        for cell in from..to.min(self.width * self.height) {
+            let row = cell / self.width;
+            let col = cell % self.width;
+            self.cells[row][col] = blank;
+            self.draw(row, col);
+        }
+    }
+
+    /**
+     * Blanks the whole screen with the current background color and moves the writing position
+     * and the cursor to the top left corner.
+     */
+    pub fn clear_screen(&mut self) {
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code
+        };
+
+        self.show_live_screen();
+        *self.cells = [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        if self.is_live() && self.covers_display() {
+            DISPLAY.lock().clear(blank);
+        } else {
+            // the rest of the display is not the window's own to clear
+            self.draw_all();
+        }
+        self.row_pos = 0;
+        self.column_pos = 0;
+        self.flush();
+        self.update_cursor();
+    }
+
+    pub fn set_color(&mut self, foreground: Colors, background: Colors) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    /**
+     * Goes back to the default white on black.
+     */
+    pub fn reset_color(&mut self) {
+        self.color_code = DEFAULT_COLOR_CODE;
+    }
+
+    /**
+     * Makes the blinking hardware cursor visible at the current writing position.
+     */
+    pub fn show_cursor(&mut self) {
+        if self.displayed {
+            DISPLAY.lock().set_cursor_visible(true);
+        }
+        self.update_cursor();
+    }
+
+    pub fn hide_cursor(&mut self) {
+        if self.displayed {
+            DISPLAY.lock().set_cursor_visible(false);
+        }
+    }
+
+    /**
+     * Sets the shape of the cursor by the first and last scanline it covers inside a character cell.
+     * A character cell is 16 scanlines high in the default 80x25 mode, so (14, 15) gives the usual underline
+     * and (0, 15) gives a full block. In 80x50 mode, cells are 8 scanlines high.
+     * The upper bits of the registers are reserved, so they are preserved.
+     */
+    pub fn set_cursor_shape(&mut self, start_scanline: u8, end_scanline: u8) {
+        let start = crtc_read(CURSOR_START_REGISTER);
+        crtc_write(CURSOR_START_REGISTER, (start & 0xC0) | (start_scanline & 0x1F));
+        let end = crtc_read(CURSOR_END_REGISTER);
+        crtc_write(CURSOR_END_REGISTER, (end & 0xE0) | (end_scanline & 0x1F));
+    }
+
+    /**
+     * Moves the hardware cursor to the cell where the next character will be written.
+     */
+    fn update_cursor(&mut self) {
+        if self.displayed {
+            let col = self.column_pos.min(self.width - 1);
+            DISPLAY.lock().set_cursor(self.top + self.row_pos, self.left + col);
         }
     }
 
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.newline(),
-            byte => {
-                if self.column_pos >= BUFFER_WIDTH || self.row_pos >= BUFFER_HEIGHT {
-                    self.newline();
-                }
-
-            let row = self.row_pos;
-            let col = self.column_pos;
-            let color_code = self.color_code;
+            byte => self.put_glyph(byte)
+        }
+    }
 
-            self.buffer.chars[row][col].write(ScreenChar {
-                ascii_char: byte,
-                color_code
-            });
-            self.column_pos += 1;
-            }
+    /**
+     * Puts the given Code Page 437 glyph at the writing position, wrapping to the next line when the current one is full.
+     * Bytes in the control range are drawn as their glyph too, not interpreted.
+     */
+    fn put_glyph(&mut self, glyph: u8) {
+        if self.column_pos >= self.width || self.row_pos >= self.height {
+            self.newline();
         }
+
+        let row = self.row_pos;
+        let col = self.column_pos;
+        let color_code = self.color_code;
+
+        self.cells[row][col] = ScreenChar {
+            ascii_char: glyph,
+            color_code
+        };
+        self.draw(row, col);
+        self.column_pos += 1;
     }
 
     fn newline(&mut self) {
-        if self.row_pos >= BUFFER_HEIGHT {
-            self.row_pos = 1;
+        if self.row_pos + 1 < self.height {
+            self.row_pos += 1;
+        } else {
+            self.scroll_up();
+            self.row_pos = self.height - 1;
         }
+        self.clear_row(self.row_pos);
+        self.column_pos = 0;
+    }
 
-        for row in self.row_pos..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row -1][col].write(character);
+    /**
+     * Fills the line with spaces up to the next tab stop.
+     */
+    fn tab(&mut self) {
+        let next_stop = (self.column_pos / self.tab_width + 1) * self.tab_width;
+        while self.column_pos < next_stop.min(self.width) {
+            self.write_byte(b' ');
+        }
+    }
+
+    /**
+     * Moves one cell to the left and blanks it. Does nothing at the start of a line.
+     */
+    fn backspace(&mut self) {
+        if self.column_pos == 0 {
+            return;
+        }
+        self.column_pos = self.column_pos.min(self.width) - 1;
+        let start = self.row_pos * self.width + self.column_pos;
+        self.blank_cells(start, start + 1);
+    }
+
+    /**
+     * Places tab stops at every multiple of the given width.
+     */
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width.max(1);
+    }
+
+    /**
+     * Shifts every line up by one, moving the top line into the scrollback.
+     */
+    fn scroll_up(&mut self) {
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code
+        };
+
+        self.scrollback.push(&self.cells[0]);
+        self.cells.copy_within(1..self.height, 0);
+        self.cells[self.height - 1] = [blank; BUFFER_WIDTH];
+        if self.is_live() && self.covers_display() {
+            DISPLAY.lock().scroll(1, blank);
+        } else {
+            self.draw_all();
+        }
+    }
+
+    /**
+     * Adapts the writer to a new display size, it keeps its top left corner and extends to the bottom right of the display.
+     */
+    pub(crate) fn resize(&mut self, width: usize, height: usize) {
+        let region = Region {
+            top: self.top,
+            left: self.left,
+            width: width.saturating_sub(self.left),
+            height: height.saturating_sub(self.top)
+        };
+        self.set_region(region);
+    }
+
+    /**
+     * Moves the window to the given region of the display and redraws it there.
+     * When the window gets shorter, the lines above the writing position are scrolled up, into the scrollback if needed,
+     * so that the most recent output stays visible. The part of the display the window leaves is not cleared.
+     */
+    pub fn set_region(&mut self, region: Region) {
+        let width = region.width.min(BUFFER_WIDTH).max(1);
+        let height = region.height.min(BUFFER_HEIGHT).max(1);
+        self.show_live_screen();
+
+        while self.row_pos >= height {
+            self.scroll_up();
+            self.row_pos -= 1;
+        }
+        // cells that come into view may still hold what was there before the window shrank
+        for row in 0..height {
+            if row >= self.height {
+                self.clear_row(row);
+            } else {
+                for col in self.width..width {
+                    self.cells[row][col] = BLANK_CHAR;
+                }
             }
         }
-        self.clear_row(self.row_pos);
-        self.column_pos = 0;
-        self.row_pos = self.row_pos + 1;
+
+        self.top = region.top;
+        self.left = region.left;
+        self.width = width;
+        self.height = height;
+        self.column_pos = self.column_pos.min(width - 1);
+        self.redraw();
+    }
+
+    pub fn region(&self) -> Region {
+        Region {
+            top: self.top,
+            left: self.left,
+            width: self.width,
+            height: self.height
+        }
+    }
+
+    /**
+     * Scrolls the view back into the history by the given number of lines.
+     */
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        let offset = (self.scrollback.view_offset + lines).min(self.scrollback.len);
+        self.set_view_offset(offset);
+    }
+
+    /**
+     * Scrolls the view towards the live screen by the given number of lines.
+     */
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        let offset = self.scrollback.view_offset.saturating_sub(lines);
+        self.set_view_offset(offset);
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll_view_up(self.height - 1);
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_view_down(self.height - 1);
+    }
+
+    fn show_live_screen(&mut self) {
+        self.set_view_offset(0);
+    }
+
+    /**
+     * Redraws the display as if the history and the live screen were one long text scrolled back by `offset` lines.
+     */
+    fn set_view_offset(&mut self, offset: usize) {
+        if offset == self.scrollback.view_offset {
+            return;
+        }
+        self.scrollback.view_offset = offset;
+        self.redraw();
     }
 
     /**
@@ -139,19 +899,320 @@ impl Writer {
             color_code: self.color_code
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        self.cells[row] = [blank; BUFFER_WIDTH];
+        for col in 0..self.width {
+            self.draw(row, col);
+        }
+    }
+}
+
+/**
+ * What bit 7 of the attribute byte, the high bit of the background color, does.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundMode {
+    // the text blinks, only the 8 dark colors are available as backgrounds, the BIOS default
+    Blink,
+    // all 16 colors are available as backgrounds
+    Bright
+}
+
+// the `blink` feature keeps the BIOS default instead of enabling bright backgrounds at init
+#[cfg(feature = "blink")]
+pub const DEFAULT_BACKGROUND_MODE: BackgroundMode = BackgroundMode::Blink;
+#[cfg(not(feature = "blink"))]
+pub const DEFAULT_BACKGROUND_MODE: BackgroundMode = BackgroundMode::Bright;
+
+/**
+ * Programs the attribute controller to blink text or to show bright backgrounds for the high bit of the background color.
+ */
+pub fn set_background_mode(mode: BackgroundMode) {
+    // the VGA registers are programmed with the writer locked
+    let _writer = WRITER.lock();
+
+    let control = attribute_read(ATTRIBUTE_MODE_CONTROL_REGISTER);
+    let control = match mode {
+        BackgroundMode::Blink => control | BLINK_ENABLE_BIT,
+        BackgroundMode::Bright => control & !BLINK_ENABLE_BIT
+    };
+    attribute_write(ATTRIBUTE_MODE_CONTROL_REGISTER, control);
+}
+
+pub fn background_mode() -> BackgroundMode {
+    let _writer = WRITER.lock();
+
+    if attribute_read(ATTRIBUTE_MODE_CONTROL_REGISTER) & BLINK_ENABLE_BIT != 0 {
+        BackgroundMode::Blink
+    } else {
+        BackgroundMode::Bright
+    }
+}
+
+/**
+ * Switches the display to the given text mode, and resizes the global writer and the virtual terminals to it.
+ * 80x50 needs the whole video memory window and the font plane, which are mapped on demand;
+ * this fails if the page tables covering them are not where the bootloader left them.
+ */
+pub fn set_mode(mode: TextMode) -> Result<(), MapToError> {
+    let mut writer = WRITER.lock();
+
+    match mode {
+        TextMode::Text80x25 => sequencer_write(CHARACTER_MAP_SELECT_REGISTER, FONT_BLOCK_0_SELECT),
+        TextMode::Text80x50 => {
+            map_video_memory()?;
+            load_half_height_font();
+            sequencer_write(CHARACTER_MAP_SELECT_REGISTER, FONT_BLOCK_1_SELECT);
         }
     }
+
+    *MODE.lock() = mode;
+    let char_height = mode.char_height();
+    let max_scan_line = crtc_read(MAX_SCAN_LINE_REGISTER);
+    crtc_write(MAX_SCAN_LINE_REGISTER, (max_scan_line & 0xE0) | (char_height - 1));
+    writer.set_cursor_shape(char_height - 2, char_height - 1);
+
+    writer.resize(mode.columns(), mode.rows());
+    crate::vt::resize(mode.columns(), mode.rows());
+    Ok(())
 }
 
-impl fmt::Write for Writer {
+/**
+ * Identity maps the whole legacy video memory window.
+ * The bootloader maps only the first page of the text buffer, which is enough for 80x25 but not for 80x50 or the font plane.
+ * Its page tables for the low memory are identity mapped themselves and already cover the window,
+ * so they can be edited in place without allocating new ones.
+ */
+fn map_video_memory() -> Result<(), MapToError> {
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = unsafe { &mut *(level_4_frame.start_address().as_u64() as *mut PageTable) };
+    let mut mapper = unsafe { OffsetPageTable::new(level_4_table, VirtAddr::new(0)) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+    let first: PhysFrame = PhysFrame::containing_address(PhysAddr::new(VIDEO_MEMORY_START));
+    let last: PhysFrame = PhysFrame::containing_address(PhysAddr::new(VIDEO_MEMORY_END - 1));
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let frame = unsafe { UnusedPhysFrame::new(frame) };
+        match unsafe { mapper.identity_map(frame, flags, &mut NoFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped) => {}
+            Err(error) => return Err(error)
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Refuses to hand out frames: mapping the video memory must not need new page tables.
+ */
+struct NoFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for NoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+        None
+    }
+}
+
+/**
+ * Builds an 8 scanline font in font block 1 out of the 16 scanline BIOS font in block 0,
+ * by merging every two scanlines of a glyph into one.
+ * There is no BIOS to ask for its 8x8 font in long mode, and this way no font has to be embedded into the kernel.
+ */
+fn load_half_height_font() {
+    // make plane 2, where the glyphs live, appear as plain memory at the start of the window
+    sequencer_write(MAP_MASK_REGISTER, 0x04);
+    sequencer_write(MEMORY_MODE_REGISTER, 0x07);
+    graphics_controller_write(READ_MAP_SELECT_REGISTER, 0x02);
+    graphics_controller_write(GRAPHICS_MODE_REGISTER, 0x00);
+    graphics_controller_write(MISCELLANEOUS_REGISTER, 0x04);
+
+    let plane = FONT_PLANE as *mut u8;
+    for glyph in 0..256 {
+        let source = glyph * GLYPH_SIZE;
+        let target = FONT_BLOCK_1_OFFSET + glyph * GLYPH_SIZE;
+        for scanline in 0..8 {
+            unsafe {
+                let top = core::ptr::read_volatile(plane.add(source + scanline * 2));
+                let bottom = core::ptr::read_volatile(plane.add(source + scanline * 2 + 1));
+                core::ptr::write_volatile(plane.add(target + scanline), top | bottom);
+            }
+        }
+    }
+
+    // back to odd/even text mode addressing at 0xB8000
+    sequencer_write(MAP_MASK_REGISTER, 0x03);
+    sequencer_write(MEMORY_MODE_REGISTER, 0x03);
+    graphics_controller_write(READ_MAP_SELECT_REGISTER, 0x00);
+    graphics_controller_write(GRAPHICS_MODE_REGISTER, 0x10);
+    graphics_controller_write(MISCELLANEOUS_REGISTER, 0x0E);
+}
+
+fn register_read(address_port: u16, register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(address_port);
+    let mut data: Port<u8> = Port::new(address_port + 1);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn register_write(address_port: u16, register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(address_port);
+    let mut data: Port<u8> = Port::new(address_port + 1);
+    unsafe {
+        address.write(register);
+        data.write(value);
+    }
+}
+
+fn crtc_read(register: u8) -> u8 {
+    register_read(CRTC_ADDRESS_PORT, register)
+}
+
+fn crtc_write(register: u8, value: u8) {
+    register_write(CRTC_ADDRESS_PORT, register, value);
+}
+
+fn sequencer_write(register: u8, value: u8) {
+    register_write(SEQUENCER_ADDRESS_PORT, register, value);
+}
+
+fn graphics_controller_write(register: u8, value: u8) {
+    register_write(GRAPHICS_CONTROLLER_ADDRESS_PORT, register, value);
+}
+
+fn attribute_read(register: u8) -> u8 {
+    let mut status: Port<u8> = Port::new(INPUT_STATUS_1_PORT);
+    let mut address: Port<u8> = Port::new(ATTRIBUTE_CONTROLLER_PORT);
+    let mut data: Port<u8> = Port::new(ATTRIBUTE_CONTROLLER_READ_PORT);
+    unsafe {
+        status.read();
+        address.write(register | PALETTE_ADDRESS_SOURCE);
+        data.read()
+    }
+}
+
+fn attribute_write(register: u8, value: u8) {
+    let mut status: Port<u8> = Port::new(INPUT_STATUS_1_PORT);
+    let mut port: Port<u8> = Port::new(ATTRIBUTE_CONTROLLER_PORT);
+    unsafe {
+        status.read();
+        port.write(register | PALETTE_ADDRESS_SOURCE);
+        port.write(value);
+    }
+}
+
+impl fmt::Write for TextWindow {
     fn write_str(&mut self, text: &str) -> fmt::Result {
         self.write_string(text);
         Ok(())
     }
 }
 
+/**
+ * Writes straight to the VGA text buffer, without taking any lock or touching the state of the writers.
+ * Meant for the panic screen: it works whatever the kernel was doing when it panicked,
+ * even if the panic hit while the global writer or the display was locked.
+ */
+pub struct PanicWriter {
+    column_pos: usize,
+    row_pos: usize,
+    width: usize,
+    height: usize,
+    color_code: ColorCode,
+    buffer: &'static mut Buffer
+}
+
+impl PanicWriter {
+    /**
+     * Makes a writer for the panic screen.
+     *
+     * # Safety
+     *
+     * It writes to the video memory behind the back of the display, nothing else may draw on the screen once it is
+     * created.
+     */
+    pub unsafe fn new(color_code: ColorCode) -> PanicWriter {
+        // a panic while the mode was being switched leaves it locked, the mode it was switching from is as good a guess as any
+        let mode = MODE.try_lock().map(|mode| *mode).unwrap_or(TextMode::Text80x25);
+        PanicWriter {
+            column_pos: 0,
+            row_pos: 0,
+            width: mode.columns(),
+            height: mode.rows(),
+            color_code,
+            buffer: &mut *(0xB8000 as *mut Buffer)
+        }
+    }
+
+    /**
+     * Blanks the whole screen with the writer's colors and moves to the top left corner.
+     */
+    pub fn clear(&mut self) {
+        for row in 0..self.height {
+            self.clear_row(row);
+        }
+        self.row_pos = 0;
+        self.column_pos = 0;
+    }
+
+    pub fn hide_cursor(&mut self) {
+        let start = crtc_read(CURSOR_START_REGISTER);
+        crtc_write(CURSOR_START_REGISTER, start | CURSOR_DISABLE_BIT);
+    }
+
+    fn put_glyph(&mut self, glyph: u8) {
+        if self.column_pos >= self.width {
+            self.newline();
+        }
+        self.buffer.chars[self.row_pos][self.column_pos].write(ScreenChar {
+            ascii_char: glyph,
+            color_code: self.color_code
+        });
+        self.column_pos += 1;
+    }
+
+    /**
+     * Moves to the next line, scrolling the screen when the last one is full.
+     * Scrolling reads back the video memory, which is slow, but a panic screen is only written once.
+     */
+    fn newline(&mut self) {
+        self.column_pos = 0;
+        if self.row_pos + 1 < self.height {
+            self.row_pos += 1;
+            return;
+        }
+        for row in 1..self.height {
+            for col in 0..self.width {
+                let character = self.buffer.chars[row][col].read();
+                self.buffer.chars[row - 1][col].write(character);
+            }
+        }
+        self.clear_row(self.height - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code
+        };
+        for col in 0..self.width {
+            self.buffer.chars[row][col].write(blank);
+        }
+    }
+}
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for character in text.chars() {
+            match character {
+                '\n' => self.newline(),
+                character => self.put_glyph(cp437::encode(character).unwrap_or(UNPRINTABLE))
+            }
+        }
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -163,12 +1224,117 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+#[macro_export]
+macro_rules! clear {
+    () => ($crate::vga_buffer::clear_screen());
+}
+
+/**
+ * Writes what the display shows to the given sink, e.g. a serial port, so it can be captured from a headless machine.
+ * The cells are read back from the active backend, see set_backend().
+ * The characters come first, one line per row, translated from Code Page 437; then the attributes,
+ * two hex digits per cell (background, foreground), one line per row.
+ * The console is locked meanwhile, so the sink must not print to it.
+ */
+pub fn dump_screen<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    let _writer = WRITER.lock();
+    let display = DISPLAY.lock();
+    let (width, height) = display.dimensions();
+
+    writeln!(out, "--- screen {}x{} ---", width, height)?;
+    for row in 0..height {
+        for col in 0..width {
+            out.write_char(cp437::decode(display.get_char(row, col).ascii_char))?;
+        }
+        out.write_char('\n')?;
+    }
+    writeln!(out, "--- attributes ---")?;
+    for row in 0..height {
+        for col in 0..width {
+            write!(out, "{:02X}", display.get_char(row, col).color_code.0)?;
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
+
+/**
+ * Makes the display draw on the given backend instead of the VGA text buffer, and redraws the screen on it.
+ * The virtual terminals are resized to the new backend too.
+ */
+pub fn set_backend(backend: &'static mut dyn ConsoleBackend) {
+    let mut writer = WRITER.lock();
+    let (width, height) = backend.dimensions();
+    *DISPLAY.lock() = backend;
+    writer.resize(width, height);
+    crate::vt::resize(width, height);
+    status_bar::redraw();
+}
+
+pub fn clear_screen() {
+    WRITER.lock().clear_screen();
+}
+
+/**
+ * Prints with the given foreground and background colors, then restores the previous color.
+ */
+#[macro_export]
+macro_rules! print_colored {
+    ($foreground:expr, $background:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_colored($crate::vga_buffer::ColorCode::new($foreground, $background), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! println_colored {
+    ($foreground:expr, $background:expr) => ($crate::print_colored!($foreground, $background, "\n"));
+    ($foreground:expr, $background:expr, $($arg:tt)*) => (
+        $crate::print_colored!($foreground, $background, "{}\n", format_args!($($arg)*))
+    );
+}
+
+/**
+ * Runs the block with the given colors set on the global writer, then restores the previous color.
+ * Unlike print_colored!(), the writer is not locked while the block runs, so it can use print!() freely.
+ */
+#[macro_export]
+macro_rules! with_color {
+    ($foreground:expr, $background:expr, $body:block) => {{
+        let previous = $crate::vga_buffer::set_color($crate::vga_buffer::ColorCode::new($foreground, $background));
+        let result = $body;
+        $crate::vga_buffer::set_color(previous);
+        result
+    }};
+}
+
+/**
+ * Sets the color of the global writer and returns the one it replaced.
+ */
+pub fn set_color(color_code: ColorCode) -> ColorCode {
+    let mut writer = WRITER.lock();
+    let previous = writer.color_code();
+    writer.set_color_code(color_code);
+    previous
+}
+
+pub fn reset_color() {
+    WRITER.lock().reset_color();
+}
+
+#[doc(hidden)]
+pub fn _print_colored(color_code: ColorCode, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    let previous = writer.color_code();
+    writer.set_color_code(color_code);
+    writer.write_fmt(args).unwrap();
+    writer.set_color_code(previous);
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts::without_interrupts;
 
-    without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,166 @@
+use crate::frame_allocator;
+use crate::kaslr::{self, Region};
+use crate::memory::{self, Subsystem};
+use crate::paging::{self, PagingError};
+use crate::sync::IrqMutex;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+
+// the kernel's range for regions, a level 4 entry of its own, moved up by the KASLR slide
+const REGIONS_START: u64 = 0x_6666_0000_0000;
+const REGIONS_SIZE: u64 = 0x_0000_8000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+lazy_static! {
+    /** The free parts of the range, as start and end addresses, in address order. */
+    static ref FREE: IrqMutex<Vec<(u64, u64)>> = IrqMutex::new({
+        let start = REGIONS_START + kaslr::slide(Region::Vmm);
+        vec![(start, start + REGIONS_SIZE)]
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    // the regions' virtual address range has no gap big enough
+    NoAddressSpace,
+    // there are no free frames left for the region
+    NoMemory,
+    Paging(PagingError)
+}
+
+impl From<PagingError> for VmmError {
+    fn from(error: PagingError) -> VmmError {
+        VmmError::Paging(error)
+    }
+}
+
+/**
+ * A virtually contiguous range of kernel pages, unmapped when it is dropped, with an unmapped page after it
+ * so running off its end faults. The frames behind it need not be contiguous.
+ */
+#[derive(Debug)]
+pub struct KernelRegion {
+    start: VirtAddr,
+    size: u64,
+    // the pages reserved for it, and how many of them are mapped
+    pages: u64,
+    mapped: u64,
+    // the frames came from the frame allocator, and go back to it
+    owns_frames: bool
+}
+
+impl KernelRegion {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.start.as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&mut self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for KernelRegion {
+    fn drop(&mut self) {
+        let first = Page::containing_address(self.start);
+        for page in Page::range(first, first + self.mapped) {
+            if let Ok(frame) = paging::unmap(page) {
+                if self.owns_frames && frame_allocator::free_frame(frame).is_ok() {
+                    memory::frames_freed(Subsystem::Vmm, 1);
+                }
+            }
+        }
+        release(first.start_address().as_u64(), self.pages + 1);
+    }
+}
+
+/**
+ * Maps size bytes of fresh frames, rounded up to whole pages, into a kernel range of their own, e.g. for a large
+ * driver buffer or a loaded module. Their contents are whatever was left in them. Needs paging::init() and the heap.
+ */
+pub fn alloc_kernel_region(size: u64, flags: PageTableFlags) -> Result<KernelRegion, VmmError> {
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut region = reserve(pages, size, true)?;
+    let first = Page::containing_address(region.start);
+    for page in Page::range(first, first + pages) {
+        let frame = frame_allocator::allocate_frame().ok_or(VmmError::NoMemory)?;
+        // the frame is fresh from the allocator
+        if let Err(error) = unsafe { paging::map_to(page, frame, flags | PageTableFlags::PRESENT) } {
+            let _ = frame_allocator::free_frame(frame);
+            return Err(error.into());
+        }
+        memory::frames_taken(Subsystem::Vmm, 1);
+        region.mapped += 1;
+    }
+    Ok(region)
+}
+
+/**
+ * Maps size bytes of physical memory starting at an address into a kernel range of their own, e.g. a device's
+ * registers with NO_CACHE among the flags, when the physical memory mapping is no good for them.
+ * The frames are not freed with the region.
+ */
+pub fn map_physical(address: PhysAddr, size: u64, flags: PageTableFlags) -> Result<KernelRegion, VmmError> {
+    let first_frame = PhysFrame::containing_address(address);
+    let offset = address.as_u64() - first_frame.start_address().as_u64();
+    let pages = (offset + size.max(1) + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut region = reserve(pages, size, false)?;
+    let first = Page::containing_address(region.start);
+    for (page, frame) in Page::range(first, first + pages).zip(PhysFrame::range(first_frame, first_frame + pages)) {
+        // the caller vouches for the memory
+        unsafe { paging::map_to(page, frame, flags | PageTableFlags::PRESENT)? };
+        region.mapped += 1;
+    }
+    region.start += offset;
+    Ok(region)
+}
+
+/**
+ * Takes the first gap with room for the pages and a guard page after them.
+ */
+fn reserve(pages: u64, size: u64, owns_frames: bool) -> Result<KernelRegion, VmmError> {
+    let length = (pages + 1) * PAGE_SIZE;
+    let mut free = FREE.lock();
+    let index = free.iter().position(|&(start, end)| end - start >= length).ok_or(VmmError::NoAddressSpace)?;
+    let start = free[index].0;
+    if free[index].1 - start == length {
+        free.remove(index);
+    } else {
+        free[index].0 += length;
+    }
+    Ok(KernelRegion {
+        start: VirtAddr::new(start),
+        size,
+        pages,
+        mapped: 0,
+        owns_frames
+    })
+}
+
+/**
+ * Gives back a range, merging it with the gaps right before and after it.
+ */
+fn release(start: u64, pages: u64) {
+    let end = start + pages * PAGE_SIZE;
+    let mut free = FREE.lock();
+    let index = free.iter().position(|&(gap_start, _)| gap_start > start).unwrap_or(free.len());
+    free.insert(index, (start, end));
+    if index + 1 < free.len() && free[index + 1].0 == end {
+        free[index].1 = free[index + 1].1;
+        free.remove(index + 1);
+    }
+    if index > 0 && free[index - 1].1 == start {
+        free[index - 1].1 = free[index].1;
+        free.remove(index);
+    }
+}
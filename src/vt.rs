@@ -0,0 +1,106 @@
+use crate::status_bar;
+use crate::sync::IrqMutex;
+use crate::vga_buffer::{BLANK_SCREEN, OffscreenBuffer, Scrollback, Writer, WRITER};
+use core::fmt;
+use lazy_static::lazy_static;
+
+/**
+ * Number of virtual terminals, selectable with Alt+F1..Alt+F4.
+ */
+pub const VT_COUNT: usize = 4;
+
+static mut VT_MEMORY: [OffscreenBuffer; VT_COUNT] = [BLANK_SCREEN; VT_COUNT];
+static mut VT_SCROLLBACK: [Scrollback; VT_COUNT] = [Scrollback::new(), Scrollback::new(), Scrollback::new(), Scrollback::new()];
+
+lazy_static! {
+    /** The off-screen side of the virtual terminals.
+    * The terminal on the display is always written through vga_buffer::WRITER, so print!() keeps working unchanged.
+    * Its slot here holds a spare writer, swapped with WRITER's screen when another terminal is brought to the front.
+    * Lock order: WRITER first, then TERMINALS.
+    */
+    static ref TERMINALS : IrqMutex<Terminals> = IrqMutex::new({
+        let [vt1, vt2, vt3, vt4] = unsafe { &mut VT_MEMORY };
+        let [history1, history2, history3, history4] = unsafe { &mut VT_SCROLLBACK };
+        Terminals {
+            writers : [
+                Writer::offscreen(vt1, history1),
+                Writer::offscreen(vt2, history2),
+                Writer::offscreen(vt3, history3),
+                Writer::offscreen(vt4, history4)
+            ],
+            active : 0
+        }
+    });
+}
+
+struct Terminals {
+    writers: [Writer; VT_COUNT],
+    active: usize
+}
+
+/**
+ * Returns the index of the terminal currently on the display.
+ */
+pub fn active() -> usize {
+    TERMINALS.lock().active
+}
+
+/**
+ * Brings the given terminal to the display, putting the current one aside with its contents, cursor and colors.
+ * Out of range indices are ignored.
+ */
+pub fn switch_to(index: usize) {
+    if index >= VT_COUNT {
+        return;
+    }
+
+    let mut writer = WRITER.lock();
+    let mut terminals = TERMINALS.lock();
+    let active = terminals.active;
+    if index == active {
+        return;
+    }
+
+    writer.exchange(&mut terminals.writers[active]);
+    writer.exchange(&mut terminals.writers[index]);
+    terminals.active = index;
+    status_bar::set_terminal(index);
+}
+
+/**
+ * Resizes the terminals that are not on the display, called when the text mode changes.
+ */
+pub(crate) fn resize(width: usize, height: usize) {
+    let mut terminals = TERMINALS.lock();
+    for writer in terminals.writers.iter_mut() {
+        writer.resize(width, height);
+    }
+}
+
+#[macro_export]
+macro_rules! vt_print {
+    ($vt:expr, $($arg:tt)*) => ($crate::vt::_print($vt, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! vt_println {
+    ($vt:expr) => ($crate::vt_print!($vt, "\n"));
+    ($vt:expr, $($arg:tt)*) => ($crate::vt_print!($vt, "{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(index: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if index >= VT_COUNT {
+        return;
+    }
+
+    let mut writer = WRITER.lock();
+    let mut terminals = TERMINALS.lock();
+    if index == terminals.active {
+        writer.write_fmt(args).unwrap();
+    } else {
+        terminals.writers[index].write_fmt(args).unwrap();
+    }
+}
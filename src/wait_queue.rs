@@ -0,0 +1,80 @@
+use crate::interrupts;
+use crate::scheduler::{self, ThreadId, ThreadState};
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
+
+/**
+ * Threads waiting for something an interrupt handler or another thread signals, e.g. a key press or a finished
+ * disk transfer. Waiters block instead of spinning, and are woken in the order they came.
+ * A wake-up only makes them check their condition again, so spurious ones are harmless.
+ */
+pub struct WaitQueue {
+    waiters: IrqMutex<Vec<ThreadId>>
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqMutex::new(Vec::new())
+        }
+    }
+
+    /**
+     * Blocks the calling thread until the condition holds, checking it again whenever the queue is woken.
+     * The condition is checked with the queue locked and interrupts disabled, so it must be quick and must not wait
+     * on this queue. Before scheduler::init(), or with nothing else to run, the CPU halts between the checks.
+     */
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        x86_64::instructions::interrupts::without_interrupts(|| loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    if let Some(current) = scheduler::current() {
+                        // it may have been blocked from an earlier check, without being woken since
+                        waiters.retain(|&waiter| waiter != current);
+                        scheduler::wake(current);
+                    }
+                    return;
+                }
+                if let Some(current) = scheduler::block_current() {
+                    if !waiters.contains(&current) {
+                        waiters.push(current);
+                    }
+                }
+            }
+            scheduler::schedule();
+            // back either woken, or right away when nothing else was ready
+            if scheduler::current_state() != Some(ThreadState::Running) {
+                interrupts::enable_and_hlt();
+                x86_64::instructions::interrupts::disable();
+            }
+        });
+    }
+
+    /**
+     * Wakes the longest waiting thread, returns whether there was one.
+     */
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        while !waiters.is_empty() {
+            if scheduler::wake(waiters.remove(0)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /**
+     * Wakes every waiting thread, returns how many there were.
+     */
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        waiters.drain(..).filter(|&waiter| scheduler::wake(waiter)).count()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> WaitQueue {
+        WaitQueue::new()
+    }
+}